@@ -69,5 +69,171 @@ CREATE TABLE IF NOT EXISTS bookmark (
     note TEXT NOT NULL
 ) STRICT;
 
+CREATE TABLE IF NOT EXISTS lexicon (
+    word TEXT NOT NULL PRIMARY KEY COLLATE NOCASE,
+    pronunciation TEXT NOT NULL
+) STRICT;
+
+-- Domain terms given to Whisper as a prompt when dictating
+CREATE TABLE IF NOT EXISTS vocabulary (
+    term TEXT NOT NULL PRIMARY KEY COLLATE NOCASE,
+    createdAt TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP
+) STRICT;
+
+-- Replacements of phrases in the text that is read aloud, e.g. "w/" -> "with"
+CREATE TABLE IF NOT EXISTS speechRule (
+    pattern TEXT NOT NULL PRIMARY KEY COLLATE NOCASE,
+    replacement TEXT NOT NULL
+) STRICT;
+
+CREATE TABLE IF NOT EXISTS earcon (
+    event TEXT NOT NULL PRIMARY KEY,
+    sound TEXT NOT NULL
+) STRICT;
+
+CREATE TABLE IF NOT EXISTS conversationSummary (
+    conversationId INTEGER NOT NULL PRIMARY KEY REFERENCES message(id) ON DELETE CASCADE,
+    upToMessageId INTEGER NOT NULL REFERENCES message(id) ON DELETE CASCADE,  -- the last message covered by the summary
+    summary TEXT NOT NULL,
+    modifiedAt TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP
+) STRICT;
+
+CREATE TABLE IF NOT EXISTS promptTemplate (
+    id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    name TEXT NOT NULL,
+    content TEXT NOT NULL,  -- {{variable}} is replaced when rendered
+    createdAt TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    modifiedAt TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP
+) STRICT;
+
+CREATE TABLE IF NOT EXISTS usage (
+    day TEXT NOT NULL,  -- YYYY-MM-DD in UTC
+    conversationId INTEGER NOT NULL,  -- the root message, or 0 for requests outside a conversation. Kept when the conversation is deleted.
+    provider TEXT NOT NULL,
+    model TEXT NOT NULL,
+    promptTokens INTEGER NOT NULL,
+    completionTokens INTEGER NOT NULL,
+    requests INTEGER NOT NULL,
+    estimatedRequests INTEGER NOT NULL,
+    PRIMARY KEY (day, conversationId, provider, model)
+) STRICT;
+
+CREATE TABLE IF NOT EXISTS mcpServer (
+    id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    commandOrUrl TEXT NOT NULL,  -- a command that speaks MCP over stdio, or the URL of a Streamable HTTP endpoint
+    name TEXT NOT NULL,  -- reported by the server
+    createdAt TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP
+) STRICT;
+
+CREATE TABLE IF NOT EXISTS knowledgeDocument (
+    id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    path TEXT NOT NULL UNIQUE,
+    model TEXT NOT NULL,  -- the embedding model, e.g. "openai:text-embedding-3-small"
+    indexedAt TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP
+) STRICT;
+
+CREATE TABLE IF NOT EXISTS knowledgeChunk (
+    documentId INTEGER NOT NULL REFERENCES knowledgeDocument(id) ON DELETE CASCADE,
+    chunkIndex INTEGER NOT NULL,
+    content TEXT NOT NULL,
+    embedding BLOB NOT NULL,  -- little-endian f32 normalized to unit length
+    PRIMARY KEY (documentId, chunkIndex)
+) STRICT;
+
+CREATE TABLE IF NOT EXISTS messageEmbedding (
+    messageId INTEGER NOT NULL REFERENCES message(id) ON DELETE CASCADE,
+    model TEXT NOT NULL,  -- the embedding model, e.g. "openai:text-embedding-3-small"
+    embedding BLOB NOT NULL,  -- little-endian f32 normalized to unit length
+    PRIMARY KEY (messageId, model)
+) STRICT;
+
+CREATE TRIGGER IF NOT EXISTS trigger_message_embedding_update AFTER UPDATE OF content ON message
+BEGIN
+    DELETE FROM messageEmbedding WHERE messageId = NEW.id;
+END;
+
+CREATE TABLE IF NOT EXISTS pendingRequest (
+    requestId INTEGER NOT NULL PRIMARY KEY,
+    request TEXT NOT NULL,  -- JSON of the arguments of start_chat_completion, without the secret key, which is stored in the keyring
+    createdAt TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP
+) STRICT;
+
+CREATE TABLE IF NOT EXISTS folder (
+    id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    name TEXT NOT NULL,
+    parent INTEGER REFERENCES folder(id) ON DELETE CASCADE,
+    createdAt TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP
+) STRICT;
+
+-- The speech settings of a conversation. NULL uses the global setting.
+CREATE TABLE IF NOT EXISTS conversationSettings (
+    conversationId INTEGER NOT NULL PRIMARY KEY REFERENCES message(id) ON DELETE CASCADE,
+    voice TEXT,
+    rate REAL,
+    pitch REAL,
+    autoSpeak INTEGER
+) STRICT;
+
+CREATE TABLE IF NOT EXISTS conversationFolder (
+    conversationId INTEGER NOT NULL PRIMARY KEY REFERENCES message(id) ON DELETE CASCADE,
+    folderId INTEGER NOT NULL REFERENCES folder(id) ON DELETE CASCADE
+) STRICT;
+
+CREATE TABLE IF NOT EXISTS conversationTag (
+    conversationId INTEGER NOT NULL REFERENCES message(id) ON DELETE CASCADE,
+    tag TEXT NOT NULL COLLATE NOCASE,
+    PRIMARY KEY (conversationId, tag)
+) STRICT;
+
+-- Messages always sent to the model, even when older messages are dropped to fit in the context window.
+CREATE TABLE IF NOT EXISTS pinnedMessage (
+    messageId INTEGER NOT NULL PRIMARY KEY REFERENCES message(id) ON DELETE CASCADE,
+    pinnedAt TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP
+) STRICT;
+
+-- The branch of each conversation shown by default, instead of the latest child at every depth.
+CREATE TABLE IF NOT EXISTS selectedBranch (
+    conversationId INTEGER NOT NULL PRIMARY KEY REFERENCES message(id) ON DELETE CASCADE,
+    leafId INTEGER NOT NULL REFERENCES message(id) ON DELETE CASCADE
+) STRICT;
+
+-- Prompt templates run on a cron schedule. lastRunAt is the local minute of the last run, "YYYY-MM-DD HH:MM".
+CREATE TABLE IF NOT EXISTS scheduledPrompt (
+    id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    cron TEXT NOT NULL,
+    templateId INTEGER NOT NULL REFERENCES promptTemplate(id) ON DELETE CASCADE,
+    conversationId INTEGER REFERENCES message(id) ON DELETE CASCADE,
+    speak INTEGER NOT NULL DEFAULT 0,
+    lastRunAt TEXT,
+    createdAt TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP
+) STRICT;
+
+CREATE VIRTUAL TABLE IF NOT EXISTS messageFTS USING fts5(
+    content,
+    content='message',
+    content_rowid='id'
+);
+
+CREATE TRIGGER IF NOT EXISTS trigger_message_fts_insert AFTER INSERT ON message
+BEGIN
+    INSERT INTO messageFTS (rowid, content) VALUES (NEW.id, NEW.content);
+END;
+
+CREATE TRIGGER IF NOT EXISTS trigger_message_fts_delete AFTER DELETE ON message
+BEGIN
+    INSERT INTO messageFTS (messageFTS, rowid, content) VALUES ('delete', OLD.id, OLD.content);
+END;
+
+CREATE TRIGGER IF NOT EXISTS trigger_message_fts_update AFTER UPDATE OF content ON message
+BEGIN
+    INSERT INTO messageFTS (messageFTS, rowid, content) VALUES ('delete', OLD.id, OLD.content);
+    INSERT INTO messageFTS (rowid, content) VALUES (NEW.id, NEW.content);
+END;
+
 INSERT OR IGNORE INTO config VALUES ('budget', 1.0);
 INSERT OR IGNORE INTO config VALUES ('maxCostPerMessage', 0.015);
+INSERT OR IGNORE INTO config VALUES ('ttsCacheMaxBytes', 209715200);
+INSERT OR IGNORE INTO config VALUES ('dictationKeepWav', 0);
+INSERT OR IGNORE INTO config VALUES ('dictationKeepLastRecording', 0);
+INSERT OR IGNORE INTO config VALUES ('dictationAutoGain', 0);
+INSERT OR IGNORE INTO config VALUES ('dictationMaxGainDb', 20);
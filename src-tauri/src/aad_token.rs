@@ -0,0 +1,231 @@
+//! Azure Active Directory tokens for Azure OpenAI, refreshed before they expire and when a request is rejected with 401.
+//!
+//! The credential is set with `set_azure_ad_credential` and stored in the OS credential store, namespaced by the profile,
+//! from which it is restored at startup and when the profile is switched. Without a credential, the token passed to
+//! `start_chat_completion` is used as is.
+
+use crate::{profile, AppState, Error};
+use serde_json::Value;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+const SCOPE: &str = "https://cognitiveservices.azure.com/.default";
+const RESOURCE: &str = "https://cognitiveservices.azure.com";
+const KEYRING_ENTRY: &str = "azureAdCredential";
+
+/// Tokens are refreshed this long before they expire, so that they do not expire during a request.
+const REFRESH_MARGIN: Duration = Duration::from_secs(5 * 60);
+/// Used when the Azure CLI does not report the expiry.
+const DEFAULT_TOKEN_LIFETIME: Duration = Duration::from_secs(30 * 60);
+
+#[derive(serde::Serialize, serde::Deserialize, Clone)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub(crate) enum AadCredential {
+    /// A service principal (OAuth 2.0 client credentials flow).
+    #[serde(rename_all = "camelCase")]
+    ClientSecret {
+        tenant_id: String,
+        client_id: String,
+        client_secret: String,
+    },
+    /// The account logged in with `az login`.
+    AzureCli,
+}
+
+impl std::fmt::Debug for AadCredential {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AadCredential::ClientSecret {
+                tenant_id,
+                client_id,
+                ..
+            } => write!(f, "ClientSecret({tenant_id}, {client_id})"),
+            AadCredential::AzureCli => write!(f, "AzureCli"),
+        }
+    }
+}
+
+/// The credential without its secret, for the settings.
+#[derive(serde::Serialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub(crate) enum AadCredentialInfo {
+    #[serde(rename_all = "camelCase")]
+    ClientSecret {
+        tenant_id: String,
+        client_id: String,
+    },
+    AzureCli,
+}
+
+#[derive(Default)]
+pub(crate) struct AadTokenProvider {
+    credential: Mutex<Option<AadCredential>>,
+    /// Held while a token is fetched, so that concurrent requests share the refresh.
+    token: tokio::sync::Mutex<Option<(String, Instant)>>,
+}
+
+async fn fetch_client_secret_token(
+    tenant_id: &str,
+    client_id: &str,
+    client_secret: &str,
+) -> Result<(String, Duration), Error> {
+    let res = reqwest::Client::new()
+        .post(format!(
+            "https://login.microsoftonline.com/{tenant_id}/oauth2/v2.0/token"
+        ))
+        .form(&[
+            ("grant_type", "client_credentials"),
+            ("client_id", client_id),
+            ("client_secret", client_secret),
+            ("scope", SCOPE),
+        ])
+        .send()
+        .await?;
+    if res.status() != 200 {
+        return Err(Error::StatusIsNot200(format!(
+            "{}: {}",
+            res.status(),
+            res.text().await?
+        )));
+    }
+    let data: Value = serde_json::from_str(&res.text().await?)?;
+    let token = data
+        .get("access_token")
+        .and_then(Value::as_str)
+        .ok_or_else(|| Error::StringError(format!("Unexpected token response: {data}")))?;
+    let expires_in = data
+        .get("expires_in")
+        .and_then(Value::as_u64)
+        .map(Duration::from_secs)
+        .unwrap_or(DEFAULT_TOKEN_LIFETIME);
+    Ok((token.to_owned(), expires_in))
+}
+
+fn fetch_azure_cli_token() -> Result<(String, Duration), Error> {
+    let output = std::process::Command::new(if cfg!(windows) { "az.cmd" } else { "az" })
+        .args([
+            "account",
+            "get-access-token",
+            "--resource",
+            RESOURCE,
+            "--output",
+            "json",
+        ])
+        .output()?;
+    if !output.status.success() {
+        return Err(Error::StringError(
+            String::from_utf8_lossy(&output.stderr).into_owned(),
+        ));
+    }
+    let data: Value = serde_json::from_slice(&output.stdout)?;
+    let token = data
+        .get("accessToken")
+        .and_then(Value::as_str)
+        .ok_or_else(|| {
+            Error::StringError("`az account get-access-token` returned no token".to_owned())
+        })?;
+    // Older versions only report `expiresOn` in the local time zone, which is not parsed.
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let expires_in = data
+        .get("expires_on")
+        .and_then(Value::as_u64)
+        .map(|expires_on| Duration::from_secs(expires_on.saturating_sub(now)))
+        .unwrap_or(DEFAULT_TOKEN_LIFETIME);
+    Ok((token.to_owned(), expires_in))
+}
+
+impl AadTokenProvider {
+    pub(crate) fn has_credential(&self) -> Result<bool, Error> {
+        Ok(self.credential.lock()?.is_some())
+    }
+
+    /// Replaces the credential and discards the token obtained with the previous one.
+    pub(crate) async fn set_credential(
+        &self,
+        credential: Option<AadCredential>,
+    ) -> Result<(), Error> {
+        *self.credential.lock()? = credential;
+        *self.token.lock().await = None;
+        Ok(())
+    }
+
+    /// Returns the cached token, or a new one if it is about to expire or `force_refresh` is set.
+    pub(crate) async fn token(&self, force_refresh: bool) -> Result<String, Error> {
+        let credential = self
+            .credential
+            .lock()?
+            .clone()
+            .ok_or_else(|| Error::StringError("No Azure AD credential is set".to_owned()))?;
+        let mut token = self.token.lock().await;
+        if let Some((token, expires_at)) = &*token {
+            if !force_refresh && Instant::now() + REFRESH_MARGIN < *expires_at {
+                return Ok(token.clone());
+            }
+        }
+        let (new_token, expires_in) = match credential {
+            AadCredential::ClientSecret {
+                tenant_id,
+                client_id,
+                client_secret,
+            } => fetch_client_secret_token(&tenant_id, &client_id, &client_secret).await?,
+            AadCredential::AzureCli => tokio::task::spawn_blocking(fetch_azure_cli_token).await??,
+        };
+        tracing::info!(
+            expires_in_secs = expires_in.as_secs(),
+            "refreshed the Azure AD token"
+        );
+        *token = Some((new_token.clone(), Instant::now() + expires_in));
+        Ok(new_token)
+    }
+}
+
+fn load_credential() -> Result<Option<AadCredential>, Error> {
+    match profile::keyring_entry(KEYRING_ENTRY)?.get_password() {
+        Ok(json) => Ok(Some(serde_json::from_str(&json)?)),
+        Err(keyring::Error::NoEntry) => Ok(None),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Restores the credential stored for the active profile.
+pub(crate) async fn restore(state: &AppState) -> Result<(), Error> {
+    state.aad_token.set_credential(load_credential()?).await
+}
+
+/// Sets the credential used to obtain Azure AD tokens for Azure OpenAI, or removes it if None.
+#[tauri::command]
+#[tracing::instrument(skip(state), err)]
+pub(crate) async fn set_azure_ad_credential(
+    credential: Option<AadCredential>,
+    state: tauri::State<'_, AppState>,
+) -> Result<(), Error> {
+    let entry = profile::keyring_entry(KEYRING_ENTRY)?;
+    match &credential {
+        Some(credential) => entry.set_password(&serde_json::to_string(credential)?)?,
+        None => match entry.delete_password() {
+            Ok(()) | Err(keyring::Error::NoEntry) => {}
+            Err(err) => return Err(err.into()),
+        },
+    }
+    state.aad_token.set_credential(credential).await
+}
+
+/// Returns the stored credential without its secret, or None if no credential is set.
+#[tauri::command]
+#[tracing::instrument(err)]
+pub(crate) fn get_azure_ad_credential() -> Result<Option<AadCredentialInfo>, Error> {
+    Ok(load_credential()?.map(|credential| match credential {
+        AadCredential::ClientSecret {
+            tenant_id,
+            client_id,
+            ..
+        } => AadCredentialInfo::ClientSecret {
+            tenant_id,
+            client_id,
+        },
+        AadCredential::AzureCli => AadCredentialInfo::AzureCli,
+    }))
+}
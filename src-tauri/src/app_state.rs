@@ -0,0 +1,218 @@
+//! The state shared by the commands, registered with `.manage()` and accessed through `tauri::State<AppState>`.
+
+use crate::aad_token::AadTokenProvider;
+use crate::audio_engine::AudioEngine;
+use crate::azure_tts::AzureVoice;
+use crate::local_model::LocalModel;
+use crate::mcp::McpClients;
+use crate::mic_processing::MicProcessingOptions;
+use crate::mixer::Mixer;
+use crate::realtime::RealtimeSession;
+use crate::recording::LastRecording;
+use crate::{encryption, AtomicF32, Error};
+use sqlx::pool::PoolConnection;
+use sqlx::sqlite::{SqliteConnectOptions, SqlitePoolOptions};
+use sqlx::{Sqlite, SqlitePool};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicI64};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+pub(crate) struct AppState {
+    pub(crate) db_pool: DbPool,
+    pub(crate) config: Config,
+    /// Shared with the threads that play and record audio.
+    pub(crate) audio: Arc<AudioState>,
+    /// The result of `list_azure_voices` and when it was fetched, by region.
+    pub(crate) azure_voices: Mutex<HashMap<String, (Instant, Vec<AzureVoice>)>>,
+    pub(crate) aad_token: AadTokenProvider,
+    /// Set to stop the task started by `set_clipboard_watch`.
+    pub(crate) clipboard_watch: Mutex<Option<Arc<AtomicBool>>>,
+    pub(crate) mcp: McpClients,
+    pub(crate) local_model: LocalModel,
+}
+
+impl AppState {
+    pub(crate) fn new(db_path: PathBuf) -> Self {
+        Self {
+            db_pool: DbPool::new(db_path),
+            config: Config::default(),
+            audio: Arc::new(AudioState::default()),
+            azure_voices: Mutex::new(HashMap::new()),
+            aad_token: AadTokenProvider::default(),
+            clipboard_watch: Mutex::new(None),
+            mcp: McpClients::default(),
+            local_model: LocalModel::default(),
+        }
+    }
+}
+
+/// A connection pool to the database, which is opened on the first use and reopened when the passphrase or the profile
+/// changes.
+pub(crate) struct DbPool {
+    path: Mutex<PathBuf>,
+    passphrase: Mutex<Option<String>>,
+    pool: Mutex<Option<SqlitePool>>,
+}
+
+impl DbPool {
+    fn new(path: PathBuf) -> Self {
+        Self {
+            path: Mutex::new(path),
+            passphrase: Mutex::new(None),
+            pool: Mutex::new(None),
+        }
+    }
+
+    pub(crate) fn path(&self) -> Result<PathBuf, Error> {
+        Ok(self.path.lock()?.clone())
+    }
+
+    pub(crate) fn passphrase(&self) -> Result<Option<String>, Error> {
+        Ok(self.passphrase.lock()?.clone())
+    }
+
+    pub(crate) async fn acquire(&self) -> Result<PoolConnection<Sqlite>, Error> {
+        let pool = {
+            let mut pool = self.pool.lock()?;
+            match &*pool {
+                Some(pool) => pool.clone(),
+                None => {
+                    let path = self.path()?;
+                    let options = SqliteConnectOptions::from_str(&format!(
+                        "sqlite://{}?mode=rwc", // rwc = create file if not exists
+                        path.to_str().ok_or_else(|| Error::StringError(format!(
+                            "{:?}.to_str() failed",
+                            path
+                        )))?
+                    ))?;
+                    let options = match self.passphrase()? {
+                        Some(passphrase) => options.pragma("key", encryption::quote(&passphrase)),
+                        None => options,
+                    };
+                    pool.insert(SqlitePoolOptions::new().connect_lazy_with(options))
+                        .clone()
+                }
+            }
+        };
+        Ok(pool.acquire().await?)
+    }
+
+    /// Waits for the connections to be returned and closes them. The pool is reopened on the next `acquire()`.
+    pub(crate) async fn close(&self) -> Result<(), Error> {
+        let pool = self.pool.lock()?.take();
+        if let Some(pool) = pool {
+            pool.close().await;
+        }
+        Ok(())
+    }
+
+    /// Applies the passphrase with `PRAGMA key` to the connections opened after this call.
+    pub(crate) async fn set_passphrase(&self, passphrase: Option<String>) -> Result<(), Error> {
+        let pool = self.pool.lock()?.take();
+        *self.passphrase.lock()? = passphrase;
+        if let Some(pool) = pool {
+            pool.close().await;
+        }
+        Ok(())
+    }
+
+    /// Closes the database and opens the one at the path on the next `acquire()`, without a passphrase.
+    pub(crate) async fn set_path(&self, path: PathBuf) -> Result<(), Error> {
+        let pool = self.pool.lock()?.take();
+        *self.path.lock()? = path;
+        *self.passphrase.lock()? = None;
+        if let Some(pool) = pool {
+            pool.close().await;
+        }
+        Ok(())
+    }
+}
+
+/// The settings that are owned by the backend, as opposed to the ones in the config table.
+#[derive(Default)]
+pub(crate) struct Config {
+    pub(crate) minimize_to_tray: AtomicBool,
+}
+
+pub(crate) struct AudioState {
+    pub(crate) engine: AudioEngine,
+    /// Incremented to interrupt the audio being played.
+    pub(crate) playback_counter: AtomicI64,
+    /// Incremented to stop the recording.
+    pub(crate) recording_counter: AtomicI64,
+    /// The recordings whose counter is less than or equal to this value are discarded.
+    pub(crate) recording_canceled: AtomicI64,
+    /// [0, infty] -> volume
+    /// -1 -> transcribing
+    pub(crate) input_loudness: AtomicF32,
+    /// Retained until the next recording starts if the `dictationKeepLastRecording` config is set.
+    pub(crate) last_recording: Mutex<Option<LastRecording>>,
+    pub(crate) mic_processing: Mutex<MicProcessingOptions>,
+    /// The voice conversation started by `start_realtime_session`.
+    pub(crate) realtime: Mutex<Option<RealtimeSession>>,
+    pub(crate) mixer: Mixer,
+    /// Set to stop the detector started by `enable_wake_word`.
+    pub(crate) wake_word: Mutex<Option<Arc<AtomicBool>>>,
+}
+
+impl Default for AudioState {
+    fn default() -> Self {
+        Self {
+            engine: AudioEngine::default(),
+            playback_counter: AtomicI64::new(0),
+            recording_counter: AtomicI64::new(0),
+            recording_canceled: AtomicI64::new(-1),
+            input_loudness: AtomicF32::new(0.0),
+            last_recording: Mutex::new(None),
+            mic_processing: Mutex::new(MicProcessingOptions::default()),
+            realtime: Mutex::new(None),
+            mixer: Mixer::default(),
+            wake_word: Mutex::new(None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sqlx::Row;
+
+    async fn count(conn: &mut sqlx::SqliteConnection, query: &str) -> i64 {
+        sqlx::query(query).fetch_one(conn).await.unwrap().get(0)
+    }
+
+    #[tokio::test]
+    async fn creates_the_tables_in_a_temporary_database() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = AppState::new(dir.path().join("chatgpt.db"));
+        crate::create_tables(&state.db_pool).await.unwrap();
+        // The migrations are applied only once, so opening the database again does not fail on them.
+        crate::create_tables(&state.db_pool).await.unwrap();
+
+        let mut conn = state.db_pool.acquire().await.unwrap();
+        assert!(count(&mut conn, "PRAGMA user_version").await > 0);
+        sqlx::query("INSERT INTO message (parent, role, status, content, syncId) VALUES (NULL, 'root', 0, 'hello', 'a')")
+            .execute(&mut conn)
+            .await
+            .unwrap();
+        assert_eq!(
+            count(
+                &mut conn,
+                "SELECT count(*) FROM messageFTS WHERE messageFTS MATCH 'hello'"
+            )
+            .await,
+            1
+        );
+        sqlx::query("DELETE FROM message")
+            .execute(&mut conn)
+            .await
+            .unwrap();
+        assert_eq!(
+            count(&mut conn, "SELECT count(*) FROM syncTombstone").await,
+            1
+        );
+    }
+}
@@ -0,0 +1,135 @@
+//! Extracts the text of documents so that they can be attached to a prompt.
+//!
+//! Files dropped on a window are extracted in the background, and the window receives `file-drop://extracted`
+//! ({ path, text, tokenCount }) for each of them, or `file-drop://error` ({ path, message }).
+
+use crate::{Error, CL100K_BASE};
+use std::path::{Path, PathBuf};
+use tauri::{FileDropEvent, GlobalWindowEvent, WindowEvent};
+
+#[derive(serde::Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct ExtractedText {
+    path: PathBuf,
+    text: String,
+    token_count: usize,
+}
+
+#[derive(serde::Serialize, Clone)]
+struct ExtractionError {
+    path: PathBuf,
+    message: String,
+}
+
+fn extract_docx(path: &Path) -> Result<String, Error> {
+    use docx_rs::{
+        DocumentChild, ParagraphChild, RunChild, TableCellContent, TableChild, TableRowChild,
+    };
+
+    fn paragraph(p: &docx_rs::Paragraph) -> String {
+        p.children
+            .iter()
+            .filter_map(|child| match child {
+                ParagraphChild::Run(run) => Some(run),
+                _ => None,
+            })
+            .flat_map(|run| &run.children)
+            .map(|child| match child {
+                RunChild::Text(t) => t.text.as_str(),
+                RunChild::Tab(_) => "\t",
+                RunChild::Break(_) => "\n",
+                _ => "",
+            })
+            .collect()
+    }
+
+    fn table(t: &docx_rs::Table, lines: &mut Vec<String>) {
+        for TableChild::TableRow(row) in &t.rows {
+            let mut cells = vec![];
+            for TableRowChild::TableCell(cell) in &row.cells {
+                let mut cell_lines = vec![];
+                for content in &cell.children {
+                    match content {
+                        TableCellContent::Paragraph(p) => cell_lines.push(paragraph(p)),
+                        TableCellContent::Table(t) => table(t, &mut cell_lines),
+                        _ => {}
+                    }
+                }
+                cells.push(cell_lines.join(" "));
+            }
+            lines.push(cells.join("\t"));
+        }
+    }
+
+    let docx = docx_rs::read_docx(&std::fs::read(path)?)
+        .map_err(|err| Error::StringError(format!("Failed to read the document: {err}")))?;
+    let mut lines = vec![];
+    for child in &docx.document.children {
+        match child {
+            DocumentChild::Paragraph(p) => lines.push(paragraph(p)),
+            DocumentChild::Table(t) => table(t, &mut lines),
+            _ => {}
+        }
+    }
+    Ok(lines.join("\n"))
+}
+
+/// Returns the text of a .txt, .md, .pdf, or .docx file.
+pub(crate) fn extract_text(path: &Path) -> Result<String, Error> {
+    let extension = path
+        .extension()
+        .and_then(|e| e.to_str())
+        .unwrap_or_default()
+        .to_lowercase();
+    let text = match extension.as_str() {
+        "txt" | "md" | "markdown" => std::fs::read_to_string(path)?,
+        "pdf" => pdf_extract::extract_text(path)
+            .map_err(|err| Error::StringError(format!("Failed to read the PDF: {err}")))?,
+        "docx" => extract_docx(path)?,
+        _ => {
+            return Err(Error::StringError(format!(
+                "Unsupported file type: {}",
+                path.display()
+            )))
+        }
+    };
+    Ok(text.trim().to_owned())
+}
+
+fn extract(path: &Path) -> Result<ExtractedText, Error> {
+    let text = extract_text(path)?;
+    Ok(ExtractedText {
+        path: path.to_owned(),
+        token_count: CL100K_BASE.encode_with_special_tokens(&text).len(),
+        text,
+    })
+}
+
+/// Extracts the files dropped on a window and sends the results to it.
+pub(crate) fn handle_file_drop(event: &GlobalWindowEvent) {
+    let WindowEvent::FileDrop(FileDropEvent::Dropped(paths)) = event.event() else {
+        return;
+    };
+    for path in paths.clone() {
+        let window = event.window().clone();
+        tauri::async_runtime::spawn_blocking(move || {
+            let _ = match extract(&path) {
+                Ok(extracted) => window.emit("file-drop://extracted", extracted),
+                Err(err) => window.emit(
+                    "file-drop://error",
+                    ExtractionError {
+                        path,
+                        message: err.to_string(),
+                    },
+                ),
+            };
+        });
+    }
+}
+
+/// Returns the text of a .txt, .md, .pdf, or .docx file and its number of tokens.
+#[tauri::command]
+#[tracing::instrument(err)]
+pub(crate) async fn extract_file_text(path: PathBuf) -> Result<ExtractedText, Error> {
+    tauri::async_runtime::spawn_blocking(move || extract(&path)).await?
+}
@@ -0,0 +1,688 @@
+//! A long-lived thread that owns the only `rodio::OutputStream` and the sinks played on it.
+//!
+//! Opening an output stream is slow and occasionally fails on WASAPI, so it is opened on the first playback and kept open.
+//! It is opened again when the default output device changes, which is checked every `DEVICE_CHECK_INTERVAL`, and when a
+//! sink cannot be created on it. The sinks are moved to the new stream, but the sources queued on the old one are lost.
+//! `OutputStream` is not Send, so the other threads control the sinks through a command channel.
+//!
+//! The speech playbacks report their progress to the listeners added with `add_listener`, which emit `audio://playback`
+//! and update the media controls of the OS.
+//! Speech synthesized sentence by sentence is played through a `SpeechQueue`, which joins the clips without gaps.
+//!
+//! The speech being played can be navigated with `navigate`: the playback takes the request on its next tick and replays
+//! its audio from the new position, so the decoded audio is kept until the playback ends.
+
+use crate::mixer::SpeechMeter;
+use crate::{AtomicF32, Error};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// The silence kept at each end of a clip in a `SpeechQueue`. The rest is trimmed, along with the padding of the MP3.
+const KEPT_SILENCE: Duration = Duration::from_millis(100);
+/// A sample below this magnitude (about -50 dBFS) is silent.
+const SILENCE_THRESHOLD: f32 = 0.003;
+/// The overlap of consecutive clips in a `SpeechQueue`.
+const CROSSFADE: Duration = Duration::from_millis(30);
+/// The fade at the beginning and the end of the speech, which avoids clicks.
+const FADE: Duration = Duration::from_millis(5);
+/// Skipping back within this time from the start of a sentence goes to the previous sentence instead of restarting it.
+const SENTENCE_RESTART_THRESHOLD: Duration = Duration::from_millis(1500);
+/// How often the default output device is checked, so that the playback follows it, e.g. to headphones plugged in.
+const DEVICE_CHECK_INTERVAL: Duration = Duration::from_secs(2);
+
+type BoxedSource = Box<dyn rodio::Source<Item = f32> + Send>;
+
+enum Command {
+    CreateSink {
+        id: u64,
+        pending: Arc<AtomicUsize>,
+        reply: Sender<Result<(), Error>>,
+    },
+    Append {
+        id: u64,
+        source: BoxedSource,
+        pending: Arc<AtomicUsize>,
+    },
+    /// Stops the sources of the sink and keeps it for the following ones.
+    Clear(u64),
+    /// Pauses every sink, including the ones created until `Resume`.
+    Pause,
+    Resume,
+    SetVolume(u64, f32),
+    /// Changes the speed and the pitch.
+    SetSpeed(u64, f32),
+    RemoveSink(u64),
+}
+
+#[derive(serde::Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct PlaybackStatus {
+    /// False once the playback has ended or been stopped.
+    pub(crate) playing: bool,
+    pub(crate) paused: bool,
+    pub(crate) position_ms: u64,
+    pub(crate) duration_ms: u64,
+    pub(crate) speed: f32,
+    /// The name of the conversation whose message is spoken.
+    pub(crate) title: Option<String>,
+    /// The sentence being spoken, if the speech is played sentence by sentence.
+    pub(crate) sentence: Option<String>,
+}
+
+type Listener = Box<dyn Fn(&PlaybackStatus) + Send + Sync>;
+
+/// A request to move within the speech being played.
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum Navigation {
+    Seek(Duration),
+    /// Goes to the start of the next sentence, or to the start of the current or previous sentence if `forward` is false.
+    SkipSentence {
+        forward: bool,
+    },
+}
+
+pub(crate) struct AudioEngine {
+    sender: Sender<Command>,
+    next_id: AtomicU64,
+    paused: AtomicBool,
+    /// The speed of speech playbacks.
+    speed: AtomicF32,
+    listeners: Mutex<Vec<Listener>>,
+    /// The navigation requested for the speech being played, which has not been taken yet.
+    navigation: Mutex<Option<Navigation>>,
+    /// The directory of the audio log while the speech is written to files instead of being played. See `audio_log`.
+    file_output: Mutex<Option<PathBuf>>,
+}
+
+impl Default for AudioEngine {
+    fn default() -> Self {
+        let (sender, receiver) = channel();
+        std::thread::Builder::new()
+            .name("audio-engine".to_owned())
+            .spawn(move || run(receiver))
+            .expect("failed to spawn the audio engine thread");
+        Self {
+            sender,
+            next_id: AtomicU64::new(0),
+            paused: AtomicBool::new(false),
+            speed: AtomicF32::new(1.0),
+            listeners: Mutex::new(vec![]),
+            navigation: Mutex::new(None),
+            file_output: Mutex::new(None),
+        }
+    }
+}
+
+fn decrement(pending: &AtomicUsize) {
+    let _ = pending.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1));
+}
+
+fn default_output_device_name() -> Option<String> {
+    use cpal::traits::{DeviceTrait, HostTrait};
+    cpal::default_host().default_output_device()?.name().ok()
+}
+
+struct Output {
+    _stream: rodio::OutputStream,
+    handle: rodio::OutputStreamHandle,
+    /// The name of the default device when the stream was opened.
+    device: Option<String>,
+}
+
+impl Output {
+    fn open() -> Result<Self, Error> {
+        let device = default_output_device_name();
+        let (stream, handle) = rodio::OutputStream::try_default()?;
+        Ok(Self {
+            _stream: stream,
+            handle,
+            device,
+        })
+    }
+}
+
+/// A sink with its volume, and the number of its sources that have not finished.
+struct SinkEntry {
+    sink: rodio::Sink,
+    volume: f32,
+    pending: Arc<AtomicUsize>,
+}
+
+fn new_sink(
+    handle: &rodio::OutputStreamHandle,
+    volume: f32,
+    speed: f32,
+    paused: bool,
+) -> Result<rodio::Sink, Error> {
+    let sink = rodio::Sink::try_new(handle)?;
+    sink.set_volume(volume);
+    sink.set_speed(speed);
+    if paused {
+        sink.pause();
+    }
+    Ok(sink)
+}
+
+/// Closes the output stream, opens it on the default device, and moves the sinks to it. Their queued sources are lost,
+/// so they are marked as finished and the playbacks waiting for them end.
+fn reopen(
+    output: &mut Option<Output>,
+    sinks: &mut HashMap<u64, SinkEntry>,
+    paused: bool,
+) -> Result<rodio::OutputStreamHandle, Error> {
+    let old = std::mem::take(sinks)
+        .into_iter()
+        .map(|(id, entry)| (id, entry.volume, entry.sink.speed(), entry.pending))
+        .collect::<Vec<_>>(); // the old sinks are dropped before the stream
+    *output = None;
+    let new_output = Output::open()?;
+    let handle = new_output.handle.clone();
+    *output = Some(new_output);
+    for (id, volume, speed, pending) in old {
+        pending.store(0, Ordering::SeqCst);
+        let sink = new_sink(&handle, volume, speed, paused)?;
+        sinks.insert(
+            id,
+            SinkEntry {
+                sink,
+                volume,
+                pending,
+            },
+        );
+    }
+    Ok(handle)
+}
+
+fn run(receiver: std::sync::mpsc::Receiver<Command>) {
+    let mut output: Option<Output> = None;
+    let mut sinks = HashMap::<u64, SinkEntry>::new();
+    let mut paused = false;
+    loop {
+        let command = match receiver.recv_timeout(DEVICE_CHECK_INTERVAL) {
+            Ok(command) => command,
+            Err(RecvTimeoutError::Timeout) => {
+                let Some(current) = &output else {
+                    continue;
+                };
+                let device = default_output_device_name();
+                if device.is_some() && device != current.device {
+                    tracing::info!(?device, "the default output device changed");
+                    if let Err(err) = reopen(&mut output, &mut sinks, paused) {
+                        tracing::error!("failed to reopen the audio output: {err}");
+                    }
+                }
+                continue;
+            }
+            Err(RecvTimeoutError::Disconnected) => break,
+        };
+        match command {
+            Command::CreateSink { id, pending, reply } => {
+                let result = (|| -> Result<(), Error> {
+                    let handle = match &output {
+                        Some(output) => output.handle.clone(),
+                        None => reopen(&mut output, &mut sinks, paused)?,
+                    };
+                    let sink = match new_sink(&handle, 1.0, 1.0, paused) {
+                        Ok(sink) => sink,
+                        Err(err) => {
+                            // The device may have been removed.
+                            tracing::warn!(
+                                "failed to create a sink, reopening the audio output: {err}"
+                            );
+                            let handle = reopen(&mut output, &mut sinks, paused)?;
+                            new_sink(&handle, 1.0, 1.0, paused)?
+                        }
+                    };
+                    sinks.insert(
+                        id,
+                        SinkEntry {
+                            sink,
+                            volume: 1.0,
+                            pending,
+                        },
+                    );
+                    Ok(())
+                })();
+                if let Err(err) = &result {
+                    tracing::error!("failed to open the audio output: {err}");
+                }
+                let _ = reply.send(result);
+            }
+            Command::Append {
+                id,
+                source,
+                pending,
+            } => match sinks.get(&id) {
+                Some(SinkEntry { sink, .. }) => {
+                    sink.append(source);
+                    sink.append(rodio::source::EmptyCallback::<f32>::new(Box::new(
+                        move || decrement(&pending),
+                    )));
+                }
+                None => decrement(&pending),
+            },
+            Command::Clear(id) => {
+                if let Some(entry) = sinks.remove(&id) {
+                    let speed = entry.sink.speed();
+                    drop(entry.sink); // dropping a sink stops it
+                    if let Some(output) = &output {
+                        if let Ok(sink) = new_sink(&output.handle, entry.volume, speed, paused) {
+                            sinks.insert(
+                                id,
+                                SinkEntry {
+                                    sink,
+                                    volume: entry.volume,
+                                    pending: entry.pending,
+                                },
+                            );
+                        }
+                    }
+                }
+            }
+            Command::Pause => {
+                paused = true;
+                for entry in sinks.values() {
+                    entry.sink.pause();
+                }
+            }
+            Command::Resume => {
+                paused = false;
+                for entry in sinks.values() {
+                    entry.sink.play();
+                }
+            }
+            Command::SetVolume(id, volume) => {
+                if let Some(entry) = sinks.get_mut(&id) {
+                    entry.sink.set_volume(volume);
+                    entry.volume = volume;
+                }
+            }
+            Command::SetSpeed(id, speed) => {
+                if let Some(entry) = sinks.get(&id) {
+                    entry.sink.set_speed(speed);
+                }
+            }
+            Command::RemoveSink(id) => {
+                sinks.remove(&id);
+            }
+        }
+    }
+}
+
+impl AudioEngine {
+    fn send(&self, command: Command) -> Result<(), Error> {
+        self.sender
+            .send(command)
+            .map_err(|_| Error::StringError("The audio engine has stopped".to_owned()))
+    }
+
+    /// Pauses every sound. The playbacks are not canceled, so the commands that play them wait for `resume()`.
+    pub(crate) fn pause(&self) -> Result<(), Error> {
+        self.paused.store(true, Ordering::SeqCst);
+        self.send(Command::Pause)
+    }
+
+    pub(crate) fn resume(&self) -> Result<(), Error> {
+        self.paused.store(false, Ordering::SeqCst);
+        self.send(Command::Resume)
+    }
+
+    pub(crate) fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::SeqCst)
+    }
+
+    /// Sets the speed of speech playbacks, including the one in progress.
+    pub(crate) fn set_speed(&self, speed: f32) {
+        self.speed.store(speed, Ordering::SeqCst);
+    }
+
+    pub(crate) fn speed(&self) -> f32 {
+        self.speed.load(Ordering::SeqCst)
+    }
+
+    pub(crate) fn add_listener(
+        &self,
+        listener: impl Fn(&PlaybackStatus) + Send + Sync + 'static,
+    ) -> Result<(), Error> {
+        self.listeners.lock()?.push(Box::new(listener));
+        Ok(())
+    }
+
+    pub(crate) fn notify(&self, status: &PlaybackStatus) {
+        if let Ok(listeners) = self.listeners.lock() {
+            for listener in &*listeners {
+                listener(status);
+            }
+        }
+    }
+
+    /// Requests the speech being played to move, replacing the previous request that has not been taken.
+    pub(crate) fn navigate(&self, navigation: Navigation) -> Result<(), Error> {
+        *self.navigation.lock()? = Some(navigation);
+        Ok(())
+    }
+
+    /// Takes the navigation requested since the last call. A playback calls this when it starts, to discard the requests
+    /// made for the previous one.
+    pub(crate) fn take_navigation(&self) -> Option<Navigation> {
+        self.navigation.lock().ok()?.take()
+    }
+
+    pub(crate) fn set_file_output(&self, dir: Option<PathBuf>) -> Result<(), Error> {
+        *self.file_output.lock()? = dir;
+        Ok(())
+    }
+
+    /// The directory of the audio log if the speech is written to files instead of being played.
+    pub(crate) fn file_output(&self) -> Option<PathBuf> {
+        self.file_output.lock().ok()?.clone()
+    }
+
+    /// Creates a sink on the shared output stream, opening it if needed.
+    pub(crate) fn sink(&self) -> Result<EngineSink, Error> {
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        let pending = Arc::new(AtomicUsize::new(0));
+        let (reply, result) = channel();
+        self.send(Command::CreateSink {
+            id,
+            pending: pending.clone(),
+            reply,
+        })?;
+        result
+            .recv()
+            .map_err(|_| Error::StringError("The audio engine has stopped".to_owned()))??;
+        Ok(EngineSink {
+            id,
+            sender: self.sender.clone(),
+            pending,
+        })
+    }
+}
+
+/// A handle to a sink of the engine, which is stopped and removed when dropped.
+pub(crate) struct EngineSink {
+    id: u64,
+    sender: Sender<Command>,
+    /// The number of appended sources that have not finished.
+    pending: Arc<AtomicUsize>,
+}
+
+impl EngineSink {
+    fn send(&self, command: Command) {
+        // The engine thread only stops when the app exits.
+        let _ = self.sender.send(command);
+    }
+
+    pub(crate) fn append<S>(&self, source: S)
+    where
+        S: rodio::Source + Send + 'static,
+        S::Item: rodio::Sample,
+        f32: dasp_sample::FromSample<S::Item>,
+    {
+        self.pending.fetch_add(1, Ordering::SeqCst);
+        self.send(Command::Append {
+            id: self.id,
+            source: Box::new(source.convert_samples::<f32>()),
+            pending: self.pending.clone(),
+        });
+    }
+
+    /// Stops the queued sources. The sink can be appended to afterwards.
+    pub(crate) fn clear(&self) {
+        self.pending.store(0, Ordering::SeqCst);
+        self.send(Command::Clear(self.id));
+    }
+
+    pub(crate) fn set_volume(&self, volume: f32) {
+        self.send(Command::SetVolume(self.id, volume));
+    }
+
+    pub(crate) fn set_speed(&self, speed: f32) {
+        self.send(Command::SetSpeed(self.id, speed));
+    }
+
+    /// The number of appended sources that have not finished, including the one being played.
+    pub(crate) fn queued(&self) -> usize {
+        self.pending.load(Ordering::SeqCst)
+    }
+
+    /// Whether every appended source has finished.
+    pub(crate) fn empty(&self) -> bool {
+        self.pending.load(Ordering::SeqCst) == 0
+    }
+
+    /// Blocks until the sources have finished or `keep_waiting` returns false, which is checked every `interval`.
+    pub(crate) fn sleep_until_end_while(
+        &self,
+        interval: Duration,
+        mut keep_waiting: impl FnMut() -> bool,
+    ) {
+        while !self.empty() && keep_waiting() {
+            std::thread::sleep(interval);
+        }
+    }
+}
+
+impl Drop for EngineSink {
+    fn drop(&mut self) {
+        self.send(Command::RemoveSink(self.id));
+    }
+}
+
+fn frames(duration: Duration, sample_rate: u32) -> usize {
+    (duration.as_secs_f64() * sample_rate as f64) as usize
+}
+
+/// The duration of interleaved samples.
+pub(crate) fn samples_duration(len: usize, channels: u16, sample_rate: u32) -> Duration {
+    Duration::from_secs_f64(len as f64 / channels.max(1) as f64 / sample_rate.max(1) as f64)
+}
+
+/// Returns the interleaved samples after `offset`, faded in so that the playback resumed there does not click.
+pub(crate) fn samples_after(
+    samples: &[f32],
+    channels: u16,
+    sample_rate: u32,
+    offset: Duration,
+) -> Vec<f32> {
+    let channels = channels.max(1) as usize;
+    let start = (frames(offset, sample_rate) * channels).min(samples.len());
+    let mut samples = samples[start..].to_vec();
+    let len = (frames(FADE, sample_rate) * channels).min(samples.len());
+    fade(&mut samples[..len], channels, true);
+    samples
+}
+
+/// Trims the silence at both ends of the interleaved samples beyond `KEPT_SILENCE`.
+fn trim_silence(samples: &mut Vec<f32>, channels: usize, sample_rate: u32) {
+    let is_sound = |frame: &[f32]| frame.iter().any(|x| x.abs() > SILENCE_THRESHOLD);
+    let frame_count = samples.len() / channels;
+    let Some(first) = samples.chunks_exact(channels).position(is_sound) else {
+        samples.clear();
+        return;
+    };
+    let last = samples
+        .chunks_exact(channels)
+        .rposition(is_sound)
+        .unwrap_or(first);
+    let kept = frames(KEPT_SILENCE, sample_rate);
+    let end = (last + 1 + kept).min(frame_count);
+    samples.truncate(end * channels);
+    samples.drain(..first.saturating_sub(kept) * channels);
+}
+
+/// Fades the interleaved samples in, or out if `fade_in` is false.
+fn fade(samples: &mut [f32], channels: usize, fade_in: bool) {
+    let frame_count = samples.len() / channels;
+    for (i, frame) in samples.chunks_exact_mut(channels).enumerate() {
+        let step = if fade_in { i + 1 } else { frame_count - i };
+        let gain = step as f32 / (frame_count + 1) as f32;
+        for x in frame {
+            *x *= gain;
+        }
+    }
+}
+
+/// Plays clips of speech back to back on one sink: the silence at their ends is shortened, and each clip is crossfaded
+/// into the next one, so that the sentences sound continuous. The end of the last clip is held back until the next
+/// clip is appended or `finish` is called.
+///
+/// The audio appended to the sink is kept, so that `seek` can play it again from any position.
+pub(crate) struct SpeechQueue {
+    sink: EngineSink,
+    meter: SpeechMeter,
+    /// The end of the last clip, in the format of `format`.
+    tail: Vec<f32>,
+    /// The channels and the sample rate of the last clip.
+    format: Option<(u16, u32)>,
+    /// The duration of the audio appended to the sink.
+    duration: Duration,
+    /// The audio appended to the sink: the start, the channels, the sample rate, and the samples of each part.
+    parts: Vec<(Duration, u16, u32, Vec<f32>)>,
+    /// The start and the text of each clip, i.e. each sentence.
+    sentences: Vec<(Duration, String)>,
+}
+
+impl SpeechQueue {
+    pub(crate) fn new(sink: EngineSink, meter: SpeechMeter) -> Self {
+        Self {
+            sink,
+            meter,
+            tail: vec![],
+            format: None,
+            duration: Duration::ZERO,
+            parts: vec![],
+            sentences: vec![],
+        }
+    }
+
+    pub(crate) fn sink(&self) -> &EngineSink {
+        &self.sink
+    }
+
+    pub(crate) fn duration(&self) -> Duration {
+        self.duration
+    }
+
+    fn push(&mut self, samples: Vec<f32>, channels: u16, sample_rate: u32) {
+        if samples.is_empty() {
+            return;
+        }
+        self.sink
+            .append(self.meter.meter(rodio::buffer::SamplesBuffer::new(
+                channels,
+                sample_rate,
+                samples.clone(),
+            )));
+        let start = self.duration;
+        self.duration += samples_duration(samples.len(), channels, sample_rate);
+        self.parts.push((start, channels, sample_rate, samples));
+    }
+
+    /// Plays the speech again from the position, which is limited to the audio appended so far, and returns the position.
+    pub(crate) fn seek(&self, position: Duration) -> Duration {
+        let position = position.min(self.duration);
+        self.sink.clear();
+        for (start, channels, sample_rate, samples) in &self.parts {
+            let end = *start + samples_duration(samples.len(), *channels, *sample_rate);
+            if end <= position {
+                continue;
+            }
+            let samples = samples_after(
+                samples,
+                *channels,
+                *sample_rate,
+                position.saturating_sub(*start),
+            );
+            self.sink
+                .append(self.meter.meter(rodio::buffer::SamplesBuffer::new(
+                    *channels,
+                    *sample_rate,
+                    samples,
+                )));
+        }
+        position
+    }
+
+    /// Returns the start of the sentence after the position, or the end of the audio appended so far if there is none.
+    /// Going back, returns the start of the sentence at the position, or of the previous one if the sentence at the
+    /// position has started within `SENTENCE_RESTART_THRESHOLD`.
+    pub(crate) fn sentence_start(&self, position: Duration, forward: bool) -> Duration {
+        let mut starts = self.sentences.iter().map(|(start, _)| *start);
+        if forward {
+            starts
+                .find(|&start| start > position)
+                .unwrap_or(self.duration)
+        } else {
+            starts
+                .rev()
+                .find(|&start| start + SENTENCE_RESTART_THRESHOLD <= position)
+                .unwrap_or(Duration::ZERO)
+        }
+    }
+
+    /// Returns the text of the sentence being played at the position.
+    pub(crate) fn sentence_at(&self, position: Duration) -> Option<&str> {
+        self.sentences
+            .iter()
+            .rev()
+            .find(|(start, _)| *start <= position)
+            .map(|(_, text)| text.as_str())
+    }
+
+    /// Appends a clip in any format that rodio decodes, e.g. MP3, and the sentence spoken in it.
+    pub(crate) fn append(&mut self, data: Vec<u8>, sentence: String) -> Result<(), Error> {
+        use rodio::Source;
+        let decoder = rodio::Decoder::new(std::io::Cursor::new(data))?;
+        let (channels, sample_rate) = (decoder.channels(), decoder.sample_rate());
+        let mut samples = decoder
+            .map(dasp_sample::conv::i16::to_f32)
+            .collect::<Vec<f32>>();
+        let frame = channels as usize;
+        trim_silence(&mut samples, frame, sample_rate);
+        if samples.is_empty() {
+            return Ok(());
+        }
+        if self.format != Some((channels, sample_rate)) {
+            self.finish(); // clips in different formats cannot be mixed
+            self.format = Some((channels, sample_rate));
+        }
+
+        if self.tail.is_empty() {
+            let len = (frames(FADE, sample_rate) * frame).min(samples.len());
+            fade(&mut samples[..len], frame, true);
+        } else {
+            // An equal-power crossfade from the end of the previous clip.
+            let tail = std::mem::take(&mut self.tail);
+            let overlap = tail.len().min(samples.len());
+            let overlap_frames = (overlap / frame).max(1) as f32;
+            for (i, (x, y)) in samples.iter_mut().zip(&tail).enumerate() {
+                let t = (i / frame) as f32 / overlap_frames * std::f32::consts::FRAC_PI_2;
+                *x = y * t.cos() + *x * t.sin();
+            }
+            samples.extend_from_slice(&tail[overlap..]);
+        }
+
+        let held = (frames(CROSSFADE, sample_rate) * frame).min(samples.len() / 2 / frame * frame);
+        self.tail = samples.split_off(samples.len() - held);
+        self.sentences.push((self.duration, sentence));
+        self.push(samples, channels, sample_rate);
+        Ok(())
+    }
+
+    /// Appends the end of the last clip, faded out.
+    pub(crate) fn finish(&mut self) {
+        let Some((channels, sample_rate)) = self.format else {
+            return;
+        };
+        let mut tail = std::mem::take(&mut self.tail);
+        let len = (frames(FADE, sample_rate) * channels as usize).min(tail.len());
+        let start = tail.len() - len;
+        fade(&mut tail[start..], channels as usize, false);
+        self.push(tail, channels, sample_rate);
+    }
+}
@@ -0,0 +1,217 @@
+//! The audio log: while `set_audio_output_to_file` is enabled, the speech is written to WAV files instead of being
+//! played, e.g. in a quiet place, so that it can be listened to later.
+//!
+//! The files are stored in the audio_log directory of the app data directory, in a subdirectory per conversation named
+//! after the id of its root message, or "other" for the speech that is not of a message, e.g. a voice preview. A file is
+//! named "<milliseconds since the Unix epoch>-<message id>.wav".
+
+use crate::{conversation, play_audio, AppState, Error};
+use sqlx::Row;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering;
+use tauri::{AppHandle, Manager};
+
+const OTHER_DIR: &str = "other";
+
+#[derive(serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct AudioLogEntry {
+    path: PathBuf,
+    message_id: Option<i64>,
+    /// Milliseconds since the Unix epoch.
+    created_at: u64,
+}
+
+fn log_dir(app: &AppHandle) -> Result<PathBuf, Error> {
+    Ok(app
+        .path_resolver()
+        .app_data_dir()
+        .ok_or_else(|| Error::StringError("The app data directory is unknown".to_owned()))?
+        .join("audio_log"))
+}
+
+/// Returns the file to write the speech of the message to, or None if the speech is played.
+pub(crate) async fn output_path(
+    state: &AppState,
+    message_id: Option<i64>,
+) -> Result<Option<PathBuf>, Error> {
+    let Some(dir) = state.audio.engine.file_output() else {
+        return Ok(None);
+    };
+    let conversation = match message_id {
+        Some(message_id) => {
+            let mut conn = state.db_pool.acquire().await?;
+            sqlx::query(
+                "
+WITH RECURSIVE ancestors(id, parent) AS (
+    SELECT id, parent FROM message WHERE id = ?
+    UNION ALL
+    SELECT message.id, message.parent FROM ancestors JOIN message ON message.id = ancestors.parent
+)
+SELECT id FROM ancestors WHERE parent IS NULL
+",
+            )
+            .bind(message_id)
+            .fetch_optional(&mut conn)
+            .await?
+            .map(|row| row.get::<i64, _>("id").to_string())
+        }
+        None => None,
+    };
+    let dir = dir.join(conversation.unwrap_or_else(|| OTHER_DIR.to_owned()));
+    tokio::fs::create_dir_all(&dir).await?;
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis();
+    let name = match message_id {
+        Some(message_id) => format!("{now}-{message_id}.wav"),
+        None => format!("{now}.wav"),
+    };
+    Ok(Some(dir.join(name)))
+}
+
+/// Decodes the clips, e.g. MP3, and writes them to a WAV file one after another. A clip whose channels or sample rate
+/// differ from the first one is skipped.
+pub(crate) async fn write(path: PathBuf, clips: Vec<Vec<u8>>) -> Result<(), Error> {
+    tokio::task::spawn_blocking(move || -> Result<(), Error> {
+        use rodio::Source;
+        let mut writer: Option<hound::WavWriter<_>> = None;
+        for clip in clips {
+            if clip.is_empty() {
+                continue;
+            }
+            let decoder = rodio::Decoder::new(std::io::Cursor::new(clip))?;
+            let spec = hound::WavSpec {
+                channels: decoder.channels(),
+                sample_rate: decoder.sample_rate(),
+                bits_per_sample: 16,
+                sample_format: hound::SampleFormat::Int,
+            };
+            if writer.is_none() {
+                writer = Some(hound::WavWriter::create(&path, spec)?);
+            }
+            let writer = writer.as_mut().unwrap();
+            if writer.spec() != spec {
+                tracing::warn!("skipped a clip in a different format: {spec:?}");
+                continue;
+            }
+            for sample in decoder {
+                writer.write_sample(sample)?;
+            }
+        }
+        if let Some(writer) = writer {
+            writer.finalize()?;
+        }
+        Ok(())
+    })
+    .await?
+}
+
+/// Plays the audio of the message, or writes it to the audio log if the output is a file.
+pub(crate) async fn play_or_write(
+    state: &AppState,
+    message_id: Option<i64>,
+    data: Vec<u8>,
+    precedence: i64,
+) -> Result<(), Error> {
+    match output_path(state, message_id).await? {
+        Some(path) => write(path, vec![data]).await,
+        None => {
+            let title = match message_id {
+                Some(message_id) => {
+                    let mut conn = state.db_pool.acquire().await?;
+                    conversation::name_of(&mut conn, message_id).await?
+                }
+                None => None,
+            };
+            play_audio(state.audio.clone(), data, precedence, title).await
+        }
+    }
+}
+
+/// Writes the speech to the audio log instead of playing it while `enabled` is set. The speech being played is stopped.
+#[tauri::command]
+#[tracing::instrument(skip(app, state), err)]
+pub(crate) fn set_audio_output_to_file(
+    enabled: bool,
+    app: AppHandle,
+    state: tauri::State<AppState>,
+) -> Result<(), Error> {
+    let dir = if enabled { Some(log_dir(&app)?) } else { None };
+    if dir.is_some() {
+        state.audio.playback_counter.fetch_add(1, Ordering::SeqCst);
+    }
+    state.audio.engine.set_file_output(dir)
+}
+
+fn list_dir(dir: &Path, entries: &mut Vec<AudioLogEntry>) -> Result<(), Error> {
+    if !dir.exists() {
+        return Ok(());
+    }
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        let Some(stem) = path
+            .extension()
+            .filter(|ext| *ext == "wav")
+            .and(path.file_stem())
+            .and_then(|stem| stem.to_str())
+        else {
+            continue;
+        };
+        let (created_at, message_id) = match stem.split_once('-') {
+            Some((created_at, message_id)) => (created_at, message_id.parse().ok()),
+            None => (stem, None),
+        };
+        let Ok(created_at) = created_at.parse() else {
+            continue;
+        };
+        entries.push(AudioLogEntry {
+            path,
+            message_id,
+            created_at,
+        });
+    }
+    Ok(())
+}
+
+/// Lists the files of the audio log of the conversation, or of the speech that is not of a message if
+/// `conversation_id` is null, oldest first.
+#[tauri::command]
+#[tracing::instrument(skip(app), err)]
+pub(crate) fn list_audio_log(
+    conversation_id: Option<i64>,
+    app: AppHandle,
+) -> Result<Vec<AudioLogEntry>, Error> {
+    let dir = log_dir(&app)?.join(
+        conversation_id
+            .map(|id| id.to_string())
+            .unwrap_or_else(|| OTHER_DIR.to_owned()),
+    );
+    let mut entries = vec![];
+    list_dir(&dir, &mut entries)?;
+    entries.sort_by_key(|entry| entry.created_at);
+    Ok(entries)
+}
+
+/// Plays a file of the audio log, even while the output is a file.
+#[tauri::command]
+#[tracing::instrument(skip(app, state), err)]
+pub(crate) async fn play_audio_log(
+    path: PathBuf,
+    app: AppHandle,
+    state: tauri::State<'_, AppState>,
+) -> Result<(), Error> {
+    if !path.starts_with(log_dir(&app)?)
+        || path
+            .components()
+            .any(|c| c == std::path::Component::ParentDir)
+    {
+        return Err(Error::StringError(
+            "The file is not in the audio log".to_owned(),
+        ));
+    }
+    let data = tokio::fs::read(&path).await?;
+    let precedence = state.audio.playback_counter.fetch_add(1, Ordering::SeqCst) + 1;
+    play_audio(state.audio.clone(), data, precedence, None).await
+}
@@ -0,0 +1,83 @@
+//! The deployments of an Azure OpenAI resource, for the deployment picker in the settings.
+//!
+//! They are listed with the inference endpoint `/openai/deployments`, which takes the same API key or Azure AD token as the
+//! completions. The management API of Azure Resource Manager also lists them, but it needs the subscription and the
+//! resource group, and a token of another scope, so it is not used. The newer API versions no longer have the listing,
+//! so `DEPLOYMENTS_API_VERSION` is fixed.
+
+use crate::{AppState, Error};
+use serde_json::Value;
+
+const DEPLOYMENTS_API_VERSION: &str = "2022-12-01";
+
+/// An entry of `/openai/deployments`.
+#[derive(serde::Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct AzureDeployment {
+    /// The deployment name, which is the path segment of the endpoint.
+    id: String,
+    /// The model, e.g. "gpt-35-turbo".
+    model: String,
+    /// "succeeded" once the deployment can be used.
+    status: String,
+}
+
+/// Returns the origin of the endpoint, e.g. "https://example.openai.azure.com" for the full URL of a deployment.
+fn resource_url(endpoint: &str) -> Result<String, Error> {
+    let url = reqwest::Url::parse(endpoint.trim())
+        .map_err(|err| Error::StringError(format!("The endpoint is not a valid URL: {err}")))?;
+    Ok(url.origin().ascii_serialization())
+}
+
+/// Lists the deployments of the Azure OpenAI resource of the endpoint, which may be the resource URL or the full URL of
+/// a deployment. `key` is an API key, or an Azure AD token if `api_key_authentication` is false. With an Azure AD
+/// credential set by `set_azure_ad_credential`, its token is used instead.
+#[tauri::command]
+#[tracing::instrument(skip(key, state), err)]
+pub(crate) async fn list_azure_deployments(
+    endpoint: String,
+    key: String,
+    api_key_authentication: bool,
+    state: tauri::State<'_, AppState>,
+) -> Result<Vec<AzureDeployment>, Error> {
+    let request = reqwest::Client::new()
+        .get(format!("{}/openai/deployments", resource_url(&endpoint)?))
+        .query(&[("api-version", DEPLOYMENTS_API_VERSION)]);
+    let request = if api_key_authentication {
+        request.header("api-key", key)
+    } else if state.aad_token.has_credential()? {
+        request.bearer_auth(state.aad_token.token(false).await?)
+    } else {
+        request.bearer_auth(key)
+    };
+    let res = request.send().await?;
+    if res.status() != 200 {
+        return Err(Error::StatusIsNot200(format!(
+            "{}: {}",
+            res.status(),
+            res.text().await?
+        )));
+    }
+    let body: Value = serde_json::from_str(&res.text().await?)?;
+    let field = |deployment: &Value, name: &str| {
+        deployment
+            .get(name)
+            .and_then(Value::as_str)
+            .unwrap_or_default()
+            .to_owned()
+    };
+    let mut deployments = body
+        .get("data")
+        .and_then(Value::as_array)
+        .into_iter()
+        .flatten()
+        .map(|deployment| AzureDeployment {
+            id: field(deployment, "id"),
+            model: field(deployment, "model"),
+            status: field(deployment, "status"),
+        })
+        .filter(|deployment| !deployment.id.is_empty())
+        .collect::<Vec<_>>();
+    deployments.sort_by(|a, b| a.id.cmp(&b.id));
+    Ok(deployments)
+}
@@ -0,0 +1,52 @@
+//! Speech-to-text with Azure Speech Service's REST API for short audio.
+
+use crate::whisper::downsample;
+use crate::Error;
+use serde_json::Value;
+use std::path::Path;
+
+/// Transcribes a WAV file. It is converted to 16 kHz mono 16-bit PCM, which the API expects, and streamed.
+pub(crate) async fn transcribe(
+    wav_path: &Path,
+    region: &str,
+    resource_key: &str,
+    language: &str,
+) -> Result<String, Error> {
+    let converted = tempfile::Builder::new().suffix(".wav").tempfile()?;
+    let (src, dst) = (wav_path.to_owned(), converted.path().to_owned());
+    let sample_rate = tokio::task::spawn_blocking(move || downsample(&src, &dst)).await??;
+    let file = tokio::fs::File::open(converted.path()).await?;
+    let len = file.metadata().await?.len();
+
+    let res = reqwest::Client::new()
+        .post(format!(
+            "https://{region}.stt.speech.microsoft.com/speech/recognition/conversation/cognitiveservices/v1"
+        ))
+        .query(&[("language", language), ("format", "simple")])
+        .header("Ocp-Apim-Subscription-Key", resource_key)
+        .header(
+            "Content-Type",
+            format!("audio/wav; codecs=audio/pcm; samplerate={sample_rate}"),
+        )
+        .header("Content-Length", len)
+        .body(reqwest::Body::from(file))
+        .send()
+        .await?;
+    if res.status() != 200 {
+        return Err(Error::StatusIsNot200(format!(
+            "{}: {}",
+            res.status(),
+            res.text().await?
+        )));
+    }
+    let data: Value = serde_json::from_str(&res.text().await?)?;
+    match data.get("RecognitionStatus").and_then(Value::as_str) {
+        Some("Success") => Ok(data
+            .get("DisplayText")
+            .and_then(Value::as_str)
+            .unwrap_or_default()
+            .to_owned()),
+        Some("NoMatch" | "InitialSilenceTimeout") => Ok("".to_owned()),
+        _ => Err(Error::StringError(format!("Unexpected response: {data}"))),
+    }
+}
@@ -0,0 +1,390 @@
+//! Azure Speech Service's text-to-speech: the voices for the voice picker in the settings, and the sentence-level pipeline
+//! that speaks long texts.
+
+use crate::app_state::DbPool;
+use crate::speech_text::SpeechText;
+use crate::{audio_log, conversation, lexicon};
+use crate::{
+    azure_text_to_speech_request, conversation_settings, play_speech_queue, speak_azure, tts_cache,
+    AppState, Error,
+};
+use futures_util::StreamExt;
+use sqlx::Row;
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
+
+/// The voice list rarely changes, so it is fetched at most once a day per region unless `refresh` is set.
+const VOICE_LIST_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+const DEFAULT_SAMPLE_TEXT: &str = "Hello! This is how I sound.";
+
+/// The number of sentences synthesized at the same time by `speak_azure_sentences`.
+const MAX_CONCURRENT_REQUESTS: usize = 3;
+/// The number of synthesized sentences that may wait to be played, which bounds the requests made ahead of the playback.
+const PREFETCHED_SENTENCES: usize = 4;
+
+/// An entry of `/cognitiveservices/voices/list`, e.g. ShortName "af-ZA-AdriNeural" and Locale "af-ZA".
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+#[serde(rename_all = "PascalCase")]
+pub(crate) struct AzureVoice {
+    name: String,
+    display_name: String,
+    local_name: String,
+    short_name: String,
+    gender: String,
+    locale: String,
+    locale_name: String,
+    #[serde(default)]
+    sample_rate_hertz: String,
+    #[serde(default)]
+    voice_type: String,
+    #[serde(default)]
+    status: String,
+    #[serde(default)]
+    words_per_minute: String,
+}
+
+/// Lists the voices available in the region, from the cache if it was fetched in the last day.
+#[tauri::command]
+#[tracing::instrument(skip(resource_key, state), err)]
+pub(crate) async fn list_azure_voices(
+    region: String,
+    resource_key: String,
+    refresh: Option<bool>,
+    state: tauri::State<'_, AppState>,
+) -> Result<Vec<AzureVoice>, Error> {
+    if !refresh.unwrap_or(false) {
+        if let Some((fetched_at, voices)) = state.azure_voices.lock()?.get(&region) {
+            if fetched_at.elapsed() < VOICE_LIST_TTL {
+                return Ok(voices.clone());
+            }
+        }
+    }
+    let res = reqwest::Client::new()
+        .get(format!(
+            "https://{region}.tts.speech.microsoft.com/cognitiveservices/voices/list"
+        ))
+        .header("Ocp-Apim-Subscription-Key", resource_key)
+        .send()
+        .await?;
+    if res.status() != 200 {
+        return Err(Error::StatusIsNot200(format!(
+            "{}: {}",
+            res.status(),
+            res.text().await?
+        )));
+    }
+    let voices: Vec<AzureVoice> = serde_json::from_str(&res.text().await?)?;
+    state
+        .azure_voices
+        .lock()?
+        .insert(region, (Instant::now(), voices.clone()));
+    Ok(voices)
+}
+
+/// Speaks `sample_text` with the voice, e.g. "en-US-JennyNeural", interrupting the audio being played. The audio is stored in
+/// the system TTS cache, so previewing the same voice again does not call the API.
+#[tauri::command]
+#[tracing::instrument(skip(resource_key, state), err)]
+pub(crate) async fn preview_azure_voice(
+    region: String,
+    resource_key: String,
+    voice: String,
+    sample_text: Option<String>,
+    state: tauri::State<'_, AppState>,
+) -> Result<(), Error> {
+    let ssml = ssml::build(
+        sample_text.as_deref().unwrap_or(DEFAULT_SAMPLE_TEXT),
+        &voice,
+        None,
+        None,
+        None,
+        None,
+    );
+    let precedence = state.audio.playback_counter.fetch_add(1, Ordering::SeqCst) + 1;
+    let data = synthesize(&state.db_pool, None, &region, &resource_key, ssml, false).await?;
+    audio_log::play_or_write(&state, None, data, precedence).await
+}
+
+/// Returns the audio of the SSML from the TTS caches, or synthesizes and caches it unless `no_cache` is set.
+async fn synthesize(
+    db_pool: &DbPool,
+    message_id: Option<i64>,
+    region: &str,
+    resource_key: &str,
+    ssml: String,
+    no_cache: bool,
+) -> Result<Vec<u8>, Error> {
+    {
+        let mut conn = db_pool.acquire().await?;
+        let cached_audio = sqlx::query(
+            "
+SELECT audio FROM messageTTSCache WHERE ssml = ?1
+UNION
+SELECT audio FROM systemTTSCache WHERE ssml = ?1
+LIMIT 1
+",
+        )
+        .bind(&ssml)
+        .fetch_optional(&mut conn)
+        .await?;
+        if let Some(row) = cached_audio {
+            tts_cache::touch(&mut conn, &ssml).await?;
+            return Ok(row.get("audio"));
+        }
+    }
+    azure_text_to_speech_request(
+        db_pool,
+        message_id,
+        region.to_owned(),
+        resource_key.to_owned(),
+        ssml,
+        no_cache,
+    )
+    .await
+}
+
+fn push_sentence(sentence: &mut String, sentences: &mut Vec<String>) {
+    let trimmed = sentence.trim();
+    if !trimmed.is_empty() {
+        sentences.push(trimmed.to_owned());
+    }
+    sentence.clear();
+}
+
+/// Splits a text after sentence-ending punctuation followed by whitespace, after CJK full stops, and at line breaks.
+pub(crate) fn split_sentences(text: &str) -> Vec<String> {
+    let mut sentences = vec![];
+    let mut sentence = String::new();
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c == '\n' {
+            push_sentence(&mut sentence, &mut sentences);
+            continue;
+        }
+        sentence.push(c);
+        let is_end = match c {
+            '。' | '！' | '？' => true,
+            '.' | '!' | '?' => chars.peek().map_or(true, |c| c.is_whitespace()),
+            _ => false,
+        };
+        if is_end {
+            push_sentence(&mut sentence, &mut sentences);
+        }
+    }
+    push_sentence(&mut sentence, &mut sentences);
+    sentences
+}
+
+/// A voice and its prosody, from which the SSML of a text is built.
+pub(crate) struct Voice {
+    /// The short name, e.g. "en-US-JennyNeural".
+    pub(crate) name: String,
+    pub(crate) lang: String,
+    /// The speaking rate, where 1 is the normal rate.
+    pub(crate) rate: Option<f64>,
+    /// The pitch, where 1 is the normal pitch.
+    pub(crate) pitch: Option<f64>,
+    /// A speaking style of the voice, e.g. "cheerful".
+    pub(crate) style: Option<String>,
+}
+
+impl Voice {
+    pub(crate) fn new(name: String, lang: String) -> Self {
+        Self {
+            name,
+            lang,
+            rate: None,
+            pitch: None,
+            style: None,
+        }
+    }
+
+    /// The SSML that speaks the text, which has been preprocessed with `SpeechText`, with the voice.
+    pub(crate) fn ssml(&self, text: &str) -> String {
+        ssml::build(
+            text,
+            &self.name,
+            Some(&self.lang),
+            self.rate,
+            self.pitch,
+            self.style.as_deref(),
+        )
+    }
+}
+
+/// Synthesizes the sentences of `speak_azure_sentences`. Their audio is cached per sentence, keyed by the SSML built here.
+pub(crate) struct SentenceSynthesizer<'a> {
+    pub(crate) db_pool: &'a DbPool,
+    pub(crate) region: &'a str,
+    pub(crate) resource_key: &'a str,
+    pub(crate) voice: &'a Voice,
+}
+
+impl SentenceSynthesizer<'_> {
+    pub(crate) async fn synthesize(
+        &self,
+        message_id: Option<i64>,
+        sentence: &str,
+        no_cache: bool,
+    ) -> Result<Vec<u8>, Error> {
+        let ssml = {
+            let mut conn = self.db_pool.acquire().await?;
+            lexicon::apply_to_ssml(&mut conn, &self.voice.ssml(sentence)).await?
+        };
+        synthesize(
+            self.db_pool,
+            message_id,
+            self.region,
+            self.resource_key,
+            ssml,
+            no_cache,
+        )
+        .await
+    }
+}
+
+/// Speaks a long text sentence by sentence, interrupting the audio being played. Up to `MAX_CONCURRENT_REQUESTS` sentences
+/// are synthesized ahead while the previous ones are played, so the first sentence starts playing as soon as it is ready.
+/// A sentence that fails to be synthesized is skipped, and the failures are reported once the rest has been played.
+#[tauri::command]
+#[tracing::instrument(skip(resource_key, content, state), err)]
+pub(crate) async fn speak_azure_sentences(
+    message_id: Option<i64>,
+    region: String,
+    resource_key: String,
+    voice: String,
+    lang: String,
+    content: String,
+    no_cache: bool,
+    state: tauri::State<'_, AppState>,
+) -> Result<(), Error> {
+    let state = &*state;
+    let precedence = state.audio.playback_counter.fetch_add(1, Ordering::SeqCst) + 1;
+    let mut voice = Voice::new(voice, lang);
+    // The code blocks span lines, so the markdown is preprocessed before the text is split.
+    let (content, title) = {
+        let mut conn = state.db_pool.acquire().await?;
+        conversation_settings::apply_to_voice(&mut conn, message_id, &mut voice).await?;
+        let title = match message_id {
+            Some(message_id) => conversation::name_of(&mut conn, message_id).await?,
+            None => None,
+        };
+        let content = SpeechText::load(&mut conn, &voice.lang)
+            .await?
+            .apply(&content);
+        (content, title)
+    };
+    let sentences = split_sentences(&content);
+    let total = sentences.len();
+    let (sender, mut receiver) = tokio::sync::mpsc::channel(PREFETCHED_SENTENCES);
+    let synthesizer = SentenceSynthesizer {
+        db_pool: &state.db_pool,
+        region: &region,
+        resource_key: &resource_key,
+        voice: &voice,
+    };
+
+    let fetch = async {
+        let synthesizer = &synthesizer;
+        let mut results = futures_util::stream::iter(sentences)
+            .map(|sentence| async move {
+                let data = synthesizer
+                    .synthesize(message_id, &sentence, no_cache)
+                    .await?;
+                Ok::<_, Error>((sentence, data))
+            })
+            .buffered(MAX_CONCURRENT_REQUESTS);
+        while let Some(result) = results.next().await {
+            if sender.send(result).await.is_err() {
+                break; // the playback was interrupted
+            }
+        }
+        drop(sender);
+    };
+    // The sentences are played as one speech, crossfaded into each other.
+    let (clips, clip_receiver) = tokio::sync::mpsc::channel(1);
+    let play = async move {
+        let mut errors = vec![];
+        while let Some(result) = receiver.recv().await {
+            if state.audio.playback_counter.load(Ordering::SeqCst) != precedence {
+                break;
+            }
+            match result {
+                Ok(clip) => {
+                    if clips.send(clip).await.is_err() {
+                        break; // the playback has ended
+                    }
+                }
+                Err(err) => {
+                    tracing::warn!("failed to synthesize a sentence: {err}");
+                    errors.push(err);
+                }
+            }
+        }
+        errors
+    };
+    let output = audio_log::output_path(state, message_id).await?;
+    let playback = async move {
+        match output {
+            // The clips are written at once when every sentence has been synthesized.
+            Some(path) => {
+                let mut clip_receiver = clip_receiver;
+                let mut data = vec![];
+                while let Some((_, clip)) = clip_receiver.recv().await {
+                    data.push(clip);
+                }
+                audio_log::write(path, data).await
+            }
+            None => play_speech_queue(state.audio.clone(), clip_receiver, precedence, title).await,
+        }
+    };
+    let ((), errors, played) = tokio::join!(fetch, play, playback);
+    played?;
+    match errors.first() {
+        Some(err) => Err(Error::StringError(format!(
+            "{} of {total} sentences could not be synthesized: {err}",
+            errors.len()
+        ))),
+        None => Ok(()),
+    }
+}
+
+/// Speaks a text of a message with the voice of its conversation, or with `voice` if the conversation has none, like
+/// `speak_azure` with the SSML built from the text. With `pre_fetch`, the audio is only synthesized and cached.
+#[tauri::command]
+#[tracing::instrument(skip(resource_key, content, state), err)]
+pub(crate) async fn speak_azure_text(
+    message_id: Option<i64>,
+    region: String,
+    resource_key: String,
+    voice: String,
+    lang: String,
+    content: String,
+    pre_fetch: bool,
+    no_cache: bool,
+    state: tauri::State<'_, AppState>,
+) -> Result<(), Error> {
+    let mut voice = Voice::new(voice, lang);
+    let ssml = {
+        let mut conn = state.db_pool.acquire().await?;
+        conversation_settings::apply_to_voice(&mut conn, message_id, &mut voice).await?;
+        voice.ssml(
+            &SpeechText::load(&mut conn, &voice.lang)
+                .await?
+                .apply(&content),
+        )
+    };
+    speak_azure(
+        message_id,
+        region,
+        resource_key,
+        ssml,
+        0.0,
+        pre_fetch,
+        no_cache,
+        state,
+    )
+    .await?;
+    Ok(())
+}
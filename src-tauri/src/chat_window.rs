@@ -0,0 +1,41 @@
+//! Additional windows that each show a conversation.
+//!
+//! Every window streams its own chat completions: `stop_all_chat_completions` only cancels the requests started by the
+//! window that calls it.
+
+use crate::conversation::conversation_name;
+use crate::{AppState, Error};
+use tauri::{AppHandle, Manager};
+
+fn label(conversation_id: i64) -> String {
+    format!("chat-{conversation_id}")
+}
+
+/// Opens the conversation in a new window, or focuses the window if it is already open.
+#[tauri::command]
+#[tracing::instrument(skip(app, state), err)]
+pub(crate) async fn open_chat_window(
+    app: AppHandle,
+    conversation_id: i64,
+    state: tauri::State<'_, AppState>,
+) -> Result<(), Error> {
+    if let Some(window) = app.get_window(&label(conversation_id)) {
+        window.unminimize()?;
+        window.set_focus()?;
+        return Ok(());
+    }
+    let name = {
+        let mut conn = state.db_pool.acquire().await?;
+        conversation_name(&mut conn, conversation_id).await?
+    };
+    tauri::WindowBuilder::new(
+        &app,
+        label(conversation_id),
+        tauri::WindowUrl::App(format!("index.html#conversation={conversation_id}").into()),
+    )
+    .title(name.unwrap_or_else(|| "ChatGPT".to_owned()))
+    .inner_size(800.0, 600.0)
+    .focused(true)
+    .build()?;
+    Ok(())
+}
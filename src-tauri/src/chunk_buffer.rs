@@ -0,0 +1,148 @@
+//! The chunks of the streamed chat completions, until the frontend drains them with `get_chat_completion`.
+//!
+//! Each buffer holds about `CAPACITY` chunks. When it is full, the stream stops reading from the network until the
+//! buffer is drained, and the request is canceled if its consumer is gone, i.e. the window was closed or did not drain
+//! the buffer for `CONSUMER_TIMEOUT`. The buffer of a finished request is removed when it is drained, or after
+//! `FINISHED_TTL` if it never is.
+
+use crate::{
+    CompletionChunk, Error, CHAT_COMPLETION_CANCELED, CHAT_COMPLETION_CONTENT,
+    CHAT_COMPLETION_WINDOW, CHAT_TOOL_CALLS, CHAT_TOOL_CALLS_PENDING,
+};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tauri::Manager;
+
+const CAPACITY: usize = 1024;
+const CONSUMER_TIMEOUT: Duration = Duration::from_secs(30);
+const FINISHED_TTL: Duration = Duration::from_secs(60);
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+struct Buffer {
+    chunks: Vec<CompletionChunk>,
+    drained_at: Instant,
+    finished_at: Option<Instant>,
+}
+
+impl Default for Buffer {
+    fn default() -> Self {
+        Self {
+            chunks: vec![],
+            drained_at: Instant::now(),
+            finished_at: None,
+        }
+    }
+}
+
+lazy_static::lazy_static! {
+    static ref BUFFERS: Mutex<HashMap<u64, Buffer>> = Mutex::new(HashMap::new());
+}
+
+/// Marks the request as finished when dropped, including when the request fails or its future is dropped.
+pub(crate) struct OpenBuffer(u64);
+
+impl Drop for OpenBuffer {
+    fn drop(&mut self) {
+        if let Ok(mut buffers) = BUFFERS.lock() {
+            if let Some(buffer) = buffers.get_mut(&self.0) {
+                buffer.finished_at = Some(Instant::now());
+            }
+        }
+        if let Err(err) = remove_expired() {
+            tracing::warn!("failed to remove the expired chat completion buffers: {err}");
+        }
+    }
+}
+
+/// Creates the buffer of the request, which also makes it cancelable by `stop_all_chat_completions`.
+pub(crate) fn open(request_id: u64) -> Result<OpenBuffer, Error> {
+    BUFFERS.lock()?.entry(request_id).or_default();
+    Ok(OpenBuffer(request_id))
+}
+
+pub(crate) fn push(request_id: u64, chunk: CompletionChunk) -> Result<(), Error> {
+    BUFFERS
+        .lock()?
+        .entry(request_id)
+        .or_default()
+        .chunks
+        .push(chunk);
+    Ok(())
+}
+
+/// Returns the buffered chunks and removes them.
+pub(crate) fn drain(request_id: u64) -> Result<Vec<CompletionChunk>, Error> {
+    let mut buffers = BUFFERS.lock()?;
+    let Some(buffer) = buffers.get_mut(&request_id) else {
+        return Ok(vec![]);
+    };
+    let chunks = std::mem::take(&mut buffer.chunks);
+    buffer.drained_at = Instant::now();
+    if buffer.finished_at.is_some() {
+        drop(buffers);
+        remove(request_id)?;
+    }
+    Ok(chunks)
+}
+
+/// The requests that have not been removed, including the finished ones.
+pub(crate) fn request_ids() -> Result<Vec<u64>, Error> {
+    Ok(BUFFERS.lock()?.keys().copied().collect())
+}
+
+pub(crate) fn contains(request_id: u64) -> Result<bool, Error> {
+    Ok(BUFFERS.lock()?.contains_key(&request_id))
+}
+
+/// Forgets the request, along with its window, content, tool calls, and cancellation.
+pub(crate) fn remove(request_id: u64) -> Result<(), Error> {
+    BUFFERS.lock()?.remove(&request_id);
+    CHAT_COMPLETION_WINDOW.lock()?.remove(&request_id);
+    CHAT_COMPLETION_CONTENT.lock()?.remove(&request_id);
+    CHAT_TOOL_CALLS_PENDING.lock()?.remove(&request_id);
+    CHAT_TOOL_CALLS.lock()?.remove(&request_id);
+    CHAT_COMPLETION_CANCELED.lock()?.remove(&request_id);
+    Ok(())
+}
+
+fn remove_expired() -> Result<(), Error> {
+    let expired = BUFFERS
+        .lock()?
+        .iter()
+        .filter(|(_, buffer)| {
+            buffer
+                .finished_at
+                .is_some_and(|at| at.elapsed() > FINISHED_TTL)
+        })
+        .map(|(id, _)| *id)
+        .collect::<Vec<_>>();
+    for request_id in expired {
+        remove(request_id)?;
+    }
+    Ok(())
+}
+
+/// Waits until the buffer has room for more chunks. Returns false if the consumer of the request is gone.
+pub(crate) async fn wait_for_consumer(
+    window: &tauri::Window,
+    request_id: u64,
+) -> Result<bool, Error> {
+    loop {
+        {
+            let buffers = BUFFERS.lock()?;
+            let Some(buffer) = buffers.get(&request_id) else {
+                return Ok(true);
+            };
+            if buffer.drained_at.elapsed() > CONSUMER_TIMEOUT
+                || window.app_handle().get_window(window.label()).is_none()
+            {
+                return Ok(false);
+            }
+            if buffer.chunks.len() < CAPACITY {
+                return Ok(true);
+            }
+        }
+        tokio::time::sleep(POLL_INTERVAL).await;
+    }
+}
@@ -0,0 +1,127 @@
+//! Asking about the text in the clipboard, and the opt-in watching of the clipboard.
+//!
+//! While the clipboard is watched, `clipboard://changed` ({ text }) is emitted when text is copied in any app.
+
+use crate::prompt_template::{render, render_template};
+use crate::quick_ask::{ask, configured_model};
+use crate::{AppState, Error};
+use sqlx::Connection;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tauri::{AppHandle, ClipboardManager, Manager};
+
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+/// Used when no template is specified. Templates receive the text as `{{clipboard}}`.
+const DEFAULT_PROMPT: &str =
+    "Explain the following text. If it is not in English, also translate it into English.\n\n{{clipboard}}";
+/// The length of the text in the name of the conversation.
+const NAME_LENGTH: usize = 40;
+
+#[derive(serde::Serialize, Clone)]
+struct ClipboardChanged {
+    text: String,
+}
+
+fn read_text(app: &AppHandle) -> Result<Option<String>, Error> {
+    Ok(app
+        .clipboard_manager()
+        .read_text()?
+        .filter(|text| !text.trim().is_empty()))
+}
+
+/// Starts or stops watching the clipboard. The text already in the clipboard is not reported.
+#[tauri::command]
+#[tracing::instrument(skip(app, state), err)]
+pub(crate) fn set_clipboard_watch(
+    app: AppHandle,
+    enabled: bool,
+    state: tauri::State<AppState>,
+) -> Result<(), Error> {
+    let mut watch = state.clipboard_watch.lock()?;
+    if let Some(stop) = watch.take() {
+        stop.store(true, Ordering::SeqCst);
+    }
+    if !enabled {
+        return Ok(());
+    }
+    let stop = Arc::new(AtomicBool::new(false));
+    *watch = Some(stop.clone());
+    tauri::async_runtime::spawn(async move {
+        let mut last = read_text(&app).ok().flatten();
+        while !stop.load(Ordering::SeqCst) {
+            tokio::time::sleep(POLL_INTERVAL).await;
+            match read_text(&app) {
+                Ok(Some(text)) if last.as_ref() != Some(&text) => {
+                    last = Some(text.clone());
+                    let _ = app.emit_all("clipboard://changed", ClipboardChanged { text });
+                }
+                Ok(_) => {}
+                Err(err) => tracing::warn!("failed to read the clipboard: {err}"),
+            }
+        }
+    });
+    Ok(())
+}
+
+/// Asks the configured model about the text in the clipboard with the prompt template, and stores the question and the
+/// answer in a new conversation, whose id is returned.
+#[tauri::command]
+#[tracing::instrument(skip(app, state), err)]
+pub(crate) async fn ask_about_clipboard(
+    app: AppHandle,
+    template_id: Option<i64>,
+    state: tauri::State<'_, AppState>,
+) -> Result<i64, Error> {
+    let text = read_text(&app)?
+        .ok_or_else(|| Error::StringError("The clipboard has no text".to_owned()))?;
+    let vars = HashMap::from([("clipboard".to_owned(), text.clone())]);
+    let mut conn = state.db_pool.acquire().await?;
+    let prompt = match template_id {
+        Some(id) => render_template(&mut conn, id, &vars).await?,
+        None => render(DEFAULT_PROMPT, &vars)?,
+    };
+    let model = configured_model(&mut conn).await?;
+    let answer = ask(&mut conn, &model, prompt.clone()).await?;
+
+    let mut tx = conn.begin().await?;
+    let root = sqlx::query(
+        "INSERT INTO message (parent, role, status, content) VALUES (NULL, 'root', 0, '')",
+    )
+    .execute(&mut *tx)
+    .await?
+    .last_insert_rowid();
+    let name = text.split_whitespace().collect::<Vec<_>>().join(" ");
+    let name = match name.char_indices().nth(NAME_LENGTH) {
+        Some((i, _)) => format!("{}…", &name[..i]),
+        None => name,
+    };
+    sqlx::query("INSERT INTO threadName (messageId, name) VALUES (?, ?)")
+        .bind(root)
+        .bind(format!("Clipboard: {name}"))
+        .execute(&mut *tx)
+        .await?;
+    let question =
+        sqlx::query("INSERT INTO message (parent, role, status, content) VALUES (?, 'user', 0, ?)")
+            .bind(root)
+            .bind(prompt)
+            .execute(&mut *tx)
+            .await?
+            .last_insert_rowid();
+    let answer = sqlx::query(
+        "INSERT INTO message (parent, role, status, content) VALUES (?, 'assistant', 0, ?)",
+    )
+    .bind(question)
+    .bind(answer)
+    .execute(&mut *tx)
+    .await?
+    .last_insert_rowid();
+    sqlx::query("INSERT INTO messageModelV2 (messageId, model) VALUES (?, ?)")
+        .bind(answer)
+        .bind(model)
+        .execute(&mut *tx)
+        .await?;
+    tx.commit().await?;
+    Ok(root)
+}
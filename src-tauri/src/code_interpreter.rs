@@ -0,0 +1,287 @@
+//! Runs Python or JavaScript snippets for the `run_code` tool in a sandboxed subprocess.
+//!
+//! The snippet runs in an empty temporary directory with a cleared environment and is killed after `TIME_LIMIT`. The
+//! sandbox confines it to that directory: on Linux with bubblewrap (`bwrap`), which mounts only the system directories
+//! read-only and the work directory writable, and on macOS with `sandbox-exec`, whose profile denies writes outside the
+//! work directory and reads outside an allowlist. The network is also cut off unless `codeInterpreterNetwork` is
+//! enabled. Where the sandbox is unavailable, i.e. on Windows or without bwrap, the snippet is not run at all. CPU time
+//! is limited with `ulimit`, the memory of Python with `ulimit`, and that of Node.js with its heap size.
+//!
+//! Each run is approved by the user beforehand, see `tools`.
+
+use crate::{read_config, Error};
+use std::path::Path;
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+use std::path::PathBuf;
+use std::process::Stdio;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+const TIME_LIMIT: Duration = Duration::from_secs(10);
+const MEMORY_LIMIT_MB: u64 = 512;
+/// The output given to the model is truncated to this.
+const MAX_OUTPUT_BYTES: usize = 16 * 1024;
+/// The directories that the snippet may read, besides the work directory and the installation of the interpreter.
+#[cfg(target_os = "linux")]
+const READABLE_DIRS: [&str; 7] = ["/usr", "/bin", "/sbin", "/lib", "/lib32", "/lib64", "/etc"];
+#[cfg(target_os = "macos")]
+const READABLE_DIRS: [&str; 9] = [
+    "/usr",
+    "/bin",
+    "/System",
+    "/Library",
+    "/private/etc",
+    "/private/var/db",
+    "/dev",
+    "/opt/homebrew",
+    "/usr/local",
+];
+
+#[derive(serde::Deserialize, Clone, Copy, Debug)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Language {
+    Python,
+    JavaScript,
+}
+
+impl Language {
+    fn interpreter(self) -> &'static str {
+        match self {
+            Language::Python => "python3",
+            Language::JavaScript => "node",
+        }
+    }
+
+    fn extension(self) -> &'static str {
+        match self {
+            Language::Python => "py",
+            Language::JavaScript => "js",
+        }
+    }
+}
+
+/// Finds the program in PATH, with its symbolic links resolved.
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+fn find_in_path(name: &str) -> Option<PathBuf> {
+    std::env::split_paths(&std::env::var_os("PATH")?)
+        .map(|dir| dir.join(name))
+        .find(|path| path.is_file())
+        .and_then(|path| path.canonicalize().ok())
+}
+
+/// The directory of the installation of the interpreter, e.g. "~/.pyenv/versions/3.12.0" for
+/// "~/.pyenv/versions/3.12.0/bin/python3", which the snippet may read for the standard library. Only the directory of the
+/// executable if that would expose the home directory, e.g. for "~/bin/node".
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+fn installation_dir(interpreter: &Path) -> PathBuf {
+    let bin = interpreter.parent().unwrap_or(Path::new("/"));
+    let prefix = bin.parent().unwrap_or(bin);
+    let home = std::env::var_os("HOME").map(PathBuf::from);
+    if prefix == Path::new("/") || home.map_or(false, |home| home.starts_with(prefix)) {
+        bin.to_owned()
+    } else {
+        prefix.to_owned()
+    }
+}
+
+#[cfg(target_os = "macos")]
+fn sandbox_profile(dir: &Path, installation: &Path, allow_network: bool) -> String {
+    let quote = |path: &Path| {
+        format!(
+            "\"{}\"",
+            path.to_string_lossy()
+                .replace('\\', "\\\\")
+                .replace('"', "\\\"")
+        )
+    };
+    let readable = READABLE_DIRS
+        .iter()
+        .map(|dir| format!("(subpath {})", quote(Path::new(dir))))
+        .collect::<String>();
+    // The later rules take precedence over the earlier ones.
+    format!(
+        "(version 1)(allow default){}(deny file-write*)(allow file-write* (subpath {dir}) (literal \"/dev/null\"))\
+         (deny file-read*)(allow file-read-metadata)(allow file-read* (literal \"/\") {readable}(subpath {}) (subpath {dir}))",
+        if allow_network { "" } else { "(deny network*)" },
+        quote(installation),
+        dir = quote(dir),
+    )
+}
+
+/// Builds the command line that runs the script in the sandbox under the limits.
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+fn command_line(
+    language: Language,
+    dir: &Path,
+    script: &Path,
+    allow_network: bool,
+) -> Result<Vec<String>, Error> {
+    let interpreter = find_in_path(language.interpreter()).ok_or_else(|| {
+        Error::StringError(format!("{} is not installed", language.interpreter()))
+    })?;
+    let interpreter = interpreter.as_path();
+    let path = |path: &Path| path.to_string_lossy().into_owned();
+    let mut args = vec![];
+    #[cfg(target_os = "linux")]
+    {
+        let bwrap = find_in_path("bwrap").ok_or_else(|| {
+            Error::StringError(
+                "Code execution requires bubblewrap (bwrap), which isolates the program from your files. Install it with the package manager of your distribution.".to_owned(),
+            )
+        })?;
+        args.push(path(&bwrap));
+        args.extend(["--unshare-all", "--die-with-parent", "--new-session"].map(str::to_owned));
+        if allow_network {
+            args.push("--share-net".to_owned());
+        }
+        for dir in READABLE_DIRS {
+            args.extend(["--ro-bind-try".to_owned(), dir.to_owned(), dir.to_owned()]);
+        }
+        let installation = path(&installation_dir(interpreter));
+        args.extend([
+            "--ro-bind-try".to_owned(),
+            installation.clone(),
+            installation,
+        ]);
+        args.extend(["--proc", "/proc", "--dev", "/dev", "--tmpfs", "/tmp"].map(str::to_owned));
+        args.extend(["--bind".to_owned(), path(dir), path(dir)]);
+        args.extend(["--chdir".to_owned(), path(dir)]);
+    }
+    #[cfg(target_os = "macos")]
+    args.extend([
+        "sandbox-exec".to_owned(),
+        "-p".to_owned(),
+        sandbox_profile(dir, &installation_dir(interpreter), allow_network),
+    ]);
+
+    // V8 reserves far more virtual memory than it uses, so `ulimit -v` would prevent Node.js from starting.
+    let memory_limit = match language {
+        Language::Python => format!("ulimit -v {} 2>/dev/null; ", MEMORY_LIMIT_MB * 1024),
+        Language::JavaScript => String::new(),
+    };
+    args.extend([
+        "/bin/sh".to_owned(),
+        "-c".to_owned(),
+        format!(
+            "ulimit -t {} 2>/dev/null; {memory_limit}exec \"$0\" \"$@\"",
+            TIME_LIMIT.as_secs()
+        ),
+        path(interpreter),
+    ]);
+    if let Language::JavaScript = language {
+        args.push(format!("--max-old-space-size={MEMORY_LIMIT_MB}"));
+    }
+    args.push(path(script));
+    Ok(args)
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+fn command_line(
+    _language: Language,
+    _dir: &Path,
+    _script: &Path,
+    _allow_network: bool,
+) -> Result<Vec<String>, Error> {
+    Err(Error::StringError(
+        "Code execution is not supported on this OS, where the program cannot be isolated from your files".to_owned(),
+    ))
+}
+
+fn truncate(mut output: String) -> String {
+    if output.len() > MAX_OUTPUT_BYTES {
+        let mut end = MAX_OUTPUT_BYTES;
+        while !output.is_char_boundary(end) {
+            end -= 1;
+        }
+        output.truncate(end);
+        output += "\n... (truncated)";
+    }
+    output
+}
+
+/// Reads the stream up to one byte more than `MAX_OUTPUT_BYTES`, so that an endless output cannot exhaust the memory.
+/// Returns whether the output was cut.
+async fn read_limited(stream: impl AsyncRead + Unpin, buf: &mut Vec<u8>) -> std::io::Result<bool> {
+    stream
+        .take(MAX_OUTPUT_BYTES as u64 + 1)
+        .read_to_end(buf)
+        .await?;
+    Ok(buf.len() > MAX_OUTPUT_BYTES)
+}
+
+/// Runs the code and returns the exit code, stdout, and stderr as the result for the model.
+pub(crate) async fn run(
+    conn: &mut sqlx::SqliteConnection,
+    language: Language,
+    code: &str,
+) -> Result<String, Error> {
+    let allow_network = read_config(conn, "codeInterpreterNetwork")
+        .await?
+        .as_deref()
+        == Some("1");
+    let tmp = tempfile::tempdir()?;
+    // The sandbox of macOS matches the real path, e.g. /private/var/folders/... for /var/folders/...
+    let dir = tmp.path().canonicalize()?;
+    let script = dir.join(format!("main.{}", language.extension()));
+    tokio::fs::write(&script, code).await?;
+
+    let args = command_line(language, &dir, &script, allow_network)?;
+    let mut command = tokio::process::Command::new(&args[0]);
+    command
+        .args(&args[1..])
+        .current_dir(&dir)
+        .env_clear()
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true);
+    if let Some(path) = std::env::var_os("PATH") {
+        command.env("PATH", path);
+    }
+    command.env("HOME", &dir);
+    let mut child = command.spawn().map_err(|err| {
+        Error::StringError(format!("Failed to run {}: {err}", language.interpreter()))
+    })?;
+
+    let mut stdout = child.stdout.take().expect("stdout is piped");
+    let mut stderr = child.stderr.take().expect("stderr is piped");
+    let (mut out, mut err) = (vec![], vec![]);
+    let mut cut = false;
+    let status = tokio::time::timeout(TIME_LIMIT, async {
+        {
+            let read_out = read_limited(&mut stdout, &mut out);
+            let read_err = read_limited(&mut stderr, &mut err);
+            tokio::pin!(read_out, read_err);
+            let (mut out_done, mut err_done) = (false, false);
+            while !(out_done && err_done) {
+                let stream_cut = tokio::select! {
+                    result = &mut read_out, if !out_done => { out_done = true; result? }
+                    result = &mut read_err, if !err_done => { err_done = true; result? }
+                };
+                // The snippet would block on the full pipe until the time limit, and the rest is dropped anyway.
+                if stream_cut && !cut {
+                    cut = true;
+                    child.start_kill()?;
+                }
+            }
+        }
+        child.wait().await
+    })
+    .await;
+    let exit = match status {
+        Ok(status) => match status?.code() {
+            _ if cut => format!("killed after printing more than {MAX_OUTPUT_BYTES} bytes"),
+            Some(code) => format!("exit code: {code}"),
+            None => "killed by a signal (e.g. the CPU or memory limit)".to_owned(),
+        },
+        Err(_) => format!(
+            "timed out after {} seconds and was killed",
+            TIME_LIMIT.as_secs()
+        ),
+    };
+    Ok(format!(
+        "{exit}\nstdout:\n{}\nstderr:\n{}",
+        truncate(String::from_utf8_lossy(&out).into_owned()),
+        truncate(String::from_utf8_lossy(&err).into_owned())
+    ))
+}
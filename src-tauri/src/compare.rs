@@ -0,0 +1,101 @@
+//! Sends one prompt to several models at once, for comparing their responses side by side.
+//!
+//! Each target is an ordinary chat completion whose request id is `sub_request_id(request_id, index)`, so the frontend
+//! polls `get_chat_completion` for each of them and `stop_all_chat_completions` cancels them all. The call is rejected if
+//! one of these ids is already used by a request that has not been removed, or does not fit in a u64. The frontend should
+//! also choose `request_id` so that `request_id + targets.length` stays within `Number.MAX_SAFE_INTEGER`.
+
+use crate::{chunk_buffer, send_chat_completion, AppState, ChatProvider, Error};
+use serde_json::Value;
+use std::collections::HashMap;
+
+#[derive(serde::Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct ProviderTarget {
+    secret_key: String,
+    /// "" for the local ollama server or the gemini endpoint of the model, as in `start_chat_completion`.
+    endpoint: String,
+    #[serde(default)]
+    api_key_authentication: bool,
+    provider: Option<ChatProvider>,
+    /// Replaces the model in the body.
+    model: String,
+    headers: Option<HashMap<String, String>>,
+    query: Option<HashMap<String, String>>,
+}
+
+#[derive(serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct TargetResult {
+    request_id: u64,
+    model: String,
+    /// None if the response was completed or canceled.
+    error: Option<String>,
+}
+
+/// The request id under which the response of the `index`-th target is streamed.
+fn sub_request_id(request_id: u64, index: usize) -> Result<u64, Error> {
+    u64::try_from(index)
+        .ok()
+        .and_then(|index| request_id.checked_add(index))
+        .ok_or_else(|| Error::StringError(format!("The request id {request_id} is too large")))
+}
+
+/// Streams the response of every target to the same body concurrently, and returns the outcome of each one when all
+/// of them have finished. A failing target does not stop the others.
+#[tauri::command]
+#[tracing::instrument(skip(window, body, state), err)]
+pub(crate) async fn start_chat_completion_multi(
+    window: tauri::Window,
+    request_id: u64,
+    targets: Vec<ProviderTarget>,
+    body: String,
+    state: tauri::State<'_, AppState>,
+) -> Result<Vec<TargetResult>, Error> {
+    let request: Value = serde_json::from_str(&body)?;
+    let sub_request_ids = (0..targets.len())
+        .map(|index| sub_request_id(request_id, index))
+        .collect::<Result<Vec<_>, _>>()?;
+    for id in &sub_request_ids {
+        if chunk_buffer::contains(*id)? {
+            return Err(Error::StringError(format!(
+                "The request id {id} is already in use"
+            )));
+        }
+    }
+    let mut completions = vec![];
+    for (target, sub_request_id) in targets.into_iter().zip(sub_request_ids) {
+        let mut request = request.clone();
+        request["model"] = Value::String(target.model.clone());
+        let completion = send_chat_completion(
+            window.clone(),
+            sub_request_id,
+            target.secret_key,
+            serde_json::to_string(&request)?,
+            target.endpoint,
+            target.api_key_authentication,
+            target.provider,
+            None,
+            None,
+            None,
+            target.headers,
+            target.query,
+            None,
+            Some(target.model.clone()),
+            None,
+            None,
+            None,
+            None,
+            None,
+            state.clone(),
+        );
+        completions.push(async move {
+            TargetResult {
+                request_id: sub_request_id,
+                model: target.model,
+                error: completion.await.err().map(|err| err.to_string()),
+            }
+        });
+    }
+    Ok(futures_util::future::join_all(completions).await)
+}
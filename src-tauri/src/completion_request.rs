@@ -0,0 +1,172 @@
+//! The body of a chat completion request, built from the parameters of `start_chat_completion` instead of being passed
+//! through as a JSON string.
+//!
+//! The parameters are given in camelCase and validated before anything is sent, so that a bad value fails with an error
+//! that names the field rather than with the provider's 400 Bad Request. The body is serialized in the snake_case of the
+//! OpenAI API, with `stream` always set, and translated for the other providers by `send_chat_completion`.
+
+use crate::Error;
+use serde_json::Value;
+
+/// The maximum number of stop sequences accepted by OpenAI.
+const MAX_STOP_SEQUENCES: usize = 4;
+
+/// `response_format`, e.g. `{ "type": "json_object" }`.
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub(crate) enum ResponseFormat {
+    Text,
+    JsonObject,
+    /// `json_schema` is `{ "name": ..., "schema": ..., "strict": ... }`.
+    JsonSchema {
+        json_schema: Value,
+    },
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+#[serde(rename_all(deserialize = "camelCase"))]
+pub(crate) struct CompletionRequest {
+    /// Optional for Azure, whose deployment is in the endpoint.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    model: Option<String>,
+    /// The models that OpenRouter falls back to, in order, if `model` fails.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    models: Option<Vec<String>>,
+    /// The chat messages. Either `messages` or `prompt` must be given.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    messages: Option<Vec<Value>>,
+    /// The ChatML prompt of the legacy Azure completions API.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    prompt: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stop: Option<Vec<String>>,
+    /// In [0, 2].
+    #[serde(skip_serializing_if = "Option::is_none")]
+    temperature: Option<f64>,
+    /// In [0, 1].
+    #[serde(skip_serializing_if = "Option::is_none")]
+    top_p: Option<f64>,
+    /// In [-2, 2].
+    #[serde(skip_serializing_if = "Option::is_none")]
+    presence_penalty: Option<f64>,
+    /// In [-2, 2].
+    #[serde(skip_serializing_if = "Option::is_none")]
+    frequency_penalty: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    response_format: Option<ResponseFormat>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    seed: Option<i64>,
+    /// Function definitions, e.g. `{ "type": "function", "function": { "name": ... } }`. The built-in tools and the tools
+    /// of MCP servers are added to these.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tools: Option<Vec<Value>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_tokens: Option<u32>,
+    /// Requests the usage in the last chunk, which OpenAI supports and Azure does not.
+    #[serde(default, skip_serializing)]
+    include_usage: bool,
+}
+
+fn invalid(field: &str, message: &str) -> Error {
+    Error::StringError(format!("Invalid completion request: {field} {message}"))
+}
+
+fn check_range(
+    field: &str,
+    value: Option<f64>,
+    range: std::ops::RangeInclusive<f64>,
+) -> Result<(), Error> {
+    match value {
+        Some(value) if !range.contains(&value) => Err(invalid(
+            field,
+            &format!(
+                "must be between {} and {}, but is {value}",
+                range.start(),
+                range.end()
+            ),
+        )),
+        _ => Ok(()),
+    }
+}
+
+impl CompletionRequest {
+    fn validate(&self) -> Result<(), Error> {
+        if self
+            .model
+            .as_ref()
+            .map_or(false, |model| model.trim().is_empty())
+        {
+            return Err(invalid("model", "must not be empty"));
+        }
+        if self
+            .models
+            .iter()
+            .flatten()
+            .any(|model| model.trim().is_empty())
+        {
+            return Err(invalid("models", "must not contain an empty model"));
+        }
+        match (&self.messages, &self.prompt) {
+            (None, None) => return Err(invalid("messages", "or prompt is required")),
+            (Some(_), Some(_)) => return Err(invalid("prompt", "must not be given with messages")),
+            (Some(messages), None) if messages.is_empty() => {
+                return Err(invalid("messages", "must not be empty"))
+            }
+            _ => {}
+        }
+        if let Some(messages) = &self.messages {
+            for (i, message) in messages.iter().enumerate() {
+                if message.get("role").and_then(Value::as_str).is_none() {
+                    return Err(invalid(&format!("messages[{i}].role"), "is required"));
+                }
+            }
+        }
+        if let Some(stop) = &self.stop {
+            if stop.len() > MAX_STOP_SEQUENCES {
+                return Err(invalid(
+                    "stop",
+                    &format!("must have at most {MAX_STOP_SEQUENCES} sequences"),
+                ));
+            }
+            if stop.iter().any(String::is_empty) {
+                return Err(invalid("stop", "must not contain an empty sequence"));
+            }
+        }
+        check_range("temperature", self.temperature, 0.0..=2.0)?;
+        check_range("topP", self.top_p, 0.0..=1.0)?;
+        check_range("presencePenalty", self.presence_penalty, -2.0..=2.0)?;
+        check_range("frequencyPenalty", self.frequency_penalty, -2.0..=2.0)?;
+        if let Some(ResponseFormat::JsonSchema { json_schema }) = &self.response_format {
+            if json_schema.get("name").and_then(Value::as_str).is_none() {
+                return Err(invalid("responseFormat.json_schema.name", "is required"));
+            }
+        }
+        for (i, tool) in self.tools.iter().flatten().enumerate() {
+            if tool.get("type").and_then(Value::as_str) != Some("function") {
+                return Err(invalid(&format!("tools[{i}].type"), "must be \"function\""));
+            }
+            if tool
+                .pointer("/function/name")
+                .and_then(Value::as_str)
+                .map_or(true, str::is_empty)
+            {
+                return Err(invalid(&format!("tools[{i}].function.name"), "is required"));
+            }
+        }
+        if self.max_tokens == Some(0) {
+            return Err(invalid("maxTokens", "must be positive"));
+        }
+        Ok(())
+    }
+
+    /// Validates the request and returns the JSON body of the streamed completion.
+    pub(crate) fn to_body(&self) -> Result<String, Error> {
+        self.validate()?;
+        let mut body = serde_json::to_value(self)?;
+        body["stream"] = Value::Bool(true);
+        if self.include_usage {
+            body["stream_options"] = serde_json::json!({ "include_usage": true });
+        }
+        Ok(body.to_string())
+    }
+}
@@ -0,0 +1,188 @@
+//! Fits the history of a conversation into the context window of a model.
+//!
+//! The oldest messages after the leading system messages are dropped until the prompt fits, and the oldest remaining one
+//! is truncated if it is still too long. Dropped messages are not summarized here, but the messages covered by the summary of
+//! `summarize_conversation` are replaced with it. Pinned messages are neither dropped nor replaced.
+
+use crate::conversation::load_thread;
+use crate::pinned::pinned_ids;
+use crate::summary::load_summary;
+use crate::{count_message_tokens, with_bpe, AppState, Error, Message};
+use tiktoken_rs::CoreBPE;
+
+/// Used for the models that are not in `context_window()`.
+const DEFAULT_CONTEXT_WINDOW: usize = 4096;
+/// The tokens kept for the response: a quarter of the context window, up to this.
+const MAX_RESPONSE_TOKENS: usize = 4096;
+const OMITTED: &str = " ... (omitted)";
+
+/// The number of tokens of the prompt and the response.
+pub(crate) fn context_window(model: &str) -> usize {
+    match model {
+        m if m.starts_with("gpt-4.1") => 1047576,
+        m if m.starts_with("o1-mini") => 128000,
+        m if m.starts_with("o1") || m.starts_with("o3") => 200000,
+        m if m.starts_with("gpt-4o") || m.starts_with("gpt-4-turbo") => 128000,
+        m if m.starts_with("chatgpt-4o") => 128000,
+        m if m.starts_with("gpt-4-1106") || m.starts_with("gpt-4-0125") => 128000,
+        m if m.starts_with("gpt-4-32k") => 32768,
+        m if m.starts_with("gpt-4") => 8192,
+        m if m.starts_with("gpt-3.5-turbo-16k")
+            || m.starts_with("gpt-3.5-turbo-1106")
+            || m.starts_with("gpt-3.5-turbo-0125") =>
+        {
+            16385
+        }
+        m if m.starts_with("gpt-3.5-turbo") => 4096,
+        m if m.starts_with("gemini-1.5") => 1048576,
+        m if m.starts_with("gemini") => 32760,
+        _ => DEFAULT_CONTEXT_WINDOW,
+    }
+}
+
+#[derive(serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct RequestMessages {
+    messages: Vec<Message>,
+    prompt_tokens: usize,
+    /// The number of oldest messages that were dropped.
+    dropped: usize,
+    /// Whether the content of the oldest remaining message was cut.
+    truncated: bool,
+    /// Whether the summary of the conversation replaced the messages it covers.
+    summarized: bool,
+    /// The number of pinned messages in `messages`.
+    pinned: usize,
+}
+
+/// Cuts the tail of the content so that it is `excess` tokens shorter, and marks it as omitted.
+fn truncate(bpe: &CoreBPE, content: &str, excess: usize) -> String {
+    let tokens = bpe.encode_with_special_tokens(content);
+    let omitted = bpe.encode_with_special_tokens(OMITTED).len();
+    let mut keep = tokens.len().saturating_sub(excess + omitted);
+    // A token boundary can split a multi-byte character.
+    loop {
+        if let Ok(prefix) = bpe.decode(tokens[..keep].to_vec()) {
+            return prefix + OMITTED;
+        }
+        keep -= 1;
+    }
+}
+
+/// `pinned` tells which of `messages` are pinned.
+fn trim(
+    bpe: &CoreBPE,
+    model: &str,
+    mut messages: Vec<Message>,
+    mut pinned: Vec<bool>,
+    limit: usize,
+    summarized: bool,
+) -> Result<RequestMessages, Error> {
+    let num_system = messages.iter().take_while(|m| m.role == "system").count();
+    let mut dropped = 0;
+    let mut prompt_tokens = count_message_tokens(bpe, model, &messages);
+    // The last message is never dropped.
+    while prompt_tokens > limit {
+        let Some(i) = (num_system..messages.len().saturating_sub(1)).find(|&i| !pinned[i]) else {
+            break;
+        };
+        messages.remove(i);
+        pinned.remove(i);
+        dropped += 1;
+        prompt_tokens = count_message_tokens(bpe, model, &messages);
+    }
+    let mut truncated = false;
+    if prompt_tokens > limit {
+        if let Some(i) = (num_system..messages.len()).find(|&i| !pinned[i]) {
+            let message = &mut messages[i];
+            message.content = truncate(bpe, &message.content, prompt_tokens - limit);
+            truncated = true;
+            prompt_tokens = count_message_tokens(bpe, model, &messages);
+        }
+    }
+    if prompt_tokens > limit {
+        return Err(Error::StringError(format!(
+            "The system and pinned messages ({prompt_tokens} tokens) do not fit in {limit} tokens"
+        )));
+    }
+    Ok(RequestMessages {
+        messages,
+        prompt_tokens,
+        dropped,
+        truncated,
+        summarized,
+        pinned: pinned.iter().filter(|&&p| p).count(),
+    })
+}
+
+/// Loads the messages of the conversation, as shown by default, and trims the oldest ones so that the prompt fits in the
+/// context window of the model with room for the response, and in `max_tokens` if specified (e.g. from the budget per
+/// message). Messages that are being generated are excluded.
+///
+/// If the conversation has been summarized up to a message of the thread, the summary is sent as a system message after
+/// the leading system messages instead of the messages it covers.
+#[tauri::command]
+#[tracing::instrument(skip(state), err)]
+pub(crate) async fn build_request_messages(
+    conversation_id: i64,
+    model: String,
+    max_tokens: Option<usize>,
+    state: tauri::State<'_, AppState>,
+) -> Result<RequestMessages, Error> {
+    let (thread, summary, pinned_ids) = {
+        let mut conn = state.db_pool.acquire().await?;
+        let thread = load_thread(&mut conn, conversation_id).await?;
+        let ids = thread.iter().map(|m| m.id).collect::<Vec<_>>();
+        (
+            thread,
+            load_summary(&mut conn, conversation_id).await?,
+            pinned_ids(&mut conn, &ids).await?,
+        )
+    };
+    let thread = thread
+        .into_iter()
+        .filter(|m| m.role != "root" && m.status != -1)
+        .collect::<Vec<_>>();
+    let num_system = thread.iter().take_while(|m| m.role == "system").count();
+    let summary = summary.and_then(|(up_to, summary)| {
+        let end = thread.iter().position(|m| m.id == up_to)?;
+        (end >= num_system).then_some((end, summary))
+    });
+    let summarized = summary.is_some();
+    let mut messages = vec![];
+    let mut rest = thread.into_iter().enumerate().map(|(i, m)| {
+        (
+            i,
+            pinned_ids.contains(&m.id),
+            Message {
+                role: m.role,
+                name: None,
+                content: m.content,
+            },
+        )
+    });
+    messages.extend(rest.by_ref().take(num_system).map(|(_, p, m)| (p, m)));
+    if let Some((end, summary)) = summary {
+        messages.push((
+            false,
+            Message {
+                role: "system".to_owned(),
+                name: None,
+                content: format!("Summary of the earlier conversation:\n{summary}"),
+            },
+        ));
+        messages.extend(
+            rest.filter(|(i, p, _)| *i > end || *p)
+                .map(|(_, p, m)| (p, m)),
+        );
+    } else {
+        messages.extend(rest.map(|(_, p, m)| (p, m)));
+    }
+    let (pinned, messages) = messages.into_iter().unzip();
+    let window = context_window(&model);
+    let limit = window - (window / 4).min(MAX_RESPONSE_TOKENS);
+    let limit = max_tokens.map_or(limit, |max_tokens| max_tokens.min(limit));
+    with_bpe(&model, |bpe| {
+        trim(bpe, &model, messages, pinned, limit, summarized)
+    })?
+}
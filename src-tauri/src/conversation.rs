@@ -0,0 +1,468 @@
+//! Typed access to the conversations stored in the `message` table.
+//!
+//! A conversation is a tree of messages whose root has no parent and the role "root". Its name is stored in `threadName`.
+//! Editing or regenerating a message adds a sibling, so each leaf of the tree ends a branch of the conversation. The
+//! branch shown by default is the one selected with `switch_branch`, or else the latest child at every depth.
+
+use crate::{AppState, Error};
+use sqlx::{Connection, Row};
+
+const DEFAULT_PAGE_SIZE: u32 = 50;
+
+#[derive(serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct ConversationSummary {
+    id: i64,
+    name: Option<String>,
+    created_at: String,
+    modified_at: String,
+    folder_id: Option<i64>,
+    tags: Vec<String>,
+}
+
+/// Returns the name of the conversation of the message, which may be the root message of the conversation.
+pub(crate) async fn name_of(
+    conn: &mut sqlx::SqliteConnection,
+    message_id: i64,
+) -> Result<Option<String>, Error> {
+    Ok(sqlx::query(
+        "
+WITH RECURSIVE ancestors(id, parent) AS (
+    SELECT id, parent FROM message WHERE id = ?
+    UNION ALL
+    SELECT message.id, message.parent FROM ancestors JOIN message ON message.id = ancestors.parent
+)
+SELECT threadName.name AS name
+FROM ancestors JOIN threadName ON threadName.messageId = ancestors.id
+WHERE ancestors.parent IS NULL
+",
+    )
+    .bind(message_id)
+    .fetch_optional(conn)
+    .await?
+    .map(|row| row.get("name")))
+}
+
+/// Creates a conversation and returns the id of its root message.
+#[tauri::command]
+#[tracing::instrument(skip(state), err)]
+pub(crate) async fn create_conversation(
+    name: Option<String>,
+    state: tauri::State<'_, AppState>,
+) -> Result<i64, Error> {
+    let mut conn = state.db_pool.acquire().await?;
+    let mut tx = conn.begin().await?;
+    let id = sqlx::query(
+        "INSERT INTO message (parent, role, status, content) VALUES (NULL, 'root', 0, '')",
+    )
+    .execute(&mut *tx)
+    .await?
+    .last_insert_rowid();
+    if let Some(name) = name {
+        sqlx::query("INSERT OR REPLACE INTO threadName (messageId, name) VALUES (?, ?)")
+            .bind(id)
+            .bind(name)
+            .execute(&mut *tx)
+            .await?;
+    }
+    tx.commit().await?;
+    Ok(id)
+}
+
+/// Appends a message as a child of `parent` and returns its id.
+#[tauri::command]
+#[tracing::instrument(skip(content, state), err)]
+pub(crate) async fn append_message(
+    parent: i64,
+    role: String,
+    status: i64, // -1 = generating, 0 = ok, 1 = error
+    content: String,
+    model: Option<String>,
+    state: tauri::State<'_, AppState>,
+) -> Result<i64, Error> {
+    let mut conn = state.db_pool.acquire().await?;
+    let mut tx = conn.begin().await?;
+    let id = sqlx::query("INSERT INTO message (parent, role, status, content) VALUES (?, ?, ?, ?)")
+        .bind(parent)
+        .bind(role)
+        .bind(status)
+        .bind(content)
+        .execute(&mut *tx)
+        .await?
+        .last_insert_rowid();
+    if let Some(model) = model {
+        sqlx::query("INSERT INTO messageModelV2 (messageId, model) VALUES (?, ?)")
+            .bind(id)
+            .bind(model)
+            .execute(&mut *tx)
+            .await?;
+    }
+    tx.commit().await?;
+    Ok(id)
+}
+
+/// Lists conversations from the newest, optionally filtered by a substring of their names, a tag, and a folder. Folder
+/// 0 lists the conversations that are not in a folder.
+#[tauri::command]
+#[tracing::instrument(skip(state), err)]
+pub(crate) async fn list_conversations(
+    page: u32, // 0-based
+    page_size: Option<u32>,
+    filter: Option<String>,
+    tag: Option<String>,
+    folder_id: Option<i64>,
+    state: tauri::State<'_, AppState>,
+) -> Result<Vec<ConversationSummary>, Error> {
+    let page_size = page_size.unwrap_or(DEFAULT_PAGE_SIZE);
+    let mut conn = state.db_pool.acquire().await?;
+    Ok(sqlx::query(
+        "
+SELECT message.id AS id, threadName.name AS name, message.createdAt AS createdAt, message.modifiedAt AS modifiedAt,
+    conversationFolder.folderId AS folderId,
+    (SELECT json_group_array(tag) FROM conversationTag WHERE conversationId = message.id) AS tags
+FROM message
+LEFT OUTER JOIN threadName ON message.id = threadName.messageId
+LEFT OUTER JOIN conversationFolder ON message.id = conversationFolder.conversationId
+WHERE message.parent IS NULL AND (?1 IS NULL OR threadName.name LIKE '%' || ?1 || '%')
+    AND (?4 IS NULL OR EXISTS (SELECT 1 FROM conversationTag WHERE conversationId = message.id AND tag = ?4))
+    AND (?5 IS NULL OR coalesce(conversationFolder.folderId, 0) = ?5)
+ORDER BY message.createdAt DESC
+LIMIT ?2 OFFSET ?3
+",
+    )
+    .bind(filter.filter(|f| !f.is_empty()))
+    .bind(page_size)
+    .bind(page.saturating_mul(page_size))
+    .bind(tag.filter(|t| !t.is_empty()))
+    .bind(folder_id)
+    .fetch_all(&mut conn)
+    .await?
+    .into_iter()
+    .map(|row| -> Result<_, Error> {
+        Ok(ConversationSummary {
+            id: row.get("id"),
+            name: row.get("name"),
+            created_at: row.get("createdAt"),
+            modified_at: row.get("modifiedAt"),
+            folder_id: row.get("folderId"),
+            tags: serde_json::from_str(row.get("tags"))?,
+        })
+    })
+    .collect::<Result<_, _>>()?)
+}
+
+/// Lists every conversation with the tag, from the newest.
+#[tauri::command]
+#[tracing::instrument(skip(state), err)]
+pub(crate) async fn list_by_tag(
+    tag: String,
+    state: tauri::State<'_, AppState>,
+) -> Result<Vec<ConversationSummary>, Error> {
+    list_conversations(0, Some(u32::MAX), None, Some(tag), None, state).await
+}
+
+/// Deletes a conversation. Its messages, names, bookmarks, and TTS caches are removed by `ON DELETE CASCADE`.
+#[tauri::command]
+#[tracing::instrument(skip(state), err)]
+pub(crate) async fn delete_conversation(
+    conversation_id: i64,
+    state: tauri::State<'_, AppState>,
+) -> Result<(), Error> {
+    let mut conn = state.db_pool.acquire().await?;
+    let mut tx = conn.begin().await?;
+    let result = sqlx::query("DELETE FROM message WHERE id = ? AND parent IS NULL")
+        .bind(conversation_id)
+        .execute(&mut *tx)
+        .await?;
+    if result.rows_affected() == 0 {
+        return Err(Error::StringError(format!(
+            "Conversation {conversation_id} does not exist"
+        )));
+    }
+    tx.commit().await?;
+    Ok(())
+}
+
+#[derive(serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct MessageSearchResult {
+    message_id: i64,
+    conversation_id: i64,
+    /// The matched part of the content, with the matched terms surrounded by <mark> and </mark>.
+    snippet: String,
+    /// bm25 score, lower is more relevant.
+    rank: f64,
+}
+
+/// Quotes each whitespace-separated term, so that the query is matched literally instead of being parsed as an FTS5 expression.
+fn fts5_query(query: &str) -> String {
+    query
+        .split_whitespace()
+        .map(|term| format!("\"{}\"", term.replace('"', "\"\"")))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Searches the content of every message with the full-text index `messageFTS`, from the most relevant.
+#[tauri::command]
+#[tracing::instrument(skip(query, state), err)]
+pub(crate) async fn search_messages(
+    query: String,
+    limit: u32,
+    offset: u32,
+    state: tauri::State<'_, AppState>,
+) -> Result<Vec<MessageSearchResult>, Error> {
+    let query = fts5_query(&query);
+    if query.is_empty() {
+        return Ok(vec![]);
+    }
+    let mut conn = state.db_pool.acquire().await?;
+    Ok(sqlx::query(
+        "
+WITH RECURSIVE hits AS (
+    SELECT rowid AS id, snippet(messageFTS, 0, '<mark>', '</mark>', '…', 16) AS snippet, rank
+    FROM messageFTS
+    WHERE messageFTS MATCH ?1
+    ORDER BY rank
+    LIMIT ?2 OFFSET ?3
+), ancestors(hit, id, parent) AS (
+    SELECT hits.id, message.id, message.parent FROM hits JOIN message ON message.id = hits.id
+    UNION ALL
+    SELECT ancestors.hit, message.id, message.parent FROM ancestors JOIN message ON message.id = ancestors.parent
+)
+SELECT hits.id AS messageId, ancestors.id AS conversationId, hits.snippet AS snippet, hits.rank AS rank
+FROM hits
+JOIN ancestors ON ancestors.hit = hits.id AND ancestors.parent IS NULL
+ORDER BY hits.rank
+",
+    )
+    .bind(query)
+    .bind(limit)
+    .bind(offset)
+    .fetch_all(&mut conn)
+    .await?
+    .into_iter()
+    .map(|row| MessageSearchResult {
+        message_id: row.get("messageId"),
+        conversation_id: row.get("conversationId"),
+        snippet: row.get("snippet"),
+        rank: row.get("rank"),
+    })
+    .collect())
+}
+
+#[derive(serde::Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct StoredMessage {
+    pub(crate) id: i64,
+    pub(crate) role: String,
+    pub(crate) status: i64,
+    pub(crate) content: String,
+    pub(crate) created_at: String,
+    pub(crate) model: Option<String>,
+}
+
+/// Returns the name of the conversation, or None if it is unnamed.
+pub(crate) async fn conversation_name(
+    conn: &mut sqlx::SqliteConnection,
+    conversation_id: i64,
+) -> Result<Option<String>, Error> {
+    Ok(
+        sqlx::query("SELECT name FROM threadName WHERE messageId = ?")
+            .bind(conversation_id)
+            .fetch_optional(conn)
+            .await?
+            .map(|row| row.get("name")),
+    )
+}
+
+/// Loads the messages of the conversation shown by default, i.e. the root followed by the messages of the selected
+/// branch, and then by the latest child at every depth, as the frontend does.
+pub(crate) async fn load_thread(
+    conn: &mut sqlx::SqliteConnection,
+    conversation_id: i64,
+) -> Result<Vec<StoredMessage>, Error> {
+    let messages = sqlx::query(
+        "
+WITH RECURSIVE selected(id, parent) AS (
+    SELECT message.id, message.parent FROM selectedBranch JOIN message ON message.id = selectedBranch.leafId
+    WHERE selectedBranch.conversationId = ?1
+    UNION ALL
+    SELECT message.id, message.parent FROM selected JOIN message ON message.id = selected.parent
+), thread(id, depth) AS (
+    SELECT id, 0 FROM message WHERE id = ?1 AND parent IS NULL
+    UNION ALL
+    SELECT coalesce(
+        (SELECT id FROM message WHERE parent = thread.id AND id IN (SELECT id FROM selected)),
+        (SELECT max(id) FROM message WHERE parent = thread.id)
+    ), depth + 1 FROM thread
+    WHERE EXISTS (SELECT 1 FROM message WHERE parent = thread.id)
+)
+SELECT message.id AS id, role, status, content, createdAt, messageModelV2.model AS model
+FROM thread
+JOIN message ON message.id = thread.id
+LEFT OUTER JOIN messageModelV2 ON message.id = messageModelV2.messageId
+ORDER BY thread.depth
+",
+    )
+    .bind(conversation_id)
+    .fetch_all(conn)
+    .await?
+    .into_iter()
+    .map(|row| StoredMessage {
+        id: row.get("id"),
+        role: row.get("role"),
+        status: row.get("status"),
+        content: row.get("content"),
+        created_at: row.get("createdAt"),
+        model: row.get("model"),
+    })
+    .collect::<Vec<_>>();
+    if messages.is_empty() {
+        return Err(Error::StringError(format!(
+            "Conversation {conversation_id} does not exist"
+        )));
+    }
+    Ok(messages)
+}
+
+/// Returns the ids of the messages from the root of the conversation to the message.
+async fn path_to(conn: &mut sqlx::SqliteConnection, message_id: i64) -> Result<Vec<i64>, Error> {
+    let path = sqlx::query(
+        "
+WITH RECURSIVE ancestors(id, parent, depth) AS (
+    SELECT id, parent, 0 FROM message WHERE id = ?
+    UNION ALL
+    SELECT message.id, message.parent, ancestors.depth + 1 FROM ancestors JOIN message ON message.id = ancestors.parent
+)
+SELECT id FROM ancestors ORDER BY depth DESC
+",
+    )
+    .bind(message_id)
+    .fetch_all(conn)
+    .await?
+    .into_iter()
+    .map(|row| row.get::<i64, _>("id"))
+    .collect::<Vec<_>>();
+    if path.is_empty() {
+        return Err(Error::StringError(format!(
+            "Message {message_id} does not exist"
+        )));
+    }
+    Ok(path)
+}
+
+async fn select_branch(
+    conn: &mut sqlx::SqliteConnection,
+    conversation_id: i64,
+    leaf_id: i64,
+) -> Result<(), Error> {
+    sqlx::query("INSERT OR REPLACE INTO selectedBranch (conversationId, leafId) VALUES (?, ?)")
+        .bind(conversation_id)
+        .bind(leaf_id)
+        .execute(conn)
+        .await?;
+    Ok(())
+}
+
+/// Adds a copy of the message as its sibling, to be edited or regenerated without losing the original, and selects the
+/// new branch. Returns the id of the copy.
+#[tauri::command]
+#[tracing::instrument(skip(state), err)]
+pub(crate) async fn branch_from_message(
+    message_id: i64,
+    state: tauri::State<'_, AppState>,
+) -> Result<i64, Error> {
+    let mut conn = state.db_pool.acquire().await?;
+    let conversation_id = path_to(&mut conn, message_id).await?[0];
+    if conversation_id == message_id {
+        return Err(Error::StringError(
+            "The root of a conversation cannot be branched".to_owned(),
+        ));
+    }
+    let mut tx = conn.begin().await?;
+    let id = sqlx::query(
+        "INSERT INTO message (parent, role, status, content, parentsFed) SELECT parent, role, status, content, parentsFed FROM message WHERE id = ?",
+    )
+    .bind(message_id)
+    .execute(&mut *tx)
+    .await?
+    .last_insert_rowid();
+    sqlx::query(
+        "INSERT INTO messageModelV2 (messageId, model) SELECT ?, model FROM messageModelV2 WHERE messageId = ?",
+    )
+    .bind(id)
+    .bind(message_id)
+    .execute(&mut *tx)
+    .await?;
+    select_branch(&mut *tx, conversation_id, id).await?;
+    tx.commit().await?;
+    Ok(id)
+}
+
+#[derive(serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct Branch {
+    leaf_id: i64,
+    /// The number of messages after the root.
+    length: i64,
+    /// The beginning of the content of the leaf.
+    preview: String,
+    created_at: String,
+    /// Whether the branch is shown by default.
+    selected: bool,
+}
+
+/// Lists the branches of the conversation, i.e. its leaves, from the oldest.
+#[tauri::command]
+#[tracing::instrument(skip(state), err)]
+pub(crate) async fn list_branches(
+    conversation_id: i64,
+    state: tauri::State<'_, AppState>,
+) -> Result<Vec<Branch>, Error> {
+    let mut conn = state.db_pool.acquire().await?;
+    let thread = load_thread(&mut conn, conversation_id).await?;
+    let shown = thread.last().map(|m| m.id);
+    Ok(sqlx::query(
+        "
+WITH RECURSIVE descendants(id, depth) AS (
+    SELECT ?, 0
+    UNION ALL
+    SELECT message.id, descendants.depth + 1 FROM message JOIN descendants ON message.parent = descendants.id
+)
+SELECT message.id AS id, descendants.depth AS depth, substr(message.content, 1, 100) AS preview, message.createdAt AS createdAt
+FROM descendants
+JOIN message ON message.id = descendants.id
+WHERE descendants.depth > 0 AND NOT EXISTS (SELECT 1 FROM message AS child WHERE child.parent = message.id)
+ORDER BY message.id
+",
+    )
+    .bind(conversation_id)
+    .fetch_all(&mut conn)
+    .await?
+    .into_iter()
+    .map(|row| {
+        let leaf_id = row.get("id");
+        Branch {
+            leaf_id,
+            length: row.get("depth"),
+            preview: row.get("preview"),
+            created_at: row.get("createdAt"),
+            selected: shown == Some(leaf_id),
+        }
+    })
+    .collect())
+}
+
+/// Makes the branch ending with the message the one shown by default, and returns the ids of its messages from the root.
+#[tauri::command]
+#[tracing::instrument(skip(state), err)]
+pub(crate) async fn switch_branch(
+    leaf_id: i64,
+    state: tauri::State<'_, AppState>,
+) -> Result<Vec<i64>, Error> {
+    let mut conn = state.db_pool.acquire().await?;
+    let path = path_to(&mut conn, leaf_id).await?;
+    select_branch(&mut conn, path[0], leaf_id).await?;
+    Ok(path)
+}
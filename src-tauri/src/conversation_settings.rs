@@ -0,0 +1,119 @@
+//! The speech settings of each conversation, stored in the `conversationSettings` table: its Azure voice, rate, pitch,
+//! and whether its replies are spoken automatically. A setting left unset uses the global setting.
+//!
+//! The Azure commands that speak a message (`speak_azure_text` and `speak_azure_sentences`) look up the settings of its
+//! conversation and build the SSML themselves.
+
+use crate::azure_tts::Voice;
+use crate::{AppState, Error};
+use sqlx::Row;
+
+#[derive(serde::Serialize, serde::Deserialize, Default, Debug)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct ConversationSettings {
+    /// The short name of an Azure voice, e.g. "ja-JP-NanamiNeural".
+    #[serde(default)]
+    voice: Option<String>,
+    /// The speaking rate, where 1 is the normal rate.
+    #[serde(default)]
+    rate: Option<f64>,
+    /// The pitch, where 1 is the normal pitch.
+    #[serde(default)]
+    pitch: Option<f64>,
+    #[serde(default)]
+    auto_speak: Option<bool>,
+}
+
+/// Returns the settings of the conversation of the message, which may be the root message of the conversation.
+pub(crate) async fn for_message(
+    conn: &mut sqlx::SqliteConnection,
+    message_id: i64,
+) -> Result<ConversationSettings, Error> {
+    let row = sqlx::query(
+        "
+WITH RECURSIVE ancestors(id, parent) AS (
+    SELECT id, parent FROM message WHERE id = ?
+    UNION ALL
+    SELECT message.id, message.parent FROM ancestors JOIN message ON message.id = ancestors.parent
+)
+SELECT voice, rate, pitch, autoSpeak
+FROM ancestors JOIN conversationSettings ON conversationSettings.conversationId = ancestors.id
+WHERE ancestors.parent IS NULL
+",
+    )
+    .bind(message_id)
+    .fetch_optional(conn)
+    .await?;
+    Ok(
+        row.map_or_else(Default::default, |row| ConversationSettings {
+            voice: row.get("voice"),
+            rate: row.get("rate"),
+            pitch: row.get("pitch"),
+            auto_speak: row.get::<Option<i64>, _>("autoSpeak").map(|v| v != 0),
+        }),
+    )
+}
+
+/// Applies the settings of the conversation of the message to the voice, if the speech is for a message.
+pub(crate) async fn apply_to_voice(
+    conn: &mut sqlx::SqliteConnection,
+    message_id: Option<i64>,
+    voice: &mut Voice,
+) -> Result<(), Error> {
+    let Some(message_id) = message_id else {
+        return Ok(());
+    };
+    let settings = for_message(conn, message_id).await?;
+    if let Some(name) = settings.voice.filter(|name| !name.trim().is_empty()) {
+        // The locale is the first two components of the short name.
+        voice.lang = name.splitn(3, '-').take(2).collect::<Vec<_>>().join("-");
+        voice.name = name;
+    }
+    voice.rate = settings.rate.or(voice.rate);
+    voice.pitch = settings.pitch.or(voice.pitch);
+    Ok(())
+}
+
+/// Returns the settings of the conversation of the message, with the unset ones as null.
+#[tauri::command]
+#[tracing::instrument(skip(state), err)]
+pub(crate) async fn get_conversation_settings(
+    message_id: i64,
+    state: tauri::State<'_, AppState>,
+) -> Result<ConversationSettings, Error> {
+    let mut conn = state.db_pool.acquire().await?;
+    for_message(&mut conn, message_id).await
+}
+
+/// Replaces the settings of the conversation. The settings that are null use the global settings.
+#[tauri::command]
+#[tracing::instrument(skip(state), err)]
+pub(crate) async fn set_conversation_settings(
+    conversation_id: i64,
+    settings: ConversationSettings,
+    state: tauri::State<'_, AppState>,
+) -> Result<(), Error> {
+    if settings
+        .rate
+        .map_or(false, |rate| !(0.1..=10.0).contains(&rate))
+        || settings
+            .pitch
+            .map_or(false, |pitch| !(0.0..=2.0).contains(&pitch))
+    {
+        return Err(Error::StringError(
+            "The rate must be between 0.1 and 10, and the pitch between 0 and 2".to_owned(),
+        ));
+    }
+    let mut conn = state.db_pool.acquire().await?;
+    sqlx::query(
+        "INSERT OR REPLACE INTO conversationSettings (conversationId, voice, rate, pitch, autoSpeak) VALUES (?, ?, ?, ?, ?)",
+    )
+    .bind(conversation_id)
+    .bind(settings.voice.filter(|name| !name.trim().is_empty()))
+    .bind(settings.rate)
+    .bind(settings.pitch)
+    .bind(settings.auto_speak.map(i64::from))
+    .execute(&mut conn)
+    .await?;
+    Ok(())
+}
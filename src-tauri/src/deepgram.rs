@@ -0,0 +1,135 @@
+//! Streaming speech-to-text with Deepgram's live transcription API over WebSocket.
+//!
+//! The audio is sent while it is recorded, so the transcript is ready as soon as the recording stops. Interim transcripts
+//! are emitted as `stt://transcript` events ({ text, isFinal }), where `text` is the whole transcript so far.
+
+use crate::recording::RecordingSink;
+use crate::whisper::Downsampler;
+use crate::{record_dictation, AppState, Error};
+use futures_util::{SinkExt, StreamExt};
+use serde_json::Value;
+use tokio::sync::{mpsc, oneshot};
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::Message;
+
+const LISTEN_ENDPOINT: &str = "wss://api.deepgram.com/v1/listen";
+
+#[derive(serde::Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+struct Transcript {
+    text: String,
+    is_final: bool,
+}
+
+/// Connects once the sample rate is known, forwards the audio until the recording stops, and returns the final transcript.
+async fn run_session(
+    window: tauri::Window,
+    api_key: String,
+    language: String,
+    sample_rate: oneshot::Receiver<u32>,
+    mut frames: mpsc::UnboundedReceiver<Vec<u8>>,
+) -> Result<String, Error> {
+    let Ok(sample_rate) = sample_rate.await else {
+        return Ok("".to_owned()); // the recording failed to start
+    };
+    let mut url = format!(
+        "{LISTEN_ENDPOINT}?encoding=linear16&sample_rate={sample_rate}&channels=1&interim_results=true&punctuate=true"
+    );
+    if !language.is_empty() {
+        url += &format!("&language={language}");
+    }
+    let mut request = url.into_client_request()?;
+    request.headers_mut().insert(
+        "Authorization",
+        format!("Token {api_key}")
+            .parse()
+            .map_err(|_| Error::StringError("Invalid Deepgram API key".to_owned()))?,
+    );
+    let (ws, _) = tokio_tungstenite::connect_async(request).await?;
+    let (mut write, mut read) = ws.split();
+
+    let send = async move {
+        while let Some(chunk) = frames.recv().await {
+            write.send(Message::Binary(chunk)).await?;
+        }
+        // Deepgram sends the remaining results and closes the connection.
+        write
+            .send(Message::Text(r#"{"type":"CloseStream"}"#.to_owned()))
+            .await?;
+        Ok::<_, Error>(())
+    };
+    let receive = async {
+        let mut transcript = String::new();
+        while let Some(message) = read.next().await {
+            let Message::Text(message) = message? else {
+                continue;
+            };
+            let data: Value = serde_json::from_str(&message)?;
+            if data.get("type").and_then(Value::as_str) != Some("Results") {
+                continue;
+            }
+            let text = data
+                .pointer("/channel/alternatives/0/transcript")
+                .and_then(Value::as_str)
+                .unwrap_or_default();
+            let is_final = data
+                .get("is_final")
+                .and_then(Value::as_bool)
+                .unwrap_or(false);
+            let joined = [transcript.as_str(), text]
+                .into_iter()
+                .filter(|s| !s.is_empty())
+                .collect::<Vec<_>>()
+                .join(" ");
+            if is_final {
+                transcript = joined.clone();
+            }
+            let _ = window.emit(
+                "stt://transcript",
+                Transcript {
+                    text: joined,
+                    is_final,
+                },
+            );
+        }
+        Ok::<_, Error>(transcript)
+    };
+    let ((), transcript) = tokio::try_join!(send, receive)?;
+    Ok(transcript)
+}
+
+/// Dictation with Deepgram. Returns the final transcript once `stop_listening` is called, or "" if canceled.
+#[tauri::command]
+#[tracing::instrument(skip(window, api_key, state), err)]
+pub(crate) async fn start_listening_deepgram(
+    window: tauri::Window,
+    api_key: String,
+    language: String, // "" for Deepgram's default
+    state: tauri::State<'_, AppState>,
+) -> Result<String, Error> {
+    let (sample_rate_sender, sample_rate_receiver) = oneshot::channel();
+    let (frame_sender, frame_receiver) = mpsc::unbounded_channel();
+    let session = tauri::async_runtime::spawn(run_session(
+        window.clone(),
+        api_key,
+        language,
+        sample_rate_receiver,
+        frame_receiver,
+    ));
+    let recorded = record_dictation(&window, &state, move |sample_rate| {
+        let _ = sample_rate_sender.send(Downsampler::output_sample_rate(sample_rate));
+        Ok(RecordingSink::stream(sample_rate, frame_sender))
+    })
+    .await;
+    match recorded {
+        Ok(true) => session.await?,
+        Ok(false) => {
+            session.abort();
+            Ok("".to_owned())
+        }
+        Err(err) => {
+            session.abort();
+            Err(err)
+        }
+    }
+}
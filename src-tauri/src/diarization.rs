@@ -0,0 +1,179 @@
+//! Labels the segments of a transcript with speakers by a simple heuristic, without a speaker embedding model.
+//!
+//! Each segment is described by the pitch, the zero-crossing rate, and the high-frequency energy of its voiced frames,
+//! and segments with similar voices are clustered agglomeratively. This separates voices that differ clearly, e.g. a man
+//! and a woman, but not similar ones.
+
+use crate::whisper::Segment;
+
+const FRAME_LEN: usize = 512;
+const HOP_LEN: usize = 256;
+/// Frames quieter than this RMS (of samples in [-1, 1]) are treated as silence.
+const SILENCE_RMS: f64 = 0.02;
+/// The range of the fundamental frequency of speech.
+const MIN_PITCH_HZ: f64 = 60.0;
+const MAX_PITCH_HZ: f64 = 400.0;
+/// Frames whose normalized autocorrelation is lower than this have no pitch.
+const VOICING_THRESHOLD: f64 = 0.3;
+/// Clusters closer than this, in standard deviations of the features, are merged even when there are at most
+/// `max_speakers` clusters.
+const MERGE_DISTANCE: f64 = 1.0;
+
+pub(crate) type Features = [f64; 3];
+
+/// Returns the pitch of the frame in Hz, if it is voiced.
+fn pitch(frame: &[f64], sample_rate: u32) -> Option<f64> {
+    let energy = frame.iter().map(|x| x * x).sum::<f64>();
+    let min_lag = (sample_rate as f64 / MAX_PITCH_HZ) as usize;
+    let max_lag = ((sample_rate as f64 / MIN_PITCH_HZ) as usize).min(frame.len() - 1);
+    let (lag, correlation) = (min_lag..=max_lag)
+        .map(|lag| {
+            let c = frame
+                .iter()
+                .zip(&frame[lag..])
+                .map(|(a, b)| a * b)
+                .sum::<f64>();
+            (lag, c / energy)
+        })
+        .max_by(|a, b| a.1.total_cmp(&b.1))?;
+    (correlation >= VOICING_THRESHOLD).then_some(sample_rate as f64 / lag as f64)
+}
+
+/// Computes the features of the part of mono samples between `start` and `end` seconds, or None if it has no voiced
+/// frame.
+pub(crate) fn segment_features(
+    samples: &[i16],
+    sample_rate: u32,
+    start: f64,
+    end: f64,
+) -> Option<Features> {
+    let from = ((start * sample_rate as f64) as usize).min(samples.len());
+    let to = ((end * sample_rate as f64) as usize).clamp(from, samples.len());
+    let samples = samples[from..to]
+        .iter()
+        .map(|&s| s as f64 / i16::MAX as f64)
+        .collect::<Vec<_>>();
+    let (mut log_pitch, mut zcr, mut high_frequency, mut count) = (0.0, 0.0, 0.0, 0.0);
+    for frame in samples.windows(FRAME_LEN).step_by(HOP_LEN) {
+        let energy = frame.iter().map(|x| x * x).sum::<f64>();
+        if (energy / FRAME_LEN as f64).sqrt() < SILENCE_RMS {
+            continue;
+        }
+        let Some(pitch) = pitch(frame, sample_rate) else {
+            continue;
+        };
+        log_pitch += pitch.ln();
+        zcr += frame
+            .windows(2)
+            .filter(|w| (w[0] < 0.0) != (w[1] < 0.0))
+            .count() as f64
+            / FRAME_LEN as f64;
+        high_frequency += frame.windows(2).map(|w| (w[1] - w[0]).powi(2)).sum::<f64>() / energy;
+        count += 1.0;
+    }
+    (count > 0.0).then_some([log_pitch / count, zcr / count, high_frequency / count])
+}
+
+fn distance(a: &Features, b: &Features) -> f64 {
+    a.iter()
+        .zip(b)
+        .map(|(a, b)| (a - b).powi(2))
+        .sum::<f64>()
+        .sqrt()
+}
+
+/// Returns the speaker of each segment, numbered from 0 in the order of appearance. Segments without features are
+/// assigned to the previous speaker.
+pub(crate) fn assign_speakers(features: &[Option<Features>], max_speakers: usize) -> Vec<usize> {
+    let voiced = features.iter().flatten().collect::<Vec<_>>();
+    if voiced.is_empty() {
+        return vec![0; features.len()];
+    }
+
+    // Standardizes each feature so that they are weighted equally.
+    let n = voiced.len() as f64;
+    let mean = [0, 1, 2].map(|i| voiced.iter().map(|f| f[i]).sum::<f64>() / n);
+    let std = [0, 1, 2].map(|i| {
+        (voiced.iter().map(|f| (f[i] - mean[i]).powi(2)).sum::<f64>() / n)
+            .sqrt()
+            .max(f64::EPSILON)
+    });
+    let normalized = voiced
+        .iter()
+        .map(|f| [0, 1, 2].map(|i| (f[i] - mean[i]) / std[i]))
+        .collect::<Vec<Features>>();
+
+    // (centroid, number of segments, members)
+    let mut clusters = normalized
+        .iter()
+        .enumerate()
+        .map(|(i, f)| (*f, 1.0, vec![i]))
+        .collect::<Vec<_>>();
+    while clusters.len() > 1 {
+        let mut closest = (0, 1, f64::INFINITY);
+        for i in 0..clusters.len() {
+            for j in i + 1..clusters.len() {
+                let d = distance(&clusters[i].0, &clusters[j].0);
+                if d < closest.2 {
+                    closest = (i, j, d);
+                }
+            }
+        }
+        let (i, j, d) = closest;
+        if clusters.len() <= max_speakers.max(1) && d > MERGE_DISTANCE {
+            break;
+        }
+        let (centroid, count, members) = clusters.remove(j);
+        let cluster = &mut clusters[i];
+        let total = cluster.1 + count;
+        cluster.0 = [0, 1, 2].map(|k| (cluster.0[k] * cluster.1 + centroid[k] * count) / total);
+        cluster.1 = total;
+        cluster.2.extend(members);
+    }
+
+    let mut cluster_of = vec![0; voiced.len()];
+    for (c, (_, _, members)) in clusters.iter().enumerate() {
+        for &m in members {
+            cluster_of[m] = c;
+        }
+    }
+    let mut labels = Vec::<usize>::new(); // cluster index -> speaker, in the order of appearance
+    let mut voiced_index = 0;
+    let mut speaker = 0;
+    features
+        .iter()
+        .map(|f| {
+            if f.is_some() {
+                let c = cluster_of[voiced_index];
+                voiced_index += 1;
+                speaker = match labels.iter().position(|&l| l == c) {
+                    Some(s) => s,
+                    None => {
+                        labels.push(c);
+                        labels.len() - 1
+                    }
+                };
+            }
+            speaker
+        })
+        .collect()
+}
+
+/// Joins the consecutive segments of each speaker into paragraphs labeled "Speaker 1", "Speaker 2", ...
+pub(crate) fn format_transcript(segments: &[Segment], speakers: &[usize]) -> String {
+    let mut paragraphs: Vec<(usize, Vec<&str>)> = vec![];
+    for (segment, &speaker) in segments.iter().zip(speakers) {
+        if segment.text.is_empty() {
+            continue;
+        }
+        match paragraphs.last_mut() {
+            Some((last, texts)) if *last == speaker => texts.push(&segment.text),
+            _ => paragraphs.push((speaker, vec![&segment.text])),
+        }
+    }
+    paragraphs
+        .into_iter()
+        .map(|(speaker, texts)| format!("Speaker {}: {}", speaker + 1, texts.join(" ")))
+        .collect::<Vec<_>>()
+        .join("\n\n")
+}
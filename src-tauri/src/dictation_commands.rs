@@ -0,0 +1,241 @@
+//! Spoken editing commands in dictated text, e.g. "new line", "delete that", and "send it".
+//!
+//! `parse_dictation_commands` splits a transcript into the text to insert and the commands, which the frontend applies
+//! to the message input. The phrases are built in for a few languages and can be extended with the
+//! `dictationCommandPhrases` config, e.g. {"en": {"next line": "newLine"}}, keyed by the primary language subtag.
+//!
+//! The phrases are matched case-insensitively, ignoring punctuation. "delete that" and "send it" are only recognized as a
+//! sentence of their own, e.g. "Sounds good. Send it.", so that the same words in the middle of a sentence are kept as
+//! text.
+
+use crate::{read_config, AppState, Error};
+use std::collections::HashMap;
+
+#[derive(serde::Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub(crate) enum Command {
+    NewLine,
+    NewParagraph,
+    DeleteLastSentence,
+    Submit,
+}
+
+impl Command {
+    /// Whether the command is only recognized as a sentence of its own.
+    fn needs_sentence_boundaries(self) -> bool {
+        matches!(self, Command::DeleteLastSentence | Command::Submit)
+    }
+}
+
+#[derive(serde::Serialize, Debug, PartialEq, Eq)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub(crate) enum EditOperation {
+    Insert {
+        text: String,
+    },
+    NewLine,
+    NewParagraph,
+    /// Deletes the last sentence of the message input, including the text inserted before in the same transcript.
+    DeleteLastSentence,
+    /// Sends the message, after the preceding operations.
+    Submit,
+}
+
+impl From<Command> for EditOperation {
+    fn from(command: Command) -> Self {
+        match command {
+            Command::NewLine => EditOperation::NewLine,
+            Command::NewParagraph => EditOperation::NewParagraph,
+            Command::DeleteLastSentence => EditOperation::DeleteLastSentence,
+            Command::Submit => EditOperation::Submit,
+        }
+    }
+}
+
+fn builtin_phrases(language: &str) -> &'static [(&'static str, Command)] {
+    use Command::*;
+    match language {
+        "en" => &[
+            ("new line", NewLine),
+            ("newline", NewLine),
+            ("new paragraph", NewParagraph),
+            ("delete that", DeleteLastSentence),
+            ("scratch that", DeleteLastSentence),
+            ("delete last sentence", DeleteLastSentence),
+            ("send it", Submit),
+            ("send message", Submit),
+        ],
+        "ja" => &[
+            ("改行", NewLine),
+            ("新しい段落", NewParagraph),
+            ("今のなし", DeleteLastSentence),
+            ("取り消し", DeleteLastSentence),
+            ("送信", Submit),
+        ],
+        "de" => &[
+            ("neue zeile", NewLine),
+            ("neuer absatz", NewParagraph),
+            ("das löschen", DeleteLastSentence),
+            ("lösche das", DeleteLastSentence),
+            ("abschicken", Submit),
+            ("senden", Submit),
+        ],
+        "fr" => &[
+            ("nouvelle ligne", NewLine),
+            ("à la ligne", NewLine),
+            ("nouveau paragraphe", NewParagraph),
+            ("efface ça", DeleteLastSentence),
+            ("supprime ça", DeleteLastSentence),
+            ("envoie", Submit),
+            ("envoyer", Submit),
+        ],
+        "es" => &[
+            ("nueva línea", NewLine),
+            ("nuevo párrafo", NewParagraph),
+            ("borra eso", DeleteLastSentence),
+            ("envíalo", Submit),
+            ("enviar", Submit),
+        ],
+        _ => &[],
+    }
+}
+
+/// Lowercase letters and digits, with the runs of other characters replaced by a space, and the byte offset of each
+/// character in the original text.
+fn normalize(text: &str) -> Vec<(char, usize)> {
+    let mut normalized: Vec<(char, usize)> = vec![];
+    for (offset, c) in text.char_indices() {
+        if c.is_alphanumeric() {
+            normalized.extend(c.to_lowercase().map(|c| (c, offset)));
+        } else if normalized.last().map_or(false, |&(c, _)| c != ' ') {
+            normalized.push((' ', offset));
+        }
+    }
+    if normalized.last().map_or(false, |&(c, _)| c == ' ') {
+        normalized.pop();
+    }
+    normalized
+}
+
+/// Whether a phrase may start or end next to `c` without splitting a word. Scripts without spaces, e.g. Japanese,
+/// have no word boundaries to check.
+fn is_boundary(c: Option<char>, phrase_edge: char) -> bool {
+    match c {
+        None | Some(' ') => true,
+        Some(c) => !(c.is_alphanumeric() && phrase_edge.is_ascii_alphanumeric()),
+    }
+}
+
+fn ends_sentence(text: &str) -> bool {
+    text.trim_end()
+        .chars()
+        .last()
+        .map_or(true, |c| ".!?。！？\n".contains(c))
+}
+
+/// Splits the transcript into text and commands.
+fn parse(transcript: &str, phrases: &[(Vec<char>, Command)]) -> Vec<EditOperation> {
+    let normalized = normalize(transcript);
+    let mut operations = vec![];
+    // The byte offset of the text that has not been added to `operations`.
+    let mut text_start = 0;
+    let mut i = 0;
+    while i < normalized.len() {
+        let previous = i.checked_sub(1).map(|j| normalized[j].0);
+        let matched = phrases.iter().find(|(phrase, _)| {
+            let end = i + phrase.len();
+            end <= normalized.len()
+                && normalized[i..end]
+                    .iter()
+                    .map(|&(c, _)| c)
+                    .eq(phrase.iter().copied())
+                && is_boundary(previous, phrase[0])
+                && is_boundary(
+                    normalized.get(end).map(|&(c, _)| c),
+                    phrase[phrase.len() - 1],
+                )
+        });
+        let Some((phrase, command)) = matched else {
+            i += 1;
+            continue;
+        };
+        let start = normalized[i].1;
+        let last = normalized[i + phrase.len() - 1].1;
+        let end = last + transcript[last..].chars().next().map_or(0, char::len_utf8);
+        // The punctuation that Whisper adds after a command, e.g. "New line.", belongs to the command.
+        let end = end
+            + transcript[end..]
+                .chars()
+                .take_while(|&c| !c.is_alphanumeric())
+                .map(char::len_utf8)
+                .sum::<usize>();
+        if command.needs_sentence_boundaries()
+            && !(ends_sentence(&transcript[..start])
+                && (end == transcript.len() || ends_sentence(&transcript[..end])))
+        {
+            i += 1;
+            continue;
+        }
+        let text = transcript[text_start..start].trim_end();
+        if !text.is_empty() {
+            operations.push(EditOperation::Insert {
+                text: text.to_owned(),
+            });
+        }
+        operations.push((*command).into());
+        text_start = end;
+        i = normalized.partition_point(|&(_, offset)| offset < end);
+    }
+    let text = &transcript[text_start..];
+    if !text.trim().is_empty() {
+        operations.push(EditOperation::Insert {
+            text: text.to_owned(),
+        });
+    }
+    operations
+}
+
+/// Parses the spoken editing commands in the transcript. `language` is e.g. "en" or "en-US", or "" for English. The
+/// transcript is returned as a single insertion if there are no commands.
+#[tauri::command]
+#[tracing::instrument(skip(transcript, state), err)]
+pub(crate) async fn parse_dictation_commands(
+    transcript: String,
+    language: String,
+    state: tauri::State<'_, AppState>,
+) -> Result<Vec<EditOperation>, Error> {
+    let language = match language.split(['-', '_']).next().unwrap_or_default() {
+        "" => "en".to_owned(),
+        language => language.to_lowercase(),
+    };
+    let mut phrases = builtin_phrases(&language)
+        .iter()
+        .map(|&(phrase, command)| (phrase.to_owned(), command))
+        .collect::<HashMap<_, _>>();
+    let mut conn = state.db_pool.acquire().await?;
+    if let Some(custom) = read_config(&mut conn, "dictationCommandPhrases").await? {
+        let mut custom: HashMap<String, HashMap<String, Command>> =
+            serde_json::from_str(if custom.trim().is_empty() {
+                "{}"
+            } else {
+                &custom
+            })?;
+        phrases.extend(custom.remove(&language).unwrap_or_default());
+    }
+    let mut phrases = phrases
+        .into_iter()
+        .map(|(phrase, command)| {
+            (
+                normalize(&phrase)
+                    .into_iter()
+                    .map(|(c, _)| c)
+                    .collect::<Vec<_>>(),
+                command,
+            )
+        })
+        .filter(|(phrase, _)| !phrase.is_empty())
+        .collect::<Vec<_>>();
+    // The longest phrase wins, e.g. "delete last sentence" over "delete".
+    phrases.sort_by_key(|(phrase, _)| std::cmp::Reverse(phrase.len()));
+    Ok(parse(&transcript, &phrases))
+}
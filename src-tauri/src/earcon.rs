@@ -0,0 +1,195 @@
+//! Earcons: the short sounds that signal UI events, customizable per event and stored in the `earcon` table.
+//!
+//! A sound is the name of a built-in sound, "none" to silence the event, or the path of an audio file (WAV, MP3, FLAC or
+//! Ogg Vorbis). The built-in sounds are the samples in resources/earcons, which are embedded in the binary and are the
+//! defaults, and the sine tones that were used before the sounds became customizable.
+
+use crate::app_state::AudioState;
+use crate::{AppState, Error};
+use rodio::Source;
+use sqlx::Row;
+use std::time::Duration;
+
+const NONE: &str = "none";
+const VOLUME: f32 = 0.5;
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "camelCase")]
+pub(crate) enum EarconEvent {
+    /// "Test speaker" in the settings.
+    Test,
+    /// The text area was focused by a shortcut.
+    FocusInput,
+    WaitingTextCompletion,
+}
+
+impl EarconEvent {
+    const ALL: [EarconEvent; 3] = [
+        EarconEvent::Test,
+        EarconEvent::FocusInput,
+        EarconEvent::WaitingTextCompletion,
+    ];
+
+    fn key(self) -> &'static str {
+        match self {
+            EarconEvent::Test => "test",
+            EarconEvent::FocusInput => "focusInput",
+            EarconEvent::WaitingTextCompletion => "waitingTextCompletion",
+        }
+    }
+
+    fn default_sound(self) -> &'static str {
+        match self {
+            EarconEvent::Test => "arpeggio",
+            EarconEvent::FocusInput => "pop",
+            EarconEvent::WaitingTextCompletion => "soft-chime",
+        }
+    }
+}
+
+enum BuiltinSound {
+    /// A WAV file.
+    Sample(&'static [u8]),
+    /// (frequency in Hz, duration in ms) tones played in sequence. A frequency of 0 is a pause.
+    Tones(&'static [(f32, u64)]),
+}
+
+const BUILTIN_SOUNDS: &[(&str, BuiltinSound)] = &[
+    (
+        "arpeggio",
+        BuiltinSound::Sample(include_bytes!("../resources/earcons/arpeggio.wav")),
+    ),
+    (
+        "pop",
+        BuiltinSound::Sample(include_bytes!("../resources/earcons/pop.wav")),
+    ),
+    (
+        "soft-chime",
+        BuiltinSound::Sample(include_bytes!("../resources/earcons/soft-chime.wav")),
+    ),
+    ("test-tone", BuiltinSound::Tones(&[(256.0, 1000)])),
+    ("high-beep", BuiltinSound::Tones(&[(880.0, 100)])),
+    ("beep", BuiltinSound::Tones(&[(440.0, 200)])), // A
+    (
+        "chime",
+        BuiltinSound::Tones(&[(659.25, 120), (987.77, 240)]), // E, B
+    ),
+    (
+        "double-beep",
+        BuiltinSound::Tones(&[(880.0, 80), (0.0, 60), (880.0, 80)]),
+    ),
+    ("click", BuiltinSound::Tones(&[(2000.0, 10)])),
+];
+
+#[derive(serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct EarconSetting {
+    event: EarconEvent,
+    sound: String,
+}
+
+async fn sound_of(conn: &mut sqlx::SqliteConnection, event: EarconEvent) -> Result<String, Error> {
+    Ok(sqlx::query("SELECT sound FROM earcon WHERE event = ?")
+        .bind(event.key())
+        .fetch_optional(conn)
+        .await?
+        .map(|row| row.get("sound"))
+        .unwrap_or_else(|| event.default_sound().to_owned()))
+}
+
+/// Plays a sound, blocking until it ends.
+fn play_sound(audio: &AudioState, sound: &str) -> Result<(), Error> {
+    if sound == NONE {
+        return Ok(());
+    }
+    match BUILTIN_SOUNDS.iter().find(|(name, _)| *name == sound) {
+        Some((_, BuiltinSound::Sample(wav))) => {
+            audio.mixer.play_cue(
+                &audio.engine,
+                rodio::Decoder::new_wav(std::io::Cursor::new(*wav))?,
+                VOLUME,
+            )?;
+        }
+        Some((_, BuiltinSound::Tones(tones))) => {
+            for &(frequency, ms) in tones.iter() {
+                let duration = Duration::from_millis(ms);
+                if frequency == 0.0 {
+                    std::thread::sleep(duration);
+                    continue;
+                }
+                audio.mixer.play_cue(
+                    &audio.engine,
+                    rodio::source::SineWave::new(frequency).take_duration(duration),
+                    VOLUME,
+                )?;
+            }
+        }
+        None => {
+            let file = std::io::BufReader::new(std::fs::File::open(sound)?);
+            audio
+                .mixer
+                .play_cue(&audio.engine, rodio::Decoder::new(file)?, VOLUME)?;
+        }
+    }
+    Ok(())
+}
+
+/// Plays the earcon of the event, unless it is silenced.
+pub(crate) async fn play(state: &AppState, event: EarconEvent) -> Result<(), Error> {
+    let sound = {
+        let mut conn = state.db_pool.acquire().await?;
+        sound_of(&mut conn, event).await?
+    };
+    let audio = state.audio.clone();
+    tokio::task::spawn_blocking(move || play_sound(&audio, &sound)).await?
+}
+
+/// Sets the sound of an event: a built-in sound, "none", or the path of an audio file. "default" restores the default.
+#[tauri::command]
+#[tracing::instrument(skip(state), err)]
+pub(crate) async fn set_earcon(
+    event: EarconEvent,
+    sound: String,
+    state: tauri::State<'_, AppState>,
+) -> Result<(), Error> {
+    let mut conn = state.db_pool.acquire().await?;
+    if sound == "default" {
+        sqlx::query("DELETE FROM earcon WHERE event = ?")
+            .bind(event.key())
+            .execute(&mut conn)
+            .await?;
+        return Ok(());
+    }
+    if sound != NONE && !BUILTIN_SOUNDS.iter().any(|(name, _)| *name == sound) {
+        // Fails early if the file is missing or in an unsupported format.
+        rodio::Decoder::new(std::io::BufReader::new(std::fs::File::open(&sound)?))?;
+    }
+    sqlx::query("INSERT OR REPLACE INTO earcon (event, sound) VALUES (?, ?)")
+        .bind(event.key())
+        .bind(sound)
+        .execute(&mut conn)
+        .await?;
+    Ok(())
+}
+
+/// Returns the sound of every event.
+#[tauri::command]
+#[tracing::instrument(skip(state), err)]
+pub(crate) async fn list_earcons(
+    state: tauri::State<'_, AppState>,
+) -> Result<Vec<EarconSetting>, Error> {
+    let mut conn = state.db_pool.acquire().await?;
+    let mut settings = vec![];
+    for event in EarconEvent::ALL {
+        settings.push(EarconSetting {
+            event,
+            sound: sound_of(&mut conn, event).await?,
+        });
+    }
+    Ok(settings)
+}
+
+#[tauri::command]
+pub(crate) fn list_builtin_earcons() -> Vec<&'static str> {
+    BUILTIN_SOUNDS.iter().map(|(name, _)| *name).collect()
+}
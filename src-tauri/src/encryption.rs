@@ -0,0 +1,127 @@
+//! Optional encryption of the database at rest with SQLCipher.
+//!
+//! The passphrase is only kept in memory, and `DbPool` applies it to every connection with `PRAGMA key`. An encrypted
+//! database stays locked after startup until `unlock_database` is called. The tauri-plugin-sql connection of the frontend
+//! is not keyed by the backend, so the windows take the passphrase from `get_database_passphrase` and execute `PRAGMA key`
+//! themselves. They close that connection before `set_database_passphrase` replaces the file, and reload on
+//! `database://passphrase-changed`.
+
+use crate::{AppState, Error};
+use sqlx::{Executor, Row};
+use std::io::Read;
+use std::path::Path;
+use tauri::Manager;
+
+/// Quotes a string as an SQL string literal, for statements that do not accept bound parameters.
+pub(crate) fn quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', "''"))
+}
+
+/// Returns true if the file exists and does not start with the header of a plaintext SQLite database.
+pub(crate) fn is_encrypted(path: &Path) -> Result<bool, Error> {
+    let mut header = [0u8; 16];
+    match std::fs::File::open(path) {
+        Ok(mut f) => match f.read_exact(&mut header) {
+            Ok(()) => Ok(&header != b"SQLite format 3\0"),
+            Err(err) if err.kind() == std::io::ErrorKind::UnexpectedEof => Ok(false),
+            Err(err) => Err(err.into()),
+        },
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(false),
+        Err(err) => Err(err.into()),
+    }
+}
+
+#[tauri::command]
+#[tracing::instrument(skip(state), err)]
+pub(crate) fn is_database_locked(state: tauri::State<AppState>) -> Result<bool, Error> {
+    Ok(is_encrypted(&state.db_pool.path()?)? && state.db_pool.passphrase()?.is_none())
+}
+
+/// Unlocks an encrypted database for this session.
+#[tauri::command]
+#[tracing::instrument(skip(passphrase, state), err)]
+pub(crate) async fn unlock_database(
+    passphrase: String,
+    state: tauri::State<'_, AppState>,
+) -> Result<(), Error> {
+    state.db_pool.set_passphrase(Some(passphrase)).await?;
+    // A wrong passphrase fails with "file is not a database" on the first read. The tables were not created at startup because the database was locked.
+    if let Err(err) = crate::create_tables(&state.db_pool).await {
+        state.db_pool.set_passphrase(None).await?;
+        return Err(err);
+    }
+    Ok(())
+}
+
+/// Returns the passphrase of the unlocked database, for the tauri-plugin-sql connection of the windows.
+#[tauri::command]
+#[tracing::instrument(skip(state), err)]
+pub(crate) fn get_database_passphrase(
+    state: tauri::State<AppState>,
+) -> Result<Option<String>, Error> {
+    state.db_pool.passphrase()
+}
+
+/// Copies the database into the attached database `target`. sqlcipher_export() copies the schema and the data, but not
+/// user_version.
+async fn export(conn: &mut sqlx::SqliteConnection, user_version: i64) -> Result<(), Error> {
+    sqlx::query("SELECT sqlcipher_export('target')")
+        .fetch_all(&mut *conn)
+        .await?;
+    conn.execute(format!("PRAGMA target.user_version = {user_version}").as_str())
+        .await?;
+    Ok(())
+}
+
+/// Encrypts the database, changes its passphrase, or decrypts it if `passphrase` is None.
+/// The database must be unlocked beforehand if it is already encrypted, and the windows must have closed their connection.
+#[tauri::command]
+#[tracing::instrument(skip(passphrase, app, state), err)]
+pub(crate) async fn set_database_passphrase(
+    passphrase: Option<String>,
+    app: tauri::AppHandle,
+    state: tauri::State<'_, AppState>,
+) -> Result<(), Error> {
+    let path = state.db_pool.path()?;
+    let tmp = path.with_extension("db.tmp");
+    if tmp.exists() {
+        std::fs::remove_file(&tmp)?;
+    }
+    let tmp_str = tmp
+        .to_str()
+        .ok_or_else(|| Error::StringError(format!("{tmp:?}.to_str() failed")))?;
+
+    let mut conn = state.db_pool.acquire().await?;
+    let user_version: i64 = sqlx::query("PRAGMA user_version")
+        .fetch_one(&mut conn)
+        .await?
+        .get(0);
+    conn.execute(
+        format!(
+            "ATTACH DATABASE {} AS target KEY {}",
+            quote(tmp_str),
+            quote(passphrase.as_deref().unwrap_or(""))
+        )
+        .as_str(),
+    )
+    .await?;
+    // The attachment would stay on the pooled connection and make the next call fail, so it is detached even on errors.
+    let exported = export(&mut conn, user_version).await;
+    let detached = conn.execute("DETACH DATABASE target").await;
+    drop(conn);
+    if let Err(err) = exported.and(detached.map(|_| ()).map_err(Error::from)) {
+        if let Err(err) = std::fs::remove_file(&tmp) {
+            tracing::warn!("failed to remove {tmp:?}: {err}");
+        }
+        return Err(err);
+    }
+
+    state.db_pool.close().await?;
+    if let Err(err) = std::fs::rename(&tmp, &path) {
+        let _ = std::fs::remove_file(&tmp);
+        return Err(err.into());
+    }
+    state.db_pool.set_passphrase(passphrase).await?;
+    app.emit_all("database://passphrase-changed", ())?;
+    Ok(())
+}
@@ -0,0 +1,285 @@
+//! Exports conversations, and the spoken audio of messages, to files.
+
+use crate::azure_tts::{split_sentences, SentenceSynthesizer, Voice};
+use crate::conversation::{conversation_name, load_thread, StoredMessage};
+use crate::speech_text::SpeechText;
+use crate::{conversation_settings, lexicon, tts_cache, AppState, Error};
+use sqlx::Row;
+use std::path::{Path, PathBuf};
+
+#[derive(serde::Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum ExportFormat {
+    Markdown,
+    Json,
+    Html,
+}
+
+impl ExportFormat {
+    fn extension(self) -> &'static str {
+        match self {
+            ExportFormat::Markdown => "md",
+            ExportFormat::Json => "json",
+            ExportFormat::Html => "html",
+        }
+    }
+}
+
+/// Asks for the file to save to without blocking the async runtime. Returns None if the dialog is canceled.
+async fn save_file_dialog(file_name: &str, filters: &[(&str, &[&str])]) -> Option<PathBuf> {
+    let (sender, receiver) = tokio::sync::oneshot::channel();
+    let mut dialog = tauri::api::dialog::FileDialogBuilder::new().set_file_name(file_name);
+    for (name, extensions) in filters {
+        dialog = dialog.add_filter(name, extensions);
+    }
+    dialog.save_file(move |path| {
+        let _ = sender.send(path);
+    });
+    receiver.await.ok().flatten()
+}
+
+fn role_header(role: &str) -> String {
+    let mut chars = role.chars();
+    match chars.next() {
+        Some(first) => first.to_uppercase().chain(chars).collect(),
+        None => String::new(),
+    }
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn render_markdown(name: &str, messages: &[StoredMessage]) -> String {
+    let mut out = format!("# {name}\n");
+    for m in messages {
+        out += &format!(
+            "\n## {} ({})\n\n{}\n",
+            role_header(&m.role),
+            m.created_at,
+            m.content
+        );
+    }
+    out
+}
+
+fn render_json(
+    conversation_id: i64,
+    name: &str,
+    messages: &[StoredMessage],
+) -> Result<String, Error> {
+    Ok(serde_json::to_string_pretty(&serde_json::json!({
+        "id": conversation_id,
+        "name": name,
+        "messages": messages,
+    }))?)
+}
+
+/// Renders fenced code blocks as <pre><code> and the other lines as paragraphs.
+fn render_html_content(content: &str) -> String {
+    let mut out = String::new();
+    let mut in_code_block = false;
+    for line in content.lines() {
+        if let Some(lang) = line.trim_start().strip_prefix("```") {
+            if in_code_block {
+                out += "</code></pre>\n";
+            } else {
+                out += &format!(
+                    "<pre><code class=\"language-{}\">",
+                    escape_html(lang.trim())
+                );
+            }
+            in_code_block = !in_code_block;
+        } else if in_code_block {
+            out += &escape_html(line);
+            out += "\n";
+        } else if !line.trim().is_empty() {
+            out += &format!("<p>{}</p>\n", escape_html(line));
+        }
+    }
+    if in_code_block {
+        out += "</code></pre>\n";
+    }
+    out
+}
+
+fn render_html(name: &str, messages: &[StoredMessage]) -> String {
+    let mut out = format!(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>{0}</title>\n</head>\n<body>\n<h1>{0}</h1>\n",
+        escape_html(name)
+    );
+    for m in messages {
+        out += &format!(
+            "<section class=\"{}\">\n<h2>{} <time>{}</time></h2>\n{}</section>\n",
+            escape_html(&m.role),
+            escape_html(&role_header(&m.role)),
+            escape_html(&m.created_at),
+            render_html_content(&m.content)
+        );
+    }
+    out += "</body>\n</html>\n";
+    out
+}
+
+/// Writes the conversation to `path`, or to a file chosen with a save dialog if `path` is None.
+/// Returns the path written to, or None if the dialog was canceled.
+#[tauri::command]
+#[tracing::instrument(skip(state), err)]
+pub(crate) async fn export_conversation(
+    conversation_id: i64,
+    format: ExportFormat,
+    path: Option<String>,
+    state: tauri::State<'_, AppState>,
+) -> Result<Option<String>, Error> {
+    let mut conn = state.db_pool.acquire().await?;
+    let name = conversation_name(&mut conn, conversation_id)
+        .await?
+        .unwrap_or_else(|| "Untitled".to_owned());
+    let messages = load_thread(&mut conn, conversation_id)
+        .await?
+        .into_iter()
+        .filter(|m| m.role != "root")
+        .collect::<Vec<_>>();
+
+    let path = match path {
+        Some(path) => PathBuf::from(path),
+        None => match save_file_dialog(
+            &format!("{name}.{}", format.extension()),
+            &[(format.extension(), &[format.extension()])],
+        )
+        .await
+        {
+            Some(path) => path,
+            None => return Ok(None),
+        },
+    };
+    let content = match format {
+        ExportFormat::Markdown => render_markdown(&name, &messages),
+        ExportFormat::Json => render_json(conversation_id, &name, &messages)?,
+        ExportFormat::Html => render_html(&name, &messages),
+    };
+    tokio::fs::write(&path, content).await?;
+    Ok(Some(path.to_string_lossy().into_owned()))
+}
+
+/// Decodes the MP3 segments and writes them to a 16-bit WAV file.
+fn write_wav(segments: Vec<Vec<u8>>, path: &Path) -> Result<(), Error> {
+    use rodio::Source;
+    let mut writer: Option<hound::WavWriter<_>> = None;
+    for segment in segments {
+        let decoder = rodio::Decoder::new(std::io::Cursor::new(segment))?;
+        let writer = match &mut writer {
+            Some(writer) => writer,
+            None => writer.insert(hound::WavWriter::create(
+                path,
+                hound::WavSpec {
+                    channels: decoder.channels(),
+                    sample_rate: decoder.sample_rate(),
+                    bits_per_sample: 16,
+                    sample_format: hound::SampleFormat::Int,
+                },
+            )?),
+        };
+        for sample in decoder {
+            writer.write_sample(sample)?;
+        }
+    }
+    if let Some(writer) = writer {
+        writer.finalize()?;
+    }
+    Ok(())
+}
+
+/// Writes the spoken audio of a message to `path`, or to a file chosen with a save dialog if `path` is None. The format is
+/// WAV if the extension is .wav, and MP3 otherwise. The message is synthesized sentence by sentence as in
+/// `speak_azure_sentences`, with the voice of the conversation, so the sentences that have already been spoken are read
+/// from the TTS cache. If the whole message has been spoken with `speak_azure_text`, its clip is used instead.
+/// Returns the path written to, or None if the dialog was canceled.
+#[tauri::command]
+#[tracing::instrument(skip(resource_key, state), err)]
+pub(crate) async fn export_message_audio(
+    message_id: i64,
+    path: Option<String>,
+    region: String,
+    resource_key: String,
+    voice: String,
+    lang: String,
+    state: tauri::State<'_, AppState>,
+) -> Result<Option<String>, Error> {
+    let mut voice = Voice::new(voice, lang);
+    let (content, clip) = {
+        let mut conn = state.db_pool.acquire().await?;
+        conversation_settings::apply_to_voice(&mut conn, Some(message_id), &mut voice).await?;
+        let content: String = sqlx::query("SELECT content FROM message WHERE id = ?")
+            .bind(message_id)
+            .fetch_optional(&mut conn)
+            .await?
+            .ok_or_else(|| Error::StringError(format!("Message {message_id} does not exist")))?
+            .get("content");
+        let content = SpeechText::load(&mut conn, &voice.lang)
+            .await?
+            .apply(&content);
+        // The key of the clip cached by speak_azure_text
+        let ssml = lexicon::apply_to_ssml(&mut conn, &voice.ssml(&content)).await?;
+        let clip: Option<Vec<u8>> =
+            sqlx::query("SELECT audio FROM messageTTSCache WHERE messageId = ? AND ssml = ?")
+                .bind(message_id)
+                .bind(&ssml)
+                .fetch_optional(&mut conn)
+                .await?
+                .map(|row| row.get("audio"));
+        if clip.is_some() {
+            tts_cache::touch(&mut conn, &ssml).await?;
+        }
+        (content, clip)
+    };
+
+    let path = match path {
+        Some(path) => PathBuf::from(path),
+        None => match save_file_dialog(
+            &format!("message-{message_id}.mp3"),
+            &[("mp3", &["mp3"]), ("wav", &["wav"])],
+        )
+        .await
+        {
+            Some(path) => path,
+            None => return Ok(None),
+        },
+    };
+
+    let segments = match clip {
+        Some(clip) => vec![clip],
+        None => {
+            let synthesizer = SentenceSynthesizer {
+                db_pool: &state.db_pool,
+                region: &region,
+                resource_key: &resource_key,
+                voice: &voice,
+            };
+            let mut segments = vec![];
+            for sentence in split_sentences(&content) {
+                segments.push(
+                    synthesizer
+                        .synthesize(Some(message_id), &sentence, false)
+                        .await?,
+                );
+            }
+            segments
+        }
+    };
+
+    let is_wav = path
+        .extension()
+        .map_or(false, |ext| ext.eq_ignore_ascii_case("wav"));
+    if is_wav {
+        let path = path.clone();
+        tokio::task::spawn_blocking(move || write_wav(segments, &path)).await??;
+    } else {
+        // MP3 frames are self-contained, so the segments can be concatenated.
+        tokio::fs::write(&path, segments.concat()).await?;
+    }
+    Ok(Some(path.to_string_lossy().into_owned()))
+}
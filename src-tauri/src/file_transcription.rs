@@ -0,0 +1,234 @@
+//! Transcription of existing audio files, e.g. meeting recordings.
+//!
+//! A file that the provider cannot take in one request is decoded, downsampled to 16 kHz mono, and split into WAV
+//! chunks that are transcribed in order. The calling window receives `transcription://progress` after each chunk.
+//!
+//! With `diarize`, the transcript is labeled with speakers by `diarization`, which needs the timestamps of Whisper.
+
+use crate::diarization::{assign_speakers, format_transcript, segment_features};
+use crate::whisper::{upload, upload_segments, Downsampler, MAX_UPLOAD_BYTES};
+use crate::{azure_stt, Error};
+use rodio::Source;
+use std::path::{Path, PathBuf};
+use tempfile::NamedTempFile;
+
+/// 10 minutes of 16 kHz mono 16-bit PCM is about 19 MB, below the upload limit of Whisper.
+const WHISPER_CHUNK_SECS: u32 = 600;
+/// Azure's REST API for short audio only accepts up to 60 seconds.
+const AZURE_CHUNK_SECS: u32 = 55;
+const DEFAULT_MAX_SPEAKERS: usize = 4;
+
+#[derive(serde::Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub(crate) enum TranscriptionProvider {
+    OpenAI {
+        #[serde(rename = "openaiKey")]
+        openai_key: String,
+    },
+    Azure {
+        region: String,
+        #[serde(rename = "resourceKey")]
+        resource_key: String,
+    },
+}
+
+#[derive(serde::Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+struct TranscriptionProgress {
+    path: PathBuf,
+    completed_chunks: usize,
+    total_chunks: usize,
+}
+
+/// Returns the MIME type of the formats that Whisper accepts without conversion.
+fn whisper_mime(path: &Path) -> Option<&'static str> {
+    match path.extension()?.to_str()?.to_lowercase().as_str() {
+        "mp3" | "mpga" | "mpeg" => Some("audio/mpeg"),
+        "m4a" | "mp4" => Some("audio/mp4"),
+        "wav" => Some("audio/x-wav"),
+        "flac" => Some("audio/flac"),
+        "ogg" => Some("audio/ogg"),
+        "webm" => Some("audio/webm"),
+        _ => None,
+    }
+}
+
+/// Decodes the file and writes it into WAV files of up to `chunk_secs` seconds of 16 kHz mono 16-bit PCM.
+fn split(path: &Path, chunk_secs: u32) -> Result<Vec<NamedTempFile>, Error> {
+    let source = rodio::Decoder::new(std::io::BufReader::new(std::fs::File::open(path)?))?;
+    let channels = source.channels() as usize;
+    let mut downsampler = Downsampler::new(source.sample_rate());
+    let spec = hound::WavSpec {
+        channels: 1,
+        sample_rate: Downsampler::output_sample_rate(source.sample_rate()),
+        bits_per_sample: 16,
+        sample_format: hound::SampleFormat::Int,
+    };
+    let samples_per_chunk = (spec.sample_rate * chunk_secs) as usize;
+
+    let mut chunks = vec![];
+    let mut writer: Option<hound::WavWriter<_>> = None;
+    let mut written = 0;
+    let mut write = |sample: i16, chunks: &mut Vec<NamedTempFile>| -> Result<(), Error> {
+        if writer.is_none() || written == samples_per_chunk {
+            if let Some(writer) = writer.take() {
+                writer.finalize()?;
+            }
+            let chunk = tempfile::Builder::new().suffix(".wav").tempfile()?;
+            writer = Some(hound::WavWriter::create(chunk.path(), spec)?);
+            chunks.push(chunk);
+            written = 0;
+        }
+        writer.as_mut().unwrap().write_sample(sample)?;
+        written += 1;
+        Ok(())
+    };
+    let mut frame = Vec::with_capacity(channels);
+    for sample in source {
+        frame.push(sample as f32 / i16::MAX as f32);
+        if frame.len() < channels {
+            continue;
+        }
+        if let Some(sample) = downsampler.push(frame.iter().sum::<f32>() / channels as f32) {
+            write(sample, &mut chunks)?;
+        }
+        frame.clear();
+    }
+    if let Some(sample) = downsampler.flush() {
+        write(sample, &mut chunks)?;
+    }
+    drop(write);
+    if let Some(writer) = writer {
+        writer.finalize()?;
+    }
+    Ok(chunks)
+}
+
+fn emit_progress(
+    window: &tauri::Window,
+    path: &Path,
+    completed_chunks: usize,
+    total_chunks: usize,
+) {
+    let _ = window.emit(
+        "transcription://progress",
+        TranscriptionProgress {
+            path: path.to_owned(),
+            completed_chunks,
+            total_chunks,
+        },
+    );
+}
+
+/// Transcribes the chunks with Whisper and labels the segments with speakers.
+async fn transcribe_with_speakers(
+    window: &tauri::Window,
+    path: &Path,
+    language: &str,
+    openai_key: &str,
+    max_speakers: usize,
+) -> Result<String, Error> {
+    let src = path.to_owned();
+    let chunks = tokio::task::spawn_blocking(move || split(&src, WHISPER_CHUNK_SECS)).await??;
+    let mut segments = vec![];
+    let mut features = vec![];
+    for (i, chunk) in chunks.iter().enumerate() {
+        let chunk_segments = upload_segments(
+            chunk.path(),
+            "audio.wav",
+            "audio/x-wav",
+            openai_key,
+            language,
+        )
+        .await?;
+        let chunk_path = chunk.path().to_owned();
+        let times = chunk_segments
+            .iter()
+            .map(|s| (s.start, s.end))
+            .collect::<Vec<_>>();
+        features.extend(
+            tokio::task::spawn_blocking(move || -> Result<_, Error> {
+                let mut reader = hound::WavReader::open(chunk_path)?;
+                let sample_rate = reader.spec().sample_rate;
+                let samples = reader.samples::<i16>().collect::<Result<Vec<_>, _>>()?;
+                Ok(times
+                    .into_iter()
+                    .map(|(start, end)| segment_features(&samples, sample_rate, start, end))
+                    .collect::<Vec<_>>())
+            })
+            .await??,
+        );
+        segments.extend(chunk_segments);
+        emit_progress(window, path, i + 1, chunks.len());
+    }
+    // The agglomerative clustering is cubic in the number of segments, which is slow for a long recording.
+    let speakers =
+        tokio::task::spawn_blocking(move || assign_speakers(&features, max_speakers)).await?;
+    Ok(format_transcript(&segments, &speakers))
+}
+
+/// Transcribes an audio file (mp3, wav, m4a, flac, ogg, ...) and returns the whole transcript. Files larger than the
+/// provider's limit are split into chunks, which may cut a word at their boundaries.
+///
+/// With `diarize`, the transcript is returned in paragraphs labeled "Speaker 1", "Speaker 2", ... of up to
+/// `max_speakers` speakers. Only OpenAI supports it.
+#[tauri::command]
+#[tracing::instrument(skip(window, provider), err)]
+pub(crate) async fn transcribe_file(
+    window: tauri::Window,
+    path: PathBuf,
+    language: String, // "" to auto-detect with OpenAI, e.g. "en-US" with Azure
+    provider: TranscriptionProvider,
+    diarize: Option<bool>,
+    max_speakers: Option<usize>, // defaults to DEFAULT_MAX_SPEAKERS
+) -> Result<String, Error> {
+    if diarize == Some(true) {
+        let TranscriptionProvider::OpenAI { openai_key } = &provider else {
+            return Err(Error::StringError(
+                "Speaker labels are only supported with OpenAI".to_owned(),
+            ));
+        };
+        let max_speakers = max_speakers.unwrap_or(DEFAULT_MAX_SPEAKERS);
+        return transcribe_with_speakers(&window, &path, &language, openai_key, max_speakers).await;
+    }
+
+    if let TranscriptionProvider::OpenAI { openai_key } = &provider {
+        if let Some(mime) = whisper_mime(&path) {
+            if tokio::fs::metadata(&path).await?.len() <= MAX_UPLOAD_BYTES {
+                let file_name = path.file_name().and_then(|n| n.to_str()).unwrap_or("audio");
+                return upload(&path, file_name, mime, openai_key, &language).await;
+            }
+        }
+    }
+
+    let chunk_secs = match provider {
+        TranscriptionProvider::OpenAI { .. } => WHISPER_CHUNK_SECS,
+        TranscriptionProvider::Azure { .. } => AZURE_CHUNK_SECS,
+    };
+    let src = path.clone();
+    let chunks = tokio::task::spawn_blocking(move || split(&src, chunk_secs)).await??;
+    let mut transcripts = vec![];
+    for (i, chunk) in chunks.iter().enumerate() {
+        let text = match &provider {
+            TranscriptionProvider::OpenAI { openai_key } => {
+                upload(
+                    chunk.path(),
+                    "audio.wav",
+                    "audio/x-wav",
+                    openai_key,
+                    &language,
+                )
+                .await?
+            }
+            TranscriptionProvider::Azure {
+                region,
+                resource_key,
+            } => azure_stt::transcribe(chunk.path(), region, resource_key, &language).await?,
+        };
+        if !text.trim().is_empty() {
+            transcripts.push(text.trim().to_owned());
+        }
+        emit_progress(&window, &path, i + 1, chunks.len());
+    }
+    Ok(transcripts.join(" "))
+}
@@ -0,0 +1,163 @@
+//! Imports the conversations of the official ChatGPT data export (Settings → Data controls → Export data).
+
+use crate::{AppState, Error};
+use serde_json::Value;
+use sqlx::Connection;
+use std::collections::HashMap;
+use std::io::Read;
+
+#[derive(serde::Deserialize)]
+struct ExportedConversation {
+    title: Option<String>,
+    create_time: Option<f64>,
+    #[serde(default)]
+    mapping: HashMap<String, ExportedNode>,
+}
+
+#[derive(serde::Deserialize)]
+struct ExportedNode {
+    message: Option<ExportedMessage>,
+    parent: Option<String>,
+    #[serde(default)]
+    children: Vec<String>,
+}
+
+#[derive(serde::Deserialize)]
+struct ExportedMessage {
+    author: ExportedAuthor,
+    content: ExportedContent,
+    create_time: Option<f64>,
+    #[serde(default)]
+    metadata: Value,
+}
+
+#[derive(serde::Deserialize)]
+struct ExportedAuthor {
+    role: String,
+}
+
+#[derive(serde::Deserialize)]
+struct ExportedContent {
+    #[serde(default)]
+    parts: Vec<Value>,
+    text: Option<String>,
+}
+
+#[derive(serde::Serialize, Clone)]
+struct ImportProgress {
+    done: usize,
+    total: usize,
+}
+
+impl ExportedMessage {
+    /// Returns None for messages that are not shown in the web app, such as tool outputs and hidden system prompts.
+    fn text(&self) -> Option<String> {
+        if !matches!(self.author.role.as_str(), "user" | "assistant" | "system")
+            || self.metadata.get("is_visually_hidden_from_conversation") == Some(&Value::Bool(true))
+        {
+            return None;
+        }
+        let text = match &self.content.text {
+            Some(text) => text.clone(),
+            None => self
+                .content
+                .parts
+                .iter()
+                .filter_map(Value::as_str) // skips images
+                .collect::<Vec<_>>()
+                .join("\n"),
+        };
+        if text.trim().is_empty() {
+            None
+        } else {
+            Some(text)
+        }
+    }
+}
+
+/// Reads `conversations.json` from the export zip, or the file itself if a JSON file is given.
+fn read_conversations(path: &str) -> Result<Vec<ExportedConversation>, Error> {
+    let mut json = String::new();
+    if path.to_lowercase().ends_with(".json") {
+        std::fs::File::open(path)?.read_to_string(&mut json)?;
+    } else {
+        zip::ZipArchive::new(std::fs::File::open(path)?)?
+            .by_name("conversations.json")?
+            .read_to_string(&mut json)?;
+    }
+    Ok(serde_json::from_str(&json)?)
+}
+
+async fn insert_conversation(
+    conn: &mut sqlx::SqliteConnection,
+    conversation: &ExportedConversation,
+) -> Result<(), Error> {
+    let mut tx = conn.begin().await?;
+    let root = sqlx::query(
+        "INSERT INTO message (parent, role, status, content, createdAt, modifiedAt) VALUES (NULL, 'root', 0, '', coalesce(datetime(?1, 'unixepoch'), CURRENT_TIMESTAMP), coalesce(datetime(?1, 'unixepoch'), CURRENT_TIMESTAMP))",
+    )
+    .bind(conversation.create_time)
+    .execute(&mut *tx)
+    .await?
+    .last_insert_rowid();
+    if let Some(title) = &conversation.title {
+        sqlx::query("INSERT OR REPLACE INTO threadName (messageId, name) VALUES (?, ?)")
+            .bind(root)
+            .bind(title.as_str())
+            .execute(&mut *tx)
+            .await?;
+    }
+
+    // Depth-first traversal of the message tree. Nodes without a shown message are skipped by attaching their children to the nearest imported ancestor.
+    let mut stack = conversation
+        .mapping
+        .iter()
+        .filter(|(_, node)| {
+            node.parent
+                .as_ref()
+                .map_or(true, |p| !conversation.mapping.contains_key(p))
+        })
+        .map(|(id, _)| (id.as_str(), root))
+        .collect::<Vec<_>>();
+    while let Some((id, parent)) = stack.pop() {
+        let node = match conversation.mapping.get(id) {
+            Some(node) => node,
+            None => continue,
+        };
+        let parent = match node.message.as_ref().and_then(|m| m.text().map(|t| (m, t))) {
+            Some((message, text)) => sqlx::query(
+                "INSERT INTO message (parent, role, status, content, createdAt, modifiedAt) VALUES (?1, ?2, 0, ?3, coalesce(datetime(?4, 'unixepoch'), CURRENT_TIMESTAMP), coalesce(datetime(?4, 'unixepoch'), CURRENT_TIMESTAMP))",
+            )
+            .bind(parent)
+            .bind(message.author.role.as_str())
+            .bind(text)
+            .bind(message.create_time)
+            .execute(&mut *tx)
+            .await?
+            .last_insert_rowid(),
+            None => parent,
+        };
+        stack.extend(node.children.iter().rev().map(|c| (c.as_str(), parent)));
+    }
+    tx.commit().await?;
+    Ok(())
+}
+
+/// Imports every conversation in the export, emitting `import://progress` events. Returns the number of imported conversations.
+#[tauri::command]
+#[tracing::instrument(skip(window, state), err)]
+pub(crate) async fn import_openai_export(
+    window: tauri::Window,
+    zip_path: String,
+    state: tauri::State<'_, AppState>,
+) -> Result<usize, Error> {
+    let conversations =
+        tokio::task::spawn_blocking(move || read_conversations(&zip_path)).await??;
+    let total = conversations.len();
+    let mut conn = state.db_pool.acquire().await?;
+    for (i, conversation) in conversations.iter().enumerate() {
+        insert_conversation(&mut conn, conversation).await?;
+        window.emit("import://progress", ImportProgress { done: i + 1, total })?;
+    }
+    Ok(total)
+}
@@ -0,0 +1,223 @@
+//! What this computer can run locally, so that the settings of the local models can tell which sizes to choose.
+//!
+//! The memory comes from sysinfo, and the memory of NVIDIA GPUs from NVML, which is loaded from the driver at runtime and
+//! is simply absent without one. Apple Silicon shares the memory between the CPU and the GPU, so its GPU is reported with
+//! the memory of the system. Other GPUs are not detected, and the models are then assumed to run on the CPU.
+//!
+//! A model fits if its weights and their working memory fit in the memory left after `RESERVED_MEMORY`. The sizes are
+//! those of the usual quantizations: ggml for Whisper and Q4_K_M GGUF for the LLMs.
+
+use crate::Error;
+use sysinfo::{CpuExt, System, SystemExt};
+
+const GIB: u64 = 1024 * 1024 * 1024;
+/// Left for the OS and the other apps.
+const RESERVED_MEMORY: u64 = 2 * GIB;
+
+/// (name, the memory it needs in MiB)
+const WHISPER_MODELS: [(&str, u64); 5] = [
+    ("tiny", 390),
+    ("base", 500),
+    ("small", 1000),
+    ("medium", 2600),
+    ("large-v3", 4700),
+];
+const LLM_MODELS: [(&str, u64); 6] = [
+    ("1B", 1200),
+    ("3B", 2600),
+    ("8B", 5600),
+    ("14B", 9800),
+    ("32B", 21000),
+    ("70B", 44000),
+];
+
+#[derive(serde::Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct Cpu {
+    brand: String,
+    physical_cores: Option<usize>,
+    logical_cores: usize,
+    /// The SIMD extensions that llama.cpp and whisper.cpp use, e.g. "avx2" or "neon".
+    features: Vec<&'static str>,
+}
+
+#[derive(serde::Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct Gpu {
+    name: String,
+    /// In bytes.
+    memory: u64,
+    /// Whether the memory is shared with the CPU.
+    unified: bool,
+}
+
+#[derive(serde::Serialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "camelCase")]
+enum Fit {
+    Gpu,
+    Cpu,
+    /// Fits in the memory, but the CPU lacks the SIMD extensions that make the inference fast.
+    SlowCpu,
+    TooLarge,
+}
+
+#[derive(serde::Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct ModelRecommendation {
+    /// "whisper" or "llm"
+    kind: &'static str,
+    /// e.g. "small" or "8B"
+    size: &'static str,
+    /// In bytes.
+    required_memory: u64,
+    fit: Fit,
+}
+
+#[derive(serde::Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct InferenceCapabilities {
+    /// In bytes.
+    total_memory: u64,
+    /// In bytes.
+    available_memory: u64,
+    cpu: Cpu,
+    gpus: Vec<Gpu>,
+    recommendations: Vec<ModelRecommendation>,
+}
+
+fn cpu_features() -> Vec<&'static str> {
+    #[allow(unused_mut)]
+    let mut features = vec![];
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    {
+        if is_x86_feature_detected!("avx") {
+            features.push("avx");
+        }
+        if is_x86_feature_detected!("avx2") {
+            features.push("avx2");
+        }
+        if is_x86_feature_detected!("fma") {
+            features.push("fma");
+        }
+        if is_x86_feature_detected!("f16c") {
+            features.push("f16c");
+        }
+        if is_x86_feature_detected!("avx512f") {
+            features.push("avx512f");
+        }
+    }
+    #[cfg(target_arch = "aarch64")]
+    {
+        if std::arch::is_aarch64_feature_detected!("neon") {
+            features.push("neon");
+        }
+        if std::arch::is_aarch64_feature_detected!("dotprod") {
+            features.push("dotprod");
+        }
+    }
+    features
+}
+
+/// The NVIDIA GPUs, or none if the driver is not installed.
+fn nvidia_gpus() -> Vec<Gpu> {
+    let nvml = match nvml_wrapper::Nvml::init() {
+        Ok(nvml) => nvml,
+        Err(err) => {
+            tracing::debug!("NVML is not available: {err}");
+            return vec![];
+        }
+    };
+    let count = nvml.device_count().unwrap_or(0);
+    (0..count)
+        .filter_map(|i| {
+            let device = nvml.device_by_index(i).ok()?;
+            Some(Gpu {
+                name: device.name().unwrap_or_default(),
+                memory: device.memory_info().ok()?.total,
+                unified: false,
+            })
+        })
+        .collect()
+}
+
+fn probe() -> InferenceCapabilities {
+    let mut system = System::new();
+    system.refresh_memory();
+    system.refresh_cpu();
+    let total_memory = system.total_memory();
+    let available_memory = system.available_memory();
+    let cpu = Cpu {
+        brand: system
+            .cpus()
+            .first()
+            .map(|cpu| cpu.brand().trim().to_owned())
+            .unwrap_or_default(),
+        physical_cores: system.physical_core_count(),
+        logical_cores: system.cpus().len(),
+        features: cpu_features(),
+    };
+    let mut gpus = nvidia_gpus();
+    if cfg!(all(target_os = "macos", target_arch = "aarch64")) {
+        gpus.push(Gpu {
+            name: cpu.brand.clone(),
+            memory: total_memory,
+            unified: true,
+        });
+    }
+
+    let fast_cpu = cpu
+        .features
+        .iter()
+        .any(|feature| matches!(*feature, "avx2" | "neon"));
+    let gpu_memory = gpus
+        .iter()
+        .map(|gpu| {
+            if gpu.unified {
+                gpu.memory.saturating_sub(RESERVED_MEMORY)
+            } else {
+                gpu.memory
+            }
+        })
+        .max()
+        .unwrap_or(0);
+    let cpu_memory = total_memory.saturating_sub(RESERVED_MEMORY);
+    let fit = |required: u64| {
+        if required <= gpu_memory {
+            Fit::Gpu
+        } else if required > cpu_memory {
+            Fit::TooLarge
+        } else if fast_cpu {
+            Fit::Cpu
+        } else {
+            Fit::SlowCpu
+        }
+    };
+    let recommendations = WHISPER_MODELS
+        .iter()
+        .map(|model| ("whisper", model))
+        .chain(LLM_MODELS.iter().map(|model| ("llm", model)))
+        .map(|(kind, &(size, mib))| {
+            let required_memory = mib * 1024 * 1024;
+            ModelRecommendation {
+                kind,
+                size,
+                required_memory,
+                fit: fit(required_memory),
+            }
+        })
+        .collect();
+    InferenceCapabilities {
+        total_memory,
+        available_memory,
+        cpu,
+        gpus,
+        recommendations,
+    }
+}
+
+/// Reports the memory, the CPU, and the GPUs, and which sizes of the local Whisper and LLM models will run on them.
+#[tauri::command]
+#[tracing::instrument(err)]
+pub(crate) async fn probe_inference_capabilities() -> Result<InferenceCapabilities, Error> {
+    Ok(tauri::async_runtime::spawn_blocking(probe).await?)
+}
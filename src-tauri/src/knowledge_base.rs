@@ -0,0 +1,403 @@
+//! A local knowledge base for retrieval-augmented generation.
+//!
+//! `index_documents` splits files into chunks of about `CHUNK_TOKENS` tokens, embeds them with OpenAI or a local Ollama
+//! model, and stores the vectors in the `knowledgeDocument` and `knowledgeChunk` tables. `retrieve` embeds the query with
+//! the same model and returns the most similar chunks by a flat scan, which the frontend prepends to the prompt. The
+//! calling window of `index_documents` receives `knowledge-base://progress` after each file.
+
+use crate::attachment::extract_text;
+use crate::{AppState, Error, CL100K_BASE, OLLAMA_BASE_URL};
+use serde_json::json;
+use sqlx::{Connection, Row};
+use std::path::PathBuf;
+
+const CHUNK_TOKENS: usize = 400;
+/// The number of chunks embedded in one request.
+pub(crate) const EMBEDDING_BATCH_SIZE: usize = 64;
+const OPENAI_EMBEDDINGS_ENDPOINT: &str = "https://api.openai.com/v1/embeddings";
+const DEFAULT_OPENAI_MODEL: &str = "text-embedding-3-small";
+const DEFAULT_K: usize = 5;
+/// The file types indexed when a directory is given.
+const EXTENSIONS: [&str; 5] = ["txt", "md", "markdown", "pdf", "docx"];
+
+#[derive(serde::Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub(crate) enum EmbeddingProvider {
+    OpenAI {
+        #[serde(rename = "openaiKey")]
+        openai_key: String,
+        model: Option<String>, // defaults to DEFAULT_OPENAI_MODEL
+    },
+    Ollama {
+        model: String, // e.g. "nomic-embed-text"
+    },
+}
+
+#[derive(serde::Deserialize)]
+struct OpenAIEmbeddings {
+    data: Vec<OpenAIEmbedding>,
+}
+
+#[derive(serde::Deserialize)]
+struct OpenAIEmbedding {
+    index: usize,
+    embedding: Vec<f32>,
+}
+
+#[derive(serde::Deserialize)]
+struct OllamaEmbeddings {
+    embeddings: Vec<Vec<f32>>,
+}
+
+impl EmbeddingProvider {
+    /// Identifies the vector space, since the vectors of different models cannot be compared.
+    pub(crate) fn model_id(&self) -> String {
+        match self {
+            EmbeddingProvider::OpenAI { model, .. } => {
+                format!(
+                    "openai:{}",
+                    model.as_deref().unwrap_or(DEFAULT_OPENAI_MODEL)
+                )
+            }
+            EmbeddingProvider::Ollama { model } => format!("ollama:{model}"),
+        }
+    }
+
+    /// Returns the embeddings of the inputs normalized to unit length.
+    pub(crate) async fn embed(&self, inputs: &[String]) -> Result<Vec<Vec<f32>>, Error> {
+        let client = reqwest::Client::new();
+        let request = match self {
+            EmbeddingProvider::OpenAI { openai_key, model } => client
+                .post(OPENAI_EMBEDDINGS_ENDPOINT)
+                .bearer_auth(openai_key)
+                .body(
+                    json!({ "model": model.as_deref().unwrap_or(DEFAULT_OPENAI_MODEL), "input": inputs })
+                        .to_string(),
+                ),
+            EmbeddingProvider::Ollama { model } => client
+                .post(format!("{OLLAMA_BASE_URL}/api/embed"))
+                .body(json!({ "model": model, "input": inputs }).to_string()),
+        };
+        let res = request
+            .header("Content-Type", "application/json")
+            .send()
+            .await?;
+        if res.status() != 200 {
+            return Err(Error::StatusIsNot200(format!(
+                "{}: {}",
+                res.status(),
+                res.text().await?
+            )));
+        }
+        let text = res.text().await?;
+        let embeddings = match self {
+            EmbeddingProvider::OpenAI { .. } => {
+                let mut data = serde_json::from_str::<OpenAIEmbeddings>(&text)?.data;
+                data.sort_by_key(|e| e.index);
+                data.into_iter().map(|e| e.embedding).collect::<Vec<_>>()
+            }
+            EmbeddingProvider::Ollama { .. } => {
+                serde_json::from_str::<OllamaEmbeddings>(&text)?.embeddings
+            }
+        };
+        if embeddings.len() != inputs.len() {
+            return Err(Error::StringError(format!(
+                "Expected {} embeddings, got {}",
+                inputs.len(),
+                embeddings.len()
+            )));
+        }
+        Ok(embeddings.into_iter().map(normalize).collect())
+    }
+}
+
+#[derive(serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct IndexedDocument {
+    id: i64,
+    path: String,
+    model: String,
+    chunks: i64,
+    indexed_at: String,
+}
+
+#[derive(serde::Serialize)]
+pub(crate) struct IndexFailure {
+    path: PathBuf,
+    message: String,
+}
+
+#[derive(serde::Serialize)]
+pub(crate) struct IndexResult {
+    documents: Vec<IndexedDocument>,
+    /// The files whose text could not be extracted.
+    failures: Vec<IndexFailure>,
+}
+
+#[derive(serde::Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+struct IndexProgress {
+    path: PathBuf,
+    completed_files: usize,
+    total_files: usize,
+}
+
+#[derive(serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct RetrievedChunk {
+    path: String,
+    chunk_index: i64,
+    content: String,
+    /// The cosine similarity to the query.
+    score: f32,
+}
+
+fn normalize(mut vector: Vec<f32>) -> Vec<f32> {
+    let norm = vector.iter().map(|x| x * x).sum::<f32>().sqrt();
+    if norm > 0.0 {
+        vector.iter_mut().for_each(|x| *x /= norm);
+    }
+    vector
+}
+
+pub(crate) fn to_blob(vector: &[f32]) -> Vec<u8> {
+    vector.iter().flat_map(|x| x.to_le_bytes()).collect()
+}
+
+/// Computes the dot product with a vector stored by `to_blob`.
+pub(crate) fn dot_blob(vector: &[f32], blob: &[u8]) -> f32 {
+    vector
+        .iter()
+        .zip(blob.chunks_exact(4))
+        .map(|(a, b)| a * f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+        .sum()
+}
+
+/// Splits the text into chunks of up to about `CHUNK_TOKENS` tokens at paragraph boundaries. Longer paragraphs are split
+/// at lines, and longer lines at characters.
+fn chunk(text: &str) -> Vec<String> {
+    let tokens = |s: &str| CL100K_BASE.encode_with_special_tokens(s).len();
+    let mut pieces = vec![];
+    for paragraph in text.split("\n\n").map(str::trim).filter(|p| !p.is_empty()) {
+        if tokens(paragraph) <= CHUNK_TOKENS {
+            pieces.push(paragraph.to_owned());
+            continue;
+        }
+        for line in paragraph.lines().map(str::trim).filter(|l| !l.is_empty()) {
+            // A token is about 4 characters in English and fewer in most other languages.
+            let chars = line.chars().collect::<Vec<_>>();
+            pieces.extend(chars.chunks(CHUNK_TOKENS * 2).map(String::from_iter));
+        }
+    }
+
+    let mut chunks = vec![];
+    let mut current = String::new();
+    let mut current_tokens = 0;
+    for piece in pieces {
+        let n = tokens(&piece);
+        if !current.is_empty() && current_tokens + n > CHUNK_TOKENS {
+            chunks.push(std::mem::take(&mut current));
+            current_tokens = 0;
+        }
+        if !current.is_empty() {
+            current.push_str("\n\n");
+        }
+        current.push_str(&piece);
+        current_tokens += n;
+    }
+    if !current.is_empty() {
+        chunks.push(current);
+    }
+    chunks
+}
+
+/// Expands directories into the supported files in them, skipping hidden entries.
+fn collect_files(paths: &[PathBuf], files: &mut Vec<PathBuf>) -> Result<(), Error> {
+    for path in paths {
+        if !path.is_dir() {
+            files.push(path.clone());
+            continue;
+        }
+        let mut entries = std::fs::read_dir(path)?
+            .map(|entry| entry.map(|e| e.path()))
+            .collect::<Result<Vec<_>, _>>()?;
+        entries.sort();
+        entries.retain(|entry| {
+            let hidden = entry
+                .file_name()
+                .and_then(|n| n.to_str())
+                .is_some_and(|n| n.starts_with('.'));
+            let supported = entry
+                .extension()
+                .and_then(|e| e.to_str())
+                .is_some_and(|e| EXTENSIONS.contains(&e.to_lowercase().as_str()));
+            !hidden && (entry.is_dir() || supported)
+        });
+        collect_files(&entries, files)?;
+    }
+    Ok(())
+}
+
+async fn list_documents(
+    conn: &mut sqlx::SqliteConnection,
+    id: Option<i64>,
+) -> Result<Vec<IndexedDocument>, Error> {
+    Ok(sqlx::query(
+        "SELECT d.id, d.path, d.model, d.indexedAt, (SELECT COUNT(*) FROM knowledgeChunk c WHERE c.documentId = d.id) AS chunks FROM knowledgeDocument d WHERE ? IS NULL OR d.id = ? ORDER BY d.path",
+    )
+    .bind(id)
+    .bind(id)
+    .fetch_all(conn)
+    .await?
+    .into_iter()
+    .map(|row| IndexedDocument {
+        id: row.get("id"),
+        path: row.get("path"),
+        model: row.get("model"),
+        chunks: row.get("chunks"),
+        indexed_at: row.get("indexedAt"),
+    })
+    .collect())
+}
+
+/// Indexes the files, and the .txt, .md, .pdf, and .docx files in the directories, replacing the previous index of the
+/// same paths. Files whose text cannot be extracted are skipped and reported in `failures`.
+#[tauri::command]
+#[tracing::instrument(skip(window, provider, state), err)]
+pub(crate) async fn index_documents(
+    window: tauri::Window,
+    paths: Vec<PathBuf>,
+    provider: EmbeddingProvider,
+    state: tauri::State<'_, AppState>,
+) -> Result<IndexResult, Error> {
+    let files = tauri::async_runtime::spawn_blocking(move || {
+        let mut files = vec![];
+        collect_files(&paths, &mut files).map(|_| files)
+    })
+    .await??;
+    let model = provider.model_id();
+    let mut result = IndexResult {
+        documents: vec![],
+        failures: vec![],
+    };
+    for (i, path) in files.iter().enumerate() {
+        let src = path.clone();
+        let chunks = match tauri::async_runtime::spawn_blocking(move || {
+            extract_text(&src).map(|t| chunk(&t))
+        })
+        .await?
+        {
+            Ok(chunks) => chunks,
+            Err(err) => {
+                result.failures.push(IndexFailure {
+                    path: path.clone(),
+                    message: err.to_string(),
+                });
+                continue;
+            }
+        };
+        let mut embeddings = vec![];
+        for batch in chunks.chunks(EMBEDDING_BATCH_SIZE) {
+            embeddings.extend(provider.embed(batch).await?);
+        }
+
+        let path_text = path.to_string_lossy().into_owned();
+        let mut conn = state.db_pool.acquire().await?;
+        let mut tx = conn.begin().await?;
+        sqlx::query("DELETE FROM knowledgeDocument WHERE path = ?")
+            .bind(&path_text)
+            .execute(&mut *tx)
+            .await?;
+        let id = sqlx::query("INSERT INTO knowledgeDocument (path, model) VALUES (?, ?)")
+            .bind(&path_text)
+            .bind(&model)
+            .execute(&mut *tx)
+            .await?
+            .last_insert_rowid();
+        for (index, (content, embedding)) in chunks.iter().zip(&embeddings).enumerate() {
+            sqlx::query(
+                "INSERT INTO knowledgeChunk (documentId, chunkIndex, content, embedding) VALUES (?, ?, ?, ?)",
+            )
+            .bind(id)
+            .bind(index as i64)
+            .bind(content)
+            .bind(to_blob(embedding))
+            .execute(&mut *tx)
+            .await?;
+        }
+        tx.commit().await?;
+        result
+            .documents
+            .extend(list_documents(&mut conn, Some(id)).await?);
+
+        let _ = window.emit(
+            "knowledge-base://progress",
+            IndexProgress {
+                path: path.clone(),
+                completed_files: i + 1,
+                total_files: files.len(),
+            },
+        );
+    }
+    Ok(result)
+}
+
+/// Returns the `k` chunks most similar to the query among the documents indexed with the same model.
+#[tauri::command]
+#[tracing::instrument(skip(query, provider, state), err)]
+pub(crate) async fn retrieve(
+    query: String,
+    k: Option<usize>, // defaults to DEFAULT_K
+    provider: EmbeddingProvider,
+    state: tauri::State<'_, AppState>,
+) -> Result<Vec<RetrievedChunk>, Error> {
+    let query = provider
+        .embed(&[query])
+        .await?
+        .pop()
+        .ok_or_else(|| Error::StringError("No embedding was returned".to_owned()))?;
+    let mut conn = state.db_pool.acquire().await?;
+    let mut chunks = sqlx::query(
+        "SELECT d.path, c.chunkIndex, c.content, c.embedding FROM knowledgeChunk c JOIN knowledgeDocument d ON d.id = c.documentId WHERE d.model = ?",
+    )
+    .bind(provider.model_id())
+    .fetch_all(&mut conn)
+    .await?
+    .into_iter()
+    .map(|row| {
+        let embedding: Vec<u8> = row.get("embedding");
+        RetrievedChunk {
+            path: row.get("path"),
+            chunk_index: row.get("chunkIndex"),
+            content: row.get("content"),
+            score: dot_blob(&query, &embedding),
+        }
+    })
+    .collect::<Vec<_>>();
+    chunks.sort_by(|a, b| b.score.total_cmp(&a.score));
+    chunks.truncate(k.unwrap_or(DEFAULT_K));
+    Ok(chunks)
+}
+
+#[tauri::command]
+#[tracing::instrument(skip(state), err)]
+pub(crate) async fn list_indexed_documents(
+    state: tauri::State<'_, AppState>,
+) -> Result<Vec<IndexedDocument>, Error> {
+    let mut conn = state.db_pool.acquire().await?;
+    list_documents(&mut conn, None).await
+}
+
+#[tauri::command]
+#[tracing::instrument(skip(state), err)]
+pub(crate) async fn remove_indexed_document(
+    id: i64,
+    state: tauri::State<'_, AppState>,
+) -> Result<(), Error> {
+    let mut conn = state.db_pool.acquire().await?;
+    sqlx::query("DELETE FROM knowledgeDocument WHERE id = ?")
+        .bind(id)
+        .execute(&mut conn)
+        .await?;
+    Ok(())
+}
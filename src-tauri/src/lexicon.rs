@@ -0,0 +1,180 @@
+//! User-managed pronunciations for Azure TTS, stored in the `lexicon` table.
+//!
+//! Azure only loads `<lexicon>` files from a public URL, so the entries are inlined into the SSML instead: every occurrence
+//! of a word in the text is wrapped in `<phoneme alphabet="ipa">` if its pronunciation is IPA written between slashes
+//! (e.g. "/ˈkjuːbərˌnɛtiz/"), or in `<sub alias>` otherwise (e.g. "SQL" -> "sequel").
+
+use crate::{AppState, Error};
+use sqlx::Row;
+
+#[derive(serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct LexiconEntry {
+    word: String,
+    pronunciation: String,
+}
+
+pub(crate) fn escape_xml(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+/// Returns the escaped word to search for and the opening and closing tags to wrap it in.
+fn element(entry: &LexiconEntry) -> (String, String, &'static str) {
+    let pronunciation = entry.pronunciation.trim();
+    let (open, close) = match pronunciation
+        .strip_prefix('/')
+        .and_then(|s| s.strip_suffix('/'))
+    {
+        Some(ipa) => (
+            format!(r#"<phoneme alphabet="ipa" ph="{}">"#, escape_xml(ipa)),
+            "</phoneme>",
+        ),
+        None => (
+            format!(r#"<sub alias="{}">"#, escape_xml(pronunciation)),
+            "</sub>",
+        ),
+    };
+    (escape_xml(&entry.word), open, close)
+}
+
+fn is_word_char(c: Option<char>) -> bool {
+    c.map_or(false, char::is_alphanumeric)
+}
+
+/// Wraps the whole-word, ASCII case-insensitive occurrences of the entries in a text node.
+fn wrap_words(text: &str, elements: &[(String, String, &'static str)], out: &mut String) {
+    let mut i = 0;
+    while i < text.len() {
+        let at_boundary = !is_word_char(text[..i].chars().next_back());
+        let found = elements.iter().find(|(word, _, _)| {
+            let end = i + word.len();
+            at_boundary
+                && !word.is_empty()
+                && end <= text.len()
+                && text.is_char_boundary(end)
+                && text.as_bytes()[i..end].eq_ignore_ascii_case(word.as_bytes())
+                && !is_word_char(text[end..].chars().next())
+        });
+        match found {
+            Some((word, open, close)) => {
+                out.push_str(open);
+                out.push_str(&text[i..i + word.len()]);
+                out.push_str(close);
+                i += word.len();
+            }
+            None => {
+                let c = text[i..].chars().next().unwrap();
+                out.push(c);
+                i += c.len_utf8();
+            }
+        }
+    }
+}
+
+/// Applies the entries to the text of an SSML document, leaving the markup and existing `<phoneme>`/`<sub>` elements as is.
+fn apply(ssml: &str, entries: &[LexiconEntry]) -> String {
+    let mut elements: Vec<_> = entries.iter().map(element).collect();
+    elements.sort_by_key(|(word, _, _)| std::cmp::Reverse(word.len())); // the longest match wins
+    let mut out = String::with_capacity(ssml.len());
+    let mut nesting = 0u32;
+    let mut rest = ssml;
+    while !rest.is_empty() {
+        if rest.starts_with('<') {
+            let end = rest.find('>').map_or(rest.len(), |i| i + 1);
+            let tag = &rest[..end];
+            if (tag.starts_with("<phoneme") || tag.starts_with("<sub")) && !tag.ends_with("/>") {
+                nesting += 1;
+            } else if tag.starts_with("</phoneme") || tag.starts_with("</sub") {
+                nesting = nesting.saturating_sub(1);
+            }
+            out.push_str(tag);
+            rest = &rest[end..];
+        } else {
+            let end = rest.find('<').unwrap_or(rest.len());
+            if nesting == 0 {
+                wrap_words(&rest[..end], &elements, &mut out);
+            } else {
+                out.push_str(&rest[..end]);
+            }
+            rest = &rest[end..];
+        }
+    }
+    out
+}
+
+async fn entries(conn: &mut sqlx::SqliteConnection) -> Result<Vec<LexiconEntry>, Error> {
+    Ok(
+        sqlx::query("SELECT word, pronunciation FROM lexicon ORDER BY word")
+            .fetch_all(conn)
+            .await?
+            .into_iter()
+            .map(|row| LexiconEntry {
+                word: row.get("word"),
+                pronunciation: row.get("pronunciation"),
+            })
+            .collect(),
+    )
+}
+
+/// Injects the lexicon into the SSML sent to Azure. The result is also the key of the TTS caches, so editing the lexicon
+/// invalidates the audio of the affected texts.
+pub(crate) async fn apply_to_ssml(
+    conn: &mut sqlx::SqliteConnection,
+    ssml: &str,
+) -> Result<String, Error> {
+    let entries = entries(conn).await?;
+    if entries.is_empty() {
+        return Ok(ssml.to_owned());
+    }
+    Ok(apply(ssml, &entries))
+}
+
+/// Adds or replaces the pronunciation of a word (case-insensitive). `ipa_or_alias` is IPA between slashes, or the text to
+/// read instead.
+#[tauri::command]
+#[tracing::instrument(skip(state), err)]
+pub(crate) async fn add_lexicon_entry(
+    word: String,
+    ipa_or_alias: String,
+    state: tauri::State<'_, AppState>,
+) -> Result<(), Error> {
+    if word.trim().is_empty() || ipa_or_alias.trim().is_empty() {
+        return Err(Error::StringError(
+            "The word and its pronunciation must not be empty".to_owned(),
+        ));
+    }
+    let mut conn = state.db_pool.acquire().await?;
+    sqlx::query("INSERT OR REPLACE INTO lexicon (word, pronunciation) VALUES (?, ?)")
+        .bind(word.trim())
+        .bind(ipa_or_alias.trim())
+        .execute(&mut conn)
+        .await?;
+    Ok(())
+}
+
+#[tauri::command]
+#[tracing::instrument(skip(state), err)]
+pub(crate) async fn list_lexicon_entries(
+    state: tauri::State<'_, AppState>,
+) -> Result<Vec<LexiconEntry>, Error> {
+    let mut conn = state.db_pool.acquire().await?;
+    entries(&mut conn).await
+}
+
+#[tauri::command]
+#[tracing::instrument(skip(state), err)]
+pub(crate) async fn delete_lexicon_entry(
+    word: String,
+    state: tauri::State<'_, AppState>,
+) -> Result<(), Error> {
+    let mut conn = state.db_pool.acquire().await?;
+    sqlx::query("DELETE FROM lexicon WHERE word = ?")
+        .bind(word)
+        .execute(&mut conn)
+        .await?;
+    Ok(())
+}
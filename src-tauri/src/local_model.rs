@@ -0,0 +1,294 @@
+//! A llama.cpp server or a llamafile run by the app, so that the chat works without any account or network.
+//!
+//! `launch_local_model` starts the server as a child process listening on localhost, and its OpenAI-compatible API is
+//! used at `http://127.0.0.1:<port>/v1/chat/completions`. A task supervises the process: its output is kept for the
+//! settings and the logs, and it is restarted when it exits, up to `MAX_RESTARTS` times in a row. The process is
+//! killed when another server is launched, by `stop_local_model`, and when the app exits.
+
+use crate::{AppState, Error};
+use std::collections::VecDeque;
+use std::path::Path;
+use std::process::Stdio;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tauri::Manager;
+use tokio::io::{AsyncBufReadExt, AsyncRead, BufReader};
+
+const DEFAULT_PORT: u16 = 8080;
+/// The number of lines of the output that are kept.
+const OUTPUT_LINES: usize = 200;
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+/// The restarts in a row after which the server is given up on.
+const MAX_RESTARTS: u32 = 3;
+/// A server that has been running for this long is assumed to have started, and its restarts are forgotten.
+const STABLE_AFTER: Duration = Duration::from_secs(60);
+const HEALTH_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// The server launched by `launch_local_model`, if any.
+#[derive(Default)]
+pub(crate) struct LocalModel(Mutex<Option<Arc<Server>>>);
+
+struct Server {
+    binary_path: String,
+    model_path: Option<String>,
+    port: u16,
+    /// Polled by the supervisor, so that it can be killed synchronously when the app exits.
+    child: Mutex<Option<tokio::process::Child>>,
+    stopped: AtomicBool,
+    restarts: AtomicU32,
+    /// Shared with the tasks that read the output, and kept across the restarts, so that the lines before a crash remain.
+    output: Arc<Mutex<VecDeque<String>>>,
+    /// Why the supervisor has given up on the server.
+    failure: Mutex<Option<String>>,
+}
+
+#[derive(serde::Serialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "camelCase")]
+enum LocalModelState {
+    Stopped,
+    /// Running, but not answering yet, e.g. while the model is loaded.
+    Starting,
+    Ready,
+    /// Exited too many times in a row.
+    Failed,
+}
+
+#[derive(serde::Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct LocalModelStatus {
+    state: LocalModelState,
+    port: Option<u16>,
+    pid: Option<u32>,
+    model_path: Option<String>,
+    restarts: u32,
+    error: Option<String>,
+    /// The last lines of the stdout and the stderr of the server.
+    output: Vec<String>,
+}
+
+impl Server {
+    fn spawn(&self) -> Result<(), Error> {
+        let mut command = tokio::process::Command::new(&self.binary_path);
+        // A llamafile runs the chat in the terminal and opens the browser unless it is told to serve only.
+        let is_llamafile = Path::new(&self.binary_path)
+            .file_name()
+            .map_or(false, |name| {
+                name.to_string_lossy().to_lowercase().contains("llamafile")
+            });
+        if is_llamafile {
+            command.args(["--server", "--nobrowser"]);
+        }
+        if let Some(model_path) = &self.model_path {
+            command.arg("-m").arg(model_path);
+        }
+        let mut child = command
+            .args(["--host", "127.0.0.1", "--port", &self.port.to_string()])
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()
+            .map_err(|err| {
+                Error::StringError(format!("Failed to run {}: {err}", self.binary_path))
+            })?;
+        if let Some(stdout) = child.stdout.take() {
+            tauri::async_runtime::spawn(capture(Arc::clone(&self.output), stdout));
+        }
+        if let Some(stderr) = child.stderr.take() {
+            tauri::async_runtime::spawn(capture(Arc::clone(&self.output), stderr));
+        }
+        *self.child.lock()? = Some(child);
+        Ok(())
+    }
+
+    fn stop(&self) -> Result<(), Error> {
+        self.stopped.store(true, Ordering::SeqCst);
+        if let Some(mut child) = self.child.lock()?.take() {
+            child.start_kill()?;
+        }
+        Ok(())
+    }
+
+    fn fail(&self, error: String) {
+        tracing::error!("the local model server has stopped: {error}");
+        if let Ok(mut failure) = self.failure.lock() {
+            *failure = Some(error);
+        }
+    }
+}
+
+/// Keeps the last lines of the output.
+async fn capture(output: Arc<Mutex<VecDeque<String>>>, stream: impl AsyncRead + Unpin) {
+    let mut lines = BufReader::new(stream).lines();
+    while let Ok(Some(line)) = lines.next_line().await {
+        tracing::debug!("llama.cpp: {line}");
+        if let Ok(mut output) = output.lock() {
+            if output.len() == OUTPUT_LINES {
+                output.pop_front();
+            }
+            output.push_back(line);
+        }
+    }
+}
+
+/// Restarts the server whenever it exits, until it is stopped or exits too many times in a row.
+async fn supervise(server: Arc<Server>) {
+    loop {
+        if let Err(err) = server.spawn() {
+            server.fail(err.to_string());
+            return;
+        }
+        let started = Instant::now();
+        let status = loop {
+            tokio::time::sleep(POLL_INTERVAL).await;
+            if server.stopped.load(Ordering::SeqCst) {
+                return;
+            }
+            let status = match server.child.lock() {
+                Ok(mut child) => match child.as_mut().map(|child| child.try_wait()) {
+                    Some(Ok(status)) => status,
+                    Some(Err(err)) => {
+                        server.fail(err.to_string());
+                        return;
+                    }
+                    None => return,
+                },
+                Err(err) => {
+                    server.fail(err.to_string());
+                    return;
+                }
+            };
+            if let Some(status) = status {
+                break status;
+            }
+        };
+        if started.elapsed() >= STABLE_AFTER {
+            server.restarts.store(0, Ordering::SeqCst);
+        }
+        let restarts = server.restarts.fetch_add(1, Ordering::SeqCst) + 1;
+        if restarts > MAX_RESTARTS {
+            server.fail(format!(
+                "The server exited {MAX_RESTARTS} times in a row ({status})"
+            ));
+            return;
+        }
+        tracing::warn!(
+            "the local model server exited ({status}), restarting ({restarts}/{MAX_RESTARTS})"
+        );
+        tokio::time::sleep(POLL_INTERVAL * restarts).await;
+        if server.stopped.load(Ordering::SeqCst) {
+            return;
+        }
+    }
+}
+
+/// Starts a llama.cpp server (`llama-server`) or a llamafile, replacing the one already launched. `model_path` is the GGUF
+/// file, and may be omitted for a llamafile that contains the model. The port defaults to `DEFAULT_PORT`.
+#[tauri::command]
+#[tracing::instrument(skip(state), err)]
+pub(crate) async fn launch_local_model(
+    binary_path: String,
+    model_path: Option<String>,
+    port: Option<u16>,
+    state: tauri::State<'_, AppState>,
+) -> Result<(), Error> {
+    if !Path::new(&binary_path).is_file() {
+        return Err(Error::StringError(format!("{binary_path} does not exist")));
+    }
+    let model_path = model_path.filter(|path| !path.trim().is_empty());
+    if let Some(model_path) = &model_path {
+        if !Path::new(model_path).is_file() {
+            return Err(Error::StringError(format!("{model_path} does not exist")));
+        }
+    }
+    let server = Arc::new(Server {
+        binary_path,
+        model_path,
+        port: port.unwrap_or(DEFAULT_PORT),
+        child: Mutex::new(None),
+        stopped: AtomicBool::new(false),
+        restarts: AtomicU32::new(0),
+        output: Arc::default(),
+        failure: Mutex::new(None),
+    });
+    if let Some(previous) = state.local_model.0.lock()?.replace(Arc::clone(&server)) {
+        previous.stop()?;
+    }
+    tauri::async_runtime::spawn(supervise(server));
+    Ok(())
+}
+
+#[tauri::command]
+#[tracing::instrument(skip(state), err)]
+pub(crate) fn stop_local_model(state: tauri::State<'_, AppState>) -> Result<(), Error> {
+    if let Some(server) = state.local_model.0.lock()?.take() {
+        server.stop()?;
+    }
+    Ok(())
+}
+
+/// Returns whether the launched server is running and answers its health check.
+#[tauri::command]
+#[tracing::instrument(skip(state), err)]
+pub(crate) async fn local_model_status(
+    state: tauri::State<'_, AppState>,
+) -> Result<LocalModelStatus, Error> {
+    let Some(server) = state.local_model.0.lock()?.clone() else {
+        return Ok(LocalModelStatus {
+            state: LocalModelState::Stopped,
+            port: None,
+            pid: None,
+            model_path: None,
+            restarts: 0,
+            error: None,
+            output: vec![],
+        });
+    };
+    let error = server.failure.lock()?.clone();
+    let pid = server.child.lock()?.as_ref().and_then(|child| child.id());
+    let state = if error.is_some() {
+        LocalModelState::Failed
+    } else if is_healthy(server.port).await {
+        LocalModelState::Ready
+    } else {
+        LocalModelState::Starting
+    };
+    Ok(LocalModelStatus {
+        state,
+        port: Some(server.port),
+        pid,
+        model_path: server.model_path.clone(),
+        restarts: server.restarts.load(Ordering::SeqCst),
+        error,
+        output: server.output.lock()?.iter().cloned().collect(),
+    })
+}
+
+/// llama.cpp answers `/health` with 503 while the model is loaded.
+async fn is_healthy(port: u16) -> bool {
+    let Ok(client) = reqwest::Client::builder().timeout(HEALTH_TIMEOUT).build() else {
+        return false;
+    };
+    client
+        .get(format!("http://127.0.0.1:{port}/health"))
+        .send()
+        .await
+        .map_or(false, |res| res.status() == 200)
+}
+
+/// Kills the server, since the child processes are not killed when the app exits.
+pub(crate) fn shutdown(app: &tauri::AppHandle) {
+    if let Some(server) = app
+        .state::<AppState>()
+        .local_model
+        .0
+        .lock()
+        .ok()
+        .and_then(|mut server| server.take())
+    {
+        if let Err(err) = server.stop() {
+            tracing::warn!("failed to stop the local model server: {err}");
+        }
+    }
+}
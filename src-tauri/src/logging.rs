@@ -0,0 +1,74 @@
+//! Structured logging with `tracing`, written to a file in the app's log directory that is rotated daily.
+//!
+//! The files are named `chatgpt.YYYY-MM-DD.log`, and only the last `MAX_LOG_FILES` of them are kept.
+
+use crate::Error;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use tracing_appender::non_blocking::WorkerGuard;
+use tracing_appender::rolling::{RollingFileAppender, Rotation};
+
+const LOG_FILE_PREFIX: &str = "chatgpt";
+const LOG_FILE_SUFFIX: &str = "log";
+const MAX_LOG_FILES: usize = 7;
+
+lazy_static::lazy_static! {
+    static ref LOG_DIR: Mutex<Option<PathBuf>> = Mutex::new(None);
+    /// Flushes the buffered logs when dropped, so it is kept until the process exits.
+    static ref WORKER_GUARD: Mutex<Option<WorkerGuard>> = Mutex::new(None);
+}
+
+pub(crate) fn init(log_dir: PathBuf) -> Result<(), Error> {
+    std::fs::create_dir_all(&log_dir)?;
+    let appender = RollingFileAppender::builder()
+        .rotation(Rotation::DAILY)
+        .filename_prefix(LOG_FILE_PREFIX)
+        .filename_suffix(LOG_FILE_SUFFIX)
+        .max_log_files(MAX_LOG_FILES)
+        .build(&log_dir)
+        .map_err(|err| Error::StringError(err.to_string()))?;
+    let (writer, guard) = tracing_appender::non_blocking(appender);
+    tracing_subscriber::fmt()
+        .with_writer(writer)
+        .with_ansi(false)
+        .with_max_level(tracing::Level::INFO)
+        .try_init()
+        .map_err(|err| Error::StringError(err.to_string()))?;
+    *WORKER_GUARD.lock()? = Some(guard);
+    *LOG_DIR.lock()? = Some(log_dir);
+    tracing::info!(version = env!("CARGO_PKG_VERSION"), "started");
+    Ok(())
+}
+
+/// Returns the last `lines` lines of the newest log file.
+#[tauri::command]
+pub(crate) fn get_recent_logs(lines: usize) -> Result<Vec<String>, Error> {
+    let Some(log_dir) = LOG_DIR.lock()?.clone() else {
+        return Ok(vec![]);
+    };
+    let mut newest: Option<PathBuf> = None;
+    for entry in std::fs::read_dir(log_dir)? {
+        let path = entry?.path();
+        let Some(name) = path.file_name().and_then(|n| n.to_str()) else {
+            continue;
+        };
+        // The date in the file names sorts chronologically.
+        if name.starts_with(&format!("{LOG_FILE_PREFIX}."))
+            && name.ends_with(&format!(".{LOG_FILE_SUFFIX}"))
+            && newest
+                .as_ref()
+                .map_or(true, |n| n.file_name() < path.file_name())
+        {
+            newest = Some(path);
+        }
+    }
+    let Some(newest) = newest else {
+        return Ok(vec![]);
+    };
+    let content = String::from_utf8_lossy(&std::fs::read(newest)?).into_owned();
+    let all = content.lines().collect::<Vec<_>>();
+    Ok(all[all.len().saturating_sub(lines)..]
+        .iter()
+        .map(|line| line.to_string())
+        .collect())
+}
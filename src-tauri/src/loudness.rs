@@ -0,0 +1,264 @@
+//! Loudness normalization of the synthesized speech, so that the voices and the cached clips play at the same volume.
+//!
+//! The integrated loudness is measured as in EBU R128 (ITU-R BS.1770 K-weighting, 400 ms blocks, absolute and relative
+//! gates), and the MP3 is brought to `TARGET_LUFS` without re-encoding, as mp3gain does: the global gain of every
+//! granule is changed in steps of 1.5 dB. The gain is lowered so that the peak stays below `PEAK_CEILING_DBFS`.
+//! The audio is normalized before it is stored in the TTS cache, so each clip is measured once.
+
+use crate::Error;
+
+const TARGET_LUFS: f64 = -16.0;
+const PEAK_CEILING_DBFS: f64 = -1.0;
+/// The gain of one step of the global gain of a granule.
+const GAIN_STEP_DB: f64 = 1.5;
+/// Limits the amplification of nearly silent clips, which would only amplify the noise.
+const MAX_GAIN_DB: f64 = 20.0;
+const ABSOLUTE_GATE_LUFS: f64 = -70.0;
+const RELATIVE_GATE_LU: f64 = -10.0;
+
+/// A second-order IIR filter in the transposed direct form II.
+struct Biquad {
+    b: [f64; 3],
+    a: [f64; 2],
+    z: [f64; 2],
+}
+
+impl Biquad {
+    fn process(&mut self, x: f64) -> f64 {
+        let y = self.b[0] * x + self.z[0];
+        self.z[0] = self.b[1] * x - self.a[0] * y + self.z[1];
+        self.z[1] = self.b[2] * x - self.a[1] * y;
+        y
+    }
+}
+
+/// The K-weighting filter of BS.1770 for the sample rate: a high shelf followed by a high-pass filter.
+fn k_weighting(sample_rate: f64) -> [Biquad; 2] {
+    let shelf = {
+        let (f0, gain_db, q) = (1681.974450955533, 3.999843853973347, 0.7071752369554196);
+        let k = (std::f64::consts::PI * f0 / sample_rate).tan();
+        let vh = 10f64.powf(gain_db / 20.0);
+        let vb = vh.powf(0.4996667741545416);
+        let a0 = 1.0 + k / q + k * k;
+        Biquad {
+            b: [
+                (vh + vb * k / q + k * k) / a0,
+                2.0 * (k * k - vh) / a0,
+                (vh - vb * k / q + k * k) / a0,
+            ],
+            a: [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+            z: [0.0; 2],
+        }
+    };
+    let high_pass = {
+        let (f0, q) = (38.13547087602444, 0.5003270373238773);
+        let k = (std::f64::consts::PI * f0 / sample_rate).tan();
+        let a0 = 1.0 + k / q + k * k;
+        Biquad {
+            b: [1.0, -2.0, 1.0],
+            a: [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+            z: [0.0; 2],
+        }
+    };
+    [shelf, high_pass]
+}
+
+fn lufs(mean_square: f64) -> f64 {
+    -0.691 + 10.0 * mean_square.log10()
+}
+
+/// The integrated loudness of the interleaved samples in LUFS, or None if the audio is silent.
+fn integrated_loudness(samples: &[f32], channels: usize, sample_rate: u32) -> Option<f64> {
+    let mut filters = (0..channels)
+        .map(|_| k_weighting(sample_rate as f64))
+        .collect::<Vec<_>>();
+    // The sum of the squares of the K-weighted channels of each frame.
+    let energies = samples
+        .chunks_exact(channels)
+        .map(|frame| {
+            frame
+                .iter()
+                .zip(&mut filters)
+                .map(|(&x, [shelf, high_pass])| {
+                    let y = high_pass.process(shelf.process(x as f64));
+                    y * y
+                })
+                .sum::<f64>()
+        })
+        .collect::<Vec<_>>();
+    if energies.is_empty() {
+        return None;
+    }
+
+    // 400 ms blocks that overlap by 75%, or a single block for shorter clips.
+    let block_len = (sample_rate as usize * 4 / 10).clamp(1, energies.len());
+    let step = (block_len / 4).max(1);
+    let blocks = (0..=energies.len() - block_len)
+        .step_by(step)
+        .map(|start| energies[start..start + block_len].iter().sum::<f64>() / block_len as f64)
+        .filter(|&power| power > 0.0 && lufs(power) > ABSOLUTE_GATE_LUFS)
+        .collect::<Vec<_>>();
+    if blocks.is_empty() {
+        return None;
+    }
+    let mean = |blocks: &[f64]| blocks.iter().sum::<f64>() / blocks.len() as f64;
+    let relative_gate = lufs(mean(&blocks)) + RELATIVE_GATE_LU;
+    let gated = blocks
+        .into_iter()
+        .filter(|&power| lufs(power) > relative_gate)
+        .collect::<Vec<_>>();
+    Some(lufs(mean(&gated)))
+}
+
+/// The number of gain steps that brings the MP3 to the target loudness.
+fn gain_steps(data: &[u8]) -> Result<i32, Error> {
+    use rodio::Source;
+    let decoder = rodio::Decoder::new(std::io::Cursor::new(data.to_vec()))?;
+    let channels = decoder.channels() as usize;
+    let sample_rate = decoder.sample_rate();
+    let samples = decoder.map(|x| x as f32 / 32768.0).collect::<Vec<f32>>();
+    let Some(loudness) = integrated_loudness(&samples, channels.max(1), sample_rate) else {
+        return Ok(0);
+    };
+    let peak = samples.iter().fold(0f32, |peak, x| peak.max(x.abs())) as f64;
+    let peak_gain = PEAK_CEILING_DBFS - 20.0 * peak.log10();
+    let gain = (TARGET_LUFS - loudness).min(MAX_GAIN_DB).min(peak_gain);
+    let mut steps = (gain / GAIN_STEP_DB).round() as i32;
+    if steps as f64 * GAIN_STEP_DB > peak_gain {
+        steps -= 1;
+    }
+    Ok(steps)
+}
+
+struct FrameHeader {
+    len: usize,
+    mpeg1: bool,
+    mono: bool,
+    crc: bool,
+}
+
+/// Parses the header of an MPEG audio Layer III frame.
+fn parse_header(b: &[u8]) -> Option<FrameHeader> {
+    const MPEG1_BITRATES: [u32; 15] = [
+        0, 32, 40, 48, 56, 64, 80, 96, 112, 128, 160, 192, 224, 256, 320,
+    ];
+    const MPEG2_BITRATES: [u32; 15] =
+        [0, 8, 16, 24, 32, 40, 48, 56, 64, 80, 96, 112, 128, 144, 160];
+    const SAMPLE_RATES: [u32; 3] = [44100, 48000, 32000];
+
+    if b.len() < 4 || b[0] != 0xff || b[1] & 0xe0 != 0xe0 || (b[1] >> 1) & 3 != 1 {
+        return None;
+    }
+    let (mpeg1, sample_rate_divisor) = match (b[1] >> 3) & 3 {
+        3 => (true, 1),
+        2 => (false, 2),
+        0 => (false, 4), // MPEG 2.5
+        _ => return None,
+    };
+    let bitrates = if mpeg1 {
+        &MPEG1_BITRATES
+    } else {
+        &MPEG2_BITRATES
+    };
+    let bitrate = *bitrates.get((b[2] >> 4) as usize)?;
+    let sample_rate = SAMPLE_RATES.get(((b[2] >> 2) & 3) as usize)? / sample_rate_divisor;
+    if bitrate == 0 {
+        return None; // free format
+    }
+    let padding = ((b[2] >> 1) & 1) as u32;
+    // The samples per frame divided by 8 bits.
+    let coefficient = if mpeg1 { 144 } else { 72 };
+    Some(FrameHeader {
+        len: (coefficient * bitrate * 1000 / sample_rate + padding) as usize,
+        mpeg1,
+        mono: b[3] >> 6 == 3,
+        crc: b[1] & 1 == 0,
+    })
+}
+
+/// The bit offsets of the global gains in the side information of a frame.
+fn global_gain_offsets(header: &FrameHeader) -> Vec<usize> {
+    let channels = if header.mono { 1 } else { 2 };
+    // main_data_begin, private_bits, and scfsi; then part2_3_length and big_values before each global_gain.
+    let (prefix, granules, granule_bits) = if header.mpeg1 {
+        (9 + if header.mono { 5 } else { 3 } + 4 * channels, 2, 59)
+    } else {
+        (8 + channels, 1, 63)
+    };
+    (0..granules * channels)
+        .map(|i| prefix + i * granule_bits + 21)
+        .collect()
+}
+
+fn read_byte_at_bit(data: &[u8], bit: usize) -> u8 {
+    let (byte, shift) = (bit / 8, bit % 8);
+    let word = u16::from_be_bytes([data[byte], data[byte + 1]]);
+    (word >> (8 - shift)) as u8
+}
+
+fn write_byte_at_bit(data: &mut [u8], bit: usize, value: u8) {
+    let (byte, shift) = (bit / 8, bit % 8);
+    let mask = 0xff00u16 >> shift;
+    let word = u16::from_be_bytes([data[byte], data[byte + 1]]);
+    let [high, low] = ((word & !mask) | ((value as u16) << (8 - shift))).to_be_bytes();
+    data[byte] = high;
+    data[byte + 1] = low;
+}
+
+/// Changes the global gain of every granule of the MP3 by `steps`.
+fn apply_gain_steps(data: &mut [u8], steps: i32) -> Result<(), Error> {
+    let mut pos = 0;
+    // An ID3v2 tag: "ID3", the version, the flags, and the synchsafe size, followed by an optional footer.
+    if data.starts_with(b"ID3") && data.len() >= 10 {
+        let size = data[6..10]
+            .iter()
+            .fold(0usize, |size, &b| size << 7 | (b & 0x7f) as usize);
+        pos = 10 + size + if data[5] & 0x10 != 0 { 10 } else { 0 };
+    }
+    while pos + 4 <= data.len() {
+        let Some(header) = parse_header(&data[pos..]).filter(|h| pos + h.len <= data.len()) else {
+            pos += 1; // skips the garbage between the frames, e.g. an ID3v1 tag
+            continue;
+        };
+        if header.crc {
+            // The CRC covers the side information, which would have to be recomputed.
+            return Err(Error::StringError(
+                "MP3 frames with CRC are not supported".to_owned(),
+            ));
+        }
+        let side_info = (pos + 4) * 8;
+        for offset in global_gain_offsets(&header) {
+            let gain = read_byte_at_bit(data, side_info + offset) as i32;
+            write_byte_at_bit(data, side_info + offset, (gain + steps).clamp(0, 255) as u8);
+        }
+        pos += header.len;
+    }
+    Ok(())
+}
+
+/// Returns the MP3 normalized to the target loudness. The audio is returned as is if it cannot be normalized, since
+/// the speech should be played anyway.
+pub(crate) fn normalize_mp3(data: Vec<u8>) -> Vec<u8> {
+    let steps = match gain_steps(&data) {
+        Ok(0) => return data,
+        Ok(steps) => steps,
+        Err(err) => {
+            tracing::warn!("failed to measure the loudness of the speech: {err}");
+            return data;
+        }
+    };
+    let mut normalized = data.clone();
+    match apply_gain_steps(&mut normalized, steps) {
+        Ok(()) => {
+            tracing::debug!(
+                gain_db = steps as f64 * GAIN_STEP_DB,
+                "normalized the speech"
+            );
+            normalized
+        }
+        Err(err) => {
+            tracing::warn!("failed to normalize the speech: {err}");
+            data
+        }
+    }
+}
@@ -3,23 +3,103 @@
     windows_subsystem = "windows"
 )]
 
+mod aad_token;
+mod app_state;
+mod attachment;
+mod audio_engine;
+mod audio_log;
+mod azure_openai;
+mod azure_stt;
+mod azure_tts;
+mod chat_window;
+mod chunk_buffer;
+mod clipboard;
+mod code_interpreter;
+mod compare;
+mod completion_request;
+mod context_window;
+mod conversation;
+mod conversation_settings;
+mod deepgram;
+mod diarization;
+mod dictation_commands;
+mod earcon;
+mod encryption;
+mod export;
+mod file_transcription;
+mod import;
+mod inference_capabilities;
+mod knowledge_base;
+mod lexicon;
+mod local_model;
+mod logging;
+mod loudness;
+mod mcp;
+mod media_keys;
+mod mic_processing;
+mod migration;
+mod mini_player;
+mod mixer;
+mod model_download;
+mod models;
+mod offline_queue;
+mod openrouter;
+mod organize;
+mod output_limits;
+mod pinned;
+mod profile;
+mod prompt_template;
+mod provider_presets;
+mod quick_ask;
+mod rate_limit;
+mod realtime;
+mod recording;
+mod scheduler;
+mod screenshot;
+mod secrets;
+mod semantic_search;
+mod settings;
+mod sse;
+mod ssml;
+mod structured_output;
+mod summary;
+mod sync;
+mod tools;
+mod tray;
+mod tts_cache;
+mod updater;
+mod usage;
+mod variations;
+mod verbalizer;
+mod vision;
+mod vocabulary;
+mod wake_word;
+mod web_page;
+mod web_search;
+mod whisper;
+mod word_boundary;
+
+use app_state::{AppState, AudioState, DbPool};
+use audio_engine::{Navigation, PlaybackStatus, SpeechQueue};
+use completion_request::CompletionRequest;
+use earcon::EarconEvent;
+use mic_processing::{EchoSuppressor, MicProcessor, SilenceTrimming};
+use mixer::BargeIn;
+use offline_queue::QueuedRequest;
+use recording::{InputLoudness, LastRecording, LoudnessMeter, RecordingFormat, RecordingSink};
 use serde_json::Value;
-use sqlx::{Connection, Row};
+use sqlx::{Connection, Executor, Row};
 use std::collections::{HashMap, HashSet};
 use std::io::Read;
-use std::path::PathBuf;
 use std::process::Command;
-use std::sync::atomic::{AtomicI64, AtomicU32, Ordering};
+use std::sync::atomic::{AtomicU32, Ordering};
 use std::sync::mpsc::TryRecvError;
 use std::sync::{Arc, Mutex};
 use std::time::Duration;
 use tauri::api::cli::ArgData;
-use tauri::api::http::{Body, ClientBuilder, FormBody, FormPart, HttpRequestBuilder, ResponseType};
+use tauri::api::http::{Body, ClientBuilder, HttpRequestBuilder, ResponseType};
+use tauri::Manager;
 use tempfile::NamedTempFile;
-use tiktoken_rs::ChatCompletionRequestMessage;
-
-static mut DB_PATH: Option<PathBuf> = None;
-static AUDIO_PLAYBACK_COUNTER: AtomicI64 = AtomicI64::new(0);
 
 #[derive(Debug, thiserror::Error)]
 enum Error {
@@ -32,6 +112,10 @@ enum Error {
     #[error(transparent)]
     TauriAPIError(#[from] tauri::api::Error),
     #[error(transparent)]
+    TauriError(#[from] tauri::Error),
+    #[error(transparent)]
+    UpdaterError(#[from] tauri::updater::Error),
+    #[error(transparent)]
     MPSCSendError(#[from] std::sync::mpsc::SendError<()>),
     #[error(transparent)]
     Utf8Error(#[from] std::str::Utf8Error),
@@ -51,12 +135,22 @@ enum Error {
     CpalPlayStreamError(#[from] cpal::PlayStreamError),
     #[error(transparent)]
     HoundError(#[from] hound::Error),
+    #[error(transparent)]
+    JSONError(#[from] serde_json::Error),
+    #[error(transparent)]
+    KeyringError(#[from] keyring::Error),
+    #[error(transparent)]
+    ZipError(#[from] zip::result::ZipError),
+    #[error(transparent)]
+    WebSocketError(#[from] tokio_tungstenite::tungstenite::Error),
     #[error("{0}")]
     SyncPoisonError(String),
     #[error("{0}")]
     StringError(String),
     #[error("{0}")]
     StatusIsNot200(String),
+    #[error("The stream stalled: no data was received for {0} seconds")]
+    StreamStalled(u64),
 }
 
 impl<T> From<std::sync::PoisonError<T>> for Error {
@@ -74,15 +168,28 @@ impl serde::Serialize for Error {
     }
 }
 
-async fn connect_db() -> Result<sqlx::SqliteConnection, Error> {
-    Ok(sqlx::SqliteConnection::connect(&format!(
-        "sqlite://{}?mode=rwc", // rwc = create file if not exists
-        unsafe { DB_PATH.clone() }
-            .ok_or_else(|| Error::StringError("DB_PATH is not initialized yet".to_string()))?
-            .to_str()
-            .ok_or_else(|| Error::StringError("to_str() on DB_PATH failed".to_string()))?
-    ))
-    .await?)
+/// The schema is shared with the frontend, which executes the same file through tauri-plugin-sql.
+const CREATE_TABLES_SQL: &str = include_str!("../../create_tables.sql");
+
+async fn create_tables(db_pool: &DbPool) -> Result<(), Error> {
+    let mut conn = db_pool.acquire().await?;
+    conn.execute(CREATE_TABLES_SQL).await?;
+    migration::migrate(&mut conn).await?;
+    secrets::migrate(&mut conn).await
+}
+
+/// Reads a value of the config table, where the frontend stores its preferences.
+async fn read_config(
+    conn: &mut sqlx::SqliteConnection,
+    key: &str,
+) -> Result<Option<String>, Error> {
+    Ok(
+        sqlx::query("SELECT CAST(value AS TEXT) AS value FROM config WHERE key = ?")
+            .bind(key)
+            .fetch_optional(conn)
+            .await?
+            .and_then(|row| row.get("value")),
+    )
 }
 
 fn main() {
@@ -93,7 +200,16 @@ fn main() {
     }
     builder
         .plugin(tauri_plugin_sql::Builder::default().build())
+        .system_tray(tray::system_tray())
+        .on_system_tray_event(tray::handle_system_tray_event)
+        .on_window_event(|event| {
+            attachment::handle_file_drop(&event);
+            tray::handle_window_event(event);
+        })
         .setup(|context| {
+            if let Some(dir) = context.path_resolver().app_log_dir() {
+                logging::init(dir)?;
+            }
             match context.get_cli_matches() {
                 Ok(matches) => {
                     if let Some(ArgData {
@@ -107,96 +223,394 @@ fn main() {
                 }
                 Err(_) => {}
             }
-            let db_path = Some(
-                tauri::api::path::resolve_path(
-                    &context.config(),
-                    context.package_info(),
-                    &tauri::Env::default(),
-                    "chatgpt_tauri.db",
-                    Some(tauri::api::path::BaseDirectory::AppConfig),
-                )
-                .unwrap(),
-            );
-            unsafe {
-                DB_PATH = db_path;
+            let config_dir = context.path_resolver().app_config_dir().ok_or_else(|| {
+                Error::StringError("The app config directory is unknown".to_owned())
+            })?;
+            std::fs::create_dir_all(&config_dir)?;
+            let db_path = profile::init(&config_dir)?;
+            let encrypted = encryption::is_encrypted(&db_path)?;
+            context.manage(AppState::new(db_path));
+            if let Err(err) =
+                tauri::async_runtime::block_on(aad_token::restore(&context.state::<AppState>()))
+            {
+                tracing::warn!("failed to restore the Azure AD credential: {err}");
+            }
+            if !encrypted {
+                let state = context.state::<AppState>();
+                tauri::async_runtime::block_on(create_tables(&state.db_pool))?; // otherwise deferred to unlock_database()
+                mcp::connect_in_background(&context.handle());
+            }
+            if let Err(err) = quick_ask::register_shortcut(&context.handle()) {
+                tracing::warn!("failed to register the quick ask shortcut, which may be taken by another app: {err}");
             }
+            offline_queue::watch(context.handle());
+            scheduler::start(context.handle());
+            sync::start(context.handle());
+            if let Err(err) = screenshot::register_shortcut(&context.handle()) {
+                tracing::warn!("failed to register the screenshot shortcut, which may be taken by another app: {err}");
+            }
+            mini_player::forward_playback_events(&context.handle())?;
+            media_keys::start(&context.handle())?;
             Ok(())
         })
         .invoke_handler(tauri::generate_handler![
             sound_test,
+            logging::get_recent_logs,
             sound_focus_input,
             sound_waiting_text_completion,
+            earcon::set_earcon,
+            earcon::list_earcons,
+            earcon::list_builtin_earcons,
             speak_azure,
+            azure_tts::list_azure_voices,
+            azure_openai::list_azure_deployments,
+            azure_tts::preview_azure_voice,
+            azure_tts::speak_azure_text,
+            ssml::build_ssml,
+            speech_text::preprocess_speech_text,
+            speech_text::add_speech_rule,
+            speech_text::list_speech_rules,
+            speech_text::delete_speech_rule,
+            azure_tts::speak_azure_sentences,
+            word_boundary::speak_azure_with_word_boundaries,
+            lexicon::add_lexicon_entry,
+            lexicon::list_lexicon_entries,
+            lexicon::delete_lexicon_entry,
+            vocabulary::add_vocabulary_term,
+            vocabulary::list_vocabulary_terms,
+            vocabulary::delete_vocabulary_term,
             count_tokens,
+            is_token_count_estimate,
+            context_window::build_request_messages,
+            summary::summarize_conversation,
+            prompt_template::save_prompt_template,
+            prompt_template::list_prompt_templates,
+            prompt_template::delete_prompt_template,
+            prompt_template::render_prompt_template,
             speak_pico2wave,
             get_input_loudness,
             start_listening,
+            start_listening_azure,
+            deepgram::start_listening_deepgram,
+            wake_word::enable_wake_word,
+            wake_word::disable_wake_word,
             stop_listening,
             cancel_listening,
+            conversation_settings::get_conversation_settings,
+            conversation_settings::set_conversation_settings,
+            dictation_commands::parse_dictation_commands,
+            realtime::start_realtime_session,
+            realtime::end_realtime_session,
             start_chat_completion,
+            aad_token::set_azure_ad_credential,
+            aad_token::get_azure_ad_credential,
             stop_all_chat_completions,
+            compare::start_chat_completion_multi,
+            variations::regenerate_with_variations,
+            chat_window::open_chat_window,
+            attachment::extract_file_text,
+            web_page::fetch_url_content,
+            knowledge_base::index_documents,
+            knowledge_base::retrieve,
+            knowledge_base::list_indexed_documents,
+            knowledge_base::remove_indexed_document,
+            vision::prepare_image_attachment,
+            screenshot::capture_screenshot,
+            file_transcription::transcribe_file,
+            tools::list_builtin_tools,
+            tools::answer_tool_approval,
+            rate_limit::set_rate_limits,
+            mcp::add_mcp_server,
+            mcp::list_mcp_servers,
+            mcp::remove_mcp_server,
+            mcp::read_mcp_resource,
+            clipboard::set_clipboard_watch,
+            clipboard::ask_about_clipboard,
             get_chat_completion,
+            get_chat_tool_calls,
+            usage::get_usage_stats,
             stop_audio,
+            pause_audio,
+            resume_audio,
+            seek_audio,
+            audio_log::set_audio_output_to_file,
+            audio_log::list_audio_log,
+            audio_log::play_audio_log,
+            skip_sentence_forward,
+            skip_sentence_back,
+            mini_player::open_mini_player,
+            mini_player::set_playback_speed,
+            mixer::set_beep_ducking,
+            mixer::set_barge_in,
+            list_ollama_models,
+            models::list_models,
+            models::get_context_window,
+            openrouter::get_openrouter_credits,
+            provider_presets::list_provider_presets,
+            secrets::store_secret,
+            secrets::get_secret,
+            secrets::delete_secret,
+            conversation::create_conversation,
+            conversation::append_message,
+            conversation::list_conversations,
+            conversation::list_by_tag,
+            conversation::delete_conversation,
+            conversation::search_messages,
+            conversation::branch_from_message,
+            conversation::list_branches,
+            conversation::switch_branch,
+            pinned::pin_message,
+            pinned::unpin_message,
+            pinned::list_pinned,
+            organize::create_folder,
+            organize::rename_folder,
+            organize::delete_folder,
+            organize::list_folders,
+            organize::move_to_folder,
+            organize::tag_conversation,
+            organize::untag_conversation,
+            organize::list_tags,
+            scheduler::schedule_prompt,
+            scheduler::list_scheduled_prompts,
+            scheduler::delete_scheduled_prompt,
+            semantic_search::semantic_search_messages,
+            tts_cache::get_tts_cache_stats,
+            tts_cache::clear_tts_cache,
+            export::export_conversation,
+            export::export_message_audio,
+            import::import_openai_export,
+            encryption::is_database_locked,
+            encryption::unlock_database,
+            encryption::set_database_passphrase,
+            encryption::get_database_passphrase,
+            tray::set_minimize_to_tray,
+            recording::save_last_recording,
+            mic_processing::set_mic_processing,
+            quick_ask::quick_ask,
+            updater::check_for_updates,
+            updater::install_update,
+            local_model::launch_local_model,
+            local_model::stop_local_model,
+            local_model::local_model_status,
+            inference_capabilities::probe_inference_capabilities,
+            model_download::start_model_download,
+            model_download::cancel_model_download,
+            model_download::list_downloaded_models,
+            sync::configure_sync,
+            sync::sync_now,
+            sync::get_sync_status,
+            sync::verify_sync_passphrase,
+            profile::list_profiles,
+            profile::create_profile,
+            profile::switch_profile,
+            settings::get_setting,
+            settings::set_setting,
         ])
-        .run(tauri::generate_context!())
-        .expect("error while running tauri application");
+        .build(tauri::generate_context!())
+        .expect("error while running tauri application")
+        .run(|app, event| {
+            if let tauri::RunEvent::Exit = event {
+                local_model::shutdown(app);
+            }
+        });
 }
 
 #[tauri::command]
-async fn sound_test() -> Result<(), Error> {
-    tokio::task::spawn_blocking(|| -> Result<(), Error> {
-        let (_stream, stream_handle) = rodio::OutputStream::try_default()?;
-        let sink = rodio::Sink::try_new(&stream_handle)?;
-        sink.set_volume(0.5);
-        sink.append(rodio::source::SineWave::new(256.0));
-        std::thread::sleep(std::time::Duration::from_secs(1));
-        Ok(())
-    })
-    .await??;
-    Ok(())
+#[tracing::instrument(skip(state), err)]
+async fn sound_test(state: tauri::State<'_, AppState>) -> Result<(), Error> {
+    earcon::play(&state, EarconEvent::Test).await
 }
 
 #[tauri::command]
-async fn sound_focus_input() -> Result<(), Error> {
-    tokio::task::spawn_blocking(|| -> Result<(), Error> {
-        let (_stream, stream_handle) = rodio::OutputStream::try_default()?;
-        let sink = rodio::Sink::try_new(&stream_handle)?;
-        sink.set_volume(0.5);
-        sink.append(rodio::source::SineWave::new(880.0));
-        std::thread::sleep(std::time::Duration::from_millis(100));
-        Ok(())
-    })
-    .await??;
-    Ok(())
+#[tracing::instrument(skip(state), err)]
+async fn sound_focus_input(state: tauri::State<'_, AppState>) -> Result<(), Error> {
+    earcon::play(&state, EarconEvent::FocusInput).await
 }
 
 #[tauri::command]
-async fn sound_waiting_text_completion() -> Result<(), Error> {
-    tokio::task::spawn_blocking(|| -> Result<(), Error> {
-        let (_stream, stream_handle) = rodio::OutputStream::try_default()?;
-        let sink = rodio::Sink::try_new(&stream_handle)?;
-        sink.set_volume(0.5);
-        sink.append(rodio::source::SineWave::new(440.0)); // A
-        std::thread::sleep(std::time::Duration::from_millis(200));
+#[tracing::instrument(skip(state), err)]
+async fn sound_waiting_text_completion(state: tauri::State<'_, AppState>) -> Result<(), Error> {
+    earcon::play(&state, EarconEvent::WaitingTextCompletion).await
+}
+
+/// The interval of the `audio://playback` events during speech.
+const PLAYBACK_PROGRESS_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Plays the audio, interrupting the audio being played. `title` is the name of the conversation, which is shown in the
+/// mini player and the media overlay of the OS.
+async fn play_audio(
+    audio: Arc<AudioState>,
+    data: Vec<u8>,
+    precedence: i64,
+    title: Option<String>,
+) -> Result<(), Error> {
+    if data.is_empty() {
+        return Ok(()); // fixes UnrecognizedFormat error
+    }
+    tokio::task::spawn_blocking(move || -> Result<(), Error> {
+        // MP3 does not tell its duration without decoding it, and the samples are kept for seeking.
+        let (channels, sample_rate, samples) = {
+            use rodio::Source;
+            let decoder = rodio::Decoder::new(std::io::Cursor::new(data))?;
+            let (channels, sample_rate) = (decoder.channels(), decoder.sample_rate());
+            let samples = decoder
+                .map(dasp_sample::conv::i16::to_f32)
+                .collect::<Vec<f32>>();
+            (channels, sample_rate, samples)
+        };
+        let duration = audio_engine::samples_duration(samples.len(), channels, sample_rate);
+        let play_from = |sink: &audio_engine::EngineSink, position: Duration| {
+            let samples = audio_engine::samples_after(&samples, channels, sample_rate, position);
+            sink.append(
+                audio
+                    .mixer
+                    .speech_meter()
+                    .meter(rodio::buffer::SamplesBuffer::new(
+                        channels,
+                        sample_rate,
+                        samples,
+                    )),
+            );
+        };
+        let sink = audio.engine.sink()?;
+        let _speaking = audio.mixer.start_speech();
+        let mut speed = audio.engine.speed();
+        sink.set_speed(speed);
+        let mut volume = audio.mixer.speech_volume();
+        sink.set_volume(volume);
+        audio.engine.take_navigation();
+        play_from(&sink, Duration::ZERO);
+        let interval = Duration::from_millis(50);
+        let mut position = Duration::ZERO;
+        let mut ticks = 0;
+        let status = |position: Duration, speed: f32, playing: bool| PlaybackStatus {
+            playing,
+            paused: audio.engine.is_paused(),
+            position_ms: position.min(duration).as_millis() as u64,
+            duration_ms: duration.as_millis() as u64,
+            speed,
+            title: title.clone(),
+            sentence: None,
+        };
+        sink.sleep_until_end_while(interval, || {
+            if audio.engine.speed() != speed {
+                speed = audio.engine.speed();
+                sink.set_speed(speed);
+            }
+            if audio.mixer.speech_volume() != volume {
+                volume = audio.mixer.speech_volume();
+                sink.set_volume(volume);
+            }
+            // The audio is one sentence, so skipping goes to its start or its end.
+            let target = match audio.engine.take_navigation() {
+                Some(Navigation::Seek(target)) => Some(target.min(duration)),
+                Some(Navigation::SkipSentence { forward }) => {
+                    Some(if forward { duration } else { Duration::ZERO })
+                }
+                None => None,
+            };
+            if let Some(target) = target {
+                sink.clear();
+                play_from(&sink, target);
+                position = target;
+                ticks = 0; // reports the new position at once
+            }
+            if ticks % (PLAYBACK_PROGRESS_INTERVAL.as_millis() / interval.as_millis()) == 0 {
+                audio.engine.notify(&status(position, speed, true));
+            }
+            ticks += 1;
+            if !audio.engine.is_paused() {
+                position += interval.mul_f32(speed);
+            }
+            precedence == audio.playback_counter.load(Ordering::SeqCst)
+        });
+        audio.engine.notify(&status(position, speed, false));
         Ok(())
     })
     .await??;
     Ok(())
 }
 
-async fn play_audio(data: Vec<u8>, precedence: i64) -> Result<(), Error> {
-    if data.is_empty() {
-        return Ok(()); // fixes UnrecognizedFormat error
-    }
+/// Plays the clips received from `clips` as one speech through a `SpeechQueue`, until `clips` is closed and everything
+/// has been played. A clip is taken from `clips` only when the previous one has started playing, so that the channel
+/// bounds how far ahead the clips are synthesized. Each clip comes with the sentence spoken in it, and `title` is the name
+/// of the conversation.
+async fn play_speech_queue(
+    audio: Arc<AudioState>,
+    mut clips: tokio::sync::mpsc::Receiver<(String, Vec<u8>)>,
+    precedence: i64,
+    title: Option<String>,
+) -> Result<(), Error> {
+    use tokio::sync::mpsc::error::TryRecvError;
     tokio::task::spawn_blocking(move || -> Result<(), Error> {
-        let (_stream, stream_handle) = rodio::OutputStream::try_default()?;
-        // sink.set_volume(0.5);
-        let source = rodio::Decoder::new(std::io::Cursor::new(data))?;
-        let sink = rodio::Sink::try_new(&stream_handle)?;
-        sink.append(source);
-        while !sink.empty() && precedence == AUDIO_PLAYBACK_COUNTER.load(Ordering::SeqCst) {
-            std::thread::sleep(std::time::Duration::from_millis(50));
+        let mut queue = SpeechQueue::new(audio.engine.sink()?, audio.mixer.speech_meter());
+        let _speaking = audio.mixer.start_speech();
+        let mut speed = audio.engine.speed();
+        queue.sink().set_speed(speed);
+        let mut volume = audio.mixer.speech_volume();
+        queue.sink().set_volume(volume);
+        let interval = Duration::from_millis(50);
+        let mut position = Duration::ZERO;
+        let mut ticks = 0;
+        let mut received_all = false;
+        audio.engine.take_navigation();
+        let status =
+            |queue: &SpeechQueue, position: Duration, speed: f32, playing: bool| PlaybackStatus {
+                playing,
+                paused: audio.engine.is_paused(),
+                position_ms: position.min(queue.duration()).as_millis() as u64,
+                duration_ms: queue.duration().as_millis() as u64,
+                speed,
+                title: title.clone(),
+                sentence: queue.sentence_at(position).map(str::to_owned),
+            };
+        while precedence == audio.playback_counter.load(Ordering::SeqCst) {
+            while !received_all && queue.sink().queued() <= 1 {
+                match clips.try_recv() {
+                    Ok((sentence, data)) => {
+                        if let Err(err) = queue.append(data, sentence) {
+                            tracing::warn!("failed to decode a clip of the speech: {err}");
+                        }
+                    }
+                    Err(TryRecvError::Empty) => break,
+                    Err(TryRecvError::Disconnected) => {
+                        queue.finish();
+                        received_all = true;
+                    }
+                }
+            }
+            if received_all && queue.sink().empty() {
+                break;
+            }
+            if audio.engine.speed() != speed {
+                speed = audio.engine.speed();
+                queue.sink().set_speed(speed);
+            }
+            if audio.mixer.speech_volume() != volume {
+                volume = audio.mixer.speech_volume();
+                queue.sink().set_volume(volume);
+            }
+            let target = match audio.engine.take_navigation() {
+                Some(Navigation::Seek(target)) => Some(target),
+                Some(Navigation::SkipSentence { forward }) => {
+                    Some(queue.sentence_start(position, forward))
+                }
+                None => None,
+            };
+            if let Some(target) = target {
+                position = queue.seek(target);
+                ticks = 0; // reports the new position at once
+            }
+            if ticks % (PLAYBACK_PROGRESS_INTERVAL.as_millis() / interval.as_millis()) == 0 {
+                audio.engine.notify(&status(&queue, position, speed, true));
+            }
+            ticks += 1;
+            std::thread::sleep(interval);
+            if !audio.engine.is_paused() && !queue.sink().empty() {
+                position += interval.mul_f32(speed);
+            }
         }
+        audio.engine.notify(&status(&queue, position, speed, false));
         Ok(())
     })
     .await??;
@@ -204,6 +618,7 @@ async fn play_audio(data: Vec<u8>, precedence: i64) -> Result<(), Error> {
 }
 
 async fn azure_text_to_speech_request(
+    db_pool: &DbPool,
     message_id: Option<i64>,
     region: String,
     resource_key: String,
@@ -234,32 +649,39 @@ async fn azure_text_to_speech_request(
         return Err(Error::StatusIsNot200(status.to_string()));
     }
     let data = response.bytes().await?.data;
+    let data = tokio::task::spawn_blocking(move || loudness::normalize_mp3(data)).await?;
 
-    let mut conn = connect_db().await?;
+    let mut conn = db_pool.acquire().await?;
 
     if !no_cache {
         if let Some(message_id) = message_id {
-            sqlx::query(
-                "INSERT OR REPLACE INTO messageTTSCache (messageId, ssml, audio) VALUES (?, ?, ?)",
-            )
+            sqlx::query(&format!(
+                "INSERT OR REPLACE INTO messageTTSCache (messageId, ssml, audio, lastAccessedAt) VALUES (?, ?, ?, {})",
+                tts_cache::NOW
+            ))
             .bind(message_id)
             .bind(ssml)
             .bind(data.clone())
             .execute(&mut conn)
             .await?;
         } else {
-            sqlx::query("INSERT OR REPLACE INTO systemTTSCache (ssml, audio) VALUES (?, ?)")
-                .bind(ssml)
-                .bind(data.clone())
-                .execute(&mut conn)
-                .await?;
+            sqlx::query(&format!(
+                "INSERT OR REPLACE INTO systemTTSCache (ssml, audio, lastAccessedAt) VALUES (?, ?, {})",
+                tts_cache::NOW
+            ))
+            .bind(ssml)
+            .bind(data.clone())
+            .execute(&mut conn)
+            .await?;
         }
+        tts_cache::evict(&mut conn).await?;
     }
 
     Ok(data)
 }
 
 #[tauri::command]
+#[tracing::instrument(skip(resource_key, ssml, state), err)]
 async fn speak_azure(
     message_id: Option<i64>,
     region: String,
@@ -268,6 +690,7 @@ async fn speak_azure(
     beep_volume: f32,
     pre_fetch: bool,
     no_cache: bool,
+    state: tauri::State<'_, AppState>,
 ) -> Result<String, Error> {
     if no_cache && pre_fetch {
         return Ok("".to_owned());
@@ -275,11 +698,12 @@ async fn speak_azure(
     let precedence = if pre_fetch {
         0
     } else {
-        AUDIO_PLAYBACK_COUNTER.fetch_add(1, Ordering::SeqCst) + 1
+        state.audio.playback_counter.fetch_add(1, Ordering::SeqCst) + 1
     };
 
-    {
-        let mut conn = connect_db().await?;
+    let ssml = {
+        let mut conn = state.db_pool.acquire().await?;
+        let ssml = lexicon::apply_to_ssml(&mut conn, &ssml).await?;
         let cached_audio = sqlx::query(
             "
 SELECT audio FROM messageTTSCache WHERE ssml = ?1
@@ -292,18 +716,29 @@ LIMIT 1
         .fetch_optional(&mut conn)
         .await?;
         if let Some(data) = cached_audio {
+            tts_cache::touch(&mut conn, &ssml).await?;
             if !pre_fetch {
-                play_audio(data.get("audio"), precedence).await?;
+                audio_log::play_or_write(&state, message_id, data.get("audio"), precedence).await?;
             }
             return Ok("".to_owned());
         }
-    }
+        ssml
+    };
 
+    // The waiting beep is silent while the speech is written to files.
+    let beep_volume = if state.audio.engine.file_output().is_some() {
+        0.0
+    } else {
+        beep_volume
+    };
     let (sender, receiver) = std::sync::mpsc::channel();
+    let audio = state.audio.clone();
     std::thread::spawn(move || {
-        let (_stream, stream_handle) = rodio::OutputStream::try_default().unwrap();
-        let sink = rodio::Sink::try_new(&stream_handle).unwrap();
-        sink.set_volume(0.5 * beep_volume);
+        let sink = match audio.engine.sink() {
+            Ok(sink) => sink,
+            Err(_) => return, // logged by the engine
+        };
+        sink.set_volume(audio.mixer.beep_volume(0.5 * beep_volume));
         sink.append(rodio::source::SineWave::new(659.25)); // E
         let mut i = 0;
         loop {
@@ -311,14 +746,25 @@ LIMIT 1
                 Err(TryRecvError::Empty) => {}
                 _ => break,
             }
-            sink.set_volume(if i % 5 == 0 { 0.5 } else { 0.0 } * beep_volume);
+            sink.set_volume(
+                audio
+                    .mixer
+                    .beep_volume(if i % 5 == 0 { 0.5 } else { 0.0 } * beep_volume),
+            );
             std::thread::sleep(std::time::Duration::from_millis(200));
             i += 1;
         }
     });
 
-    let data = match azure_text_to_speech_request(message_id, region, resource_key, ssml, no_cache)
-        .await
+    let data = match azure_text_to_speech_request(
+        &state.db_pool,
+        message_id,
+        region,
+        resource_key,
+        ssml,
+        no_cache,
+    )
+    .await
     {
         Err(err) => {
             sender.send(())?;
@@ -329,7 +775,7 @@ LIMIT 1
 
     sender.send(())?;
     if !pre_fetch {
-        play_audio(data, precedence).await?;
+        audio_log::play_or_write(&state, message_id, data, precedence).await?;
     }
     Ok("".to_owned())
 }
@@ -354,18 +800,58 @@ impl AtomicF32 {
 }
 
 lazy_static::lazy_static! {
-    /// [0, infty] -> volume
-    /// -1 -> transcribing
-    static ref INPUT_LOUDNESS: AtomicF32 = AtomicF32::new(0.0);
-
-    static ref CHAT_COMPLETION_RESPONSE: Arc<Mutex<HashMap<u64, Vec<String>>>> = Arc::new(Mutex::new(HashMap::new()));
     static ref CHAT_COMPLETION_CANCELED: Arc<Mutex<HashSet<u64>>> = Arc::new(Mutex::new(HashSet::new()));
+    /// The label of the window that started each request.
+    static ref CHAT_COMPLETION_WINDOW: Arc<Mutex<HashMap<u64, String>>> = Arc::new(Mutex::new(HashMap::new()));
+    /// The concatenated `delta.content` of each request.
+    static ref CHAT_COMPLETION_CONTENT: Arc<Mutex<HashMap<u64, String>>> = Arc::new(Mutex::new(HashMap::new()));
+
+    /// Tool calls whose deltas are still being streamed, indexed by `tool_calls[].index`.
+    static ref CHAT_TOOL_CALLS_PENDING: Arc<Mutex<HashMap<u64, Vec<ToolCall>>>> = Arc::new(Mutex::new(HashMap::new()));
+    static ref CHAT_TOOL_CALLS: Arc<Mutex<HashMap<u64, Vec<ToolCall>>>> = Arc::new(Mutex::new(HashMap::new()));
+
+    /// Parsing the BPE ranks takes hundreds of milliseconds, so they are loaded only once.
+    /// Every chat model (gpt-3.5-turbo, gpt-4) uses cl100k_base, and models unknown to tiktoken are approximated with it.
+    static ref CL100K_BASE: tiktoken_rs::CoreBPE = tiktoken_rs::cl100k_base().expect("failed to load cl100k_base");
+    /// The other encodings, each loaded on the first use by a model.
+    static ref BPES: Mutex<HashMap<tiktoken_rs::tokenizer::Tokenizer, Arc<tiktoken_rs::CoreBPE>>> = Mutex::new(HashMap::new());
+}
+
+/// Calls `f` with the encoding of the model. Models unknown to tiktoken (local or third-party ones, or ones newer than
+/// tiktoken-rs such as gpt-4o) are approximated with cl100k_base.
+pub(crate) fn with_bpe<T>(
+    model: &str,
+    f: impl FnOnce(&tiktoken_rs::CoreBPE) -> T,
+) -> Result<T, Error> {
+    use tiktoken_rs::tokenizer::{get_tokenizer, Tokenizer};
+    let tokenizer = match get_tokenizer(model) {
+        None | Some(Tokenizer::Cl100kBase) => return Ok(f(&CL100K_BASE)),
+        Some(tokenizer) => tokenizer,
+    };
+    let cached = BPES.lock()?.get(&tokenizer).cloned();
+    let bpe = match cached {
+        Some(bpe) => bpe,
+        None => {
+            let bpe = Arc::new(
+                tiktoken_rs::get_bpe_from_model(model)
+                    .map_err(|err| Error::StringError(err.to_string()))?,
+            );
+            BPES.lock()?.insert(tokenizer, bpe.clone());
+            bpe
+        }
+    };
+    Ok(f(&bpe))
 }
 
 /// lang: en-US, en-GB, de-DE, es-ES, fr-FR, or it-IT
 #[tauri::command]
-async fn speak_pico2wave(content: String, lang: String) -> Result<(), Error> {
-    let precedence = AUDIO_PLAYBACK_COUNTER.fetch_add(1, Ordering::SeqCst) + 1;
+#[tracing::instrument(skip(content, state), err)]
+async fn speak_pico2wave(
+    content: String,
+    lang: String,
+    state: tauri::State<'_, AppState>,
+) -> Result<(), Error> {
+    let precedence = state.audio.playback_counter.fetch_add(1, Ordering::SeqCst) + 1;
     let mut f = tempfile::Builder::new().suffix(".wav").tempfile()?;
     let path = f
         .path()
@@ -379,51 +865,183 @@ async fn speak_pico2wave(content: String, lang: String) -> Result<(), Error> {
             std::str::from_utf8(&output.stderr)?.to_owned(),
         ));
     }
-    println!("{:?}", output.status.success());
     let mut buf = Vec::<u8>::new();
     f.read_to_end(&mut buf)?;
-    println!("{:?}", buf.len());
-    play_audio(buf, precedence).await?;
+    tracing::debug!(bytes = buf.len(), "pico2wave finished");
+    audio_log::play_or_write(&state, None, buf, precedence).await?;
     Ok(())
 }
 
-static RECORDING_COUNTER: AtomicI64 = AtomicI64::new(0);
-static RECORDING_CANCELED: AtomicI64 = AtomicI64::new(-1);
-
 #[tauri::command]
-fn stop_listening() {
-    RECORDING_COUNTER.fetch_add(1, Ordering::SeqCst);
+#[tracing::instrument(skip(state))]
+fn stop_listening(state: tauri::State<AppState>) {
+    state.audio.recording_counter.fetch_add(1, Ordering::SeqCst);
 }
 
 #[tauri::command]
-fn cancel_listening() {
-    RECORDING_CANCELED.store(
-        RECORDING_COUNTER.fetch_add(1, Ordering::SeqCst),
+#[tracing::instrument(skip(state))]
+fn cancel_listening(state: tauri::State<AppState>) {
+    state.audio.recording_canceled.store(
+        state.audio.recording_counter.fetch_add(1, Ordering::SeqCst),
         Ordering::SeqCst,
     );
 }
 
 #[tauri::command]
-fn get_input_loudness() -> f32 {
-    INPUT_LOUDNESS.load(Ordering::SeqCst)
+fn get_input_loudness(state: tauri::State<AppState>) -> f32 {
+    state.audio.input_loudness.load(Ordering::SeqCst)
+}
+
+#[tauri::command]
+#[tracing::instrument(skip(state))]
+fn stop_audio(state: tauri::State<AppState>) {
+    state.audio.playback_counter.fetch_add(1, Ordering::SeqCst);
+}
+
+#[tauri::command]
+#[tracing::instrument(skip(state), err)]
+fn pause_audio(state: tauri::State<AppState>) -> Result<(), Error> {
+    state.audio.engine.pause()
+}
+
+#[tauri::command]
+#[tracing::instrument(skip(state), err)]
+fn resume_audio(state: tauri::State<AppState>) -> Result<(), Error> {
+    state.audio.engine.resume()
+}
+
+/// Moves the speech being played to the position in milliseconds, which is limited to the audio synthesized so far.
+#[tauri::command]
+#[tracing::instrument(skip(state), err)]
+fn seek_audio(ms: u64, state: tauri::State<AppState>) -> Result<(), Error> {
+    state
+        .audio
+        .engine
+        .navigate(Navigation::Seek(Duration::from_millis(ms)))
 }
 
+/// Moves the speech being played to the start of the next sentence.
 #[tauri::command]
-fn stop_audio() {
-    AUDIO_PLAYBACK_COUNTER.fetch_add(1, Ordering::SeqCst);
+#[tracing::instrument(skip(state), err)]
+fn skip_sentence_forward(state: tauri::State<AppState>) -> Result<(), Error> {
+    state
+        .audio
+        .engine
+        .navigate(Navigation::SkipSentence { forward: true })
 }
 
+/// Moves the speech being played to the start of the current sentence, or of the previous one if the current one has
+/// just started.
 #[tauri::command]
+#[tracing::instrument(skip(state), err)]
+fn skip_sentence_back(state: tauri::State<AppState>) -> Result<(), Error> {
+    state
+        .audio
+        .engine
+        .navigate(Navigation::SkipSentence { forward: false })
+}
+
+const DEFAULT_MAX_GAIN_DB: f32 = 20.0;
+
+/// The result of `start_listening`. With `translate`, `text` is the English translation and `original` is the transcript
+/// in the spoken language. `language` is the ISO-639-1 code of the spoken language detected by Whisper when the language
+/// is not given, e.g. to choose a voice of that language for the reply.
+#[derive(serde::Serialize)]
+struct Dictation {
+    text: String,
+    original: Option<String>,
+    language: Option<String>,
+}
+
+#[tauri::command]
+#[tracing::instrument(skip(window, openai_key, state), err)]
 async fn start_listening(
+    window: tauri::Window,
     openai_key: String,
     language: String, // "" to auto-detect
-) -> Result<String, Error> {
-    INPUT_LOUDNESS.store(0.0, Ordering::SeqCst);
-    let mut f = NamedTempFile::new()?;
+    auto_gain: Option<bool>,
+    max_gain_db: Option<f32>,
+    trim_silence: Option<bool>,
+    max_pause_ms: Option<u64>,    // 0 or None to keep the pauses
+    context_hint: Option<String>, // e.g. the previous message, to help Whisper with the names in it
+    translate: Option<bool>,
+    state: tauri::State<'_, AppState>,
+) -> Result<Dictation, Error> {
+    let max_gain_db = match auto_gain {
+        Some(true) => Some(max_gain_db.unwrap_or(DEFAULT_MAX_GAIN_DB)),
+        _ => None,
+    };
+    let silence = match trim_silence {
+        Some(true) => Some(SilenceTrimming {
+            max_pause: max_pause_ms.filter(|&ms| ms > 0).map(Duration::from_millis),
+        }),
+        _ => None,
+    };
+    let (format, prompt) = {
+        let mut conn = state.db_pool.acquire().await?;
+        let format = match read_config(&mut conn, "dictationKeepWav").await?.as_deref() {
+            Some("1") => RecordingFormat::Wav,
+            _ => RecordingFormat::Flac,
+        };
+        let prompt =
+            vocabulary::whisper_prompt(&mut conn, context_hint.as_deref().unwrap_or_default())
+                .await?;
+        (format, prompt)
+    };
+    let f = tempfile::Builder::new()
+        .suffix(&format!(".{}", format.extension()))
+        .tempfile()?;
+    let path = f.path().to_owned();
+    if !record_dictation(&window, &state, move |sample_rate| {
+        RecordingSink::create(format, &path, sample_rate, max_gain_db, silence)
+    })
+    .await?
+    {
+        return Ok(Dictation {
+            text: "".to_owned(),
+            original: None,
+            language: None,
+        });
+    }
+    let result = if translate == Some(true) {
+        tokio::try_join!(
+            whisper::transcribe(f.path(), format, &openai_key, &language, &prompt),
+            whisper::translate(f.path(), format, &openai_key, &prompt),
+        )
+        .map(|(original, text)| Dictation {
+            text,
+            original: Some(original.text),
+            language: original.language,
+        })
+    } else {
+        whisper::transcribe(f.path(), format, &openai_key, &language, &prompt)
+            .await
+            .map(|transcript| Dictation {
+                text: transcript.text,
+                original: None,
+                language: transcript.language,
+            })
+    };
+    retain_last_recording(&state, f, format).await?;
+    result
+}
+
+/// Records the microphone into the sink created with the input device's sample rate, until `stop_listening` is called.
+/// Returns false if `cancel_listening` is called instead.
+async fn record_dictation(
+    window: &tauri::Window,
+    state: &AppState,
+    create_sink: impl FnOnce(u32) -> Result<RecordingSink, Error> + Send + 'static,
+) -> Result<bool, Error> {
+    let audio = state.audio.clone();
+    audio.input_loudness.store(0.0, Ordering::SeqCst);
+    // The previous recording is deleted once a new one starts.
+    *audio.last_recording.lock()? = None;
 
     {
-        let path = f.path().to_owned();
-        let precedence = RECORDING_COUNTER.fetch_add(1, Ordering::SeqCst) + 1;
+        let precedence = audio.recording_counter.fetch_add(1, Ordering::SeqCst) + 1;
+        let recording_audio = audio.clone();
+        let window = window.clone();
         tokio::task::spawn_blocking(move || -> Result<(), Error> {
             use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
             use cpal::SampleFormat;
@@ -433,42 +1051,65 @@ async fn start_listening(
                 .default_input_device()
                 .expect("Failed to get default input device");
             let config = device.default_output_config()?;
-            let mut wav_writer = hound::WavWriter::create(
-                path,
-                hound::WavSpec {
-                    channels: 1,
-                    sample_rate: config.config().sample_rate.0,
-                    bits_per_sample: 32,
-                    sample_format: hound::SampleFormat::Float,
-                },
-            )?;
+            let mic_processing = recording_audio.mic_processing.lock()?.clone();
+            let sink = Arc::new(Mutex::new(Some(create_sink(
+                config.config().sample_rate.0,
+            )?)));
 
             let channels = config.channels() as usize;
-            fn update_input_loudness(samples: &[f32]) {
+            fn update_input_loudness(audio: &AudioState, samples: &[f32]) {
                 let mut result = 0.0;
                 for x in samples {
                     result += (x * x) / samples.len() as f32;
                 }
-                INPUT_LOUDNESS.store(result.sqrt(), Ordering::SeqCst);
+                audio.input_loudness.store(result.sqrt(), Ordering::SeqCst);
             }
             macro_rules! build {
-                ($sample_format:pat, $sample_converter:expr) => {
+                ($sample_format:pat, $sample_converter:expr) => {{
+                    let audio = recording_audio.clone();
+                    let sink = sink.clone();
+                    let mut meter = LoudnessMeter::new(window.clone());
+                    let mut processor =
+                        MicProcessor::new(&mic_processing, config.config().sample_rate.0);
+                    let mut echo_suppressor = EchoSuppressor::new(config.config().sample_rate.0);
+                    let window = window.clone();
                     device.build_input_stream(
                         &config.config(),
                         move |data, _| {
+                            meter.push(data.iter().map($sample_converter));
                             let mut f32_samples = vec![];
                             for sample in data.chunks(channels) {
                                 let sum: f32 = sample.iter().map($sample_converter).sum();
                                 let avg = sum / channels as f32;
                                 f32_samples.push(avg);
-                                wav_writer.write_sample(avg).unwrap();
                             }
-                            update_input_loudness(&f32_samples);
+                            // The user speaking over the speech stops it if the barge-in policy is voice.
+                            if echo_suppressor.process(&mut f32_samples, audio.mixer.speech_level())
+                                && audio.mixer.barge_in() == BargeIn::Voice
+                                && audio.mixer.is_speaking()
+                            {
+                                audio.playback_counter.fetch_add(1, Ordering::SeqCst);
+                                let _ = window.emit("audio://barge-in", ());
+                            }
+                            // A poisoned lock means that the recording has already failed.
+                            if let Ok(mut sink) = sink.lock() {
+                                if let Some(writer) = sink.as_mut() {
+                                    let result = processor
+                                        .process(&f32_samples)
+                                        .into_iter()
+                                        .try_for_each(|x| writer.write(x));
+                                    if let Err(err) = result {
+                                        tracing::error!("failed to write the recording: {err}");
+                                        *sink = None; // reported when the recording stops
+                                    }
+                                }
+                            }
+                            update_input_loudness(&audio, &f32_samples);
                         },
                         |_| {},
                         None,
                     )?
-                };
+                }};
             }
 
             let stream = match config.sample_format() {
@@ -480,162 +1121,1015 @@ async fn start_listening(
                 SampleFormat::U16 => build!(SampleFormat::U16, |&x| conv::u16::to_f32(x)),
                 SampleFormat::U32 => build!(SampleFormat::U32, |&x| conv::u32::to_f32(x)),
                 SampleFormat::U64 => build!(SampleFormat::U64, |&x| conv::u64::to_f32(x)),
-                SampleFormat::F32 => build!(SampleFormat::F32, |x| x),
+                SampleFormat::F32 => build!(SampleFormat::F32, |&x| x),
                 SampleFormat::F64 => build!(SampleFormat::F64, |&x| conv::f64::to_f32(x)),
                 _ => unimplemented!(),
             };
+            let _recording = recording_audio.mixer.start_recording();
             stream.play()?;
 
-            while precedence == RECORDING_COUNTER.load(Ordering::SeqCst) {
+            while precedence == recording_audio.recording_counter.load(Ordering::SeqCst) {
                 std::thread::sleep(Duration::from_millis(50)); // `stream does` not implement Send`
             }
+            drop(stream);
 
+            match sink.lock()?.take() {
+                Some(sink) => sink.finish()?,
+                None => {
+                    return Err(Error::StringError(
+                        "Failed to write the recording".to_owned(),
+                    ))
+                }
+            }
             Ok(())
         })
         .await??;
-        if precedence <= RECORDING_CANCELED.load(Ordering::SeqCst) {
-            return Ok("".to_owned());
+        if precedence <= audio.recording_canceled.load(Ordering::SeqCst) {
+            return Ok(false);
         }
     }
-    INPUT_LOUDNESS.store(-1f32, Ordering::SeqCst);
-
-    let mut buf = vec![];
-    f.read_to_end(&mut buf)?;
-    let mut body = HashMap::new();
-    body.insert(
-        "file".to_owned(),
-        FormPart::File {
-            file: tauri::api::http::FilePart::Contents(buf),
-            mime: Some("audio/x-wav".to_owned()),
-            file_name: Some("audio.wav".to_owned()),
+    audio.input_loudness.store(-1f32, Ordering::SeqCst);
+    window.emit(
+        "mic://loudness",
+        InputLoudness {
+            rms: -1.0,
+            peak: 0.0,
+            clipping: false,
         },
-    );
-    body.insert("model".to_owned(), FormPart::Text("whisper-1".to_owned()));
-    if !language.is_empty() {
-        body.insert("language".to_owned(), FormPart::Text(language));
+    )?;
+    Ok(true)
+}
+
+/// Keeps the recording for `save_last_recording` if the `dictationKeepLastRecording` config is set.
+async fn retain_last_recording(
+    state: &AppState,
+    f: NamedTempFile,
+    format: RecordingFormat,
+) -> Result<(), Error> {
+    let mut conn = state.db_pool.acquire().await?;
+    if read_config(&mut conn, "dictationKeepLastRecording")
+        .await?
+        .as_deref()
+        == Some("1")
+    {
+        *state.audio.last_recording.lock()? = Some(LastRecording {
+            path: f.into_temp_path(),
+            format,
+        });
     }
-    let request =
-        HttpRequestBuilder::new("POST", "https://api.openai.com/v1/audio/transcriptions")?
-            .header("Authorization", format!("Bearer {openai_key}"))?
-            .header("Content-Type", "multipart/form-data")?
-            .body(Body::Form(FormBody::new(body)))
-            .response_type(ResponseType::Json);
+    Ok(())
+}
 
-    let client = ClientBuilder::new().max_redirections(3).build()?;
-    let response = client.send(request).await?;
-    let status = response.status();
-    if status != 200 {
-        return Err(Error::StringError(format!(
-            "{}: {}",
-            status,
-            &response.read().await?.data
-        )));
+/// Transcribes the dictation with Azure Speech-to-Text, using the same resource as `speak_azure`.
+/// Azure's REST API for short audio only accepts up to 60 seconds of audio.
+#[tauri::command]
+#[tracing::instrument(skip(window, resource_key, state), err)]
+async fn start_listening_azure(
+    window: tauri::Window,
+    region: String,
+    resource_key: String,
+    language: String, // e.g. "en-US"
+    state: tauri::State<'_, AppState>,
+) -> Result<String, Error> {
+    // The REST API accepts neither FLAC nor float samples, so the recording is converted by azure_stt::transcribe().
+    let format = RecordingFormat::Wav;
+    let f = tempfile::Builder::new().suffix(".wav").tempfile()?;
+    let path = f.path().to_owned();
+    if !record_dictation(&window, &state, move |sample_rate| {
+        RecordingSink::create(format, &path, sample_rate, None, None)
+    })
+    .await?
+    {
+        return Ok("".to_owned());
     }
-    let data = response.read().await?.data;
-    Ok(data
-        .as_object()
-        .ok_or_else(|| Error::StringError(format!("Unexpected response: {data}")))?
-        .get("text")
-        .ok_or_else(|| Error::StringError(format!("Unexpected response: {data}")))?
-        .as_str()
-        .ok_or_else(|| Error::StringError(format!("Unexpected response: {data}")))?
-        .to_owned())
+    let result = azure_stt::transcribe(f.path(), &region, &resource_key, &language).await;
+    retain_last_recording(&state, f, format).await?;
+    result
+}
+
+/// A streamed chunk of any provider, as the frontend receives it from `get_chat_completion`.
+#[derive(serde::Serialize, Clone, Default, Debug)]
+#[serde(rename_all = "camelCase")]
+struct CompletionChunk {
+    role: Option<String>,
+    content: Option<String>,
+    finish_reason: Option<String>,
+    /// Deltas to merge by `index`. The assembled tool calls are returned by `get_chat_tool_calls`.
+    tool_calls: Vec<ToolCallDelta>,
+    usage: Option<ChunkUsage>,
+}
+
+#[derive(serde::Serialize, Clone, Default, Debug)]
+#[serde(rename_all = "camelCase")]
+struct ToolCallDelta {
+    index: u64,
+    id: Option<String>,
+    name: Option<String>,
+    arguments: Option<String>,
 }
 
-fn handle_chat_completion_server_event(request_id: u64, buf: &[u8]) -> Result<(), Error> {
-    if !buf.starts_with(b"data: [DONE]") && buf.starts_with(b"data: ") {
-        CHAT_COMPLETION_RESPONSE
+#[derive(serde::Serialize, Clone, Copy, Debug)]
+#[serde(rename_all = "camelCase")]
+struct ChunkUsage {
+    prompt_tokens: i64,
+    completion_tokens: i64,
+}
+
+impl CompletionChunk {
+    /// Reads the first choice of an OpenAI-style `chat.completion.chunk`.
+    fn from_openai(data: &Value) -> Self {
+        let string = |pointer: &str| {
+            data.pointer(pointer)
+                .and_then(Value::as_str)
+                .map(str::to_owned)
+        };
+        let count = |pointer: &str| data.pointer(pointer).and_then(Value::as_i64);
+        let usage = count("/usage/prompt_tokens")
+            .zip(count("/usage/completion_tokens"))
+            .map(|(prompt_tokens, completion_tokens)| ChunkUsage {
+                prompt_tokens,
+                completion_tokens,
+            });
+        Self {
+            role: string("/choices/0/delta/role"),
+            content: string("/choices/0/delta/content"),
+            finish_reason: string("/choices/0/finish_reason"),
+            tool_calls: data
+                .pointer("/choices/0/delta/tool_calls")
+                .and_then(Value::as_array)
+                .map(Vec::as_slice)
+                .unwrap_or_default()
+                .iter()
+                .map(|delta| ToolCallDelta {
+                    index: delta.get("index").and_then(Value::as_u64).unwrap_or(0),
+                    id: delta.get("id").and_then(Value::as_str).map(str::to_owned),
+                    name: delta
+                        .pointer("/function/name")
+                        .and_then(Value::as_str)
+                        .map(str::to_owned),
+                    arguments: delta
+                        .pointer("/function/arguments")
+                        .and_then(Value::as_str)
+                        .map(str::to_owned),
+                })
+                .collect(),
+            usage,
+        }
+    }
+}
+
+fn push_chat_completion_chunk(request_id: u64, data: &Value) -> Result<(), Error> {
+    match output_limits::apply(request_id, CompletionChunk::from_openai(data))? {
+        Some(chunk) => push_completion_chunk(request_id, chunk),
+        None => Ok(()),
+    }
+}
+
+fn push_completion_chunk(request_id: u64, chunk: CompletionChunk) -> Result<(), Error> {
+    if let Some(content) = &chunk.content {
+        CHAT_COMPLETION_CONTENT
             .lock()?
             .entry(request_id)
-            .or_insert(vec![])
-            .push(String::from_utf8_lossy(&buf[b"data: ".len()..]).into());
+            .or_default()
+            .push_str(content);
+    }
+    chunk_buffer::push(request_id, chunk)
+}
+
+#[derive(serde::Serialize, Clone, Default, Debug)]
+struct ToolCall {
+    id: String,
+    #[serde(rename = "type")]
+    kind: String,
+    function: FunctionCall,
+}
+
+#[derive(serde::Serialize, Clone, Default, Debug)]
+struct FunctionCall {
+    name: String,
+    arguments: String, // JSON encoded
+}
+
+/// Merges the `tool_calls` deltas of a chunk into the tool calls being assembled for the request.
+fn accumulate_tool_call_deltas(request_id: u64, chunk: &Value) -> Result<(), Error> {
+    let choice = match chunk.pointer("/choices/0") {
+        Some(choice) => choice,
+        None => return Ok(()),
+    };
+    if let Some(deltas) = choice
+        .pointer("/delta/tool_calls")
+        .and_then(Value::as_array)
+    {
+        let mut pending = CHAT_TOOL_CALLS_PENDING.lock()?;
+        let calls = pending.entry(request_id).or_default();
+        for delta in deltas {
+            let index = delta.get("index").and_then(Value::as_u64).unwrap_or(0) as usize;
+            if calls.len() <= index {
+                calls.resize(index + 1, ToolCall::default());
+            }
+            let call = &mut calls[index];
+            if let Some(id) = delta.get("id").and_then(Value::as_str) {
+                call.id = id.to_owned();
+            }
+            if let Some(kind) = delta.get("type").and_then(Value::as_str) {
+                call.kind = kind.to_owned();
+            }
+            if let Some(name) = delta.pointer("/function/name").and_then(Value::as_str) {
+                call.function.name += name;
+            }
+            if let Some(arguments) = delta.pointer("/function/arguments").and_then(Value::as_str) {
+                call.function.arguments += arguments;
+            }
+        }
+    }
+    if matches!(choice.get("finish_reason"), Some(Value::String(_))) {
+        finish_tool_calls(request_id)?;
+    }
+    Ok(())
+}
+
+/// Moves the assembled tool calls of the request to where `get_chat_tool_calls` can read them.
+fn finish_tool_calls(request_id: u64) -> Result<(), Error> {
+    if let Some(calls) = CHAT_TOOL_CALLS_PENDING.lock()?.remove(&request_id) {
+        CHAT_TOOL_CALLS
+            .lock()?
+            .entry(request_id)
+            .or_default()
+            .extend(calls);
+    }
+    Ok(())
+}
+
+fn handle_chat_completion_server_event(request_id: u64, data: &str) -> Result<(), Error> {
+    if data == "[DONE]" {
+        finish_tool_calls(request_id)?;
+    } else {
+        let chunk: Value = serde_json::from_str(data)
+            .map_err(|_| Error::StringError(format!("Parse error: {data}")))?;
+        accumulate_tool_call_deltas(request_id, &chunk)?;
+        usage::capture(request_id, &chunk)?;
+        push_chat_completion_chunk(request_id, &chunk)?;
     }
     Ok(())
 }
 
+/// Converts a line of Ollama's NDJSON stream into an OpenAI-style `chat.completion.chunk`, so that the frontend can consume every provider the same way.
+fn handle_ollama_chat_event(request_id: u64, buf: &[u8]) -> Result<(), Error> {
+    if buf.iter().all(|c| c.is_ascii_whitespace()) {
+        return Ok(());
+    }
+    let data: Value = serde_json::from_slice(buf)?;
+    if let Some(err) = data.get("error") {
+        return Err(Error::StringError(match err {
+            Value::String(s) => s.to_owned(),
+            _ => err.to_string(),
+        }));
+    }
+    let done = data.get("done").and_then(Value::as_bool).unwrap_or(false);
+    usage::capture(request_id, &data)?;
+    let message = data.get("message").cloned().unwrap_or(Value::Null);
+    let chunk = serde_json::json!({
+        "object": "chat.completion.chunk",
+        "model": data.get("model"),
+        "choices": [{
+            "index": 0,
+            "delta": {
+                "role": message.get("role"),
+                "content": message.get("content"),
+            },
+            "finish_reason": if done { Some("stop") } else { None },
+        }],
+        "usage": {
+            "prompt_tokens": data.get("prompt_eval_count"),
+            "completion_tokens": data.get("eval_count"),
+        },
+    });
+    push_chat_completion_chunk(request_id, &chunk)
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "lowercase")]
+enum ChatProvider {
+    /// OpenAI and OpenAI-compatible endpoints (Azure, proxies), streamed as server-sent events.
+    OpenAI,
+    /// Ollama's `/api/chat`, streamed as newline-delimited JSON.
+    Ollama,
+    /// Google Gemini's `streamGenerateContent`, streamed as server-sent events with `alt=sse`.
+    Gemini,
+    /// OpenRouter's OpenAI-compatible API, which routes the request to the provider of the model, e.g. "anthropic/claude-3.5-sonnet".
+    OpenRouter,
+}
+
+const OLLAMA_BASE_URL: &str = "http://localhost:11434";
+const GEMINI_BASE_URL: &str = "https://generativelanguage.googleapis.com/v1beta";
+
+/// Translates an OpenAI chat completion request body into a Gemini `generateContent` request, and returns the model name and the translated body.
+fn gemini_request_body(body: &str) -> Result<(String, String), Error> {
+    let body: Value = serde_json::from_str(body)?;
+    let model = body
+        .get("model")
+        .and_then(Value::as_str)
+        .ok_or_else(|| Error::StringError("model is not specified".to_owned()))?
+        .to_owned();
+    let mut system = vec![];
+    let mut contents = vec![];
+    for message in body
+        .get("messages")
+        .and_then(Value::as_array)
+        .map(Vec::as_slice)
+        .unwrap_or_default()
+    {
+        let part = serde_json::json!({
+            "text": message.get("content").and_then(Value::as_str).unwrap_or(""),
+        });
+        let mut parts = vec![part.clone()];
+        parts.extend(
+            message
+                .get("images")
+                .and_then(Value::as_array)
+                .into_iter()
+                .flatten()
+                .filter_map(|url| vision::split_data_url(url.as_str()?))
+                .map(|(mime_type, data)| {
+                    serde_json::json!({ "inline_data": { "mime_type": mime_type, "data": data } })
+                }),
+        );
+        match message.get("role").and_then(Value::as_str) {
+            Some("system") => system.push(part),
+            Some("assistant") => {
+                contents.push(serde_json::json!({ "role": "model", "parts": parts }))
+            }
+            _ => contents.push(serde_json::json!({ "role": "user", "parts": parts })),
+        }
+    }
+    let mut request = serde_json::json!({ "contents": contents });
+    if !system.is_empty() {
+        request["systemInstruction"] = serde_json::json!({ "parts": system });
+    }
+    let mut generation_config = serde_json::Map::new();
+    for (from, to) in [
+        ("temperature", "temperature"),
+        ("top_p", "topP"),
+        ("max_tokens", "maxOutputTokens"),
+        ("stop", "stopSequences"),
+    ] {
+        match body.get(from) {
+            Some(Value::String(s)) if to == "stopSequences" => {
+                generation_config.insert(to.to_owned(), serde_json::json!([s]));
+            }
+            Some(value) => {
+                generation_config.insert(to.to_owned(), value.clone());
+            }
+            None => {}
+        }
+    }
+    if !generation_config.is_empty() {
+        request["generationConfig"] = Value::Object(generation_config);
+    }
+    Ok((model, request.to_string()))
+}
+
+/// Converts a Gemini server-sent event into OpenAI-style `chat.completion.chunk`s.
+fn handle_gemini_server_event(request_id: u64, data: &str) -> Result<(), Error> {
+    let data: Value = serde_json::from_str(data)?;
+    if let Some(err) = data.get("error") {
+        return Err(Error::StringError(err.to_string()));
+    }
+    usage::capture(request_id, &data)?;
+    for (index, candidate) in data
+        .get("candidates")
+        .and_then(Value::as_array)
+        .map(Vec::as_slice)
+        .unwrap_or_default()
+        .iter()
+        .enumerate()
+    {
+        let content = candidate
+            .pointer("/content/parts")
+            .and_then(Value::as_array)
+            .map(Vec::as_slice)
+            .unwrap_or_default()
+            .iter()
+            .filter_map(|part| part.get("text").and_then(Value::as_str))
+            .collect::<String>();
+        let finish_reason = candidate
+            .get("finishReason")
+            .and_then(Value::as_str)
+            .map(|reason| match reason {
+                "MAX_TOKENS" => "length",
+                "SAFETY" | "RECITATION" | "BLOCKLIST" | "PROHIBITED_CONTENT" => "content_filter",
+                _ => "stop",
+            });
+        let chunk = serde_json::json!({
+            "object": "chat.completion.chunk",
+            "choices": [{
+                "index": candidate.get("index").and_then(Value::as_u64).unwrap_or(index as u64),
+                "delta": { "role": "assistant", "content": content },
+                "finish_reason": finish_reason,
+            }],
+            "usage": {
+                "prompt_tokens": data.pointer("/usageMetadata/promptTokenCount"),
+                "completion_tokens": data.pointer("/usageMetadata/candidatesTokenCount"),
+            },
+        });
+        push_chat_completion_chunk(request_id, &chunk)?;
+    }
+    Ok(())
+}
+
+fn handle_server_event(
+    provider: ChatProvider,
+    request_id: u64,
+    event: &sse::Event,
+) -> Result<(), Error> {
+    if event.event.as_deref() == Some("error") {
+        return Err(Error::StringError(event.data.clone())); // sent by some proxies instead of an error status
+    }
+    match provider {
+        ChatProvider::Gemini => handle_gemini_server_event(request_id, &event.data),
+        _ => handle_chat_completion_server_event(request_id, &event.data),
+    }
+}
+
+/// Cancels the requests started by the window.
 #[tauri::command]
-fn stop_all_chat_completions() -> Result<(), Error> {
-    for id in CHAT_COMPLETION_RESPONSE.lock()?.keys() {
-        CHAT_COMPLETION_CANCELED.lock()?.insert(*id);
+#[tracing::instrument(skip(window), err)]
+fn stop_all_chat_completions(window: tauri::Window) -> Result<(), Error> {
+    let owners = CHAT_COMPLETION_WINDOW.lock()?;
+    for id in chunk_buffer::request_ids()? {
+        if owners
+            .get(&id)
+            .map_or(true, |label| label == window.label())
+        {
+            CHAT_COMPLETION_CANCELED.lock()?.insert(id);
+        }
     }
     Ok(())
 }
 
+const DEFAULT_CHAT_COMPLETION_MAX_RETRIES: u32 = 3;
+const DEFAULT_CHAT_COMPLETION_CONNECT_TIMEOUT_SECS: u64 = 10;
+const DEFAULT_CHAT_COMPLETION_STALL_TIMEOUT_SECS: u64 = 60;
+
+/// Awaits `future`, or fails with `Error::StreamStalled` if it does not complete within `stall_timeout_secs` seconds (0 = wait forever).
+async fn with_stall_timeout<T>(
+    stall_timeout_secs: u64,
+    future: impl std::future::Future<Output = Result<T, reqwest::Error>>,
+) -> Result<T, Error> {
+    if stall_timeout_secs == 0 {
+        return Ok(future.await?);
+    }
+    match tokio::time::timeout(Duration::from_secs(stall_timeout_secs), future).await {
+        Ok(result) => Ok(result?),
+        Err(_) => Err(Error::StreamStalled(stall_timeout_secs)),
+    }
+}
+
+#[derive(serde::Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+struct ChatCompletionRetrying {
+    request_id: u64,
+    attempt: u32,
+    max_retries: u32,
+    status: u16,
+    delay_ms: u64,
+}
+
+/// The longest delay between retries, which caps the `Retry-After` headers, so that a server cannot stall a request forever.
+const MAX_CHAT_COMPLETION_RETRY_DELAY: Duration = Duration::from_secs(60);
+
+/// Honors the `retry-after-ms` (Azure) and `Retry-After` headers up to `MAX_CHAT_COMPLETION_RETRY_DELAY`, and falls back
+/// to exponential backoff (1s, 2s, 4s, ..., up to 60s) if they are missing or invalid.
+fn chat_completion_retry_delay(res: &reqwest::Response, attempt: u32) -> Duration {
+    let header = |name: &str, scale: f64| {
+        res.headers()
+            .get(name)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.trim().parse::<f64>().ok())
+            .filter(|v| v.is_finite() && *v >= 0.0)
+            .and_then(|v| Duration::try_from_secs_f64(v * scale).ok())
+    };
+    if let Some(delay) = header("retry-after-ms", 0.001).or_else(|| header("retry-after", 1.0)) {
+        return delay.min(MAX_CHAT_COMPLETION_RETRY_DELAY);
+    }
+    Duration::from_secs((1u64 << attempt.saturating_sub(1).min(6)).min(60))
+}
+
+/// Starts a chat completion whose body is built from `request`. See `send_chat_completion` for the other parameters.
 #[tauri::command]
+#[tracing::instrument(skip(window, secret_key, request, headers, state), err)]
 async fn start_chat_completion(
+    window: tauri::Window,
+    request_id: u64,
+    secret_key: String,
+    request: CompletionRequest,
+    endpoint: String,
+    api_key_authentication: bool,
+    provider: Option<ChatProvider>,
+    max_retries: Option<u32>,
+    connect_timeout_secs: Option<u64>,
+    stall_timeout_secs: Option<u64>,
+    headers: Option<HashMap<String, String>>,
+    query: Option<HashMap<String, String>>,
+    conversation_id: Option<i64>,
+    model: Option<String>,
+    tools: Option<Vec<String>>,
+    mcp: Option<bool>,
+    queue_when_offline: Option<bool>,
+    stop_sequences: Option<Vec<String>>,
+    max_output_tokens: Option<u32>,
+    preset: Option<String>, // the id of a provider preset, whose endpoint is used if `endpoint` is empty
+    state: tauri::State<'_, AppState>,
+) -> Result<(), Error> {
+    let (body, endpoint, api_key_authentication) = match preset {
+        Some(preset) => {
+            let preset = provider_presets::get(&preset)?;
+            (
+                preset.adapt_body(&request.to_body()?)?,
+                if endpoint.is_empty() {
+                    preset.endpoint()
+                } else {
+                    endpoint
+                },
+                preset.api_key_authentication(),
+            )
+        }
+        None => (request.to_body()?, endpoint, api_key_authentication),
+    };
+    send_chat_completion(
+        window,
+        request_id,
+        secret_key,
+        body,
+        endpoint,
+        api_key_authentication,
+        provider,
+        max_retries,
+        connect_timeout_secs,
+        stall_timeout_secs,
+        headers,
+        query,
+        conversation_id,
+        model,
+        tools,
+        mcp,
+        queue_when_offline,
+        stop_sequences,
+        max_output_tokens,
+        state,
+    )
+    .await
+}
+
+/// Streams a chat completion of the JSON body into the chunk buffer of `request_id`.
+#[tracing::instrument(skip(window, secret_key, body, headers, state), err)]
+async fn send_chat_completion(
+    window: tauri::Window,
     request_id: u64,
     secret_key: String, // OpenAI API key or Azure Active Directory token
     body: String,
-    endpoint: String, // use "https://api.openai.com/v1/chat/completions" for openai
+    endpoint: String, // use "https://api.openai.com/v1/chat/completions" for openai, "" for the local ollama server or the gemini endpoint of the model
     api_key_authentication: bool, // use false for openai, see https://learn.microsoft.com/en-us/azure/cognitive-services/openai/reference#authentication for azure
+    provider: Option<ChatProvider>, // defaults to openai
+    max_retries: Option<u32>, // retries on 429 and 5xx, defaults to DEFAULT_CHAT_COMPLETION_MAX_RETRIES
+    connect_timeout_secs: Option<u64>, // defaults to DEFAULT_CHAT_COMPLETION_CONNECT_TIMEOUT_SECS
+    stall_timeout_secs: Option<u64>, // aborts when no data arrives for this long, 0 to disable, defaults to DEFAULT_CHAT_COMPLETION_STALL_TIMEOUT_SECS
+    headers: Option<HashMap<String, String>>, // extra headers, e.g. for LiteLLM, Helicone or Cloudflare AI Gateway
+    query: Option<HashMap<String, String>>,   // extra query parameters
+    conversation_id: Option<i64>, // the root message of the conversation, for the usage accounting
+    model: Option<String>,        // for the usage accounting, defaults to the model in body
+    tools: Option<Vec<String>>, // built-in tools executed by the backend, e.g. ["web_search"], only with openai
+    mcp: Option<bool>, // adds the tools of the connected MCP servers, only with openai, defaults to false
+    queue_when_offline: Option<bool>, // waits for the network instead of failing when the connection fails, see offline_queue
+    stop_sequences: Option<Vec<String>>, // enforced by the backend for every provider, see output_limits
+    max_output_tokens: Option<u32>, // enforced by the backend for every provider, see output_limits
+    state: tauri::State<'_, AppState>,
 ) -> Result<(), Error> {
-    let client = reqwest::Client::new()
-        .post(endpoint)
-        .header("Content-Type", "application/json");
-    let client = if api_key_authentication {
-        client.header("api-key", secret_key)
+    let queued = (queue_when_offline == Some(true)).then(|| QueuedRequest {
+        body: body.clone(),
+        endpoint: endpoint.clone(),
+        api_key_authentication,
+        provider,
+        max_retries,
+        connect_timeout_secs,
+        stall_timeout_secs,
+        headers: headers.clone(),
+        query: query.clone(),
+        conversation_id,
+        model: model.clone(),
+        tools: tools.clone(),
+        mcp,
+        stop_sequences: stop_sequences.clone(),
+        max_output_tokens,
+    });
+    let provider = provider.unwrap_or(ChatProvider::OpenAI);
+    let mut tools = tools.unwrap_or_default();
+    let has_messages = serde_json::from_str::<Value>(&body)
+        .map_or(false, |request| request.get("messages").is_some()); // not a completion of a prompt
+    if mcp == Some(true) && provider == ChatProvider::OpenAI && has_messages {
+        tools.extend(mcp::function_names(&window.app_handle())?);
+    }
+    let max_retries = max_retries.unwrap_or(DEFAULT_CHAT_COMPLETION_MAX_RETRIES);
+    let stall_timeout_secs =
+        stall_timeout_secs.unwrap_or(DEFAULT_CHAT_COMPLETION_STALL_TIMEOUT_SECS);
+    let _buffer = chunk_buffer::open(request_id)?; // makes the request cancelable by stop_all_chat_completions() while waiting for a retry
+    let _limits = output_limits::register(request_id, stop_sequences, max_output_tokens)?;
+    CHAT_COMPLETION_WINDOW
+        .lock()?
+        .insert(request_id, window.label().to_owned());
+    let (model, estimated_prompt_tokens, max_tokens, json_expectation) = {
+        let request: Value = serde_json::from_str(&body).unwrap_or_default();
+        let model = model
+            .or_else(|| {
+                request
+                    .get("model")
+                    .and_then(Value::as_str)
+                    .map(str::to_owned)
+            })
+            .unwrap_or_else(|| "unknown".to_owned());
+        let estimated_prompt_tokens = estimate_prompt_tokens(&model, &request);
+        let max_tokens = request
+            .get("max_tokens")
+            .or_else(|| request.get("max_completion_tokens"))
+            .and_then(Value::as_i64)
+            .unwrap_or(0);
+        let json_expectation = structured_output::JsonExpectation::from_request(&request);
+        (model, estimated_prompt_tokens, max_tokens, json_expectation)
+    };
+    let usage_provider = match provider {
+        ChatProvider::OpenAI
+            if api_key_authentication || endpoint.contains(".openai.azure.com") =>
+        {
+            "azure"
+        }
+        ChatProvider::OpenAI => {
+            provider_presets::find_by_endpoint(&endpoint).map_or("openai", |preset| preset.id())
+        }
+        ChatProvider::Ollama => "ollama",
+        ChatProvider::Gemini => "gemini",
+        ChatProvider::OpenRouter => "openrouter",
+    };
+    let body = vision::expand_images(provider, body)?;
+    let body = if tools.is_empty() {
+        body
+    } else if matches!(provider, ChatProvider::OpenAI | ChatProvider::OpenRouter) {
+        tools::add_definitions(&state, &body, &tools)?
     } else {
-        client.header("Authorization", format!("Bearer {secret_key}"))
+        return Err(Error::StringError(
+            "Built-in tools are only supported with OpenAI and OpenRouter".to_owned(),
+        ));
     };
-    let mut res = client.body(body).send().await?;
-    let mut buf = Vec::<u8>::new();
-    let mut is_prev_char_newline = false;
-    if res.status() != 200 {
-        return Err(Error::StatusIsNot200(format!(
-            "{}: {}",
-            res.status(),
-            res.text().await?
-        )));
-    }
-    while let Some(chunk) = res.chunk().await? {
-        for value in chunk {
-            // split with "\n\n"
-            let newline = value == '\n' as u8;
-            if newline && is_prev_char_newline {
-                is_prev_char_newline = false;
-                handle_chat_completion_server_event(request_id, &buf)?;
-                buf.clear();
+    let (endpoint, body) = match provider {
+        ChatProvider::Ollama if endpoint.is_empty() => {
+            (format!("{OLLAMA_BASE_URL}/api/chat"), body)
+        }
+        ChatProvider::OpenRouter if endpoint.is_empty() => {
+            (format!("{}/chat/completions", openrouter::BASE_URL), body)
+        }
+        ChatProvider::Gemini => {
+            let (model, body) = gemini_request_body(&body)?;
+            if endpoint.is_empty() {
+                (
+                    format!("{GEMINI_BASE_URL}/models/{model}:streamGenerateContent"),
+                    body,
+                )
             } else {
-                buf.push(value);
-                is_prev_char_newline = newline;
+                (endpoint, body)
             }
         }
-
-        if CHAT_COMPLETION_CANCELED.lock()?.contains(&request_id) {
+        _ => (endpoint, body),
+    };
+    let http = reqwest::Client::builder()
+        .connect_timeout(Duration::from_secs(
+            connect_timeout_secs.unwrap_or(DEFAULT_CHAT_COMPLETION_CONNECT_TIMEOUT_SECS),
+        ))
+        .build()?;
+    // With an Azure AD credential, the token is obtained by the backend and refreshed once if it is rejected.
+    let aad = provider == ChatProvider::OpenAI
+        && !api_key_authentication
+        && state.aad_token.has_credential()?;
+    let mut secret_key = if aad {
+        state.aad_token.token(false).await?
+    } else {
+        secret_key
+    };
+    let build_request = |secret_key: &str, body: &str| -> Result<reqwest::Request, Error> {
+        let client = http
+            .post(&endpoint)
+            .header("Content-Type", "application/json");
+        let client = match provider {
+            ChatProvider::Gemini => client.query(&[("alt", "sse"), ("key", secret_key)]),
+            ChatProvider::Ollama if secret_key.is_empty() => client, // a local ollama server does not require authentication
+            ChatProvider::OpenRouter => openrouter::add_headers(client).bearer_auth(secret_key),
+            _ if api_key_authentication => client.header("api-key", secret_key),
+            _ => client.header("Authorization", format!("Bearer {secret_key}")),
+        };
+        let client = match &query {
+            Some(query) => client.query(query),
+            None => client,
+        };
+        let client = headers
+            .iter()
+            .flatten()
+            .fold(client, |client, (name, value)| client.header(name, value));
+        Ok(client.body(body.to_owned()).build()?)
+    };
+    // Each round sends the results of the previous round's built-in tool calls.
+    let mut body = body;
+    let mut estimated_prompt_tokens = estimated_prompt_tokens;
+    let mut round = 0;
+    loop {
+        // The providers count max_tokens against the tokens per minute.
+        if !rate_limit::acquire(
+            usage_provider,
+            estimated_prompt_tokens + max_tokens,
+            Some(request_id),
+        )
+        .await?
+            || CHAT_COMPLETION_CANCELED.lock()?.contains(&request_id)
+        {
             return Ok(());
         }
+        let mut refreshed_token = false;
+        let mut res = loop {
+            let request = build_request(&secret_key, &body)?;
+            let mut attempt = 0;
+            let res = loop {
+                let res = match (
+                    with_stall_timeout(
+                        stall_timeout_secs,
+                        http.execute(request.try_clone().ok_or_else(|| {
+                            Error::StringError(
+                                "failed to clone the chat completion request".to_owned(),
+                            )
+                        })?),
+                    )
+                    .await,
+                    &queued,
+                ) {
+                    (Err(Error::ReqwestError(err)), Some(queued)) if err.is_connect() => {
+                        if !offline_queue::wait_until_online(
+                            &window,
+                            &state,
+                            request_id,
+                            &secret_key,
+                            queued,
+                        )
+                        .await?
+                        {
+                            return Ok(());
+                        }
+                        continue;
+                    }
+                    (res, _) => res?,
+                };
+                let status = res.status();
+                if !(status == 429 || status.is_server_error()) || attempt >= max_retries {
+                    break res;
+                }
+                attempt += 1;
+                let delay = chat_completion_retry_delay(&res, attempt);
+                window.emit(
+                    "chat-completion://retrying",
+                    ChatCompletionRetrying {
+                        request_id,
+                        attempt,
+                        max_retries,
+                        status: status.as_u16(),
+                        delay_ms: delay.as_millis() as u64,
+                    },
+                )?;
+                tokio::time::sleep(delay).await;
+                if CHAT_COMPLETION_CANCELED.lock()?.contains(&request_id) {
+                    return Ok(());
+                }
+            };
+            if res.status() == 401 && aad && !refreshed_token {
+                refreshed_token = true;
+                secret_key = state.aad_token.token(true).await?;
+                continue;
+            }
+            break res;
+        };
+        let mut buf = Vec::<u8>::new(); // the current line of ollama
+        let mut events = sse::Parser::default();
+        if res.status() != 200 {
+            return Err(Error::StatusIsNot200(format!(
+                "{}: {}",
+                res.status(),
+                res.text().await?
+            )));
+        }
+        while let Some(chunk) = with_stall_timeout(stall_timeout_secs, res.chunk()).await? {
+            if provider == ChatProvider::Ollama {
+                for value in chunk {
+                    if value == b'\n' {
+                        handle_ollama_chat_event(request_id, &buf)?;
+                        buf.clear();
+                    } else {
+                        buf.push(value);
+                    }
+                }
+            } else {
+                for event in events.feed(&chunk) {
+                    handle_server_event(provider, request_id, &event)?;
+                }
+            }
+
+            if !chunk_buffer::wait_for_consumer(&window, request_id).await? {
+                CHAT_COMPLETION_CANCELED.lock()?.insert(request_id);
+            }
+            if CHAT_COMPLETION_CANCELED.lock()?.contains(&request_id) {
+                let content = CHAT_COMPLETION_CONTENT
+                    .lock()?
+                    .remove(&request_id)
+                    .unwrap_or_default();
+                usage::record(
+                    &state,
+                    request_id,
+                    conversation_id,
+                    usage_provider,
+                    &model,
+                    estimated_prompt_tokens,
+                    &content,
+                )
+                .await?;
+                return Ok(());
+            }
+            if output_limits::reached(request_id)? {
+                break;
+            }
+        }
+        if provider == ChatProvider::Ollama {
+            handle_ollama_chat_event(request_id, &buf)?;
+        } else if let Some(event) = events.finish() {
+            handle_server_event(provider, request_id, &event)?;
+        }
+        if let Some(content) = output_limits::flush(request_id)? {
+            push_completion_chunk(
+                request_id,
+                CompletionChunk {
+                    content: Some(content),
+                    ..Default::default()
+                },
+            )?;
+        }
+        finish_tool_calls(request_id)?;
+        let content = CHAT_COMPLETION_CONTENT
+            .lock()?
+            .remove(&request_id)
+            .unwrap_or_default();
+        usage::record(
+            &state,
+            request_id,
+            conversation_id,
+            usage_provider,
+            &model,
+            estimated_prompt_tokens,
+            &content,
+        )
+        .await?;
+        round += 1;
+        if round <= tools::MAX_ROUNDS {
+            if let Some(calls) = tools::take_builtin_calls(request_id, &tools)? {
+                body = tools::run_calls(&window, &state, request_id, &body, calls).await?;
+                estimated_prompt_tokens =
+                    estimate_prompt_tokens(&model, &serde_json::from_str(&body)?);
+                continue;
+            }
+        }
+        // A response of only tool calls has no content to validate.
+        if let Some(expectation) = json_expectation.as_ref().filter(|_| !content.is_empty()) {
+            expectation.validate_response(&window, request_id, &content)?;
+        }
+        notify_chat_completion_done(&window, &content)?;
+        return Ok(());
     }
-    handle_chat_completion_server_event(request_id, &buf)?;
-    buf.clear();
+}
+
+/// Shows a system notification if the window is in the background when the response is completed.
+/// tauri::api::notification cannot handle clicks on the notification, but most desktops focus the app when it is clicked.
+fn notify_chat_completion_done(window: &tauri::Window, content: &str) -> Result<(), Error> {
+    if window.is_focused()? && !window.is_minimized()? {
+        return Ok(());
+    }
+    tauri::api::notification::Notification::new(&window.config().tauri.bundle.identifier)
+        .title("Response ready")
+        .body(format!(
+            "Response ready – {} tokens",
+            CL100K_BASE.encode_with_special_tokens(content).len()
+        ))
+        .show()?;
     Ok(())
 }
 
 #[tauri::command]
-async fn get_chat_completion(request_id: u64) -> Result<Vec<String>, Error> {
-    let mut stream = CHAT_COMPLETION_RESPONSE.lock()?;
-    let vec = stream.entry(request_id).or_default();
-    let result = vec.clone();
-    vec.clear();
-    Ok(result)
+#[tracing::instrument(err)]
+async fn get_chat_completion(request_id: u64) -> Result<Vec<CompletionChunk>, Error> {
+    chunk_buffer::drain(request_id)
+}
+
+/// Returns the tool calls of the request that have been streamed completely, and removes them from the queue.
+#[tauri::command]
+#[tracing::instrument(err)]
+async fn get_chat_tool_calls(request_id: u64) -> Result<Vec<ToolCall>, Error> {
+    Ok(CHAT_TOOL_CALLS
+        .lock()?
+        .remove(&request_id)
+        .unwrap_or_default())
 }
 
 #[derive(serde::Serialize, serde::Deserialize)]
 struct Message {
     role: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
     name: Option<String>,
     content: String,
 }
 
+/// Returns (tokens_per_message, tokens_per_name).
+fn message_token_overheads(model: &str) -> (i64, i64) {
+    if model.starts_with("gpt-3.5-turbo-0301") {
+        (4, -1) // every message follows <|start|>{role/name}\n{content}<|end|>\n, and the role is omitted if a name is present
+    } else {
+        (3, 1)
+    }
+}
+
 /// https://github.com/openai/openai-cookbook/blob/main/examples/How_to_count_tokens_with_tiktoken.ipynb
 #[tauri::command]
+#[tracing::instrument(skip(messages), err)]
 async fn count_tokens(model: String, messages: Vec<Message>) -> Result<usize, Error> {
-    let mut request_messages = vec![];
+    with_bpe(&model, |bpe| count_message_tokens(bpe, &model, &messages))
+}
+
+/// Whether `count_tokens` approximates the model with cl100k_base, because tiktoken does not know its encoding.
+#[tauri::command]
+fn is_token_count_estimate(model: String) -> bool {
+    tiktoken_rs::tokenizer::get_tokenizer(&model).is_none()
+}
+
+/// Estimates the prompt tokens of a chat completion request body, or of a completion request body with a `prompt`.
+fn estimate_prompt_tokens(model: &str, body: &Value) -> i64 {
+    let count = |bpe: &tiktoken_rs::CoreBPE| {
+        if let Some(Ok(messages)) = body
+            .get("messages")
+            .map(|messages| serde_json::from_value::<Vec<Message>>(messages.clone()))
+        {
+            return count_message_tokens(bpe, model, &messages) as i64;
+        }
+        body.get("prompt")
+            .and_then(Value::as_str)
+            .map(|prompt| bpe.encode_with_special_tokens(prompt).len() as i64)
+            .unwrap_or(0)
+    };
+    with_bpe(model, count).unwrap_or_else(|_| count(&CL100K_BASE))
+}
+
+fn count_message_tokens(bpe: &tiktoken_rs::CoreBPE, model: &str, messages: &[Message]) -> usize {
+    let (tokens_per_message, tokens_per_name) = message_token_overheads(model);
+    let mut num_tokens = 3; // every reply is primed with <|start|>assistant<|message|>
     for m in messages {
-        request_messages.push(ChatCompletionRequestMessage {
-            content: m.content,
-            role: m.role,
-            name: m.name,
-        });
+        num_tokens += tokens_per_message;
+        num_tokens += bpe.encode_with_special_tokens(&m.role).len() as i64;
+        num_tokens += bpe.encode_with_special_tokens(&m.content).len() as i64;
+        if let Some(name) = &m.name {
+            num_tokens += bpe.encode_with_special_tokens(name).len() as i64 + tokens_per_name;
+        }
     }
-    Ok(tiktoken_rs::num_tokens_from_messages(&model, &request_messages).unwrap_or(0))
+    num_tokens.max(0) as usize
 }
+
+#[derive(serde::Serialize, serde::Deserialize)]
+struct OllamaModel {
+    name: String,
+    #[serde(default)]
+    size: u64,
+    #[serde(default)]
+    modified_at: String,
+    #[serde(default)]
+    digest: String,
+}
+
+/// Lists the models pulled into the local ollama server.
+#[tauri::command]
+#[tracing::instrument(err)]
+async fn list_ollama_models(base_url: Option<String>) -> Result<Vec<OllamaModel>, Error> {
+    #[derive(serde::Deserialize)]
+    struct Tags {
+        models: Vec<OllamaModel>,
+    }
+    let base_url = base_url.unwrap_or_else(|| OLLAMA_BASE_URL.to_owned());
+    let res = reqwest::get(format!("{}/api/tags", base_url.trim_end_matches('/'))).await?;
+    if res.status() != 200 {
+        return Err(Error::StatusIsNot200(format!(
+            "{}: {}",
+            res.status(),
+            res.text().await?
+        )));
+    }
+    Ok(serde_json::from_str::<Tags>(&res.text().await?)?.models)
+}
+
+/// The service name under which secrets are stored in the OS credential store (Windows Credential Manager, macOS Keychain, or Secret Service on Linux).
+const KEYRING_SERVICE: &str = "yy0931.chatgpt";
@@ -0,0 +1,707 @@
+//! A client of the Model Context Protocol (https://modelcontextprotocol.io), through which external servers provide
+//! tools and resources, e.g. a filesystem, GitHub, or a database.
+//!
+//! A server is either a command that speaks newline-delimited JSON-RPC over its stdin and stdout, or the URL of a
+//! Streamable HTTP endpoint. The servers are stored in the `mcpServer` table and connected in the background after the
+//! app starts. `start_chat_completion` with `mcp` adds the tools of the connected servers to OpenAI requests as
+//! `mcp<server id>_<tool name>`, and they are executed in the same loop as the built-in tools. The calls of the tools
+//! that the server does not mark as read-only are approved by the user first. Requests from the servers to the client,
+//! e.g. sampling, are not supported.
+
+use crate::{sse, AppState, Error};
+use serde_json::{json, Value};
+use sqlx::Row;
+use std::collections::{BTreeMap, HashMap};
+use std::process::Stdio;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tauri::Manager;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::sync::oneshot;
+
+const PROTOCOL_VERSION: &str = "2025-03-26";
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(60);
+/// OpenAI limits the names of functions to 64 characters.
+const MAX_FUNCTION_NAME_LEN: usize = 64;
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct McpTool {
+    name: String,
+    #[serde(default)]
+    description: Option<String>,
+    /// The JSON schema of the arguments.
+    #[serde(default)]
+    input_schema: Value,
+    #[serde(default)]
+    annotations: ToolAnnotations,
+}
+
+/// The hints of the server about the behavior of a tool, which are not verified.
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct ToolAnnotations {
+    /// Whether the tool does not modify its environment.
+    #[serde(default)]
+    read_only_hint: Option<bool>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct McpResource {
+    uri: String,
+    name: String,
+    #[serde(default)]
+    description: Option<String>,
+    #[serde(default)]
+    mime_type: Option<String>,
+}
+
+#[derive(serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct McpServer {
+    id: i64,
+    command_or_url: String,
+    /// The name reported by the server when it was added.
+    name: String,
+    tools: Vec<McpTool>,
+    resources: Vec<McpResource>,
+    /// Why the server could not be connected, in which case it has no tools or resources.
+    error: Option<String>,
+}
+
+/// The servers that have been connected, or failed to connect, since the app started, by id.
+#[derive(Default)]
+pub(crate) struct McpClients(Mutex<BTreeMap<i64, Result<Arc<Connection>, String>>>);
+
+/// Whether `connect_in_background` is connecting the servers.
+static CONNECTING: AtomicBool = AtomicBool::new(false);
+/// Incremented by `disconnect_all`, so that a connection made for the previous profile is not kept.
+static GENERATION: AtomicU64 = AtomicU64::new(0);
+
+type Pending = Arc<Mutex<HashMap<u64, oneshot::Sender<Value>>>>;
+
+enum Transport {
+    Stdio {
+        stdin: tokio::sync::Mutex<tokio::process::ChildStdin>,
+        /// The requests waiting for their responses, by id.
+        pending: Pending,
+        _child: tokio::process::Child, // killed when dropped
+    },
+    Http {
+        client: reqwest::Client,
+        url: String,
+        session_id: Mutex<Option<String>>,
+    },
+}
+
+/// Splits a command line into arguments at whitespace outside quotes.
+fn split_command(command_line: &str) -> Vec<String> {
+    let mut args = vec![];
+    let mut arg: Option<String> = None;
+    let mut quote = None;
+    for c in command_line.chars() {
+        match quote {
+            Some(q) if c == q => quote = None,
+            Some(_) => arg.get_or_insert_with(String::new).push(c),
+            None if c == '"' || c == '\'' => {
+                quote = Some(c);
+                arg.get_or_insert_with(String::new);
+            }
+            None if c.is_whitespace() => args.extend(arg.take()),
+            None => arg.get_or_insert_with(String::new).push(c),
+        }
+    }
+    args.extend(arg);
+    args
+}
+
+/// Passes a response read from the server to the request waiting for it.
+fn dispatch(pending: &Pending, message: Value) {
+    if message.get("method").is_some() {
+        return; // a request or notification from the server
+    }
+    let Some(id) = message.get("id").and_then(Value::as_u64) else {
+        return;
+    };
+    if let Some(sender) = pending.lock().ok().and_then(|mut p| p.remove(&id)) {
+        let _ = sender.send(message);
+    }
+}
+
+/// Reads the response to the request `id` from a response of the Streamable HTTP transport, which is either JSON or an
+/// event stream that may contain other messages of the server before the response.
+async fn read_http_response(mut res: reqwest::Response, id: u64) -> Result<Value, Error> {
+    let is_event_stream = res
+        .headers()
+        .get("Content-Type")
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|t| t.starts_with("text/event-stream"));
+    if !is_event_stream {
+        return Ok(serde_json::from_str(&res.text().await?)?);
+    }
+    let mut events = sse::Parser::default();
+    while let Some(chunk) = res.chunk().await? {
+        for event in events.feed(&chunk) {
+            let message: Value = serde_json::from_str(&event.data)?;
+            if message.get("method").is_none()
+                && message.get("id").and_then(Value::as_u64) == Some(id)
+            {
+                return Ok(message);
+            }
+        }
+    }
+    Err(Error::StringError(
+        "The MCP server closed the stream without a response".to_owned(),
+    ))
+}
+
+impl Transport {
+    fn spawn(command_line: &str) -> Result<Self, Error> {
+        let args = split_command(command_line);
+        let Some((program, args)) = args.split_first() else {
+            return Err(Error::StringError("The command is empty".to_owned()));
+        };
+        let mut command = if cfg!(windows) {
+            // npx and other scripts are not executables on Windows.
+            let mut command = tokio::process::Command::new("cmd");
+            command.arg("/C").arg(program);
+            command
+        } else {
+            tokio::process::Command::new(program)
+        };
+        let mut child = command
+            .args(args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()
+            .map_err(|err| Error::StringError(format!("Failed to run {program}: {err}")))?;
+        let stdin = child.stdin.take().expect("stdin is piped");
+        let stdout = child.stdout.take().expect("stdout is piped");
+        let stderr = child.stderr.take().expect("stderr is piped");
+
+        let pending = Pending::default();
+        let responses = Arc::clone(&pending);
+        tauri::async_runtime::spawn(async move {
+            let mut lines = BufReader::new(stdout).lines();
+            while let Ok(Some(line)) = lines.next_line().await {
+                match serde_json::from_str(&line) {
+                    Ok(message) => dispatch(&responses, message),
+                    Err(_) => tracing::debug!("non-JSON output of the MCP server: {line}"),
+                }
+            }
+            if let Ok(mut pending) = responses.lock() {
+                pending.clear(); // fails the waiting requests
+            }
+        });
+        let program = program.clone();
+        tauri::async_runtime::spawn(async move {
+            let mut lines = BufReader::new(stderr).lines();
+            while let Ok(Some(line)) = lines.next_line().await {
+                tracing::debug!("{program}: {line}");
+            }
+        });
+        Ok(Transport::Stdio {
+            stdin: tokio::sync::Mutex::new(stdin),
+            pending,
+            _child: child,
+        })
+    }
+
+    async fn write(
+        stdin: &tokio::sync::Mutex<tokio::process::ChildStdin>,
+        message: &Value,
+    ) -> Result<(), Error> {
+        let mut line = serde_json::to_vec(message)?;
+        line.push(b'\n');
+        let mut stdin = stdin.lock().await;
+        stdin.write_all(&line).await?;
+        stdin.flush().await?;
+        Ok(())
+    }
+
+    async fn post(
+        client: &reqwest::Client,
+        url: &str,
+        session_id: &Mutex<Option<String>>,
+        message: &Value,
+    ) -> Result<reqwest::Response, Error> {
+        let mut request = client
+            .post(url)
+            .header("Content-Type", "application/json")
+            .header("Accept", "application/json, text/event-stream")
+            .body(message.to_string());
+        if let Some(id) = session_id.lock()?.clone() {
+            request = request.header("Mcp-Session-Id", id);
+        }
+        let res = request.send().await?;
+        if !res.status().is_success() {
+            return Err(Error::StatusIsNot200(format!(
+                "{}: {}",
+                res.status(),
+                res.text().await?
+            )));
+        }
+        if let Some(id) = res
+            .headers()
+            .get("Mcp-Session-Id")
+            .and_then(|v| v.to_str().ok())
+        {
+            *session_id.lock()? = Some(id.to_owned());
+        }
+        Ok(res)
+    }
+
+    async fn request(&self, id: u64, message: &Value) -> Result<Value, Error> {
+        match self {
+            Transport::Stdio { stdin, pending, .. } => {
+                let (sender, receiver) = oneshot::channel();
+                pending.lock()?.insert(id, sender);
+                Self::write(stdin, message).await?;
+                receiver
+                    .await
+                    .map_err(|_| Error::StringError("The MCP server exited".to_owned()))
+            }
+            Transport::Http {
+                client,
+                url,
+                session_id,
+            } => {
+                let res = Self::post(client, url, session_id, message).await?;
+                read_http_response(res, id).await
+            }
+        }
+    }
+
+    async fn notify(&self, message: &Value) -> Result<(), Error> {
+        match self {
+            Transport::Stdio { stdin, .. } => Self::write(stdin, message).await,
+            Transport::Http {
+                client,
+                url,
+                session_id,
+            } => Self::post(client, url, session_id, message)
+                .await
+                .map(|_| ()),
+        }
+    }
+}
+
+struct Connection {
+    transport: Transport,
+    next_id: AtomicU64,
+    name: String,
+    tools: Vec<McpTool>,
+    resources: Vec<McpResource>,
+}
+
+impl Connection {
+    /// Starts the command or connects to the URL, initializes the session, and lists the tools and resources.
+    async fn connect(command_or_url: &str) -> Result<Self, Error> {
+        let transport =
+            if command_or_url.starts_with("http://") || command_or_url.starts_with("https://") {
+                Transport::Http {
+                    client: reqwest::Client::new(),
+                    url: command_or_url.to_owned(),
+                    session_id: Mutex::new(None),
+                }
+            } else {
+                Transport::spawn(command_or_url)?
+            };
+        let mut connection = Connection {
+            transport,
+            next_id: AtomicU64::new(1),
+            name: String::new(),
+            tools: vec![],
+            resources: vec![],
+        };
+        let result = connection
+            .request(
+                "initialize",
+                json!({
+                    "protocolVersion": PROTOCOL_VERSION,
+                    "capabilities": {},
+                    "clientInfo": { "name": env!("CARGO_PKG_NAME"), "version": env!("CARGO_PKG_VERSION") },
+                }),
+            )
+            .await?;
+        connection
+            .transport
+            .notify(&json!({ "jsonrpc": "2.0", "method": "notifications/initialized" }))
+            .await?;
+        connection.name = result
+            .pointer("/serverInfo/name")
+            .and_then(Value::as_str)
+            .unwrap_or(command_or_url)
+            .to_owned();
+        if result.pointer("/capabilities/tools").is_some() {
+            connection.tools = connection.list("tools/list", "tools").await?;
+        }
+        if result.pointer("/capabilities/resources").is_some() {
+            connection.resources = connection.list("resources/list", "resources").await?;
+        }
+        Ok(connection)
+    }
+
+    async fn request(&self, method: &str, params: Value) -> Result<Value, Error> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let message = json!({ "jsonrpc": "2.0", "id": id, "method": method, "params": params });
+        let response = tokio::time::timeout(REQUEST_TIMEOUT, self.transport.request(id, &message))
+            .await
+            .map_err(|_| Error::StringError(format!("{method} of the MCP server timed out")))??;
+        if let Some(error) = response.get("error") {
+            return Err(Error::StringError(format!(
+                "{method} of the MCP server failed: {}",
+                error
+                    .get("message")
+                    .and_then(Value::as_str)
+                    .unwrap_or("unknown error")
+            )));
+        }
+        Ok(response.get("result").cloned().unwrap_or_default())
+    }
+
+    /// Fetches all pages of a paginated list.
+    async fn list<T: serde::de::DeserializeOwned>(
+        &self,
+        method: &str,
+        field: &str,
+    ) -> Result<Vec<T>, Error> {
+        let mut items = vec![];
+        let mut cursor: Option<String> = None;
+        loop {
+            let params = match &cursor {
+                Some(cursor) => json!({ "cursor": cursor }),
+                None => json!({}),
+            };
+            let mut result = self.request(method, params).await?;
+            let page = result
+                .get_mut(field)
+                .map(Value::take)
+                .unwrap_or_else(|| json!([]));
+            items.extend(serde_json::from_value::<Vec<T>>(page)?);
+            cursor = result
+                .get("nextCursor")
+                .and_then(Value::as_str)
+                .map(str::to_owned);
+            if cursor.is_none() {
+                return Ok(items);
+            }
+        }
+    }
+}
+
+/// The name of the function given to the model for the tool, which is unique among the servers.
+fn function_name(server_id: i64, tool: &str) -> String {
+    format!("mcp{server_id}_{tool}")
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '_' || c == '-' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .take(MAX_FUNCTION_NAME_LEN)
+        .collect()
+}
+
+/// Connects the servers that have not been connected since the app started. Failures are remembered, and retried only
+/// with `retry_failed`.
+async fn connect_all(state: &AppState, retry_failed: bool) -> Result<(), Error> {
+    let generation = GENERATION.load(Ordering::SeqCst);
+    let mut conn = state.db_pool.acquire().await?;
+    let servers = sqlx::query("SELECT id, commandOrUrl FROM mcpServer")
+        .fetch_all(&mut conn)
+        .await?;
+    drop(conn);
+    for row in servers {
+        let id: i64 = row.get("id");
+        let connected = match state.mcp.0.lock()?.get(&id) {
+            Some(Ok(_)) => true,
+            Some(Err(_)) => !retry_failed,
+            None => false,
+        };
+        if connected {
+            continue;
+        }
+        let command_or_url: String = row.get("commandOrUrl");
+        let connection = Connection::connect(&command_or_url)
+            .await
+            .map(Arc::new)
+            .map_err(|err| err.to_string());
+        if let Err(err) = &connection {
+            tracing::warn!("failed to connect to the MCP server {id}: {err}");
+        }
+        let mut connections = state.mcp.0.lock()?;
+        if GENERATION.load(Ordering::SeqCst) != generation {
+            return Ok(());
+        }
+        connections.insert(id, connection);
+    }
+    Ok(())
+}
+
+/// Disconnects all the servers, stopping their processes. Called when the profile, and so the servers, change.
+pub(crate) fn disconnect_all(state: &AppState) -> Result<(), Error> {
+    let mut connections = state.mcp.0.lock()?;
+    GENERATION.fetch_add(1, Ordering::SeqCst);
+    connections.clear();
+    Ok(())
+}
+
+/// Connects the servers that have not been connected since the app started in a background task, so that a chat
+/// completion does not wait for a slow server. Their tools are given to the model from the next request.
+pub(crate) fn connect_in_background(app: &tauri::AppHandle) {
+    if CONNECTING.swap(true, Ordering::SeqCst) {
+        return;
+    }
+    let app = app.clone();
+    tauri::async_runtime::spawn(async move {
+        if let Err(err) = connect_all(&app.state::<AppState>(), false).await {
+            tracing::warn!("failed to connect to the MCP servers: {err}");
+        }
+        CONNECTING.store(false, Ordering::SeqCst);
+    });
+}
+
+fn connected(state: &AppState, id: i64) -> Result<Arc<Connection>, Error> {
+    match state.mcp.0.lock()?.get(&id) {
+        Some(Ok(connection)) => Ok(Arc::clone(connection)),
+        Some(Err(err)) => Err(Error::StringError(err.clone())),
+        None => Err(Error::StringError(format!(
+            "MCP server {id} does not exist"
+        ))),
+    }
+}
+
+/// Finds the connection and the tool by the name of its function.
+fn find_tool(state: &AppState, name: &str) -> Result<Option<(Arc<Connection>, McpTool)>, Error> {
+    for (&id, connection) in state.mcp.0.lock()?.iter() {
+        let Ok(connection) = connection else {
+            continue;
+        };
+        if let Some(tool) = connection
+            .tools
+            .iter()
+            .find(|tool| function_name(id, &tool.name) == name)
+        {
+            return Ok(Some((Arc::clone(connection), tool.clone())));
+        }
+    }
+    Ok(None)
+}
+
+/// Returns the names of the functions of the tools of the connected servers, and connects the others in the background.
+pub(crate) fn function_names(app: &tauri::AppHandle) -> Result<Vec<String>, Error> {
+    connect_in_background(app);
+    Ok(app
+        .state::<AppState>()
+        .mcp
+        .0
+        .lock()?
+        .iter()
+        .filter_map(|(&id, connection)| Some((id, connection.as_ref().ok()?)))
+        .flat_map(|(id, connection)| {
+            connection
+                .tools
+                .iter()
+                .map(move |tool| function_name(id, &tool.name))
+        })
+        .collect())
+}
+
+/// Returns the definition of the function in the format of OpenAI's `tools`, if it is a tool of a connected server.
+pub(crate) fn definition(state: &AppState, name: &str) -> Result<Option<Value>, Error> {
+    Ok(find_tool(state, name)?.map(|(_, tool)| {
+        let parameters = if tool.input_schema.is_object() {
+            tool.input_schema
+        } else {
+            json!({ "type": "object", "properties": {} })
+        };
+        json!({
+            "type": "function",
+            "function": {
+                "name": name,
+                "description": tool.description.unwrap_or_default(),
+                "parameters": parameters,
+            },
+        })
+    }))
+}
+
+/// Whether a call of the tool has to be approved by the user, i.e. unless the server marks it as read-only.
+pub(crate) fn requires_approval(state: &AppState, name: &str) -> Result<bool, Error> {
+    Ok(find_tool(state, name)?.map_or(true, |(_, tool)| {
+        tool.annotations.read_only_hint != Some(true)
+    }))
+}
+
+/// Joins the text of the content of a tool result. Other types of content, e.g. images, are replaced with their type.
+fn content_text(content: Option<&Value>) -> String {
+    content
+        .and_then(Value::as_array)
+        .into_iter()
+        .flatten()
+        .map(|item| match item.get("type").and_then(Value::as_str) {
+            Some("text") => item
+                .get("text")
+                .and_then(Value::as_str)
+                .unwrap_or_default()
+                .to_owned(),
+            Some("resource") => resource_text(item.get("resource").unwrap_or(&Value::Null)),
+            Some(other) => format!("[{other}]"),
+            None => String::new(),
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Returns the text of the contents of a resource, or its URI if it is binary.
+fn resource_text(contents: &Value) -> String {
+    match contents.get("text").and_then(Value::as_str) {
+        Some(text) => text.to_owned(),
+        None => format!(
+            "[binary {}]",
+            contents
+                .get("uri")
+                .and_then(Value::as_str)
+                .unwrap_or_default()
+        ),
+    }
+}
+
+/// Calls the tool by the name of its function and returns the result given to the model.
+pub(crate) async fn call_tool(
+    state: &AppState,
+    name: &str,
+    arguments: &str,
+) -> Result<String, Error> {
+    let (connection, tool) = find_tool(state, name)?
+        .ok_or_else(|| Error::StringError(format!("Unknown tool: {name}")))?;
+    let arguments: Value = serde_json::from_str(arguments)?;
+    let result = connection
+        .request(
+            "tools/call",
+            json!({ "name": tool.name, "arguments": arguments }),
+        )
+        .await?;
+    let text = content_text(result.get("content"));
+    if result.get("isError").and_then(Value::as_bool) == Some(true) {
+        return Err(Error::StringError(text));
+    }
+    Ok(text)
+}
+
+fn server(
+    id: i64,
+    command_or_url: String,
+    name: String,
+    connection: Option<&Result<Arc<Connection>, String>>,
+) -> McpServer {
+    let (tools, resources, error) = match connection {
+        Some(Ok(connection)) => (connection.tools.clone(), connection.resources.clone(), None),
+        Some(Err(err)) => (vec![], vec![], Some(err.clone())),
+        None => (vec![], vec![], None),
+    };
+    McpServer {
+        id,
+        command_or_url,
+        name,
+        tools,
+        resources,
+        error,
+    }
+}
+
+/// Connects to a server, e.g. "npx -y @modelcontextprotocol/server-filesystem /path/to/dir" or
+/// "https://example.com/mcp", and saves it if it works. Its tools are given to the model from the next request.
+#[tauri::command]
+#[tracing::instrument(skip(command_or_url, state), err)]
+pub(crate) async fn add_mcp_server(
+    command_or_url: String,
+    state: tauri::State<'_, AppState>,
+) -> Result<McpServer, Error> {
+    let command_or_url = command_or_url.trim().to_owned();
+    let connection = Arc::new(Connection::connect(&command_or_url).await?);
+    let name = connection.name.clone();
+    let mut conn = state.db_pool.acquire().await?;
+    let id = sqlx::query("INSERT INTO mcpServer (commandOrUrl, name) VALUES (?, ?)")
+        .bind(&command_or_url)
+        .bind(&name)
+        .execute(&mut conn)
+        .await?
+        .last_insert_rowid();
+    let connection: Result<_, String> = Ok(connection);
+    let server = server(id, command_or_url, name, Some(&connection));
+    state.mcp.0.lock()?.insert(id, connection);
+    Ok(server)
+}
+
+/// Lists the servers with their tools and resources, retrying the connection to the servers that failed.
+#[tauri::command]
+#[tracing::instrument(skip(state), err)]
+pub(crate) async fn list_mcp_servers(
+    state: tauri::State<'_, AppState>,
+) -> Result<Vec<McpServer>, Error> {
+    connect_all(&state, true).await?;
+    let mut conn = state.db_pool.acquire().await?;
+    let rows = sqlx::query("SELECT id, commandOrUrl, name FROM mcpServer ORDER BY id")
+        .fetch_all(&mut conn)
+        .await?;
+    let connections = state.mcp.0.lock()?;
+    Ok(rows
+        .into_iter()
+        .map(|row| {
+            let id = row.get("id");
+            server(
+                id,
+                row.get("commandOrUrl"),
+                row.get("name"),
+                connections.get(&id),
+            )
+        })
+        .collect())
+}
+
+/// Deletes the server and stops its process.
+#[tauri::command]
+#[tracing::instrument(skip(state), err)]
+pub(crate) async fn remove_mcp_server(
+    id: i64,
+    state: tauri::State<'_, AppState>,
+) -> Result<(), Error> {
+    let mut conn = state.db_pool.acquire().await?;
+    sqlx::query("DELETE FROM mcpServer WHERE id = ?")
+        .bind(id)
+        .execute(&mut conn)
+        .await?;
+    state.mcp.0.lock()?.remove(&id);
+    Ok(())
+}
+
+/// Returns the text of a resource listed by the server. Binary contents are replaced with their URI.
+#[tauri::command]
+#[tracing::instrument(skip(state), err)]
+pub(crate) async fn read_mcp_resource(
+    server_id: i64,
+    uri: String,
+    state: tauri::State<'_, AppState>,
+) -> Result<String, Error> {
+    connect_all(&state, false).await?;
+    let connection = connected(&state, server_id)?;
+    let result = connection
+        .request("resources/read", json!({ "uri": uri }))
+        .await?;
+    Ok(result
+        .get("contents")
+        .and_then(Value::as_array)
+        .into_iter()
+        .flatten()
+        .map(resource_text)
+        .collect::<Vec<_>>()
+        .join("\n"))
+}
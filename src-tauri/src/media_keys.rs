@@ -0,0 +1,138 @@
+//! The media keys and the media overlay of the OS (the System Media Transport Controls on Windows, the Now Playing
+//! widget on macOS, and MPRIS on Linux) control the speech through souvlaki.
+//!
+//! Play/pause pauses and resumes the speech, next and previous skip sentences, and stop is handled by the frontend like the
+//! "Stop speaking" item of the tray menu, which also clears the queue of the speech. The media controls are owned by a
+//! thread, since they are not Send on every platform, and the thread updates them with the progress of the speech.
+//!
+//! The speech is published as the media being played: its title is the sentence being spoken, or "Speech" if the speech
+//! is not played sentence by sentence, and its album is the name of the conversation.
+
+use crate::audio_engine::{Navigation, PlaybackStatus};
+use crate::{AppState, Error};
+use souvlaki::{
+    MediaControlEvent, MediaControls, MediaMetadata, MediaPlayback, MediaPosition, PlatformConfig,
+};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Receiver};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tauri::{AppHandle, Manager};
+
+const DBUS_NAME: &str = "chatgptui_desktop";
+const DISPLAY_NAME: &str = "ChatGPT";
+
+/// Handles a media key. The keys other than play are ignored while no speech is being played, so that they do not pause
+/// the speech that starts later.
+fn handle_event(app: &AppHandle, playing: &AtomicBool, event: MediaControlEvent) {
+    let engine = &app.state::<AppState>().audio.engine;
+    let playing = playing.load(Ordering::SeqCst);
+    let result = match event {
+        MediaControlEvent::Play => engine.resume(),
+        MediaControlEvent::Pause if playing => engine.pause(),
+        MediaControlEvent::Toggle if playing && engine.is_paused() => engine.resume(),
+        MediaControlEvent::Toggle if playing => engine.pause(),
+        MediaControlEvent::Next if playing => {
+            engine.navigate(Navigation::SkipSentence { forward: true })
+        }
+        MediaControlEvent::Previous if playing => {
+            engine.navigate(Navigation::SkipSentence { forward: false })
+        }
+        MediaControlEvent::SetPosition(MediaPosition(position)) if playing => {
+            engine.navigate(Navigation::Seek(position))
+        }
+        MediaControlEvent::Stop if playing => app
+            .emit_all("tray://action", "speaker.stop")
+            .map_err(Error::from),
+        _ => Ok(()),
+    };
+    if let Err(err) = result {
+        tracing::warn!("failed to handle the media key {event:?}: {err}");
+    }
+}
+
+fn run(
+    app: AppHandle,
+    hwnd: Option<isize>,
+    statuses: Receiver<PlaybackStatus>,
+) -> Result<(), Error> {
+    let mut controls = MediaControls::new(PlatformConfig {
+        dbus_name: DBUS_NAME,
+        display_name: DISPLAY_NAME,
+        hwnd: hwnd.map(|hwnd| hwnd as *mut std::ffi::c_void),
+    })
+    .map_err(|err| Error::StringError(format!("failed to create the media controls: {err:?}")))?;
+    let playing = Arc::new(AtomicBool::new(false));
+    {
+        let playing = playing.clone();
+        controls
+            .attach(move |event| handle_event(&app, &playing, event))
+            .map_err(|err| {
+                Error::StringError(format!("failed to attach to the media controls: {err:?}"))
+            })?;
+    }
+    let mut metadata = None;
+    for status in statuses {
+        playing.store(status.playing, Ordering::SeqCst);
+        let progress = Some(MediaPosition(Duration::from_millis(status.position_ms)));
+        let playback = match (status.playing, status.paused) {
+            (false, _) => MediaPlayback::Stopped,
+            (true, true) => MediaPlayback::Paused { progress },
+            (true, false) => MediaPlayback::Playing { progress },
+        };
+        // The duration grows while the sentences are synthesized.
+        let status_metadata = (
+            status.sentence.clone(),
+            status.title.clone(),
+            status.duration_ms,
+        );
+        if status.playing && metadata.as_ref() != Some(&status_metadata) {
+            let result = controls.set_metadata(MediaMetadata {
+                title: Some(status.sentence.as_deref().unwrap_or("Speech")),
+                album: status.title.as_deref(),
+                artist: Some(DISPLAY_NAME),
+                duration: Some(Duration::from_millis(status.duration_ms)),
+                ..Default::default()
+            });
+            if let Err(err) = result {
+                tracing::warn!("failed to update the media controls: {err:?}");
+            }
+            metadata = Some(status_metadata);
+        }
+        if let Err(err) = controls.set_playback(playback) {
+            tracing::warn!("failed to update the media controls: {err:?}");
+        }
+    }
+    Ok(())
+}
+
+/// Starts the thread that owns the media controls and forwards the progress of the speech to it.
+pub(crate) fn start(app: &AppHandle) -> Result<(), Error> {
+    // The media controls of Windows are tied to a window.
+    #[cfg(target_os = "windows")]
+    let hwnd = match app.get_window("main") {
+        Some(window) => Some(window.hwnd()?.0),
+        None => None,
+    };
+    #[cfg(not(target_os = "windows"))]
+    let hwnd = None;
+
+    let (sender, receiver) = channel();
+    let handle = app.clone();
+    std::thread::Builder::new()
+        .name("media-keys".to_owned())
+        .spawn(move || {
+            if let Err(err) = run(handle, hwnd, receiver) {
+                tracing::error!("{err}");
+            }
+        })?;
+    let sender = Mutex::new(sender);
+    app.state::<AppState>()
+        .audio
+        .engine
+        .add_listener(move |status| {
+            if let Ok(sender) = sender.lock() {
+                let _ = sender.send(status.clone());
+            }
+        })
+}
@@ -0,0 +1,369 @@
+//! Optional clean-up of the microphone input, applied to the mixed-down samples before they are encoded, and the
+//! suppression of the echo of the assistant's speech.
+
+use crate::{AppState, Error};
+use nnnoiseless::DenoiseState;
+use std::time::Duration;
+
+/// RNNoise only supports this sample rate.
+const RNNOISE_SAMPLE_RATE: u32 = 48000;
+
+/// How quickly the noise gate opens and closes, to avoid clicks.
+const GATE_SMOOTHING_SECS: f32 = 0.005;
+/// How long the gate stays open after the level drops below the threshold, so that the ends of words are not cut.
+const GATE_HOLD_SECS: f32 = 0.2;
+
+/// The level that automatic gain control normalizes speech to, -20 dBFS.
+const AGC_TARGET_RMS: f32 = 0.1;
+/// Blocks quieter than this are treated as silence and do not lower the measured level.
+const AGC_SILENCE_RMS: f32 = 0.003;
+const AGC_BLOCK_SECS: f32 = 0.02;
+/// The gain is also limited so that the peak stays below this amplitude.
+const AGC_MAX_PEAK: f32 = 0.99;
+
+/// The length of the blocks whose level decides whether they are silent.
+const TRIM_BLOCK_SECS: f32 = 0.02;
+/// The silence kept around the speech, so that the beginnings and ends of words are not cut.
+const TRIM_PADDING_SECS: f32 = 0.2;
+/// A block is silent if it is quieter than the noise floor, the 10th percentile of the levels of the blocks, by this
+/// factor (+6 dB), or than `AGC_SILENCE_RMS`.
+const TRIM_NOISE_FLOOR_MARGIN: f32 = 2.0;
+
+/// How long the level of the speech is held, to cover the latency between the speakers and the microphone.
+const ECHO_HOLD_SECS: f32 = 0.25;
+/// The speech is considered silent below this RMS.
+const ECHO_SPEECH_SILENCE_RMS: f32 = 0.001;
+/// The input is the user's voice, rather than the echo, if it is this much louder than the estimated echo (+6 dB).
+const ECHO_DOUBLE_TALK_MARGIN: f32 = 2.0;
+/// The user's voice is quieter than this RMS is ignored.
+const ECHO_VOICE_MIN_RMS: f32 = 0.01;
+/// How quickly the suppression starts and ends, to avoid clicks.
+const ECHO_SMOOTHING_SECS: f32 = 0.01;
+/// How long the user has to speak over the speech for a barge-in.
+const BARGE_IN_SECS: f32 = 0.2;
+
+#[derive(serde::Deserialize, Clone, Debug, Default)]
+#[serde(rename_all = "camelCase", default)]
+pub(crate) struct MicProcessingOptions {
+    /// RNNoise, if the input device runs at 48 kHz.
+    pub(crate) noise_suppression: bool,
+    /// The cutoff frequency of the first-order high-pass filter, e.g. 80 to remove rumble and hum.
+    pub(crate) high_pass_hz: Option<f32>,
+    /// Input below this amplitude in [0, 1] is muted.
+    pub(crate) noise_gate_threshold: Option<f32>,
+}
+
+/// Applies `MicProcessingOptions` to a mono stream.
+pub(crate) struct MicProcessor {
+    denoise: Option<(Box<DenoiseState<'static>>, Vec<f32>)>,
+    high_pass: Option<HighPass>,
+    noise_gate: Option<NoiseGate>,
+}
+
+impl MicProcessor {
+    pub(crate) fn new(options: &MicProcessingOptions, sample_rate: u32) -> Self {
+        let denoise = if options.noise_suppression {
+            if sample_rate == RNNOISE_SAMPLE_RATE {
+                Some((
+                    DenoiseState::new(),
+                    Vec::with_capacity(DenoiseState::FRAME_SIZE),
+                ))
+            } else {
+                tracing::warn!(
+                    sample_rate,
+                    "noise suppression requires a 48 kHz input device"
+                );
+                None
+            }
+        } else {
+            None
+        };
+        Self {
+            denoise,
+            high_pass: options
+                .high_pass_hz
+                .map(|cutoff| HighPass::new(cutoff, sample_rate)),
+            noise_gate: options
+                .noise_gate_threshold
+                .map(|threshold| NoiseGate::new(threshold, sample_rate)),
+        }
+    }
+
+    /// Processes samples in [-1, 1]. RNNoise works on 10 ms frames, so the output may lag behind the input.
+    pub(crate) fn process(&mut self, input: &[f32]) -> Vec<f32> {
+        let mut samples = input.to_vec();
+        if let Some(high_pass) = &mut self.high_pass {
+            for x in &mut samples {
+                *x = high_pass.process(*x);
+            }
+        }
+        if let Some((state, buf)) = &mut self.denoise {
+            let mut output = vec![];
+            let mut frame_out = vec![0f32; DenoiseState::FRAME_SIZE];
+            for x in samples {
+                buf.push(x * i16::MAX as f32); // RNNoise expects the range of 16-bit PCM
+                if buf.len() == DenoiseState::FRAME_SIZE {
+                    state.process_frame(&mut frame_out, buf);
+                    output.extend(frame_out.iter().map(|x| x / i16::MAX as f32));
+                    buf.clear();
+                }
+            }
+            samples = output;
+        }
+        if let Some(noise_gate) = &mut self.noise_gate {
+            for x in &mut samples {
+                *x = noise_gate.process(*x);
+            }
+        }
+        samples
+    }
+}
+
+struct HighPass {
+    alpha: f32,
+    prev_x: f32,
+    prev_y: f32,
+}
+
+impl HighPass {
+    fn new(cutoff_hz: f32, sample_rate: u32) -> Self {
+        let rc = 1.0 / (2.0 * std::f32::consts::PI * cutoff_hz.max(1.0));
+        let dt = 1.0 / sample_rate as f32;
+        Self {
+            alpha: rc / (rc + dt),
+            prev_x: 0.0,
+            prev_y: 0.0,
+        }
+    }
+
+    fn process(&mut self, x: f32) -> f32 {
+        let y = self.alpha * (self.prev_y + x - self.prev_x);
+        (self.prev_x, self.prev_y) = (x, y);
+        y
+    }
+}
+
+struct NoiseGate {
+    threshold: f32,
+    hold_samples: u32,
+    held: u32,
+    smoothing: f32,
+    gain: f32,
+}
+
+impl NoiseGate {
+    fn new(threshold: f32, sample_rate: u32) -> Self {
+        Self {
+            threshold,
+            hold_samples: (GATE_HOLD_SECS * sample_rate as f32) as u32,
+            held: 0,
+            smoothing: 1.0 - (-1.0 / (GATE_SMOOTHING_SECS * sample_rate as f32)).exp(),
+            gain: 0.0,
+        }
+    }
+
+    fn process(&mut self, x: f32) -> f32 {
+        if x.abs() >= self.threshold {
+            self.held = 0;
+        } else {
+            self.held = self.held.saturating_add(1);
+        }
+        let target = if self.held <= self.hold_samples {
+            1.0
+        } else {
+            0.0
+        };
+        self.gain += (target - self.gain) * self.smoothing;
+        x * self.gain
+    }
+}
+
+/// An echo suppressor: mutes the microphone while it only picks up the assistant's speech from the speakers, and lets
+/// the user's voice through when it is clearly louder than the echo. The ratio of the echo to the speech is learned
+/// while the speech is played, and decreases quickly but increases slowly, so that it follows the echo rather than
+/// the user's voice.
+pub(crate) struct EchoSuppressor {
+    sample_rate: u32,
+    /// The level of the speech, held for `ECHO_HOLD_SECS`.
+    speech_level: f32,
+    /// The estimated ratio of the level of the echo to the level of the speech.
+    coupling: f32,
+    gain: f32,
+    /// How long the user has been speaking over the speech, in seconds.
+    double_talk: f32,
+}
+
+impl EchoSuppressor {
+    pub(crate) fn new(sample_rate: u32) -> Self {
+        Self {
+            sample_rate,
+            speech_level: 0.0,
+            coupling: 1.0,
+            gain: 1.0,
+            double_talk: 0.0,
+        }
+    }
+
+    /// Suppresses the echo in a block of mono samples, given the current level of the speech being played. Returns
+    /// true once the user has spoken over the speech for `BARGE_IN_SECS`.
+    pub(crate) fn process(&mut self, samples: &mut [f32], speech_level: f32) -> bool {
+        if samples.is_empty() {
+            return false;
+        }
+        let secs = samples.len() as f32 / self.sample_rate as f32;
+        self.speech_level = speech_level.max(self.speech_level * (-secs / ECHO_HOLD_SECS).exp());
+        let rms = (samples.iter().map(|x| x * x).sum::<f32>() / samples.len() as f32).sqrt();
+
+        let speech_is_playing = self.speech_level >= ECHO_SPEECH_SILENCE_RMS;
+        let talking = if speech_is_playing {
+            let ratio = rms / self.speech_level;
+            let rate = if ratio < self.coupling { 0.2 } else { 0.001 };
+            self.coupling += (ratio - self.coupling) * rate;
+            rms > ECHO_VOICE_MIN_RMS
+                && rms > self.coupling * self.speech_level * ECHO_DOUBLE_TALK_MARGIN
+        } else {
+            true
+        };
+
+        let target = if talking { 1.0 } else { 0.0 };
+        let smoothing = 1.0 - (-1.0 / (ECHO_SMOOTHING_SECS * self.sample_rate as f32)).exp();
+        for x in samples.iter_mut() {
+            self.gain += (target - self.gain) * smoothing;
+            *x *= self.gain;
+        }
+
+        if speech_is_playing && talking {
+            self.double_talk += secs;
+        } else {
+            self.double_talk = 0.0;
+        }
+        if self.double_talk >= BARGE_IN_SECS {
+            self.double_talk = 0.0;
+            return true;
+        }
+        false
+    }
+}
+
+/// Automatic gain control: scales a whole 16-bit recording so that the RMS of its non-silent parts reaches `AGC_TARGET_RMS`,
+/// amplifying by at most `max_gain_db` and without clipping. Returns the applied gain in dB.
+pub(crate) fn normalize_gain(samples: &mut [i32], sample_rate: u32, max_gain_db: f32) -> f32 {
+    let full_scale = i16::MAX as f32;
+    let block_size = ((AGC_BLOCK_SECS * sample_rate as f32) as usize).max(1);
+    let (mut sum_squares, mut count, mut peak) = (0f64, 0usize, 0f32);
+    for block in samples.chunks(block_size) {
+        let block_sum_squares = block
+            .iter()
+            .map(|&x| (x as f64 / full_scale as f64).powi(2))
+            .sum::<f64>();
+        if ((block_sum_squares / block.len() as f64).sqrt() as f32) < AGC_SILENCE_RMS {
+            continue;
+        }
+        sum_squares += block_sum_squares;
+        count += block.len();
+        peak = block
+            .iter()
+            .map(|&x| (x as f32 / full_scale).abs())
+            .fold(peak, f32::max);
+    }
+    if count == 0 {
+        return 0.0;
+    }
+    let rms = (sum_squares / count as f64).sqrt() as f32;
+    let gain = (AGC_TARGET_RMS / rms)
+        .min(10f32.powf(max_gain_db / 20.0))
+        .min(AGC_MAX_PEAK / peak);
+    for x in samples.iter_mut() {
+        *x = (*x as f32 * gain).clamp(-full_scale, full_scale) as i32;
+    }
+    20.0 * gain.log10()
+}
+
+/// How `trim_silence` shortens a recording.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct SilenceTrimming {
+    /// The pauses in the speech longer than this are shortened to it, or kept as is if None.
+    pub(crate) max_pause: Option<Duration>,
+}
+
+/// Removes the silence at the beginning and the end of a 16-bit recording except for `TRIM_PADDING_SECS`, and shortens
+/// the long pauses, which reduces the upload and keeps Whisper from hallucinating text in the silence. A recording with
+/// no speech is kept as is. Returns the length of the removed audio.
+pub(crate) fn trim_silence(
+    samples: &mut Vec<i32>,
+    sample_rate: u32,
+    options: SilenceTrimming,
+) -> Duration {
+    let full_scale = i16::MAX as f32;
+    let block_size = ((TRIM_BLOCK_SECS * sample_rate as f32) as usize).max(1);
+    let levels = samples
+        .chunks(block_size)
+        .map(|block| {
+            let sum_squares = block
+                .iter()
+                .map(|&x| (x as f32 / full_scale).powi(2))
+                .sum::<f32>();
+            (sum_squares / block.len() as f32).sqrt()
+        })
+        .collect::<Vec<_>>();
+    let mut sorted = levels.clone();
+    sorted.sort_by(f32::total_cmp);
+    let Some(&noise_floor) = sorted.get(sorted.len() / 10) else {
+        return Duration::ZERO;
+    };
+    let threshold = (noise_floor * TRIM_NOISE_FLOOR_MARGIN).max(AGC_SILENCE_RMS);
+    let is_sound = levels
+        .iter()
+        .map(|&level| level >= threshold)
+        .collect::<Vec<_>>();
+    let (Some(first), Some(last)) = (
+        is_sound.iter().position(|&b| b),
+        is_sound.iter().rposition(|&b| b),
+    ) else {
+        return Duration::ZERO;
+    };
+
+    // The ranges of blocks to keep.
+    let padding = (TRIM_PADDING_SECS / TRIM_BLOCK_SECS).ceil() as usize;
+    let max_pause = options
+        .max_pause
+        .map(|pause| ((pause.as_secs_f32() / TRIM_BLOCK_SECS) as usize).max(2 * padding));
+    let mut kept = vec![first.saturating_sub(padding)..(last + 1 + padding).min(levels.len())];
+    if let Some(max_pause) = max_pause {
+        let range = kept.pop().unwrap_or_default();
+        let mut start = range.start;
+        let mut i = first;
+        while i <= last {
+            let pause_len = is_sound[i..=last].iter().take_while(|&&b| !b).count();
+            if pause_len > max_pause {
+                // Half of the kept pause after the speech, and half before the following speech.
+                kept.push(start..i + max_pause / 2);
+                start = i + pause_len - (max_pause - max_pause / 2);
+            }
+            i += pause_len.max(1);
+        }
+        kept.push(start..range.end);
+    }
+
+    let original_len = samples.len();
+    let trimmed = kept
+        .into_iter()
+        .flat_map(|blocks| {
+            let end = (blocks.end * block_size).min(original_len);
+            samples[blocks.start * block_size..end].iter().copied()
+        })
+        .collect::<Vec<_>>();
+    *samples = trimmed;
+    Duration::from_secs_f64((original_len - samples.len()) as f64 / sample_rate as f64)
+}
+
+/// Sets the processing applied to the following recordings. Every stage is disabled by default.
+#[tauri::command]
+#[tracing::instrument(skip(state), err)]
+pub(crate) fn set_mic_processing(
+    options: MicProcessingOptions,
+    state: tauri::State<AppState>,
+) -> Result<(), Error> {
+    *state.audio.mic_processing.lock()? = options;
+    Ok(())
+}
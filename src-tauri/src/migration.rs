@@ -0,0 +1,47 @@
+//! Versioned schema migrations.
+//!
+//! create_tables.sql only contains idempotent `CREATE ... IF NOT EXISTS` statements, which cannot change existing tables. Changes
+//! such as new columns are appended to `MIGRATIONS` instead (and must not be added to create_tables.sql), and are applied once
+//! and in order after create_tables.sql. `PRAGMA user_version` records the number of migrations that have been applied.
+
+use crate::Error;
+use sqlx::{Connection, Row};
+
+const MIGRATIONS: &[&str] = &[
+    // 1: index the messages stored before the full-text search was introduced
+    "INSERT INTO messageFTS (messageFTS) VALUES ('rebuild')",
+    // 2: least-recently-used eviction of the TTS caches (unix time)
+    "
+ALTER TABLE messageTTSCache ADD COLUMN lastAccessedAt INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE systemTTSCache ADD COLUMN lastAccessedAt INTEGER NOT NULL DEFAULT 0;
+",
+    // 3: the ids that match the messages across computers, assigned on the first sync
+    "
+ALTER TABLE message ADD COLUMN syncId TEXT;
+CREATE UNIQUE INDEX IF NOT EXISTS messageSyncId ON message (syncId);
+",
+    // 4: the synced messages that were deleted, so that the deletion is synced too
+    "
+CREATE TABLE syncTombstone (syncId TEXT NOT NULL PRIMARY KEY) STRICT;
+CREATE TRIGGER trigger_message_sync_tombstone AFTER DELETE ON message WHEN OLD.syncId IS NOT NULL
+BEGIN
+    INSERT OR IGNORE INTO syncTombstone (syncId) VALUES (OLD.syncId);
+END;
+",
+];
+
+pub(crate) async fn migrate(conn: &mut sqlx::SqliteConnection) -> Result<(), Error> {
+    let version: i64 = sqlx::query("PRAGMA user_version")
+        .fetch_one(&mut *conn)
+        .await?
+        .get(0);
+    for (i, migration) in MIGRATIONS.iter().enumerate().skip(version.max(0) as usize) {
+        let mut tx = conn.begin().await?;
+        sqlx::query(migration).execute(&mut *tx).await?;
+        sqlx::query(&format!("PRAGMA user_version = {}", i + 1))
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+    }
+    Ok(())
+}
@@ -0,0 +1,47 @@
+//! A compact always-on-top window that shows the progress of the speech and controls it, e.g. while the main window is
+//! minimized.
+
+use crate::{AppState, Error};
+use tauri::{AppHandle, Manager};
+
+const MINI_PLAYER_WINDOW: &str = "mini-player";
+
+/// Emits the progress reported by the audio engine to every window as `audio://playback`.
+pub(crate) fn forward_playback_events(app: &AppHandle) -> Result<(), Error> {
+    let handle = app.clone();
+    app.state::<AppState>()
+        .audio
+        .engine
+        .add_listener(move |status| {
+            let _ = handle.emit_all("audio://playback", status);
+        })
+}
+
+/// Opens the mini player, or focuses it if it is already open.
+#[tauri::command]
+#[tracing::instrument(skip(app), err)]
+pub(crate) fn open_mini_player(app: AppHandle) -> Result<(), Error> {
+    if let Some(window) = app.get_window(MINI_PLAYER_WINDOW) {
+        window.set_focus()?;
+        return Ok(());
+    }
+    tauri::WindowBuilder::new(
+        &app,
+        MINI_PLAYER_WINDOW,
+        tauri::WindowUrl::App("index.html#mini-player".into()),
+    )
+    .title("Speech")
+    .inner_size(360.0, 116.0)
+    .resizable(false)
+    .always_on_top(true)
+    .skip_taskbar(true)
+    .build()?;
+    Ok(())
+}
+
+/// Sets the speed of speech, in [0.5, 2]. The pitch changes with the speed.
+#[tauri::command]
+#[tracing::instrument(skip(state))]
+pub(crate) fn set_playback_speed(speed: f32, state: tauri::State<AppState>) {
+    state.audio.engine.set_speed(speed.clamp(0.5, 2.0));
+}
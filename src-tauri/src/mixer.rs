@@ -0,0 +1,242 @@
+//! Coordinates the notification beeps and the dictation with speech playback.
+//!
+//! While speech is played, beeps are ducked to `beep_ducking` times their volume, or suppressed if it is 0, and are restored
+//! once the speech ends.
+//!
+//! The `BargeIn` policy decides what happens to the speech when the user dictates over it. The level of the speech is
+//! metered as it is played, so that the echo suppression of the recorder can tell the assistant's voice from the user's.
+
+use crate::audio_engine::AudioEngine;
+use crate::{AppState, AtomicF32, Error};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+const DEFAULT_BEEP_DUCKING: f32 = 0.2;
+/// The volume of the speech while the user dictates with `BargeIn::Duck`.
+const SPEECH_DUCKING: f32 = 0.25;
+/// The length of the blocks over which the level of the speech is metered.
+const SPEECH_LEVEL_BLOCK: Duration = Duration::from_millis(10);
+
+/// What happens to the speech being played when the user starts dictating.
+#[derive(serde::Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub(crate) enum BargeIn {
+    /// The speech is stopped when the dictation starts. The frontend stops it, along with the queued speech.
+    #[default]
+    Stop,
+    /// The speech is played at a lower volume during the dictation.
+    Duck,
+    /// The speech keeps playing until the user's voice is heard, and then stops.
+    Voice,
+}
+
+/// How often a beep re-evaluates its volume, so that a long sound is ducked as soon as speech starts.
+const BEEP_VOLUME_UPDATE_INTERVAL: Duration = Duration::from_millis(20);
+
+pub(crate) struct Mixer {
+    /// The number of speech playbacks in progress.
+    speaking: AtomicU32,
+    beep_ducking: AtomicF32,
+    /// The number of dictations in progress.
+    recording: AtomicU32,
+    barge_in: Mutex<BargeIn>,
+    /// The RMS of the speech being played, before its volume is applied.
+    speech_level: Arc<AtomicF32>,
+}
+
+impl Default for Mixer {
+    fn default() -> Self {
+        Self {
+            speaking: AtomicU32::new(0),
+            beep_ducking: AtomicF32::new(DEFAULT_BEEP_DUCKING),
+            recording: AtomicU32::new(0),
+            barge_in: Mutex::new(BargeIn::default()),
+            speech_level: Arc::new(AtomicF32::new(0.0)),
+        }
+    }
+}
+
+/// Marks speech as playing until dropped.
+pub(crate) struct SpeechGuard<'a>(&'a Mixer);
+
+impl Drop for SpeechGuard<'_> {
+    fn drop(&mut self) {
+        if self.0.speaking.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.0.speech_level.store(0.0, Ordering::SeqCst);
+        }
+    }
+}
+
+/// Marks a dictation as in progress until dropped.
+pub(crate) struct RecordingGuard<'a>(&'a Mixer);
+
+impl Drop for RecordingGuard<'_> {
+    fn drop(&mut self) {
+        self.0.recording.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Meters the level of the sources of speech for `Mixer::speech_level`.
+#[derive(Clone)]
+pub(crate) struct SpeechMeter(Arc<AtomicF32>);
+
+impl SpeechMeter {
+    pub(crate) fn meter<S>(&self, source: S) -> Metered<S> {
+        Metered {
+            source,
+            level: self.0.clone(),
+            sum_squares: 0.0,
+            count: 0,
+        }
+    }
+}
+
+/// A source that stores its level in `Mixer::speech_level` as it is played.
+pub(crate) struct Metered<S> {
+    source: S,
+    level: Arc<AtomicF32>,
+    sum_squares: f32,
+    count: usize,
+}
+
+impl<S> Iterator for Metered<S>
+where
+    S: rodio::Source,
+    S::Item: rodio::Sample,
+{
+    type Item = S::Item;
+
+    fn next(&mut self) -> Option<S::Item> {
+        let sample = self.source.next()?;
+        let x = rodio::Sample::to_f32(sample);
+        self.sum_squares += x * x;
+        self.count += 1;
+        let block = (self.source.sample_rate() as f32
+            * self.source.channels() as f32
+            * SPEECH_LEVEL_BLOCK.as_secs_f32()) as usize;
+        if self.count >= block.max(1) {
+            let rms = (self.sum_squares / self.count as f32).sqrt();
+            self.level.store(rms, Ordering::SeqCst);
+            self.sum_squares = 0.0;
+            self.count = 0;
+        }
+        Some(sample)
+    }
+}
+
+impl<S> rodio::Source for Metered<S>
+where
+    S: rodio::Source,
+    S::Item: rodio::Sample,
+{
+    fn current_frame_len(&self) -> Option<usize> {
+        self.source.current_frame_len()
+    }
+
+    fn channels(&self) -> u16 {
+        self.source.channels()
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.source.sample_rate()
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        self.source.total_duration()
+    }
+}
+
+impl Mixer {
+    pub(crate) fn start_speech(&self) -> SpeechGuard<'_> {
+        self.speaking.fetch_add(1, Ordering::SeqCst);
+        SpeechGuard(self)
+    }
+
+    pub(crate) fn start_recording(&self) -> RecordingGuard<'_> {
+        self.recording.fetch_add(1, Ordering::SeqCst);
+        RecordingGuard(self)
+    }
+
+    pub(crate) fn is_speaking(&self) -> bool {
+        self.speaking.load(Ordering::SeqCst) > 0
+    }
+
+    pub(crate) fn barge_in(&self) -> BargeIn {
+        self.barge_in
+            .lock()
+            .map_or(BargeIn::default(), |policy| *policy)
+    }
+
+    /// The volume to play speech at, which is lowered while the user dictates with `BargeIn::Duck`.
+    pub(crate) fn speech_volume(&self) -> f32 {
+        if self.recording.load(Ordering::SeqCst) > 0 && self.barge_in() == BargeIn::Duck {
+            SPEECH_DUCKING
+        } else {
+            1.0
+        }
+    }
+
+    /// The level of the speech coming out of the speakers, or 0 if no speech is played.
+    pub(crate) fn speech_level(&self) -> f32 {
+        if self.is_speaking() {
+            self.speech_level.load(Ordering::SeqCst) * self.speech_volume()
+        } else {
+            0.0
+        }
+    }
+
+    pub(crate) fn speech_meter(&self) -> SpeechMeter {
+        SpeechMeter(self.speech_level.clone())
+    }
+
+    /// The volume to play a beep at, given its nominal volume.
+    pub(crate) fn beep_volume(&self, volume: f32) -> f32 {
+        if self.speaking.load(Ordering::SeqCst) > 0 {
+            volume * self.beep_ducking.load(Ordering::SeqCst)
+        } else {
+            volume
+        }
+    }
+
+    /// Plays a notification sound at the ducked volume, blocking until it ends.
+    pub(crate) fn play_cue<S>(
+        &self,
+        engine: &AudioEngine,
+        source: S,
+        volume: f32,
+    ) -> Result<(), Error>
+    where
+        S: rodio::Source + Send + 'static,
+        S::Item: rodio::Sample,
+        f32: dasp_sample::FromSample<S::Item>,
+    {
+        let sink = engine.sink()?;
+        sink.set_volume(self.beep_volume(volume));
+        sink.append(source);
+        sink.sleep_until_end_while(BEEP_VOLUME_UPDATE_INTERVAL, || {
+            sink.set_volume(self.beep_volume(volume));
+            true
+        });
+        Ok(())
+    }
+}
+
+/// Sets the factor applied to the volume of the beeps while speech is played, in [0, 1]. 0 suppresses them.
+#[tauri::command]
+#[tracing::instrument(skip(state))]
+pub(crate) fn set_beep_ducking(volume: f32, state: tauri::State<AppState>) {
+    state
+        .audio
+        .mixer
+        .beep_ducking
+        .store(volume.clamp(0.0, 1.0), Ordering::SeqCst);
+}
+
+/// Sets what happens to the speech being played when the user starts dictating.
+#[tauri::command]
+#[tracing::instrument(skip(state), err)]
+pub(crate) fn set_barge_in(policy: BargeIn, state: tauri::State<AppState>) -> Result<(), Error> {
+    *state.audio.mixer.barge_in.lock()? = policy;
+    Ok(())
+}
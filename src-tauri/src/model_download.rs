@@ -0,0 +1,312 @@
+//! Downloads of the model files of the local Whisper and the local LLMs into `<app data>/models`.
+//!
+//! A file is downloaded to `<name>.part` and renamed when its SHA-256 matches, so a file under its own name is always
+//! complete. An interrupted download is resumed with a `Range` request, and started over if the server ignores it. If the
+//! server rejects the range with 416, the part is already complete and only verified. Before
+//! anything is downloaded, the free space of the disk is checked against the remaining size.
+//!
+//! The window receives `model-download://progress` while a file is downloaded, at most every `PROGRESS_INTERVAL`, and
+//! then either `model-download://finished` or `model-download://failed`.
+
+use crate::Error;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use sysinfo::{DiskExt, System, SystemExt};
+use tauri::{AppHandle, Manager};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(250);
+/// Left free on the disk after the download.
+const DISK_SPACE_MARGIN: u64 = 512 * 1024 * 1024;
+
+lazy_static::lazy_static! {
+    static ref NEXT_ID: AtomicU64 = AtomicU64::new(1);
+    /// The file of each download in progress, and the flag set to cancel it, by id.
+    static ref DOWNLOADS: Mutex<HashMap<u64, (PathBuf, Arc<AtomicBool>)>> = Mutex::new(HashMap::new());
+}
+
+#[derive(serde::Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+struct Progress {
+    id: u64,
+    file_name: String,
+    /// In bytes, including the part downloaded before a resume.
+    downloaded: u64,
+    /// None if the server does not tell the size.
+    total: Option<u64>,
+}
+
+#[derive(serde::Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+struct Finished {
+    id: u64,
+    path: PathBuf,
+}
+
+#[derive(serde::Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+struct Failed {
+    id: u64,
+    error: String,
+}
+
+#[derive(serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct DownloadedModel {
+    file_name: String,
+    path: PathBuf,
+    size: u64,
+}
+
+pub(crate) fn models_dir(app: &AppHandle) -> Result<PathBuf, Error> {
+    Ok(app
+        .path_resolver()
+        .app_data_dir()
+        .ok_or_else(|| Error::StringError("The app data directory is unknown".to_owned()))?
+        .join("models"))
+}
+
+/// Returns the last path segment of the URL, which must be a plain file name.
+fn file_name_of(url: &reqwest::Url) -> Result<String, Error> {
+    let name = url
+        .path_segments()
+        .and_then(|segments| segments.last())
+        .unwrap_or_default();
+    if name.is_empty() || name.starts_with('.') || name.contains(['\\', '%']) {
+        return Err(Error::StringError(format!(
+            "The URL does not end with a file name: {url}"
+        )));
+    }
+    Ok(name.to_owned())
+}
+
+/// Returns the free space of the disk that contains the path, or None if the disk is not found.
+fn available_space(path: &Path) -> Option<u64> {
+    let mut system = System::new();
+    system.refresh_disks_list();
+    system
+        .disks()
+        .iter()
+        .filter(|disk| path.starts_with(disk.mount_point()))
+        .max_by_key(|disk| disk.mount_point().as_os_str().len())
+        .map(|disk| disk.available_space())
+}
+
+/// Hashes the part downloaded before, so that the hash of the whole file is known when the rest has been downloaded.
+async fn hash_file(path: &Path, hasher: &mut Sha256) -> Result<u64, Error> {
+    let mut file = tokio::fs::File::open(path).await?;
+    let mut buf = vec![0; 1024 * 1024];
+    let mut len = 0;
+    loop {
+        let n = file.read(&mut buf).await?;
+        if n == 0 {
+            return Ok(len);
+        }
+        hasher.update(&buf[..n]);
+        len += n as u64;
+    }
+}
+
+async fn download(
+    app: &AppHandle,
+    id: u64,
+    url: reqwest::Url,
+    sha256: &str,
+    path: &Path,
+    canceled: &AtomicBool,
+) -> Result<(), Error> {
+    let file_name = file_name_of(&url)?;
+    let part = path.with_file_name(format!("{file_name}.part"));
+    let mut hasher = Sha256::new();
+    let mut downloaded = if part.exists() {
+        hash_file(&part, &mut hasher).await?
+    } else {
+        0
+    };
+
+    let mut request = reqwest::Client::new().get(url);
+    if downloaded > 0 {
+        request = request.header(reqwest::header::RANGE, format!("bytes={downloaded}-"));
+    }
+    let mut res = request.send().await?;
+    if downloaded > 0 && res.status() == reqwest::StatusCode::RANGE_NOT_SATISFIABLE {
+        // The part is complete, e.g. because the app quit before it was renamed.
+        return verify_and_rename(hasher, sha256, &part, path, &file_name).await;
+    }
+    let resumed = res.status() == reqwest::StatusCode::PARTIAL_CONTENT;
+    if !resumed && res.status() != 200 {
+        return Err(Error::StatusIsNot200(format!(
+            "{}: {}",
+            res.status(),
+            res.text().await?
+        )));
+    }
+    if !resumed {
+        hasher = Sha256::new();
+        downloaded = 0;
+    }
+    let remaining = res.content_length();
+    let total = remaining.map(|remaining| downloaded + remaining);
+    if let (Some(remaining), Some(available)) = (remaining, available_space(path)) {
+        if remaining + DISK_SPACE_MARGIN > available {
+            return Err(Error::StringError(format!(
+                "Not enough disk space: {file_name} needs {} MiB more, but {} MiB is free",
+                (remaining + DISK_SPACE_MARGIN) / 1024 / 1024,
+                available / 1024 / 1024
+            )));
+        }
+    }
+
+    let mut file = tokio::fs::OpenOptions::new()
+        .create(true)
+        .write(true)
+        .append(resumed)
+        .truncate(!resumed)
+        .open(&part)
+        .await?;
+    let mut last_progress = Instant::now();
+    while let Some(chunk) = res.chunk().await? {
+        if canceled.load(Ordering::SeqCst) {
+            // The part is kept, so that the download can be resumed.
+            file.flush().await?;
+            return Err(Error::StringError("The download was canceled".to_owned()));
+        }
+        file.write_all(&chunk).await?;
+        hasher.update(&chunk);
+        downloaded += chunk.len() as u64;
+        if last_progress.elapsed() >= PROGRESS_INTERVAL {
+            last_progress = Instant::now();
+            app.emit_all(
+                "model-download://progress",
+                Progress {
+                    id,
+                    file_name: file_name.clone(),
+                    downloaded,
+                    total,
+                },
+            )?;
+        }
+    }
+    file.flush().await?;
+    drop(file);
+    verify_and_rename(hasher, sha256, &part, path, &file_name).await
+}
+
+/// Renames the part to the file if its hash matches, and deletes it otherwise.
+async fn verify_and_rename(
+    hasher: Sha256,
+    sha256: &str,
+    part: &Path,
+    path: &Path,
+    file_name: &str,
+) -> Result<(), Error> {
+    let actual = hasher
+        .finalize()
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect::<String>();
+    if !actual.eq_ignore_ascii_case(sha256.trim()) {
+        tokio::fs::remove_file(part).await?;
+        return Err(Error::StringError(format!(
+            "The checksum of {file_name} does not match: expected {sha256}, got {actual}"
+        )));
+    }
+    tokio::fs::rename(part, path).await?;
+    Ok(())
+}
+
+/// Starts downloading the file of the URL into the models directory, and returns the id of the download. The file is
+/// named after the last segment of the URL, and `sha256` is its expected hash in hex.
+#[tauri::command]
+#[tracing::instrument(skip(app), err)]
+pub(crate) async fn start_model_download(
+    app: AppHandle,
+    url: String,
+    sha256: String,
+) -> Result<u64, Error> {
+    let url = reqwest::Url::parse(&url)
+        .map_err(|err| Error::StringError(format!("Invalid URL: {err}")))?;
+    if sha256.trim().len() != 64 || !sha256.trim().chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(Error::StringError(
+            "The SHA-256 must be 64 hexadecimal digits".to_owned(),
+        ));
+    }
+    let dir = models_dir(&app)?;
+    tokio::fs::create_dir_all(&dir).await?;
+    let path = dir.join(file_name_of(&url)?);
+    let id = NEXT_ID.fetch_add(1, Ordering::SeqCst);
+    let canceled = Arc::new(AtomicBool::new(false));
+    {
+        let mut downloads = DOWNLOADS.lock()?;
+        if downloads.values().any(|(other, _)| *other == path) {
+            return Err(Error::StringError(format!(
+                "{} is already being downloaded",
+                path.display()
+            )));
+        }
+        downloads.insert(id, (path.clone(), Arc::clone(&canceled)));
+    }
+    tauri::async_runtime::spawn(async move {
+        let result = download(&app, id, url, &sha256, &path, &canceled).await;
+        if let Ok(mut downloads) = DOWNLOADS.lock() {
+            downloads.remove(&id);
+        }
+        let emitted = match result {
+            Ok(()) => app.emit_all("model-download://finished", Finished { id, path }),
+            Err(err) => {
+                tracing::warn!("failed to download a model: {err}");
+                app.emit_all(
+                    "model-download://failed",
+                    Failed {
+                        id,
+                        error: err.to_string(),
+                    },
+                )
+            }
+        };
+        if let Err(err) = emitted {
+            tracing::error!("{err}");
+        }
+    });
+    Ok(id)
+}
+
+/// Stops the download. Its part is kept, and starting the same download again resumes it.
+#[tauri::command]
+#[tracing::instrument(err)]
+pub(crate) fn cancel_model_download(id: u64) -> Result<(), Error> {
+    if let Some((_, canceled)) = DOWNLOADS.lock()?.get(&id) {
+        canceled.store(true, Ordering::SeqCst);
+    }
+    Ok(())
+}
+
+/// Lists the completely downloaded files of the models directory.
+#[tauri::command]
+#[tracing::instrument(skip(app), err)]
+pub(crate) async fn list_downloaded_models(app: AppHandle) -> Result<Vec<DownloadedModel>, Error> {
+    let dir = models_dir(&app)?;
+    if !dir.exists() {
+        return Ok(vec![]);
+    }
+    let mut models = vec![];
+    let mut entries = tokio::fs::read_dir(&dir).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let file_name = entry.file_name().to_string_lossy().into_owned();
+        let metadata = entry.metadata().await?;
+        if !metadata.is_file() || file_name.ends_with(".part") {
+            continue;
+        }
+        models.push(DownloadedModel {
+            file_name,
+            path: entry.path(),
+            size: metadata.len(),
+        });
+    }
+    models.sort_by(|a, b| a.file_name.cmp(&b.file_name));
+    Ok(models)
+}
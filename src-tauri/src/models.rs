@@ -0,0 +1,186 @@
+//! The models of a provider, for the model picker in the settings.
+//!
+//! `/v1/models` of OpenAI lists every model the key can use, including embeddings, speech, and image models, and tells
+//! neither whether a model can chat nor the size of its context window. The chat models are recognized by their names,
+//! and the context windows are looked up in the table of `context_window()`. Gemini and OpenRouter report both, and every
+//! model of ollama is assumed to chat.
+
+use crate::context_window::context_window;
+use crate::{openrouter, ChatProvider, Error, GEMINI_BASE_URL, OLLAMA_BASE_URL};
+use serde_json::Value;
+
+const OPENAI_MODELS_URL: &str = "https://api.openai.com/v1/models";
+
+/// The prefixes of the OpenAI chat models.
+const CHAT_MODEL_PREFIXES: [&str; 5] = ["gpt-3.5-turbo", "gpt-4", "chatgpt-", "o1", "o3"];
+/// The models with a chat prefix that are not chat models, e.g. "gpt-3.5-turbo-instruct" and "gpt-4o-realtime-preview".
+const NON_CHAT_MODEL_MARKERS: [&str; 5] = ["instruct", "audio", "realtime", "transcribe", "tts"];
+
+#[derive(serde::Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct ModelInfo {
+    /// The name passed as `model`.
+    id: String,
+    /// The number of tokens of the prompt and the response.
+    context_window: usize,
+    /// The maximum number of tokens of the response, if the provider reports it.
+    max_output_tokens: Option<usize>,
+}
+
+/// Whether the OpenAI model can be used with the chat completions.
+fn is_chat_model(id: &str) -> bool {
+    CHAT_MODEL_PREFIXES
+        .iter()
+        .any(|prefix| id.starts_with(prefix))
+        && !NON_CHAT_MODEL_MARKERS
+            .iter()
+            .any(|marker| id.contains(marker))
+}
+
+async fn get_json(request: reqwest::RequestBuilder) -> Result<Value, Error> {
+    let res = request.send().await?;
+    if res.status() != 200 {
+        return Err(Error::StatusIsNot200(format!(
+            "{}: {}",
+            res.status(),
+            res.text().await?
+        )));
+    }
+    Ok(serde_json::from_str(&res.text().await?)?)
+}
+
+fn openai_models(body: &Value) -> Vec<ModelInfo> {
+    body.get("data")
+        .and_then(Value::as_array)
+        .into_iter()
+        .flatten()
+        .filter_map(|model| model.get("id").and_then(Value::as_str))
+        .filter(|id| is_chat_model(id))
+        .map(|id| ModelInfo {
+            id: id.to_owned(),
+            context_window: context_window(id),
+            max_output_tokens: None,
+        })
+        .collect()
+}
+
+fn gemini_models(body: &Value) -> Vec<ModelInfo> {
+    let limit = |model: &Value, name: &str| {
+        model
+            .get(name)
+            .and_then(Value::as_u64)
+            .map(|limit| limit as usize)
+    };
+    body.get("models")
+        .and_then(Value::as_array)
+        .into_iter()
+        .flatten()
+        .filter(|model| {
+            model
+                .get("supportedGenerationMethods")
+                .and_then(Value::as_array)
+                .map_or(false, |methods| {
+                    methods
+                        .iter()
+                        .any(|method| method == "streamGenerateContent")
+                })
+        })
+        .filter_map(|model| {
+            let id = model
+                .get("name")
+                .and_then(Value::as_str)?
+                .trim_start_matches("models/");
+            let max_output_tokens = limit(model, "outputTokenLimit");
+            Some(ModelInfo {
+                id: id.to_owned(),
+                context_window: limit(model, "inputTokenLimit").map_or_else(
+                    || context_window(id),
+                    |input| input + max_output_tokens.unwrap_or(0),
+                ),
+                max_output_tokens,
+            })
+        })
+        .collect()
+}
+
+fn openrouter_models(body: &Value) -> Vec<ModelInfo> {
+    let limit = |model: &Value, pointer: &str| {
+        model
+            .pointer(pointer)
+            .and_then(Value::as_u64)
+            .map(|limit| limit as usize)
+    };
+    body.get("data")
+        .and_then(Value::as_array)
+        .into_iter()
+        .flatten()
+        .filter(|model| {
+            // e.g. "text+image->text"
+            model
+                .pointer("/architecture/modality")
+                .and_then(Value::as_str)
+                .map_or(true, |modality| modality.ends_with("->text"))
+        })
+        .filter_map(|model| {
+            let id = model.get("id").and_then(Value::as_str)?;
+            Some(ModelInfo {
+                id: id.to_owned(),
+                context_window: limit(model, "/context_length")
+                    .unwrap_or_else(|| context_window(id)),
+                max_output_tokens: limit(model, "/top_provider/max_completion_tokens"),
+            })
+        })
+        .collect()
+}
+
+fn ollama_models(body: &Value) -> Vec<ModelInfo> {
+    body.get("models")
+        .and_then(Value::as_array)
+        .into_iter()
+        .flatten()
+        .filter_map(|model| model.get("name").and_then(Value::as_str))
+        .map(|id| ModelInfo {
+            id: id.to_owned(),
+            context_window: context_window(id),
+            max_output_tokens: None,
+        })
+        .collect()
+}
+
+/// Lists the chat models of the provider, sorted by name. `key` is the API key of OpenAI or Gemini, and is not used for
+/// ollama and OpenRouter, whose lists are public.
+#[tauri::command]
+#[tracing::instrument(skip(key), err)]
+pub(crate) async fn list_models(
+    provider: ChatProvider,
+    key: String,
+) -> Result<Vec<ModelInfo>, Error> {
+    let client = reqwest::Client::new();
+    let mut models = match provider {
+        ChatProvider::OpenAI => {
+            openai_models(&get_json(client.get(OPENAI_MODELS_URL).bearer_auth(key)).await?)
+        }
+        ChatProvider::Gemini => gemini_models(
+            &get_json(
+                client
+                    .get(format!("{GEMINI_BASE_URL}/models"))
+                    .query(&[("key", key.as_str()), ("pageSize", "1000")]),
+            )
+            .await?,
+        ),
+        ChatProvider::OpenRouter => openrouter_models(
+            &get_json(client.get(format!("{}/models", openrouter::BASE_URL))).await?,
+        ),
+        ChatProvider::Ollama => {
+            ollama_models(&get_json(client.get(format!("{OLLAMA_BASE_URL}/api/tags"))).await?)
+        }
+    };
+    models.sort_by(|a, b| a.id.cmp(&b.id));
+    Ok(models)
+}
+
+/// Returns the context window of the model, for the warning shown when a conversation does not fit in it.
+#[tauri::command]
+pub(crate) fn get_context_window(model: String) -> usize {
+    context_window(&model)
+}
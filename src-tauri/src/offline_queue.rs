@@ -0,0 +1,255 @@
+//! Holds chat completion requests made while the network is down and sends them when it is back.
+//!
+//! With `queue_when_offline`, a request that fails to connect is saved in the `pendingRequest` table, with its secret key
+//! in the keyring, and the window receives `chat-completion://queued` ({ requestId }). The request checks the
+//! connectivity to its endpoint every `CHECK_INTERVAL`, and once online the window receives
+//! `chat-completion://dequeued` ({ requestId }) and the request is sent as usual.
+//!
+//! Requests left in the table when the app quit are replayed by `watch`. Their response is written to the last
+//! unfinished assistant message of the conversation, and the main window receives `chat-completion://replayed`
+//! ({ requestId, conversationId }).
+
+use crate::{
+    chunk_buffer, openrouter, profile, send_chat_completion, AppState, ChatProvider, Error,
+    CHAT_COMPLETION_CANCELED, GEMINI_BASE_URL, OLLAMA_BASE_URL,
+};
+use sqlx::Row;
+use std::collections::HashMap;
+use std::time::Duration;
+use tauri::Manager;
+
+const CHECK_INTERVAL: Duration = Duration::from_secs(10);
+const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+/// Replayed requests have no window polling their chunks, so they are drained here instead.
+const DRAIN_INTERVAL: Duration = Duration::from_millis(500);
+
+/// The arguments of `send_chat_completion` other than the secret key, as they were given.
+#[derive(serde::Serialize, serde::Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct QueuedRequest {
+    pub(crate) body: String,
+    pub(crate) endpoint: String,
+    pub(crate) api_key_authentication: bool,
+    pub(crate) provider: Option<ChatProvider>,
+    pub(crate) max_retries: Option<u32>,
+    pub(crate) connect_timeout_secs: Option<u64>,
+    pub(crate) stall_timeout_secs: Option<u64>,
+    pub(crate) headers: Option<HashMap<String, String>>,
+    pub(crate) query: Option<HashMap<String, String>>,
+    pub(crate) conversation_id: Option<i64>,
+    pub(crate) model: Option<String>,
+    pub(crate) tools: Option<Vec<String>>,
+    pub(crate) mcp: Option<bool>,
+    pub(crate) stop_sequences: Option<Vec<String>>,
+    pub(crate) max_output_tokens: Option<u32>,
+}
+
+#[derive(serde::Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+struct QueueEvent {
+    request_id: u64,
+}
+
+#[derive(serde::Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+struct ReplayedEvent {
+    request_id: u64,
+    conversation_id: Option<i64>,
+}
+
+fn secret_entry(request_id: u64) -> Result<keyring::Entry, Error> {
+    profile::keyring_entry(&format!("pendingRequest.{request_id}"))
+}
+
+/// The URL whose reachability tells whether the request can be sent.
+fn probe_url(request: &QueuedRequest) -> &str {
+    match request.provider {
+        _ if !request.endpoint.is_empty() => &request.endpoint,
+        Some(ChatProvider::Ollama) => OLLAMA_BASE_URL,
+        Some(ChatProvider::Gemini) => GEMINI_BASE_URL,
+        Some(ChatProvider::OpenRouter) => openrouter::BASE_URL,
+        _ => &request.endpoint,
+    }
+}
+
+/// Any response, including an error status, means that the server is reachable.
+async fn is_online(url: &str) -> bool {
+    let Ok(client) = reqwest::Client::builder().timeout(PROBE_TIMEOUT).build() else {
+        return false;
+    };
+    client.head(url).send().await.is_ok()
+}
+
+async fn save(
+    state: &AppState,
+    request_id: u64,
+    secret_key: &str,
+    request: &QueuedRequest,
+) -> Result<(), Error> {
+    secret_entry(request_id)?.set_password(secret_key)?;
+    let mut conn = state.db_pool.acquire().await?;
+    sqlx::query("INSERT OR REPLACE INTO pendingRequest (requestId, request) VALUES (?, ?)")
+        .bind(request_id as i64)
+        .bind(serde_json::to_string(request)?)
+        .execute(&mut conn)
+        .await?;
+    Ok(())
+}
+
+async fn remove(state: &AppState, request_id: u64) -> Result<(), Error> {
+    let mut conn = state.db_pool.acquire().await?;
+    sqlx::query("DELETE FROM pendingRequest WHERE requestId = ?")
+        .bind(request_id as i64)
+        .execute(&mut conn)
+        .await?;
+    match secret_entry(request_id)?.delete_password() {
+        Ok(()) | Err(keyring::Error::NoEntry) => Ok(()),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Queues the request and waits until its endpoint is reachable. Returns false if the request was canceled meanwhile.
+pub(crate) async fn wait_until_online(
+    window: &tauri::Window,
+    state: &AppState,
+    request_id: u64,
+    secret_key: &str,
+    request: &QueuedRequest,
+) -> Result<bool, Error> {
+    save(state, request_id, secret_key, request).await?;
+    window.emit("chat-completion://queued", QueueEvent { request_id })?;
+    loop {
+        tokio::time::sleep(CHECK_INTERVAL).await;
+        if CHAT_COMPLETION_CANCELED.lock()?.contains(&request_id) {
+            remove(state, request_id).await?;
+            return Ok(false);
+        }
+        if is_online(probe_url(request)).await {
+            remove(state, request_id).await?;
+            window.emit("chat-completion://dequeued", QueueEvent { request_id })?;
+            return Ok(true);
+        }
+    }
+}
+
+/// Sends a request queued before the app quit and stores the response in the conversation.
+async fn replay(
+    app: &tauri::AppHandle,
+    request_id: u64,
+    request: QueuedRequest,
+) -> Result<(), Error> {
+    let window = app
+        .get_window("main")
+        .ok_or_else(|| Error::StringError("The main window does not exist".to_owned()))?;
+    let state = app.state::<AppState>();
+    let secret_key = match secret_entry(request_id)?.get_password() {
+        Ok(secret_key) => secret_key,
+        Err(keyring::Error::NoEntry) => String::new(),
+        Err(err) => return Err(err.into()),
+    };
+    remove(&state, request_id).await?;
+    window.emit("chat-completion://dequeued", QueueEvent { request_id })?;
+
+    let conversation_id = request.conversation_id;
+    let completion = send_chat_completion(
+        window.clone(),
+        request_id,
+        secret_key,
+        request.body,
+        request.endpoint,
+        request.api_key_authentication,
+        request.provider,
+        request.max_retries,
+        request.connect_timeout_secs,
+        request.stall_timeout_secs,
+        request.headers,
+        request.query,
+        request.conversation_id,
+        request.model,
+        request.tools,
+        request.mcp,
+        Some(true),
+        request.stop_sequences,
+        request.max_output_tokens,
+        app.state::<AppState>(),
+    );
+    tokio::pin!(completion);
+    let mut chunks = vec![];
+    let result = loop {
+        tokio::select! {
+            result = &mut completion => break result,
+            _ = tokio::time::sleep(DRAIN_INTERVAL) => chunks.extend(chunk_buffer::drain(request_id)?),
+        }
+    };
+    chunks.extend(chunk_buffer::drain(request_id)?);
+    let (status, content) = match result {
+        Ok(()) => (
+            0,
+            chunks
+                .iter()
+                .filter_map(|chunk| chunk.content.as_deref())
+                .collect::<String>(),
+        ),
+        Err(err) => (1, err.to_string()),
+    };
+    if let Some(conversation_id) = conversation_id {
+        let mut conn = state.db_pool.acquire().await?;
+        sqlx::query(
+            "
+WITH RECURSIVE descendants(id) AS (
+    SELECT ?1
+    UNION ALL
+    SELECT message.id FROM message JOIN descendants ON message.parent = descendants.id
+)
+UPDATE message SET content = ?2, status = ?3
+WHERE id = (SELECT id FROM message WHERE id IN descendants AND role = 'assistant' AND status = -1 ORDER BY id DESC LIMIT 1)
+",
+        )
+        .bind(conversation_id)
+        .bind(content)
+        .bind(status)
+        .execute(&mut conn)
+        .await?;
+    }
+    window.emit(
+        "chat-completion://replayed",
+        ReplayedEvent {
+            request_id,
+            conversation_id,
+        },
+    )?;
+    Ok(())
+}
+
+async fn replay_all(app: &tauri::AppHandle) -> Result<(), Error> {
+    let rows = {
+        let state = app.state::<AppState>();
+        let mut conn = state.db_pool.acquire().await?;
+        sqlx::query("SELECT requestId, request FROM pendingRequest ORDER BY createdAt")
+            .fetch_all(&mut conn)
+            .await?
+    };
+    for row in rows {
+        let request_id = row.get::<i64, _>("requestId") as u64;
+        if chunk_buffer::contains(request_id)? {
+            continue; // waiting in wait_until_online()
+        }
+        let request: QueuedRequest = serde_json::from_str(row.get("request"))?;
+        if is_online(probe_url(&request)).await {
+            replay(app, request_id, request).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Replays the requests queued before the app quit when the network is available.
+pub(crate) fn watch(app: tauri::AppHandle) {
+    tauri::async_runtime::spawn(async move {
+        loop {
+            tokio::time::sleep(CHECK_INTERVAL).await;
+            if let Err(err) = replay_all(&app).await {
+                tracing::debug!("failed to replay the queued requests: {err}"); // e.g. the database is locked
+            }
+        }
+    });
+}
@@ -0,0 +1,60 @@
+//! OpenRouter, which gives access to the models of many providers with a single key and prepaid credits.
+//!
+//! Its API is compatible with OpenAI's, so the chat completions are sent like OpenAI's with the attribution headers
+//! added. The model is given as "<provider>/<model>", and `models` of the request body lists the models that OpenRouter
+//! falls back to, in order, when the first one is unavailable or rejects the request.
+
+use crate::Error;
+use serde_json::Value;
+
+pub(crate) const BASE_URL: &str = "https://openrouter.ai/api/v1";
+/// Identifies the app in the rankings of OpenRouter.
+const REFERER: &str = "https://github.com/chatgptui/desktop";
+const TITLE: &str = "ChatGPT Desktop";
+
+/// Adds the headers with which OpenRouter attributes the requests to this app.
+pub(crate) fn add_headers(request: reqwest::RequestBuilder) -> reqwest::RequestBuilder {
+    request
+        .header("HTTP-Referer", REFERER)
+        .header("X-Title", TITLE)
+}
+
+#[derive(serde::Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct OpenRouterCredits {
+    /// The credits purchased, in US dollars.
+    total_credits: f64,
+    /// The credits used, in US dollars.
+    total_usage: f64,
+    remaining: f64,
+}
+
+/// Returns the credits of the account of the key.
+#[tauri::command]
+#[tracing::instrument(skip(key), err)]
+pub(crate) async fn get_openrouter_credits(key: String) -> Result<OpenRouterCredits, Error> {
+    let res = add_headers(reqwest::Client::new().get(format!("{BASE_URL}/credits")))
+        .bearer_auth(key)
+        .send()
+        .await?;
+    if res.status() != 200 {
+        return Err(Error::StatusIsNot200(format!(
+            "{}: {}",
+            res.status(),
+            res.text().await?
+        )));
+    }
+    let body: Value = serde_json::from_str(&res.text().await?)?;
+    let field = |name: &str| {
+        body.pointer(&format!("/data/{name}"))
+            .and_then(Value::as_f64)
+            .ok_or_else(|| Error::StringError(format!("The response of OpenRouter has no {name}")))
+    };
+    let total_credits = field("total_credits")?;
+    let total_usage = field("total_usage")?;
+    Ok(OpenRouterCredits {
+        total_credits,
+        total_usage,
+        remaining: total_credits - total_usage,
+    })
+}
@@ -0,0 +1,183 @@
+//! Folders and tags of conversations, for filtering `list_conversations`.
+//!
+//! A conversation is in at most one folder and may have any number of tags. Folders can be nested, and deleting a folder
+//! deletes its subfolders, while the conversations in them are kept outside of any folder.
+
+use crate::{AppState, Error};
+use sqlx::Row;
+
+#[derive(serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct Folder {
+    id: i64,
+    name: String,
+    parent: Option<i64>,
+    conversations: i64,
+}
+
+#[derive(serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct TagCount {
+    tag: String,
+    conversations: i64,
+}
+
+/// Creates a folder and returns its id.
+#[tauri::command]
+#[tracing::instrument(skip(state), err)]
+pub(crate) async fn create_folder(
+    name: String,
+    parent: Option<i64>,
+    state: tauri::State<'_, AppState>,
+) -> Result<i64, Error> {
+    let name = name.trim();
+    if name.is_empty() {
+        return Err(Error::StringError("The folder name is empty".to_owned()));
+    }
+    let mut conn = state.db_pool.acquire().await?;
+    Ok(
+        sqlx::query("INSERT INTO folder (name, parent) VALUES (?, ?)")
+            .bind(name)
+            .bind(parent)
+            .execute(&mut conn)
+            .await?
+            .last_insert_rowid(),
+    )
+}
+
+#[tauri::command]
+#[tracing::instrument(skip(state), err)]
+pub(crate) async fn rename_folder(
+    folder_id: i64,
+    name: String,
+    state: tauri::State<'_, AppState>,
+) -> Result<(), Error> {
+    let mut conn = state.db_pool.acquire().await?;
+    sqlx::query("UPDATE folder SET name = ? WHERE id = ?")
+        .bind(name.trim())
+        .bind(folder_id)
+        .execute(&mut conn)
+        .await?;
+    Ok(())
+}
+
+#[tauri::command]
+#[tracing::instrument(skip(state), err)]
+pub(crate) async fn delete_folder(
+    folder_id: i64,
+    state: tauri::State<'_, AppState>,
+) -> Result<(), Error> {
+    let mut conn = state.db_pool.acquire().await?;
+    sqlx::query("DELETE FROM folder WHERE id = ?")
+        .bind(folder_id)
+        .execute(&mut conn)
+        .await?;
+    Ok(())
+}
+
+/// Lists the folders by name, with the number of conversations directly in each one.
+#[tauri::command]
+#[tracing::instrument(skip(state), err)]
+pub(crate) async fn list_folders(state: tauri::State<'_, AppState>) -> Result<Vec<Folder>, Error> {
+    let mut conn = state.db_pool.acquire().await?;
+    Ok(sqlx::query(
+        "
+SELECT folder.id AS id, folder.name AS name, folder.parent AS parent, count(conversationFolder.conversationId) AS conversations
+FROM folder
+LEFT OUTER JOIN conversationFolder ON folder.id = conversationFolder.folderId
+GROUP BY folder.id
+ORDER BY folder.name COLLATE NOCASE
+",
+    )
+    .fetch_all(&mut conn)
+    .await?
+    .into_iter()
+    .map(|row| Folder {
+        id: row.get("id"),
+        name: row.get("name"),
+        parent: row.get("parent"),
+        conversations: row.get("conversations"),
+    })
+    .collect())
+}
+
+/// Moves the conversation into the folder, or out of any folder if `folder_id` is None.
+#[tauri::command]
+#[tracing::instrument(skip(state), err)]
+pub(crate) async fn move_to_folder(
+    conversation_id: i64,
+    folder_id: Option<i64>,
+    state: tauri::State<'_, AppState>,
+) -> Result<(), Error> {
+    let mut conn = state.db_pool.acquire().await?;
+    match folder_id {
+        Some(folder_id) => sqlx::query(
+            "INSERT OR REPLACE INTO conversationFolder (conversationId, folderId) VALUES (?, ?)",
+        )
+        .bind(conversation_id)
+        .bind(folder_id)
+        .execute(&mut conn)
+        .await?,
+        None => {
+            sqlx::query("DELETE FROM conversationFolder WHERE conversationId = ?")
+                .bind(conversation_id)
+                .execute(&mut conn)
+                .await?
+        }
+    };
+    Ok(())
+}
+
+#[tauri::command]
+#[tracing::instrument(skip(state), err)]
+pub(crate) async fn tag_conversation(
+    conversation_id: i64,
+    tag: String,
+    state: tauri::State<'_, AppState>,
+) -> Result<(), Error> {
+    let tag = tag.trim();
+    if tag.is_empty() {
+        return Err(Error::StringError("The tag is empty".to_owned()));
+    }
+    let mut conn = state.db_pool.acquire().await?;
+    sqlx::query("INSERT OR IGNORE INTO conversationTag (conversationId, tag) VALUES (?, ?)")
+        .bind(conversation_id)
+        .bind(tag)
+        .execute(&mut conn)
+        .await?;
+    Ok(())
+}
+
+#[tauri::command]
+#[tracing::instrument(skip(state), err)]
+pub(crate) async fn untag_conversation(
+    conversation_id: i64,
+    tag: String,
+    state: tauri::State<'_, AppState>,
+) -> Result<(), Error> {
+    let mut conn = state.db_pool.acquire().await?;
+    sqlx::query("DELETE FROM conversationTag WHERE conversationId = ? AND tag = ?")
+        .bind(conversation_id)
+        .bind(tag.trim())
+        .execute(&mut conn)
+        .await?;
+    Ok(())
+}
+
+/// Lists the tags in use, from the most used.
+#[tauri::command]
+#[tracing::instrument(skip(state), err)]
+pub(crate) async fn list_tags(state: tauri::State<'_, AppState>) -> Result<Vec<TagCount>, Error> {
+    let mut conn = state.db_pool.acquire().await?;
+    Ok(sqlx::query(
+        "SELECT tag, count(*) AS conversations FROM conversationTag GROUP BY tag ORDER BY conversations DESC, tag",
+    )
+    .fetch_all(&mut conn)
+    .await?
+    .into_iter()
+    .map(|row| TagCount {
+        tag: row.get("tag"),
+        conversations: row.get("conversations"),
+    })
+    .collect())
+}
@@ -0,0 +1,150 @@
+//! Stop sequences and the maximum number of output tokens, enforced on the streamed content regardless of whether the
+//! provider supports them.
+//!
+//! The end of the content that may be the beginning of a stop sequence is held back until the next chunk tells whether
+//! it is, so the frontend never receives a part of a stop sequence. Once a limit is reached, the rest of the stream is
+//! dropped, the last chunk has the `finish_reason` "stop" or "length", and `start_chat_completion` closes the stream.
+//! The output tokens are counted with cl100k_base, which is an estimate for models with other tokenizers.
+
+use crate::{CompletionChunk, Error, CL100K_BASE};
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+struct Limits {
+    stop_sequences: Vec<String>,
+    max_output_tokens: Option<usize>,
+    /// The content received but not sent yet.
+    held: String,
+    sent_tokens: usize,
+    reached: bool,
+}
+
+lazy_static::lazy_static! {
+    static ref LIMITS: Mutex<HashMap<u64, Limits>> = Mutex::new(HashMap::new());
+}
+
+/// Removes the limits of the request when dropped.
+pub(crate) struct Registered(Option<u64>);
+
+impl Drop for Registered {
+    fn drop(&mut self) {
+        if let (Some(request_id), Ok(mut limits)) = (self.0, LIMITS.lock()) {
+            limits.remove(&request_id);
+        }
+    }
+}
+
+/// Applies the limits to the chunks of the request until the returned value is dropped.
+pub(crate) fn register(
+    request_id: u64,
+    stop_sequences: Option<Vec<String>>,
+    max_output_tokens: Option<u32>,
+) -> Result<Registered, Error> {
+    let stop_sequences = stop_sequences
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|s| !s.is_empty())
+        .collect::<Vec<_>>();
+    if stop_sequences.is_empty() && max_output_tokens.is_none() {
+        return Ok(Registered(None));
+    }
+    LIMITS.lock()?.insert(
+        request_id,
+        Limits {
+            stop_sequences,
+            max_output_tokens: max_output_tokens.map(|n| n as usize),
+            held: String::new(),
+            sent_tokens: 0,
+            reached: false,
+        },
+    );
+    Ok(Registered(Some(request_id)))
+}
+
+/// Whether the stream of the request can be closed because a limit has been reached.
+pub(crate) fn reached(request_id: u64) -> Result<bool, Error> {
+    Ok(LIMITS
+        .lock()?
+        .get(&request_id)
+        .is_some_and(|limits| limits.reached))
+}
+
+/// The length of the longest end of `text` that is the beginning of a stop sequence.
+fn partial_stop_len(text: &str, stop_sequences: &[String]) -> usize {
+    let longest = stop_sequences.iter().map(String::len).max().unwrap_or(0);
+    (1..longest.min(text.len() + 1))
+        .rev()
+        .filter(|&len| text.is_char_boundary(text.len() - len))
+        .find(|&len| {
+            let end = &text[text.len() - len..];
+            stop_sequences.iter().any(|s| s.starts_with(end))
+        })
+        .unwrap_or(0)
+}
+
+/// The beginning of `text` that has at most `max_tokens` tokens.
+fn truncate_tokens(text: &str, max_tokens: usize) -> String {
+    let tokens = CL100K_BASE.encode_with_special_tokens(text);
+    // A token may end in the middle of a character, which fails to decode.
+    (0..=max_tokens.min(tokens.len()))
+        .rev()
+        .find_map(|n| CL100K_BASE.decode(tokens[..n].to_vec()).ok())
+        .unwrap_or_default()
+}
+
+/// Cuts the content of the chunk at the limits of the request. Returns None if the chunk should not be sent.
+pub(crate) fn apply(
+    request_id: u64,
+    mut chunk: CompletionChunk,
+) -> Result<Option<CompletionChunk>, Error> {
+    let mut all_limits = LIMITS.lock()?;
+    let Some(limits) = all_limits.get_mut(&request_id) else {
+        return Ok(Some(chunk));
+    };
+    if limits.reached {
+        return Ok(None);
+    }
+    limits.held += chunk.content.as_deref().unwrap_or_default();
+
+    let stop = limits
+        .stop_sequences
+        .iter()
+        .filter_map(|s| limits.held.find(s.as_str()))
+        .min();
+    if let Some(stop) = stop {
+        limits.held.truncate(stop);
+        limits.reached = true;
+        chunk.finish_reason = Some("stop".to_owned());
+    }
+    if let Some(max_output_tokens) = limits.max_output_tokens {
+        let remaining = max_output_tokens.saturating_sub(limits.sent_tokens);
+        if CL100K_BASE.encode_with_special_tokens(&limits.held).len() > remaining {
+            limits.held = truncate_tokens(&limits.held, remaining);
+            limits.reached = true;
+            chunk.finish_reason = Some("length".to_owned());
+        }
+    }
+
+    // Nothing follows the last chunk, so it cannot complete a stop sequence.
+    let held_len = if limits.reached || chunk.finish_reason.is_some() {
+        0
+    } else {
+        partial_stop_len(&limits.held, &limits.stop_sequences)
+    };
+    let sent = limits
+        .held
+        .drain(..limits.held.len() - held_len)
+        .collect::<String>();
+    limits.sent_tokens += CL100K_BASE.encode_with_special_tokens(&sent).len();
+    chunk.content = (!sent.is_empty()).then_some(sent);
+    Ok(Some(chunk))
+}
+
+/// Returns the content held back at the end of the stream.
+pub(crate) fn flush(request_id: u64) -> Result<Option<String>, Error> {
+    Ok(LIMITS
+        .lock()?
+        .get_mut(&request_id)
+        .map(|limits| std::mem::take(&mut limits.held))
+        .filter(|held| !held.is_empty()))
+}
@@ -0,0 +1,97 @@
+//! Pinned messages, which `build_request_messages` keeps in the prompt when it drops older messages or replaces them
+//! with the summary, e.g. for instructions like "always answer in French".
+
+use crate::{AppState, Error};
+use sqlx::Row;
+use std::collections::HashSet;
+
+#[derive(serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct PinnedMessage {
+    message_id: i64,
+    conversation_id: i64,
+    role: String,
+    content: String,
+    pinned_at: String,
+}
+
+/// Returns the pinned messages among `message_ids`.
+pub(crate) async fn pinned_ids(
+    conn: &mut sqlx::SqliteConnection,
+    message_ids: &[i64],
+) -> Result<HashSet<i64>, Error> {
+    Ok(sqlx::query(
+        "SELECT messageId FROM pinnedMessage WHERE messageId IN (SELECT value FROM json_each(?))",
+    )
+    .bind(serde_json::to_string(message_ids)?)
+    .fetch_all(conn)
+    .await?
+    .into_iter()
+    .map(|row| row.get("messageId"))
+    .collect())
+}
+
+#[tauri::command]
+#[tracing::instrument(skip(state), err)]
+pub(crate) async fn pin_message(
+    message_id: i64,
+    state: tauri::State<'_, AppState>,
+) -> Result<(), Error> {
+    let mut conn = state.db_pool.acquire().await?;
+    sqlx::query("INSERT OR IGNORE INTO pinnedMessage (messageId) SELECT id FROM message WHERE id = ? AND parent IS NOT NULL")
+        .bind(message_id)
+        .execute(&mut conn)
+        .await?;
+    Ok(())
+}
+
+#[tauri::command]
+#[tracing::instrument(skip(state), err)]
+pub(crate) async fn unpin_message(
+    message_id: i64,
+    state: tauri::State<'_, AppState>,
+) -> Result<(), Error> {
+    let mut conn = state.db_pool.acquire().await?;
+    sqlx::query("DELETE FROM pinnedMessage WHERE messageId = ?")
+        .bind(message_id)
+        .execute(&mut conn)
+        .await?;
+    Ok(())
+}
+
+/// Lists the pinned messages of the conversation, or of every conversation, from the oldest message.
+#[tauri::command]
+#[tracing::instrument(skip(state), err)]
+pub(crate) async fn list_pinned(
+    conversation_id: Option<i64>,
+    state: tauri::State<'_, AppState>,
+) -> Result<Vec<PinnedMessage>, Error> {
+    let mut conn = state.db_pool.acquire().await?;
+    Ok(sqlx::query(
+        "
+WITH RECURSIVE ancestors(pinned, id, parent) AS (
+    SELECT message.id, message.id, message.parent FROM pinnedMessage JOIN message ON message.id = pinnedMessage.messageId
+    UNION ALL
+    SELECT ancestors.pinned, message.id, message.parent FROM ancestors JOIN message ON message.id = ancestors.parent
+)
+SELECT message.id AS messageId, ancestors.id AS conversationId, message.role AS role, message.content AS content, pinnedMessage.pinnedAt AS pinnedAt
+FROM ancestors
+JOIN message ON message.id = ancestors.pinned
+JOIN pinnedMessage ON pinnedMessage.messageId = ancestors.pinned
+WHERE ancestors.parent IS NULL AND (?1 IS NULL OR ancestors.id = ?1)
+ORDER BY message.id
+",
+    )
+    .bind(conversation_id)
+    .fetch_all(&mut conn)
+    .await?
+    .into_iter()
+    .map(|row| PinnedMessage {
+        message_id: row.get("messageId"),
+        conversation_id: row.get("conversationId"),
+        role: row.get("role"),
+        content: row.get("content"),
+        pinned_at: row.get("pinnedAt"),
+    })
+    .collect())
+}
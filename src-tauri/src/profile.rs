@@ -0,0 +1,216 @@
+//! Profiles, e.g. "Work" and "Personal", which keep the conversations, the settings, and the API keys strictly separated.
+//!
+//! Each profile has its own database file in the app config directory. The settings and the API keys are in its config
+//! table, and the secrets in the OS credential store are namespaced by the profile through `keyring_entry`. The default
+//! profile uses `chatgpt_tauri.db` and the unprefixed secrets, so the data from before the profiles stays in it.
+//!
+//! The profiles and the active one are recorded in `PROFILES_FILE`. `switch_profile` reopens the pool of the backend on
+//! the database of the profile, disconnects the MCP servers of the previous profile, restores the Azure AD credential of the profile,
+//! and emits `profile://switched`, upon which the windows reload and open it too.
+
+use crate::{AppState, Error, KEYRING_SERVICE};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use tauri::{AppHandle, Manager};
+
+const PROFILES_FILE: &str = "profiles.json";
+const DEFAULT_PROFILE: &str = "default";
+
+lazy_static::lazy_static! {
+    /// The id of the active profile, which namespaces the secrets.
+    static ref ACTIVE: Mutex<String> = Mutex::new(DEFAULT_PROFILE.to_owned());
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct Profile {
+    /// Lowercase letters, digits, and hyphens, so that it can be a part of a file name.
+    id: String,
+    name: String,
+    /// Given by `database_file` when the profiles are loaded, for the frontend.
+    #[serde(default)]
+    database_file: String,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct Profiles {
+    active: String,
+    profiles: Vec<Profile>,
+}
+
+impl Default for Profiles {
+    fn default() -> Self {
+        Self {
+            active: DEFAULT_PROFILE.to_owned(),
+            profiles: vec![Profile {
+                id: DEFAULT_PROFILE.to_owned(),
+                name: "Default".to_owned(),
+                database_file: database_file(DEFAULT_PROFILE),
+            }],
+        }
+    }
+}
+
+impl Profiles {
+    fn find(&self, id: &str) -> Result<&Profile, Error> {
+        self.profiles
+            .iter()
+            .find(|profile| profile.id == id)
+            .ok_or_else(|| Error::StringError(format!("Unknown profile: {id}")))
+    }
+}
+
+/// The file name of the database of the profile, relative to the app config directory, as tauri-plugin-sql opens it.
+pub(crate) fn database_file(id: &str) -> String {
+    if id == DEFAULT_PROFILE {
+        "chatgpt_tauri.db".to_owned()
+    } else {
+        format!("chatgpt_tauri-{id}.db")
+    }
+}
+
+fn load(dir: &Path) -> Result<Profiles, Error> {
+    let mut profiles: Profiles = match std::fs::read_to_string(dir.join(PROFILES_FILE)) {
+        Ok(json) => serde_json::from_str(&json)?,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Profiles::default()),
+        Err(err) => return Err(err.into()),
+    };
+    for profile in &mut profiles.profiles {
+        profile.database_file = database_file(&profile.id);
+    }
+    Ok(profiles)
+}
+
+fn save(dir: &Path, profiles: &Profiles) -> Result<(), Error> {
+    let path = dir.join(PROFILES_FILE);
+    let tmp = path.with_extension("json.tmp");
+    std::fs::write(&tmp, serde_json::to_string_pretty(profiles)?)?;
+    std::fs::rename(&tmp, &path)?;
+    Ok(())
+}
+
+fn config_dir(app: &AppHandle) -> Result<PathBuf, Error> {
+    app.path_resolver()
+        .app_config_dir()
+        .ok_or_else(|| Error::StringError("The app config directory is unknown".to_owned()))
+}
+
+/// Activates the profile recorded as active, and returns the path of its database. Called at startup.
+pub(crate) fn init(dir: &Path) -> Result<PathBuf, Error> {
+    let profiles = load(dir)?;
+    let active = match profiles.find(&profiles.active) {
+        Ok(profile) => profile.id.clone(),
+        Err(err) => {
+            tracing::warn!("{err}, opening the default profile");
+            DEFAULT_PROFILE.to_owned()
+        }
+    };
+    let path = dir.join(database_file(&active));
+    *ACTIVE.lock()? = active;
+    Ok(path)
+}
+
+/// Returns the entry of the secret in the OS credential store, namespaced by the active profile.
+pub(crate) fn keyring_entry(name: &str) -> Result<keyring::Entry, Error> {
+    let active = ACTIVE.lock()?.clone();
+    let name = if active == DEFAULT_PROFILE {
+        name.to_owned()
+    } else {
+        format!("profile.{active}.{name}")
+    };
+    Ok(keyring::Entry::new(KEYRING_SERVICE, &name)?)
+}
+
+/// Returns the id made of the name, e.g. "work" for "Work", which is made unique with a number.
+fn new_id(profiles: &Profiles, name: &str) -> String {
+    let mut base = String::new();
+    for c in name.trim().to_lowercase().chars() {
+        if c.is_ascii_alphanumeric() {
+            base.push(c);
+        } else if !base.is_empty() && !base.ends_with('-') {
+            base.push('-');
+        }
+    }
+    let base = match base.trim_end_matches('-') {
+        "" => "profile".to_owned(),
+        base => base.to_owned(),
+    };
+    let taken = |id: &str| profiles.profiles.iter().any(|profile| profile.id == id);
+    if !taken(&base) {
+        return base;
+    }
+    (2..)
+        .map(|n| format!("{base}-{n}"))
+        .find(|id| !taken(id))
+        .expect("some number is free")
+}
+
+#[tauri::command]
+#[tracing::instrument(skip(app), err)]
+pub(crate) fn list_profiles(app: AppHandle) -> Result<Profiles, Error> {
+    load(&config_dir(&app)?)
+}
+
+/// Adds an empty profile. Its database is created when it is switched to.
+#[tauri::command]
+#[tracing::instrument(skip(app), err)]
+pub(crate) fn create_profile(app: AppHandle, name: String) -> Result<Profile, Error> {
+    if name.trim().is_empty() {
+        return Err(Error::StringError(
+            "The name of the profile must not be empty".to_owned(),
+        ));
+    }
+    let dir = config_dir(&app)?;
+    let mut profiles = load(&dir)?;
+    if profiles
+        .profiles
+        .iter()
+        .any(|profile| profile.name.eq_ignore_ascii_case(name.trim()))
+    {
+        return Err(Error::StringError(format!(
+            "A profile named {} already exists",
+            name.trim()
+        )));
+    }
+    let id = new_id(&profiles, &name);
+    let profile = Profile {
+        database_file: database_file(&id),
+        id,
+        name: name.trim().to_owned(),
+    };
+    profiles.profiles.push(profile.clone());
+    save(&dir, &profiles)?;
+    Ok(profile)
+}
+
+/// Closes the database of the active profile, opens the one of the profile, and tells the windows to reload.
+#[tauri::command]
+#[tracing::instrument(skip(app, state), err)]
+pub(crate) async fn switch_profile(
+    app: AppHandle,
+    state: tauri::State<'_, AppState>,
+    id: String,
+) -> Result<Profile, Error> {
+    let dir = config_dir(&app)?;
+    let mut profiles = load(&dir)?;
+    let profile = profiles.find(&id)?.clone();
+    if profiles.active == id {
+        return Ok(profile);
+    }
+    let path = dir.join(database_file(&id));
+    let encrypted = crate::encryption::is_encrypted(&path)?;
+    // The passphrase of the previous database does not apply to this one.
+    state.db_pool.set_path(path).await?;
+    *ACTIVE.lock()? = id.clone(); // create_tables() moves the secrets to the entries of this profile
+    if !encrypted {
+        crate::create_tables(&state.db_pool).await?; // otherwise deferred to unlock_database()
+    }
+    crate::mcp::disconnect_all(&state)?;
+    crate::aad_token::restore(&state).await?;
+    crate::sync::reset_status()?;
+    profiles.active = id;
+    save(&dir, &profiles)?;
+    app.emit_all("profile://switched", profile.clone())?;
+    Ok(profile)
+}
@@ -0,0 +1,169 @@
+//! A library of reusable prompts stored in the `promptTemplate` table.
+//!
+//! `{{variable}}` in the content of a template is replaced with the value of the variable when it is rendered. Spaces
+//! inside the braces are ignored.
+
+use crate::{AppState, Error};
+use sqlx::Row;
+use std::collections::HashMap;
+
+#[derive(serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct PromptTemplate {
+    id: i64,
+    name: String,
+    content: String,
+    /// The variables used in the content, in the order of their first appearance.
+    variables: Vec<String>,
+    modified_at: String,
+}
+
+/// Splits the template into literal text and variable names.
+fn parse(template: &str) -> Vec<Result<&str, &str>> {
+    let mut parts = vec![];
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        let Some(len) = rest[start + 2..].find("}}") else {
+            break;
+        };
+        parts.push(Ok(&rest[..start]));
+        parts.push(Err(rest[start + 2..start + 2 + len].trim()));
+        rest = &rest[start + 2 + len + 2..];
+    }
+    parts.push(Ok(rest));
+    parts
+}
+
+fn variables(template: &str) -> Vec<String> {
+    let mut variables = Vec::<String>::new();
+    for name in parse(template).into_iter().filter_map(Result::err) {
+        if !variables.iter().any(|v| v == name) {
+            variables.push(name.to_owned());
+        }
+    }
+    variables
+}
+
+pub(crate) fn render(template: &str, vars: &HashMap<String, String>) -> Result<String, Error> {
+    let missing = variables(template)
+        .into_iter()
+        .filter(|name| !vars.contains_key(name))
+        .collect::<Vec<_>>();
+    if !missing.is_empty() {
+        return Err(Error::StringError(format!(
+            "Missing variables: {}",
+            missing.join(", ")
+        )));
+    }
+    Ok(parse(template)
+        .into_iter()
+        .map(|part| match part {
+            Ok(text) => text,
+            Err(name) => &vars[name],
+        })
+        .collect())
+}
+
+/// Creates a template, or updates it if `id` is specified, and returns its id.
+#[tauri::command]
+#[tracing::instrument(skip(content, state), err)]
+pub(crate) async fn save_prompt_template(
+    id: Option<i64>,
+    name: String,
+    content: String,
+    state: tauri::State<'_, AppState>,
+) -> Result<i64, Error> {
+    let mut conn = state.db_pool.acquire().await?;
+    match id {
+        Some(id) => {
+            let result = sqlx::query(
+                "UPDATE promptTemplate SET name = ?, content = ?, modifiedAt = CURRENT_TIMESTAMP WHERE id = ?",
+            )
+            .bind(name)
+            .bind(content)
+            .bind(id)
+            .execute(&mut conn)
+            .await?;
+            if result.rows_affected() == 0 {
+                return Err(Error::StringError(format!(
+                    "Prompt template {id} does not exist"
+                )));
+            }
+            Ok(id)
+        }
+        None => Ok(
+            sqlx::query("INSERT INTO promptTemplate (name, content) VALUES (?, ?)")
+                .bind(name)
+                .bind(content)
+                .execute(&mut conn)
+                .await?
+                .last_insert_rowid(),
+        ),
+    }
+}
+
+/// Lists the templates in alphabetical order.
+#[tauri::command]
+#[tracing::instrument(skip(state), err)]
+pub(crate) async fn list_prompt_templates(
+    state: tauri::State<'_, AppState>,
+) -> Result<Vec<PromptTemplate>, Error> {
+    let mut conn = state.db_pool.acquire().await?;
+    Ok(sqlx::query(
+        "SELECT id, name, content, modifiedAt FROM promptTemplate ORDER BY name COLLATE NOCASE",
+    )
+    .fetch_all(&mut conn)
+    .await?
+    .into_iter()
+    .map(|row| {
+        let content: String = row.get("content");
+        PromptTemplate {
+            id: row.get("id"),
+            name: row.get("name"),
+            variables: variables(&content),
+            content,
+            modified_at: row.get("modifiedAt"),
+        }
+    })
+    .collect())
+}
+
+#[tauri::command]
+#[tracing::instrument(skip(state), err)]
+pub(crate) async fn delete_prompt_template(
+    id: i64,
+    state: tauri::State<'_, AppState>,
+) -> Result<(), Error> {
+    let mut conn = state.db_pool.acquire().await?;
+    sqlx::query("DELETE FROM promptTemplate WHERE id = ?")
+        .bind(id)
+        .execute(&mut conn)
+        .await?;
+    Ok(())
+}
+
+/// Returns the content of the template with its variables replaced. Fails if a variable has no value.
+#[tauri::command]
+#[tracing::instrument(skip(vars, state), err)]
+pub(crate) async fn render_prompt_template(
+    id: i64,
+    vars: HashMap<String, String>,
+    state: tauri::State<'_, AppState>,
+) -> Result<String, Error> {
+    let mut conn = state.db_pool.acquire().await?;
+    render_template(&mut conn, id, &vars).await
+}
+
+pub(crate) async fn render_template(
+    conn: &mut sqlx::SqliteConnection,
+    id: i64,
+    vars: &HashMap<String, String>,
+) -> Result<String, Error> {
+    let content: String = sqlx::query("SELECT content FROM promptTemplate WHERE id = ?")
+        .bind(id)
+        .fetch_optional(conn)
+        .await?
+        .ok_or_else(|| Error::StringError(format!("Prompt template {id} does not exist")))?
+        .get("content");
+    render(&content, vars)
+}
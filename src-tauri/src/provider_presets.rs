@@ -0,0 +1,137 @@
+//! Presets of the OpenAI-compatible providers, so that they can be used by choosing their name instead of typing the
+//! endpoint.
+//!
+//! Each provider follows the chat completions API of OpenAI with small differences: the fields that it rejects or names
+//! differently, and where the usage is reported in the stream. A preset records them, and
+//! `start_chat_completion` adapts the request body to it.
+
+use crate::Error;
+use serde_json::Value;
+
+/// How the key is sent.
+#[derive(serde::Serialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "camelCase")]
+pub(crate) enum AuthStyle {
+    /// `Authorization: Bearer <key>`
+    Bearer,
+    /// `api-key: <key>`
+    ApiKeyHeader,
+}
+
+/// Where the usage of a streamed completion is reported.
+#[derive(serde::Serialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "camelCase")]
+pub(crate) enum StreamUsage {
+    /// In the last chunk if `stream_options.include_usage` is set, as OpenAI does.
+    StreamOptions,
+    /// In the last chunk, always. `stream_options` is removed.
+    Always,
+    /// In `x_groq.usage` of the last chunk. `stream_options` is removed.
+    XGroq,
+}
+
+#[derive(serde::Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct ProviderPreset {
+    /// Also the provider of the usage accounting and the rate limits.
+    id: &'static str,
+    name: &'static str,
+    /// The chat completions are at `<base_url>/chat/completions`.
+    base_url: &'static str,
+    auth: AuthStyle,
+    stream_usage: StreamUsage,
+    /// The fields of the request body that the provider rejects, which are removed.
+    unsupported_fields: &'static [&'static str],
+    /// The fields that the provider names differently, as (OpenAI's name, the provider's name).
+    renamed_fields: &'static [(&'static str, &'static str)],
+    /// Where the API keys are issued.
+    keys_url: &'static str,
+}
+
+static PRESETS: [ProviderPreset; 3] = [
+    ProviderPreset {
+        id: "mistral",
+        name: "Mistral AI",
+        base_url: "https://api.mistral.ai/v1",
+        auth: AuthStyle::Bearer,
+        stream_usage: StreamUsage::Always,
+        unsupported_fields: &["logit_bias", "logprobs", "top_logprobs", "user"],
+        renamed_fields: &[("seed", "random_seed")],
+        keys_url: "https://console.mistral.ai/api-keys/",
+    },
+    ProviderPreset {
+        id: "groq",
+        name: "Groq",
+        base_url: "https://api.groq.com/openai/v1",
+        auth: AuthStyle::Bearer,
+        stream_usage: StreamUsage::XGroq,
+        unsupported_fields: &["logit_bias", "logprobs", "top_logprobs"],
+        renamed_fields: &[],
+        keys_url: "https://console.groq.com/keys",
+    },
+    ProviderPreset {
+        id: "together",
+        name: "Together AI",
+        base_url: "https://api.together.xyz/v1",
+        auth: AuthStyle::Bearer,
+        stream_usage: StreamUsage::Always,
+        unsupported_fields: &[],
+        renamed_fields: &[],
+        keys_url: "https://api.together.ai/settings/api-keys",
+    },
+];
+
+impl ProviderPreset {
+    pub(crate) fn id(&self) -> &'static str {
+        self.id
+    }
+
+    pub(crate) fn endpoint(&self) -> String {
+        format!("{}/chat/completions", self.base_url)
+    }
+
+    /// Whether the key is sent in the `api-key` header, as `api_key_authentication` of `send_chat_completion`.
+    pub(crate) fn api_key_authentication(&self) -> bool {
+        self.auth == AuthStyle::ApiKeyHeader
+    }
+
+    /// Removes and renames the fields of the OpenAI request body for the provider.
+    pub(crate) fn adapt_body(&self, body: &str) -> Result<String, Error> {
+        let mut body: Value = serde_json::from_str(body)?;
+        let Some(fields) = body.as_object_mut() else {
+            return Ok(body.to_string());
+        };
+        if self.stream_usage != StreamUsage::StreamOptions {
+            fields.remove("stream_options");
+        }
+        for field in self.unsupported_fields {
+            fields.remove(*field);
+        }
+        for (from, to) in self.renamed_fields {
+            if let Some(value) = fields.remove(*from) {
+                fields.insert((*to).to_owned(), value);
+            }
+        }
+        Ok(body.to_string())
+    }
+}
+
+/// Returns the preset with the id.
+pub(crate) fn get(id: &str) -> Result<&'static ProviderPreset, Error> {
+    PRESETS
+        .iter()
+        .find(|preset| preset.id == id)
+        .ok_or_else(|| Error::StringError(format!("Unknown provider preset: {id}")))
+}
+
+/// Returns the preset whose base URL the endpoint is under, so that an endpoint typed by hand is accounted to its provider.
+pub(crate) fn find_by_endpoint(endpoint: &str) -> Option<&'static ProviderPreset> {
+    PRESETS
+        .iter()
+        .find(|preset| endpoint.starts_with(preset.base_url))
+}
+
+#[tauri::command]
+pub(crate) fn list_provider_presets() -> &'static [ProviderPreset] {
+    &PRESETS
+}
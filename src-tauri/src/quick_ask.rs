@@ -0,0 +1,172 @@
+//! The Spotlight-style "quick ask" window, toggled with a global shortcut.
+
+use crate::app_state::DbPool;
+use crate::{read_config, AppState, Error};
+use serde_json::Value;
+use tauri::{AppHandle, GlobalShortcutManager, Manager};
+
+pub(crate) const QUICK_ASK_SHORTCUT: &str = "CmdOrCtrl+Shift+Space";
+const QUICK_ASK_WINDOW: &str = "quick-ask";
+
+pub(crate) fn register_shortcut(app: &AppHandle) -> Result<(), Error> {
+    let handle = app.clone();
+    app.global_shortcut_manager()
+        .register(QUICK_ASK_SHORTCUT, move || {
+            let handle = handle.clone();
+            // Creating a window in the event loop's callback deadlocks on Windows.
+            tauri::async_runtime::spawn(async move {
+                if let Err(err) = toggle_window(&handle) {
+                    tracing::error!("failed to toggle the quick ask window: {err}");
+                }
+            });
+        })?;
+    Ok(())
+}
+
+fn toggle_window(app: &AppHandle) -> Result<(), Error> {
+    if let Some(window) = app.get_window(QUICK_ASK_WINDOW) {
+        window.close()?;
+        return Ok(());
+    }
+    tauri::WindowBuilder::new(
+        app,
+        QUICK_ASK_WINDOW,
+        tauri::WindowUrl::App("index.html#quick-ask".into()),
+    )
+    .title("Quick Ask")
+    .inner_size(640.0, 240.0)
+    .always_on_top(true)
+    .decorations(false)
+    .skip_taskbar(true)
+    .center()
+    .focused(true)
+    .build()?;
+    Ok(())
+}
+
+/// The model configured in the main window.
+pub(crate) async fn configured_model(conn: &mut sqlx::SqliteConnection) -> Result<String, Error> {
+    Ok(read_config(conn, "model")
+        .await?
+        .unwrap_or_else(|| "gpt-3.5-turbo".to_owned()))
+}
+
+/// The chat completion endpoint and the secret key of the service configured in the main window.
+pub(crate) async fn configured_service(
+    conn: &mut sqlx::SqliteConnection,
+) -> Result<(String, String), Error> {
+    let (endpoint, secret_key) = match read_config(&mut *conn, "openaiService").await?.as_deref() {
+        Some("azure") => {
+            return Err(Error::StringError(
+                "Azure OpenAI Service is not supported".to_owned(),
+            ))
+        }
+        Some("openai-proxy") => (
+            read_config(&mut *conn, "openaiProxyUrl").await?,
+            crate::secrets::read("openaiProxyAPIKey")?,
+        ),
+        _ => (
+            Some("https://api.openai.com/v1/chat/completions".to_owned()),
+            crate::secrets::read("APIKey")?,
+        ),
+    };
+    Ok((endpoint.unwrap_or_default(), secret_key.unwrap_or_default()))
+}
+
+/// Sends a non-streaming chat completion request and returns the content of the response.
+pub(crate) async fn send(endpoint: &str, secret_key: &str, body: &Value) -> Result<String, Error> {
+    let res = reqwest::Client::new()
+        .post(endpoint)
+        .header("Content-Type", "application/json")
+        .header("Authorization", format!("Bearer {secret_key}"))
+        .body(body.to_string())
+        .send()
+        .await?;
+    if res.status() != 200 {
+        return Err(Error::StatusIsNot200(format!(
+            "{}: {}",
+            res.status(),
+            res.text().await?
+        )));
+    }
+    let data: Value = serde_json::from_str(&res.text().await?)?;
+    Ok(data
+        .pointer("/choices/0/message/content")
+        .and_then(Value::as_str)
+        .ok_or_else(|| Error::StringError(format!("Unexpected response: {data}")))?
+        .to_owned())
+}
+
+/// Runs a non-streaming chat completion with the service configured in the main window, and returns the content of the
+/// response.
+pub(crate) async fn complete(
+    conn: &mut sqlx::SqliteConnection,
+    model: &str,
+    messages: Vec<Value>,
+) -> Result<String, Error> {
+    let (endpoint, secret_key) = configured_service(conn).await?;
+    send(
+        &endpoint,
+        &secret_key,
+        &serde_json::json!({ "model": model, "messages": messages }),
+    )
+    .await
+}
+
+/// Runs a one-shot chat completion with the model, service, and custom instructions configured in the main window.
+#[tauri::command]
+#[tracing::instrument(skip(prompt, state), err)]
+pub(crate) async fn quick_ask(
+    prompt: String,
+    state: tauri::State<'_, AppState>,
+) -> Result<String, Error> {
+    let mut conn = state.db_pool.acquire().await?;
+    let model = configured_model(&mut conn).await?;
+    ask(&mut conn, &model, prompt).await
+}
+
+/// The prompt with the custom instructions configured in the main window.
+async fn ask_messages(
+    conn: &mut sqlx::SqliteConnection,
+    prompt: String,
+) -> Result<Vec<Value>, Error> {
+    let mut messages = vec![];
+    if let Some(instructions) = read_config(&mut *conn, "customInstructions").await? {
+        if !instructions.trim().is_empty() {
+            messages.push(serde_json::json!({ "role": "system", "content": instructions }));
+        }
+    }
+    messages.push(serde_json::json!({ "role": "user", "content": prompt }));
+    Ok(messages)
+}
+
+/// Asks the model with the custom instructions configured in the main window.
+pub(crate) async fn ask(
+    conn: &mut sqlx::SqliteConnection,
+    model: &str,
+    prompt: String,
+) -> Result<String, Error> {
+    let messages = ask_messages(&mut *conn, prompt).await?;
+    complete(conn, model, messages).await
+}
+
+/// Same as `ask`, but returns the connection to the pool before waiting for the answer.
+pub(crate) async fn ask_pooled(
+    db_pool: &DbPool,
+    model: &str,
+    prompt: String,
+) -> Result<String, Error> {
+    let ((endpoint, secret_key), messages) = {
+        let mut conn = db_pool.acquire().await?;
+        (
+            configured_service(&mut conn).await?,
+            ask_messages(&mut conn, prompt).await?,
+        )
+    };
+    send(
+        &endpoint,
+        &secret_key,
+        &serde_json::json!({ "model": model, "messages": messages }),
+    )
+    .await
+}
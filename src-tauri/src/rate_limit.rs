@@ -0,0 +1,153 @@
+//! Client-side rate limiting, so that bursts of requests, e.g. from several windows, stay within the limits of the
+//! provider instead of failing with 429.
+//!
+//! Each provider ("openai", "azure", "ollama", "gemini", or "whisper" for transcriptions) has a token bucket of requests
+//! per minute and one of tokens per minute, configured by `set_rate_limits`. A request waits until both buckets have
+//! enough capacity, and the waiting requests are sent in order. Providers without limits are not throttled.
+
+use crate::{Error, CHAT_COMPLETION_CANCELED};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+#[derive(serde::Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct RateLimit {
+    rpm: Option<u32>, // requests per minute
+    tpm: Option<u32>, // tokens per minute
+}
+
+/// Refills `capacity` per minute.
+struct Bucket {
+    capacity: f64,
+    /// Negative after a request larger than the capacity.
+    available: f64,
+}
+
+impl Bucket {
+    fn new(per_minute: u32) -> Self {
+        Self {
+            capacity: per_minute as f64,
+            available: per_minute as f64,
+        }
+    }
+
+    fn refill(&mut self, elapsed: Duration) {
+        self.available =
+            (self.available + self.capacity * elapsed.as_secs_f64() / 60.0).min(self.capacity);
+    }
+
+    /// Returns how long it takes until `amount` is available. An amount larger than the capacity waits for a full bucket.
+    fn wait_time(&self, amount: f64) -> Duration {
+        let deficit = amount.min(self.capacity) - self.available;
+        if deficit <= 0.0 || self.capacity <= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(deficit * 60.0 / self.capacity)
+        }
+    }
+}
+
+struct Limiter {
+    requests: Option<Bucket>,
+    tokens: Option<Bucket>,
+    refilled_at: Instant,
+}
+
+impl Limiter {
+    fn new(limit: RateLimit) -> Self {
+        Self {
+            requests: limit.rpm.map(Bucket::new),
+            tokens: limit.tpm.map(Bucket::new),
+            refilled_at: Instant::now(),
+        }
+    }
+
+    fn buckets(&mut self) -> impl Iterator<Item = (&mut Bucket, bool)> {
+        [
+            (self.requests.as_mut(), true),
+            (self.tokens.as_mut(), false),
+        ]
+        .into_iter()
+        .filter_map(|(bucket, is_requests)| Some((bucket?, is_requests)))
+    }
+}
+
+/// How often a waiting chat completion checks whether it has been canceled.
+const CANCEL_CHECK_INTERVAL: Duration = Duration::from_millis(500);
+
+lazy_static::lazy_static! {
+    /// The limits and the limiter of each provider. Each limiter is locked by the request waiting for it, so that requests
+    /// are sent in order.
+    static ref LIMITERS: Mutex<HashMap<String, (RateLimit, Arc<tokio::sync::Mutex<Limiter>>)>> = Mutex::new(HashMap::new());
+}
+
+fn is_canceled(request_id: Option<u64>) -> Result<bool, Error> {
+    Ok(match request_id {
+        Some(request_id) => CHAT_COMPLETION_CANCELED.lock()?.contains(&request_id),
+        None => false,
+    })
+}
+
+/// Waits until a request of `tokens` tokens can be sent to the provider, and counts it. Returns false without counting it
+/// if the chat completion `request_id` is canceled while waiting.
+pub(crate) async fn acquire(
+    provider: &str,
+    tokens: i64,
+    request_id: Option<u64>,
+) -> Result<bool, Error> {
+    let Some((_, limiter)) = LIMITERS.lock()?.get(provider).cloned() else {
+        return Ok(true);
+    };
+    // The same future keeps the place of the request in the queue of the lock while the cancellation is checked.
+    let lock = limiter.lock();
+    tokio::pin!(lock);
+    let mut interval = tokio::time::interval(CANCEL_CHECK_INTERVAL);
+    let mut limiter = loop {
+        tokio::select! {
+            limiter = &mut lock => break limiter,
+            _ = interval.tick() => {
+                if is_canceled(request_id)? {
+                    return Ok(false);
+                }
+            }
+        }
+    };
+    let tokens = tokens.max(0) as f64;
+    loop {
+        if is_canceled(request_id)? {
+            return Ok(false);
+        }
+        let now = Instant::now();
+        let elapsed = now - limiter.refilled_at;
+        limiter.refilled_at = now;
+        let mut wait = Duration::ZERO;
+        for (bucket, is_requests) in limiter.buckets() {
+            bucket.refill(elapsed);
+            wait = wait.max(bucket.wait_time(if is_requests { 1.0 } else { tokens }));
+        }
+        if wait.is_zero() {
+            for (bucket, is_requests) in limiter.buckets() {
+                bucket.available -= if is_requests { 1.0 } else { tokens };
+            }
+            return Ok(true);
+        }
+        tracing::debug!("waiting {wait:?} for the rate limit of {provider}");
+        tokio::time::sleep(wait.min(CANCEL_CHECK_INTERVAL)).await;
+    }
+}
+
+/// Replaces the limits of all providers, e.g. { "openai": { "rpm": 500, "tpm": 30000 } }. Either limit may be omitted.
+/// The limiters of the providers whose limits are unchanged are kept, so that their usage is still counted.
+#[tauri::command]
+#[tracing::instrument(err)]
+pub(crate) fn set_rate_limits(limits: HashMap<String, RateLimit>) -> Result<(), Error> {
+    let mut limiters = LIMITERS.lock()?;
+    limiters.retain(|provider, _| limits.contains_key(provider));
+    for (provider, limit) in limits {
+        if limiters.get(&provider).map(|(l, _)| *l) != Some(limit) {
+            let limiter = Arc::new(tokio::sync::Mutex::new(Limiter::new(limit)));
+            limiters.insert(provider, (limit, limiter));
+        }
+    }
+    Ok(())
+}
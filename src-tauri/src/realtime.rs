@@ -0,0 +1,354 @@
+//! Hands-free voice conversation with the OpenAI Realtime API over WebSocket.
+//!
+//! The microphone is streamed to the API, which detects the end of each utterance with server-side VAD and answers with
+//! audio that is played as it arrives. Speaking while the answer is played interrupts it, so headphones are recommended.
+//!
+//! Events:
+//! - `realtime://transcript` ({ role: "user" | "assistant", text, isFinal }): the assistant's transcript is emitted as deltas,
+//!   followed by the whole text with isFinal = true.
+//! - `realtime://audio-delta` (string): a base64-encoded chunk of the answer, 24 kHz mono 16-bit PCM.
+//! - `realtime://ended` (string | null): the error message, if the session ended because of an error.
+
+use crate::app_state::AudioState;
+use crate::{AppState, Error};
+use base64::Engine;
+use futures_util::{SinkExt, StreamExt};
+use serde_json::{json, Value};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::Message;
+
+const REALTIME_ENDPOINT: &str = "wss://api.openai.com/v1/realtime";
+const DEFAULT_MODEL: &str = "gpt-4o-realtime-preview";
+/// The API's pcm16 format is 24 kHz mono.
+const SAMPLE_RATE: u32 = 24000;
+/// 100 ms.
+const CHUNK_SAMPLES: usize = 2400;
+
+pub(crate) struct RealtimeSession {
+    stop: Arc<AtomicBool>,
+}
+
+#[derive(serde::Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+struct Transcript {
+    role: &'static str,
+    text: String,
+    is_final: bool,
+}
+
+enum Playback {
+    Audio(Vec<i16>),
+    /// Discards the queued audio when the user starts speaking.
+    Interrupt,
+}
+
+/// Converts a mono stream to `SAMPLE_RATE` by linear interpolation.
+struct Resampler {
+    step: f64,
+    /// The position of the next output sample between the previous input sample (0) and the current one (1).
+    position: f64,
+    prev: f32,
+}
+
+impl Resampler {
+    fn new(input_sample_rate: u32) -> Self {
+        Self {
+            step: input_sample_rate as f64 / SAMPLE_RATE as f64,
+            position: 0.0,
+            prev: 0.0,
+        }
+    }
+
+    fn push(&mut self, x: f32, out: &mut Vec<i16>) {
+        while self.position < 1.0 {
+            let y = self.prev + (x - self.prev) * self.position as f32;
+            out.push((y.clamp(-1.0, 1.0) * i16::MAX as f32) as i16);
+            self.position += self.step;
+        }
+        self.position -= 1.0;
+        self.prev = x;
+    }
+}
+
+fn build_input_stream<T: cpal::SizedSample + dasp_sample::ToSample<f32>>(
+    device: &cpal::Device,
+    config: &cpal::StreamConfig,
+    mut on_sample: impl FnMut(f32) + Send + 'static,
+) -> Result<cpal::Stream, Error> {
+    use cpal::traits::DeviceTrait;
+    let channels = config.channels as usize;
+    Ok(device.build_input_stream(
+        config,
+        move |data: &[T], _| {
+            for frame in data.chunks(channels) {
+                let sum: f32 = frame.iter().map(|&x| x.to_sample_()).sum();
+                on_sample(sum / channels as f32);
+            }
+        },
+        |err| tracing::error!("realtime input stream: {err}"),
+        None,
+    )?)
+}
+
+/// Sends 100 ms chunks of 24 kHz PCM16 until `stop` is set. Runs on its own thread because `cpal::Stream` is not Send.
+fn capture(stop: Arc<AtomicBool>, frames: mpsc::UnboundedSender<Vec<u8>>) -> Result<(), Error> {
+    use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+    use cpal::SampleFormat;
+
+    let device = cpal::default_host()
+        .default_input_device()
+        .ok_or_else(|| Error::StringError("No input device is available".to_owned()))?;
+    let supported = device.default_input_config()?;
+    let config = supported.config();
+    let mut resampler = Resampler::new(config.sample_rate.0);
+    let mut buffer = Vec::with_capacity(CHUNK_SAMPLES * 2);
+    let on_sample = move |x: f32| {
+        resampler.push(x, &mut buffer);
+        if buffer.len() >= CHUNK_SAMPLES {
+            let bytes = buffer.iter().flat_map(|s| s.to_le_bytes()).collect();
+            buffer.clear();
+            let _ = frames.send(bytes);
+        }
+    };
+    let stream = match supported.sample_format() {
+        SampleFormat::I8 => build_input_stream::<i8>(&device, &config, on_sample)?,
+        SampleFormat::I16 => build_input_stream::<i16>(&device, &config, on_sample)?,
+        SampleFormat::I32 => build_input_stream::<i32>(&device, &config, on_sample)?,
+        SampleFormat::I64 => build_input_stream::<i64>(&device, &config, on_sample)?,
+        SampleFormat::U8 => build_input_stream::<u8>(&device, &config, on_sample)?,
+        SampleFormat::U16 => build_input_stream::<u16>(&device, &config, on_sample)?,
+        SampleFormat::U32 => build_input_stream::<u32>(&device, &config, on_sample)?,
+        SampleFormat::U64 => build_input_stream::<u64>(&device, &config, on_sample)?,
+        SampleFormat::F32 => build_input_stream::<f32>(&device, &config, on_sample)?,
+        SampleFormat::F64 => build_input_stream::<f64>(&device, &config, on_sample)?,
+        format => {
+            return Err(Error::StringError(format!(
+                "Unsupported sample format: {format}"
+            )))
+        }
+    };
+    stream.play()?;
+    while !stop.load(Ordering::SeqCst) {
+        std::thread::sleep(Duration::from_millis(50));
+    }
+    Ok(())
+}
+
+/// Plays the answers until `stop` is set or the session ends.
+fn play(
+    audio: &AudioState,
+    stop: Arc<AtomicBool>,
+    commands: std::sync::mpsc::Receiver<Playback>,
+) -> Result<(), Error> {
+    let sink = audio.engine.sink()?;
+    // The beeps are ducked for the whole conversation, not only while the assistant speaks.
+    let _speaking = audio.mixer.start_speech();
+    while !stop.load(Ordering::SeqCst) {
+        match commands.recv_timeout(Duration::from_millis(50)) {
+            Ok(Playback::Audio(samples)) => {
+                sink.append(rodio::buffer::SamplesBuffer::new(1, SAMPLE_RATE, samples))
+            }
+            Ok(Playback::Interrupt) => sink.clear(),
+            Err(std::sync::mpsc::RecvTimeoutError::Timeout) => {}
+            Err(std::sync::mpsc::RecvTimeoutError::Disconnected) => {
+                // Lets the last answer finish.
+                sink.sleep_until_end_while(Duration::from_millis(50), || {
+                    !stop.load(Ordering::SeqCst)
+                });
+                break;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn handle_server_event(
+    window: &tauri::Window,
+    playback: &std::sync::mpsc::Sender<Playback>,
+    message: &str,
+) -> Result<(), Error> {
+    let data: Value = serde_json::from_str(message)?;
+    let str_field = |key: &str| data.get(key).and_then(Value::as_str).unwrap_or_default();
+    match str_field("type") {
+        "response.audio.delta" => {
+            let bytes = base64::engine::general_purpose::STANDARD
+                .decode(str_field("delta"))
+                .map_err(|err| Error::StringError(err.to_string()))?;
+            let samples = bytes
+                .chunks_exact(2)
+                .map(|b| i16::from_le_bytes([b[0], b[1]]))
+                .collect();
+            let _ = playback.send(Playback::Audio(samples));
+            window.emit("realtime://audio-delta", str_field("delta"))?;
+        }
+        "response.audio_transcript.delta" => window.emit(
+            "realtime://transcript",
+            Transcript {
+                role: "assistant",
+                text: str_field("delta").to_owned(),
+                is_final: false,
+            },
+        )?,
+        "response.audio_transcript.done" => window.emit(
+            "realtime://transcript",
+            Transcript {
+                role: "assistant",
+                text: str_field("transcript").to_owned(),
+                is_final: true,
+            },
+        )?,
+        "conversation.item.input_audio_transcription.completed" => window.emit(
+            "realtime://transcript",
+            Transcript {
+                role: "user",
+                text: str_field("transcript").to_owned(),
+                is_final: true,
+            },
+        )?,
+        "input_audio_buffer.speech_started" => {
+            let _ = playback.send(Playback::Interrupt);
+        }
+        "error" => {
+            return Err(Error::StringError(
+                data.pointer("/error/message")
+                    .and_then(Value::as_str)
+                    .unwrap_or(message)
+                    .to_owned(),
+            ))
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+async fn run_session(
+    window: tauri::Window,
+    api_key: String,
+    model: String,
+    session: Value,
+    mut frames: mpsc::UnboundedReceiver<Vec<u8>>,
+    playback: std::sync::mpsc::Sender<Playback>,
+) -> Result<(), Error> {
+    let mut request = format!("{REALTIME_ENDPOINT}?model={model}").into_client_request()?;
+    let headers = request.headers_mut();
+    headers.insert(
+        "Authorization",
+        format!("Bearer {api_key}")
+            .parse()
+            .map_err(|_| Error::StringError("Invalid OpenAI API key".to_owned()))?,
+    );
+    headers.insert("OpenAI-Beta", "realtime=v1".parse().unwrap());
+    let (ws, _) = tokio_tungstenite::connect_async(request).await?;
+    let (mut write, mut read) = ws.split();
+    write
+        .send(Message::Text(
+            json!({ "type": "session.update", "session": session }).to_string(),
+        ))
+        .await?;
+    loop {
+        tokio::select! {
+            chunk = frames.recv() => match chunk {
+                Some(chunk) => {
+                    let audio = base64::engine::general_purpose::STANDARD.encode(chunk);
+                    write
+                        .send(Message::Text(json!({ "type": "input_audio_buffer.append", "audio": audio }).to_string()))
+                        .await?;
+                }
+                None => break, // end_realtime_session() was called
+            },
+            message = read.next() => match message {
+                Some(message) => {
+                    if let Message::Text(message) = message? {
+                        handle_server_event(&window, &playback, &message)?;
+                    }
+                }
+                None => break,
+            },
+        }
+    }
+    let _ = write.close().await;
+    Ok(())
+}
+
+/// Starts a voice conversation, ending the current one if any.
+#[tauri::command]
+#[tracing::instrument(skip(window, api_key, instructions, state), err)]
+pub(crate) async fn start_realtime_session(
+    window: tauri::Window,
+    api_key: String,
+    model: Option<String>,
+    instructions: Option<String>,
+    voice: Option<String>, // e.g. "alloy"
+    state: tauri::State<'_, AppState>,
+) -> Result<(), Error> {
+    let stop = Arc::new(AtomicBool::new(false));
+    if let Some(previous) = state
+        .audio
+        .realtime
+        .lock()?
+        .replace(RealtimeSession { stop: stop.clone() })
+    {
+        previous.stop.store(true, Ordering::SeqCst);
+    }
+
+    let mut session = json!({
+        "modalities": ["text", "audio"],
+        "input_audio_format": "pcm16",
+        "output_audio_format": "pcm16",
+        "input_audio_transcription": { "model": "whisper-1" },
+        "turn_detection": { "type": "server_vad" },
+    });
+    if let Some(instructions) = instructions {
+        session["instructions"] = Value::String(instructions);
+    }
+    if let Some(voice) = voice {
+        session["voice"] = Value::String(voice);
+    }
+
+    let (frame_sender, frame_receiver) = mpsc::unbounded_channel();
+    let (playback_sender, playback_receiver) = std::sync::mpsc::channel();
+    {
+        let stop = stop.clone();
+        std::thread::spawn(move || {
+            if let Err(err) = capture(stop, frame_sender) {
+                tracing::error!("realtime capture: {err}");
+            }
+        });
+    }
+    {
+        let stop = stop.clone();
+        let audio = state.audio.clone();
+        std::thread::spawn(move || {
+            if let Err(err) = play(&audio, stop, playback_receiver) {
+                tracing::error!("realtime playback: {err}");
+            }
+        });
+    }
+    tauri::async_runtime::spawn(async move {
+        let result = run_session(
+            window.clone(),
+            api_key,
+            model.unwrap_or_else(|| DEFAULT_MODEL.to_owned()),
+            session,
+            frame_receiver,
+            playback_sender,
+        )
+        .await;
+        stop.store(true, Ordering::SeqCst);
+        let _ = window.emit("realtime://ended", result.err().map(|err| err.to_string()));
+    });
+    Ok(())
+}
+
+#[tauri::command]
+#[tracing::instrument(skip(state), err)]
+pub(crate) fn end_realtime_session(state: tauri::State<AppState>) -> Result<(), Error> {
+    if let Some(session) = state.audio.realtime.lock()?.take() {
+        session.stop.store(true, Ordering::SeqCst);
+    }
+    Ok(())
+}
@@ -0,0 +1,364 @@
+//! Encoding of the dictation recorded by `start_listening`.
+//!
+//! Recordings are compressed to 16 kHz mono FLAC while they are captured, unless the `dictationKeepWav` config is set,
+//! in which case the device's samples are written to a 32-bit float WAV file as is for debugging. The FLAC frames are
+//! encoded and written as soon as a block of samples is complete. Silence trimming and automatic gain control need the
+//! whole recording, so when either is enabled the samples are kept until the recording is finished.
+
+use crate::mic_processing::{normalize_gain, trim_silence, SilenceTrimming};
+use crate::whisper::Downsampler;
+use crate::{AppState, Error};
+use flacenc::component::BitRepr;
+use flacenc::error::Verify;
+use flacenc::source::Fill;
+use std::io::{Seek, Write};
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub(crate) enum RecordingFormat {
+    Wav,
+    Flac,
+}
+
+impl RecordingFormat {
+    pub(crate) fn extension(self) -> &'static str {
+        match self {
+            RecordingFormat::Wav => "wav",
+            RecordingFormat::Flac => "flac",
+        }
+    }
+
+    pub(crate) fn mime(self) -> &'static str {
+        match self {
+            RecordingFormat::Wav => "audio/x-wav",
+            RecordingFormat::Flac => "audio/flac",
+        }
+    }
+}
+
+pub(crate) enum RecordingSink {
+    Wav(hound::WavWriter<std::io::BufWriter<std::fs::File>>),
+    Flac {
+        writer: FlacWriter,
+        sample_rate: u32,
+        downsampler: Downsampler,
+        /// The samples kept until the recording is finished, if silence trimming or automatic gain control is enabled.
+        samples: Option<Vec<i32>>,
+        /// The maximum gain of automatic gain control in dB, or None if it is disabled.
+        max_gain_db: Option<f32>,
+        /// None if silence trimming is disabled.
+        silence: Option<SilenceTrimming>,
+    },
+    /// Sends 16 kHz mono 16-bit little-endian PCM in chunks of `STREAM_CHUNK_SAMPLES`, for streaming speech-to-text.
+    Stream {
+        downsampler: Downsampler,
+        buffer: Vec<u8>,
+        sender: tokio::sync::mpsc::UnboundedSender<Vec<u8>>,
+    },
+}
+
+/// 100 ms at 16 kHz.
+const STREAM_CHUNK_SAMPLES: usize = 1600;
+
+impl RecordingSink {
+    pub(crate) fn create(
+        format: RecordingFormat,
+        path: &Path,
+        sample_rate: u32,
+        max_gain_db: Option<f32>,
+        silence: Option<SilenceTrimming>,
+    ) -> Result<Self, Error> {
+        Ok(match format {
+            RecordingFormat::Wav => RecordingSink::Wav(hound::WavWriter::create(
+                path,
+                hound::WavSpec {
+                    channels: 1,
+                    sample_rate,
+                    bits_per_sample: 32,
+                    sample_format: hound::SampleFormat::Float,
+                },
+            )?),
+            RecordingFormat::Flac => {
+                let output_sample_rate = Downsampler::output_sample_rate(sample_rate);
+                RecordingSink::Flac {
+                    writer: FlacWriter::create(path, output_sample_rate)?,
+                    sample_rate: output_sample_rate,
+                    downsampler: Downsampler::new(sample_rate),
+                    samples: (max_gain_db.is_some() || silence.is_some()).then(Vec::new),
+                    max_gain_db,
+                    silence,
+                }
+            }
+        })
+    }
+
+    /// The sink is closed when `finish()` is called, by dropping `sender`.
+    pub(crate) fn stream(
+        sample_rate: u32,
+        sender: tokio::sync::mpsc::UnboundedSender<Vec<u8>>,
+    ) -> Self {
+        RecordingSink::Stream {
+            downsampler: Downsampler::new(sample_rate),
+            buffer: Vec::with_capacity(STREAM_CHUNK_SAMPLES * 2),
+            sender,
+        }
+    }
+
+    /// Writes a mono sample in [-1, 1].
+    pub(crate) fn write(&mut self, sample: f32) -> Result<(), Error> {
+        match self {
+            RecordingSink::Wav(writer) => writer.write_sample(sample)?,
+            RecordingSink::Flac {
+                writer,
+                downsampler,
+                samples,
+                ..
+            } => {
+                if let Some(sample) = downsampler.push(sample) {
+                    match samples {
+                        Some(samples) => samples.push(sample as i32),
+                        None => writer.push(sample as i32)?,
+                    }
+                }
+            }
+            RecordingSink::Stream {
+                downsampler,
+                buffer,
+                sender,
+            } => {
+                if let Some(sample) = downsampler.push(sample) {
+                    buffer.extend(sample.to_le_bytes());
+                    if buffer.len() >= STREAM_CHUNK_SAMPLES * 2 {
+                        // The receiver is dropped if the connection failed, which is reported by the session instead.
+                        let _ = sender.send(std::mem::take(buffer));
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Finalizes the WAV header, encodes the FLAC file, or sends the remaining samples.
+    pub(crate) fn finish(self) -> Result<(), Error> {
+        match self {
+            RecordingSink::Wav(writer) => writer.finalize()?,
+            RecordingSink::Flac {
+                mut writer,
+                sample_rate,
+                mut downsampler,
+                samples,
+                max_gain_db,
+                silence,
+            } => {
+                let last = downsampler.flush().map(i32::from);
+                match samples {
+                    Some(mut samples) => {
+                        samples.extend(last);
+                        if let Some(silence) = silence {
+                            let removed = trim_silence(&mut samples, sample_rate, silence);
+                            tracing::info!(
+                                removed_ms = removed.as_millis() as u64,
+                                "trimmed the silence"
+                            );
+                        }
+                        if let Some(max_gain_db) = max_gain_db {
+                            let gain_db = normalize_gain(&mut samples, sample_rate, max_gain_db);
+                            tracing::info!(gain_db, "applied automatic gain control");
+                        }
+                        for sample in samples {
+                            writer.push(sample)?;
+                        }
+                    }
+                    None => {
+                        if let Some(sample) = last {
+                            writer.push(sample)?;
+                        }
+                    }
+                }
+                writer.finish()?;
+            }
+            RecordingSink::Stream {
+                mut downsampler,
+                mut buffer,
+                sender,
+            } => {
+                if let Some(sample) = downsampler.flush() {
+                    buffer.extend(sample.to_le_bytes());
+                }
+                if !buffer.is_empty() {
+                    let _ = sender.send(buffer);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn flac_error(err: impl std::fmt::Debug) -> Error {
+    Error::StringError(format!("Failed to encode the recording: {err:?}"))
+}
+
+/// Writes 16-bit mono FLAC, encoding a frame whenever a block of samples is complete.
+///
+/// The STREAMINFO header is written first with the sample counts unknown, and rewritten in place by `finish`.
+pub(crate) struct FlacWriter {
+    file: std::io::BufWriter<std::fs::File>,
+    config: flacenc::error::Verified<flacenc::config::Encoder>,
+    stream_info: flacenc::component::StreamInfo,
+    block: Vec<i32>,
+    frame_number: usize,
+}
+
+impl FlacWriter {
+    fn create(path: &Path, sample_rate: u32) -> Result<Self, Error> {
+        let config = flacenc::config::Encoder::default()
+            .into_verified()
+            .map_err(|(_, err)| flac_error(err))?;
+        let stream_info =
+            flacenc::component::StreamInfo::new(sample_rate as usize, 1, 16).map_err(flac_error)?;
+        let mut writer = Self {
+            file: std::io::BufWriter::new(std::fs::File::create(path)?),
+            block: Vec::with_capacity(config.block_size),
+            config,
+            stream_info,
+            frame_number: 0,
+        };
+        writer.write_header()?;
+        Ok(writer)
+    }
+
+    /// Writes the "fLaC" marker and the STREAMINFO block, which has the same length however many samples there are.
+    fn write_header(&mut self) -> Result<(), Error> {
+        let stream = flacenc::component::Stream::with_stream_info(self.stream_info.clone());
+        let mut sink = flacenc::bitsink::ByteSink::new();
+        stream.write(&mut sink).map_err(flac_error)?;
+        self.file.write_all(sink.as_slice())?;
+        Ok(())
+    }
+
+    fn push(&mut self, sample: i32) -> Result<(), Error> {
+        self.block.push(sample);
+        if self.block.len() == self.config.block_size {
+            self.encode_block()?;
+        }
+        Ok(())
+    }
+
+    fn encode_block(&mut self) -> Result<(), Error> {
+        let mut framebuf =
+            flacenc::source::FrameBuf::with_size(1, self.block.len()).map_err(flac_error)?;
+        framebuf.fill_interleaved(&self.block).map_err(flac_error)?;
+        let frame = flacenc::encode_fixed_size_frame(
+            &self.config,
+            &framebuf,
+            self.frame_number,
+            &self.stream_info,
+        )
+        .map_err(flac_error)?;
+        self.stream_info.update_frame_info(&frame);
+        let mut sink = flacenc::bitsink::ByteSink::new();
+        frame.write(&mut sink).map_err(flac_error)?;
+        self.file.write_all(sink.as_slice())?;
+        self.frame_number += 1;
+        self.block.clear();
+        Ok(())
+    }
+
+    /// Encodes the last, shorter block and rewrites the header with the sample counts.
+    fn finish(mut self) -> Result<(), Error> {
+        if !self.block.is_empty() {
+            self.encode_block()?;
+        }
+        self.file.seek(std::io::SeekFrom::Start(0))?;
+        self.write_header()?;
+        self.file.flush()?;
+        Ok(())
+    }
+}
+
+const LOUDNESS_EVENT_INTERVAL: Duration = Duration::from_millis(50); // 20 Hz
+
+/// A sample is considered clipped if its magnitude reaches this value.
+const CLIPPING_THRESHOLD: f32 = 0.999;
+
+#[derive(serde::Serialize, Clone)]
+pub(crate) struct InputLoudness {
+    /// Root mean square of the samples since the previous event, or -1 once the recording has stopped and is being transcribed.
+    pub(crate) rms: f32,
+    pub(crate) peak: f32,
+    pub(crate) clipping: bool,
+}
+
+/// Emits the `mic://loudness` event from the input stream's callback, at most every `LOUDNESS_EVENT_INTERVAL`.
+pub(crate) struct LoudnessMeter {
+    window: tauri::Window,
+    last_emit: Instant,
+    sum_squares: f64,
+    count: u64,
+    peak: f32,
+}
+
+impl LoudnessMeter {
+    pub(crate) fn new(window: tauri::Window) -> Self {
+        Self {
+            window,
+            last_emit: Instant::now(),
+            sum_squares: 0.0,
+            count: 0,
+            peak: 0.0,
+        }
+    }
+
+    /// Accumulates the samples of every channel, before they are mixed down, so that clipping in any channel is reported.
+    pub(crate) fn push(&mut self, samples: impl IntoIterator<Item = f32>) {
+        for x in samples {
+            self.sum_squares += (x * x) as f64;
+            self.count += 1;
+            self.peak = self.peak.max(x.abs());
+        }
+        if self.count == 0 || self.last_emit.elapsed() < LOUDNESS_EVENT_INTERVAL {
+            return;
+        }
+        let _ = self.window.emit(
+            "mic://loudness",
+            InputLoudness {
+                rms: (self.sum_squares / self.count as f64).sqrt() as f32,
+                peak: self.peak,
+                clipping: self.peak >= CLIPPING_THRESHOLD,
+            },
+        );
+        self.last_emit = Instant::now();
+        (self.sum_squares, self.count, self.peak) = (0.0, 0, 0.0);
+    }
+}
+
+/// The audio file of the last dictation, which is deleted when dropped.
+pub(crate) struct LastRecording {
+    pub(crate) path: tempfile::TempPath,
+    pub(crate) format: RecordingFormat,
+}
+
+/// Copies the audio of the last dictation to `path`, and returns the path written to.
+/// Its format is FLAC, or WAV if `dictationKeepWav` is set, and the extension is appended if `path` has none.
+#[tauri::command]
+#[tracing::instrument(skip(state), err)]
+pub(crate) async fn save_last_recording(
+    path: String,
+    state: tauri::State<'_, AppState>,
+) -> Result<String, Error> {
+    let (src, format) = match &*state.audio.last_recording.lock()? {
+        Some(recording) => (recording.path.to_path_buf(), recording.format),
+        None => {
+            return Err(Error::StringError(
+                "No recording is retained. Enable dictationKeepLastRecording to keep the last dictation.".to_owned(),
+            ))
+        }
+    };
+    let mut path = PathBuf::from(path);
+    if path.extension().is_none() {
+        path.set_extension(format.extension());
+    }
+    tokio::fs::copy(src, &path).await?;
+    Ok(path.to_string_lossy().into_owned())
+}
@@ -0,0 +1,477 @@
+//! Prompts run on a schedule, e.g. a daily summary or a standup draft, stored in `scheduledPrompt`.
+//!
+//! A schedule is a cron expression of five fields (minute, hour, day of month, month, day of week) in local time, with
+//! `*`, lists, ranges, and steps, e.g. "30 8 * * 1-5" for 8:30 on weekdays. The timer loop started by `start` checks the
+//! schedules at the beginning of every minute. Runs missed while the app was not running are skipped.
+//!
+//! A run renders the prompt template with `{{date}}` and `{{time}}`, asks the configured model, and appends the question
+//! and the answer to the conversation, or to a new one. A notification is shown, and every window receives
+//! `scheduler://completed` ({ scheduleId, conversationId, content, speak }) so that the answer can be read aloud. The runs
+//! are spawned, so that a slow model does not delay the other schedules, and they do not hold a connection of the pool
+//! while waiting for the answer.
+
+use crate::conversation::load_thread;
+use crate::prompt_template::render_template;
+use crate::quick_ask::{ask_pooled, configured_model};
+use crate::{AppState, Error};
+use sqlx::{Connection, Row};
+use std::collections::HashMap;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tauri::{AppHandle, Manager};
+
+/// The values allowed by each field of a cron expression, as bit sets.
+struct Cron {
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    /// Whether the day of month or the day of week is "*", in which case a day only has to match the other one.
+    any_day: bool,
+    any_weekday: bool,
+}
+
+fn parse_field(field: &str, min: u32, max: u32) -> Result<u64, Error> {
+    let invalid = || Error::StringError(format!("Invalid cron field: {field}"));
+    let mut bits = 0u64;
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (range, step.parse::<u32>().map_err(|_| invalid())?),
+            None => (part, 1),
+        };
+        let (start, end) = match range {
+            "*" => (min, max),
+            _ => match range.split_once('-') {
+                Some((start, end)) => (
+                    start.parse().map_err(|_| invalid())?,
+                    end.parse().map_err(|_| invalid())?,
+                ),
+                None => {
+                    let value = range.parse().map_err(|_| invalid())?;
+                    (value, if part.contains('/') { max } else { value })
+                }
+            },
+        };
+        if step == 0 || start < min || end > max || start > end {
+            return Err(invalid());
+        }
+        for value in (start..=end).step_by(step as usize) {
+            bits |= 1 << value;
+        }
+    }
+    Ok(bits)
+}
+
+impl Cron {
+    fn parse(expression: &str) -> Result<Self, Error> {
+        let fields = expression.split_whitespace().collect::<Vec<_>>();
+        let [minutes, hours, days, months, weekdays] = fields[..] else {
+            return Err(Error::StringError(format!(
+                "A cron expression has 5 fields: {expression}"
+            )));
+        };
+        let mut weekday_bits = parse_field(weekdays, 0, 7)?;
+        if weekday_bits & (1 << 7) != 0 {
+            weekday_bits |= 1; // 7 is also Sunday
+        }
+        Ok(Self {
+            minutes: parse_field(minutes, 0, 59)?,
+            hours: parse_field(hours, 0, 23)?,
+            days: parse_field(days, 1, 31)?,
+            months: parse_field(months, 1, 12)?,
+            weekdays: weekday_bits,
+            any_day: days == "*",
+            any_weekday: weekdays == "*",
+        })
+    }
+
+    fn matches(&self, now: &LocalTime) -> bool {
+        let has = |bits: u64, value: u32| bits & (1 << value) != 0;
+        let day = has(self.days, now.day);
+        let weekday = has(self.weekdays, now.weekday);
+        let day_matches = match (self.any_day, self.any_weekday) {
+            (false, false) => day || weekday,
+            _ => day && weekday,
+        };
+        has(self.minutes, now.minute)
+            && has(self.hours, now.hour)
+            && has(self.months, now.month)
+            && day_matches
+    }
+}
+
+struct LocalTime {
+    minute: u32,
+    hour: u32,
+    day: u32,
+    month: u32,
+    weekday: u32, // 0 = Sunday
+    /// "YYYY-MM-DD HH:MM", which identifies the minute of a run.
+    key: String,
+}
+
+/// The local time, from SQLite since the standard library has no time zones.
+async fn local_time(conn: &mut sqlx::SqliteConnection) -> Result<LocalTime, Error> {
+    let row = sqlx::query(
+        "
+SELECT CAST(strftime('%M', 'now', 'localtime') AS INTEGER) AS minute, CAST(strftime('%H', 'now', 'localtime') AS INTEGER) AS hour,
+    CAST(strftime('%d', 'now', 'localtime') AS INTEGER) AS day, CAST(strftime('%m', 'now', 'localtime') AS INTEGER) AS month,
+    CAST(strftime('%w', 'now', 'localtime') AS INTEGER) AS weekday, strftime('%Y-%m-%d %H:%M', 'now', 'localtime') AS key
+",
+    )
+    .fetch_one(conn)
+    .await?;
+    Ok(LocalTime {
+        minute: row.get("minute"),
+        hour: row.get("hour"),
+        day: row.get("day"),
+        month: row.get("month"),
+        weekday: row.get("weekday"),
+        key: row.get("key"),
+    })
+}
+
+#[derive(serde::Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+struct Completed {
+    schedule_id: i64,
+    conversation_id: i64,
+    content: String,
+    speak: bool,
+}
+
+#[derive(serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct ScheduledPrompt {
+    id: i64,
+    cron: String,
+    template_id: i64,
+    template_name: String,
+    /// None to start a new conversation on each run.
+    conversation_id: Option<i64>,
+    speak: bool,
+    /// The local time of the last run, "YYYY-MM-DD HH:MM".
+    last_run_at: Option<String>,
+}
+
+/// Appends the messages to the end of the conversation shown by default, or to a new conversation if `conversation_id`
+/// is None. Returns the id of the conversation.
+async fn store(
+    conn: &mut sqlx::SqliteConnection,
+    conversation_id: Option<i64>,
+    name: &str,
+    model: &str,
+    question: &str,
+    answer: &str,
+) -> Result<i64, Error> {
+    let parent = match conversation_id {
+        Some(id) => Some(
+            load_thread(&mut *conn, id)
+                .await?
+                .last()
+                .map_or(id, |m| m.id),
+        ),
+        None => None,
+    };
+    let mut tx = conn.begin().await?;
+    let (conversation_id, parent) = match parent {
+        Some(parent) => (conversation_id.unwrap_or(parent), parent),
+        None => {
+            let root = sqlx::query(
+                "INSERT INTO message (parent, role, status, content) VALUES (NULL, 'root', 0, '')",
+            )
+            .execute(&mut *tx)
+            .await?
+            .last_insert_rowid();
+            sqlx::query("INSERT INTO threadName (messageId, name) VALUES (?, ?)")
+                .bind(root)
+                .bind(name)
+                .execute(&mut *tx)
+                .await?;
+            (root, root)
+        }
+    };
+    let question =
+        sqlx::query("INSERT INTO message (parent, role, status, content) VALUES (?, 'user', 0, ?)")
+            .bind(parent)
+            .bind(question)
+            .execute(&mut *tx)
+            .await?
+            .last_insert_rowid();
+    let answer = sqlx::query(
+        "INSERT INTO message (parent, role, status, content) VALUES (?, 'assistant', 0, ?)",
+    )
+    .bind(question)
+    .bind(answer)
+    .execute(&mut *tx)
+    .await?
+    .last_insert_rowid();
+    sqlx::query("INSERT INTO messageModelV2 (messageId, model) VALUES (?, ?)")
+        .bind(answer)
+        .bind(model)
+        .execute(&mut *tx)
+        .await?;
+    tx.commit().await?;
+    Ok(conversation_id)
+}
+
+/// `key` is the local time of the run, "YYYY-MM-DD HH:MM".
+async fn run(
+    app: &AppHandle,
+    key: &str,
+    schedule_id: i64,
+    template_id: i64,
+    conversation_id: Option<i64>,
+    speak: bool,
+) -> Result<(), Error> {
+    let state = app.state::<AppState>();
+    let (date, time) = key.split_once(' ').unwrap_or_default();
+    let vars = HashMap::from([
+        ("date".to_owned(), date.to_owned()),
+        ("time".to_owned(), time.to_owned()),
+    ]);
+    let (prompt, name, model) = {
+        let mut conn = state.db_pool.acquire().await?;
+        let prompt = render_template(&mut conn, template_id, &vars).await?;
+        let name: String = sqlx::query("SELECT name FROM promptTemplate WHERE id = ?")
+            .bind(template_id)
+            .fetch_one(&mut conn)
+            .await?
+            .get("name");
+        (prompt, name, configured_model(&mut conn).await?)
+    };
+    let answer = ask_pooled(&state.db_pool, &model, prompt.clone()).await?;
+    let mut conn = state.db_pool.acquire().await?;
+    let conversation_id = store(
+        &mut conn,
+        conversation_id,
+        &format!("{name} {date}"),
+        &model,
+        &prompt,
+        &answer,
+    )
+    .await?;
+    drop(conn);
+
+    tauri::api::notification::Notification::new(&app.config().tauri.bundle.identifier)
+        .title(&name)
+        .body(answer.chars().take(200).collect::<String>())
+        .show()?;
+    app.emit_all(
+        "scheduler://completed",
+        Completed {
+            schedule_id,
+            conversation_id,
+            content: answer,
+            speak,
+        },
+    )?;
+    Ok(())
+}
+
+/// Runs the schedules that match the current minute and have not run in it yet.
+async fn run_due(app: &AppHandle) -> Result<(), Error> {
+    let state = app.state::<AppState>();
+    let mut conn = state.db_pool.acquire().await?;
+    let now = local_time(&mut conn).await?;
+    let schedules = sqlx::query(
+        "SELECT id, cron, templateId, conversationId, speak FROM scheduledPrompt WHERE lastRunAt IS NULL OR lastRunAt != ?",
+    )
+    .bind(&now.key)
+    .fetch_all(&mut conn)
+    .await?;
+    for row in schedules {
+        let id: i64 = row.get("id");
+        match Cron::parse(row.get("cron")) {
+            Ok(cron) if cron.matches(&now) => {}
+            Ok(_) => continue,
+            Err(err) => {
+                tracing::warn!("invalid schedule {id}: {err}");
+                continue;
+            }
+        }
+        sqlx::query("UPDATE scheduledPrompt SET lastRunAt = ? WHERE id = ?")
+            .bind(&now.key)
+            .bind(id)
+            .execute(&mut conn)
+            .await?;
+        let app = app.clone();
+        let key = now.key.clone();
+        let template_id = row.get("templateId");
+        let conversation_id = row.get("conversationId");
+        let speak = row.get::<i64, _>("speak") != 0;
+        tauri::async_runtime::spawn(async move {
+            if let Err(err) = run(&app, &key, id, template_id, conversation_id, speak).await {
+                tracing::error!("failed to run the scheduled prompt {id}: {err}");
+            }
+        });
+    }
+    Ok(())
+}
+
+/// Starts the timer loop that runs the scheduled prompts.
+pub(crate) fn start(app: AppHandle) {
+    tauri::async_runtime::spawn(async move {
+        loop {
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default();
+            tokio::time::sleep(Duration::from_secs(60 - now.as_secs() % 60)).await;
+            if let Err(err) = run_due(&app).await {
+                tracing::warn!("failed to run the scheduled prompts: {err}");
+            }
+        }
+    });
+}
+
+/// Runs the prompt template on the cron schedule, and returns the id of the schedule. The answers are appended to the
+/// conversation, or to a new conversation on each run if `conversation_id` is None.
+#[tauri::command]
+#[tracing::instrument(skip(state), err)]
+pub(crate) async fn schedule_prompt(
+    cron: String,
+    template_id: i64,
+    conversation_id: Option<i64>,
+    speak: Option<bool>, // reads the answer aloud, defaults to false
+    state: tauri::State<'_, AppState>,
+) -> Result<i64, Error> {
+    Cron::parse(&cron)?;
+    let mut conn = state.db_pool.acquire().await?;
+    Ok(sqlx::query(
+        "INSERT INTO scheduledPrompt (cron, templateId, conversationId, speak) VALUES (?, ?, ?, ?)",
+    )
+    .bind(cron.trim())
+    .bind(template_id)
+    .bind(conversation_id)
+    .bind(speak.unwrap_or(false))
+    .execute(&mut conn)
+    .await?
+    .last_insert_rowid())
+}
+
+#[tauri::command]
+#[tracing::instrument(skip(state), err)]
+pub(crate) async fn list_scheduled_prompts(
+    state: tauri::State<'_, AppState>,
+) -> Result<Vec<ScheduledPrompt>, Error> {
+    let mut conn = state.db_pool.acquire().await?;
+    Ok(sqlx::query(
+        "
+SELECT scheduledPrompt.id AS id, cron, templateId, promptTemplate.name AS templateName, conversationId, speak, lastRunAt
+FROM scheduledPrompt
+JOIN promptTemplate ON promptTemplate.id = scheduledPrompt.templateId
+ORDER BY scheduledPrompt.id
+",
+    )
+    .fetch_all(&mut conn)
+    .await?
+    .into_iter()
+    .map(|row| ScheduledPrompt {
+        id: row.get("id"),
+        cron: row.get("cron"),
+        template_id: row.get("templateId"),
+        template_name: row.get("templateName"),
+        conversation_id: row.get("conversationId"),
+        speak: row.get::<i64, _>("speak") != 0,
+        last_run_at: row.get("lastRunAt"),
+    })
+    .collect())
+}
+
+#[tauri::command]
+#[tracing::instrument(skip(state), err)]
+pub(crate) async fn delete_scheduled_prompt(
+    id: i64,
+    state: tauri::State<'_, AppState>,
+) -> Result<(), Error> {
+    let mut conn = state.db_pool.acquire().await?;
+    sqlx::query("DELETE FROM scheduledPrompt WHERE id = ?")
+        .bind(id)
+        .execute(&mut conn)
+        .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn values(bits: u64) -> Vec<u32> {
+        (0..64).filter(|value| bits & (1 << value) != 0).collect()
+    }
+
+    fn at(minute: u32, hour: u32, day: u32, month: u32, weekday: u32) -> LocalTime {
+        LocalTime {
+            minute,
+            hour,
+            day,
+            month,
+            weekday,
+            key: String::new(),
+        }
+    }
+
+    #[test]
+    fn fields() {
+        assert_eq!(
+            values(parse_field("*", 0, 23).unwrap()),
+            (0..=23).collect::<Vec<_>>()
+        );
+        assert_eq!(values(parse_field("1,5,7", 0, 59).unwrap()), [1, 5, 7]);
+        assert_eq!(values(parse_field("10-12", 0, 59).unwrap()), [10, 11, 12]);
+        assert_eq!(values(parse_field("*/15", 0, 59).unwrap()), [0, 15, 30, 45]);
+        assert_eq!(values(parse_field("5/20", 0, 59).unwrap()), [5, 25, 45]);
+        assert_eq!(
+            values(parse_field("1-10/3,20", 0, 59).unwrap()),
+            [1, 4, 7, 10, 20]
+        );
+    }
+
+    #[test]
+    fn invalid_fields_are_rejected() {
+        assert!(parse_field("60", 0, 59).is_err());
+        assert!(parse_field("0-24", 0, 23).is_err());
+        assert!(parse_field("0", 1, 31).is_err());
+        assert!(parse_field("*/0", 0, 59).is_err());
+        assert!(parse_field("5-1", 0, 59).is_err());
+        assert!(parse_field("a", 0, 59).is_err());
+        assert!(parse_field("", 0, 59).is_err());
+        assert!(Cron::parse("0 9 * *").is_err());
+        assert!(Cron::parse("0 9 * * * *").is_err());
+        assert!(Cron::parse("0 9 * 13 *").is_err());
+    }
+
+    #[test]
+    fn weekday_7_is_sunday() {
+        let cron = Cron::parse("0 9 * * 7").unwrap();
+        assert!(cron.matches(&at(0, 9, 7, 1, 0)));
+        assert!(!cron.matches(&at(0, 9, 8, 1, 1)));
+    }
+
+    #[test]
+    fn matches_the_time() {
+        let cron = Cron::parse("30 8 * * 1-5").unwrap();
+        assert!(cron.matches(&at(30, 8, 15, 5, 1)));
+        assert!(!cron.matches(&at(31, 8, 15, 5, 1)));
+        assert!(!cron.matches(&at(30, 9, 15, 5, 1)));
+        assert!(!cron.matches(&at(30, 8, 14, 5, 0)));
+        let cron = Cron::parse("0 0 1 */3 *").unwrap();
+        assert!(cron.matches(&at(0, 0, 1, 4, 3)));
+        assert!(!cron.matches(&at(0, 0, 1, 5, 3)));
+        assert!(!cron.matches(&at(0, 0, 2, 4, 3)));
+    }
+
+    #[test]
+    fn day_of_month_or_day_of_week() {
+        // Either the 13th or a Friday, as in standard cron.
+        let cron = Cron::parse("0 9 13 * 5").unwrap();
+        assert!(cron.matches(&at(0, 9, 12, 6, 5)));
+        assert!(cron.matches(&at(0, 9, 13, 6, 3)));
+        assert!(!cron.matches(&at(0, 9, 14, 6, 3)));
+        // Only the restricted one when the other is "*".
+        let cron = Cron::parse("0 9 13 * *").unwrap();
+        assert!(!cron.matches(&at(0, 9, 12, 6, 5)));
+        let cron = Cron::parse("0 9 * * 5").unwrap();
+        assert!(!cron.matches(&at(0, 9, 13, 6, 3)));
+    }
+}
@@ -0,0 +1,138 @@
+//! Captures the screen to attach it to a prompt for a vision model.
+//!
+//! The global shortcut and the tray menu capture the whole screen, then show the main window and send it
+//! `screenshot://captured` (an `ImageAttachment`).
+//!
+//! The capture is saved to a temporary PNG only to be prepared as an attachment, and the file is deleted right after.
+
+use crate::tray::show_main_window;
+use crate::vision::{prepare, ImageAttachment, DEFAULT_MAX_DIMENSION};
+use crate::Error;
+use std::time::Duration;
+use tauri::{AppHandle, GlobalShortcutManager, Manager};
+
+pub(crate) const SCREENSHOT_SHORTCUT: &str = "CmdOrCtrl+Alt+S";
+/// Waits for the tray menu to close before capturing.
+const TRAY_MENU_DELAY: Duration = Duration::from_millis(300);
+
+#[derive(serde::Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub(crate) enum CaptureTarget {
+    /// The primary monitor.
+    Full,
+    /// The frontmost window whose title contains `title`, or the frontmost window of another app.
+    Window { title: Option<String> },
+    /// A rectangle in the coordinates of the virtual screen.
+    Region {
+        x: i32,
+        y: i32,
+        width: u32,
+        height: u32,
+    },
+}
+
+fn xcap_error(err: xcap::XCapError) -> Error {
+    Error::StringError(format!("Failed to capture the screen: {err}"))
+}
+
+fn capture(app: &AppHandle, target: &CaptureTarget) -> Result<ImageAttachment, Error> {
+    let image = match target {
+        CaptureTarget::Full => {
+            let monitors = xcap::Monitor::all().map_err(xcap_error)?;
+            let monitor = monitors
+                .iter()
+                .find(|m| m.is_primary())
+                .or(monitors.first())
+                .ok_or_else(|| Error::StringError("No monitor is found".to_owned()))?;
+            monitor.capture_image().map_err(xcap_error)?
+        }
+        CaptureTarget::Window { title } => {
+            let own_titles = app
+                .windows()
+                .values()
+                .filter_map(|w| w.title().ok())
+                .collect::<Vec<_>>();
+            xcap::Window::all()
+                .map_err(xcap_error)?
+                .into_iter()
+                .filter(|w| !w.is_minimized() && !w.title().is_empty())
+                .find(|w| match title {
+                    Some(title) => w.title().contains(title.as_str()),
+                    None => !own_titles.iter().any(|t| t == w.title()),
+                })
+                .ok_or_else(|| Error::StringError("No window is found".to_owned()))?
+                .capture_image()
+                .map_err(xcap_error)?
+        }
+        CaptureTarget::Region {
+            x,
+            y,
+            width,
+            height,
+        } => {
+            let monitor = xcap::Monitor::from_point(*x, *y).map_err(xcap_error)?;
+            let image = monitor.capture_image().map_err(xcap_error)?;
+            image::imageops::crop_imm(
+                &image,
+                (x - monitor.x()).max(0) as u32,
+                (y - monitor.y()).max(0) as u32,
+                *width,
+                *height,
+            )
+            .to_image()
+        }
+    };
+    let path = tempfile::Builder::new()
+        .prefix("screenshot-")
+        .suffix(".png")
+        .tempfile()?
+        .into_temp_path(); // deleted when dropped
+    image
+        .save(&path)
+        .map_err(|err| Error::StringError(format!("Failed to save the screenshot: {err}")))?;
+    prepare(&path, DEFAULT_MAX_DIMENSION)
+}
+
+/// Captures the whole screen in the background and sends it to the main window.
+pub(crate) fn capture_and_emit(app: &AppHandle, delay: Duration) {
+    let app = app.clone();
+    tauri::async_runtime::spawn(async move {
+        tokio::time::sleep(delay).await;
+        let handle = app.clone();
+        let result =
+            tauri::async_runtime::spawn_blocking(move || capture(&handle, &CaptureTarget::Full))
+                .await;
+        match result {
+            Ok(Ok(screenshot)) => {
+                show_main_window(&app);
+                let _ = app.emit_to("main", "screenshot://captured", screenshot);
+            }
+            Ok(Err(err)) => tracing::error!("failed to capture the screen: {err}"),
+            Err(err) => tracing::error!("failed to capture the screen: {err}"),
+        }
+    });
+}
+
+/// Captures from the tray menu.
+pub(crate) fn capture_from_tray(app: &AppHandle) {
+    capture_and_emit(app, TRAY_MENU_DELAY);
+}
+
+pub(crate) fn register_shortcut(app: &AppHandle) -> Result<(), Error> {
+    let handle = app.clone();
+    app.global_shortcut_manager()
+        .register(SCREENSHOT_SHORTCUT, move || {
+            capture_and_emit(&handle, Duration::ZERO);
+        })?;
+    Ok(())
+}
+
+/// Captures the screen, a window, or a region, and returns it as an attachment for a vision model.
+#[tauri::command]
+#[tracing::instrument(skip(app), err)]
+pub(crate) async fn capture_screenshot(
+    app: AppHandle,
+    target: CaptureTarget,
+) -> Result<ImageAttachment, Error> {
+    tauri::async_runtime::spawn_blocking(move || capture(&app, &target)).await?
+}
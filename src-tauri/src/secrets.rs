@@ -0,0 +1,69 @@
+//! API keys, stored in the OS credential store of the active profile instead of the config table.
+//!
+//! The frontend reads and writes the settings in `SETTINGS` with `get_secret` and `store_secret`, and the backend reads
+//! them with `read`. Keys stored in the config table by older versions are moved by `migrate`.
+
+use crate::{profile, read_config, Error};
+
+/// The settings that are secrets, which must match `secretConfigKeys` of the frontend.
+pub(crate) const SETTINGS: [&str; 6] = [
+    "APIKey",
+    "azureAPIKey",
+    "deepgramAPIKey",
+    "openaiProxyAPIKey",
+    "openrouterAPIKey",
+    "webSearchAPIKey",
+];
+
+/// Returns None if the secret has not been stored.
+pub(crate) fn read(name: &str) -> Result<Option<String>, Error> {
+    match profile::keyring_entry(name)?.get_password() {
+        Ok(value) => Ok(Some(value)),
+        Err(keyring::Error::NoEntry) => Ok(None),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Moves the secrets stored in the config table into the credential store. A secret that cannot be stored, e.g. because
+/// there is no Secret Service on Linux, is left in the config table and moved on the next start.
+pub(crate) async fn migrate(conn: &mut sqlx::SqliteConnection) -> Result<(), Error> {
+    for name in SETTINGS {
+        let Some(value) = read_config(&mut *conn, name).await? else {
+            continue;
+        };
+        if !value.is_empty() {
+            if let Err(err) = profile::keyring_entry(name)?.set_password(&value) {
+                tracing::warn!("failed to move {name} to the credential store: {err}");
+                continue;
+            }
+        }
+        sqlx::query("DELETE FROM config WHERE key = ?")
+            .bind(name)
+            .execute(&mut *conn)
+            .await?;
+    }
+    Ok(())
+}
+
+#[tauri::command]
+#[tracing::instrument(skip(value), err)]
+pub(crate) fn store_secret(name: String, value: String) -> Result<(), Error> {
+    profile::keyring_entry(&name)?.set_password(&value)?;
+    Ok(())
+}
+
+/// Returns None if the secret has not been stored.
+#[tauri::command]
+#[tracing::instrument(err)]
+pub(crate) fn get_secret(name: String) -> Result<Option<String>, Error> {
+    read(&name)
+}
+
+#[tauri::command]
+#[tracing::instrument(err)]
+pub(crate) fn delete_secret(name: String) -> Result<(), Error> {
+    match profile::keyring_entry(&name)?.delete_password() {
+        Ok(()) | Err(keyring::Error::NoEntry) => Ok(()),
+        Err(err) => Err(err.into()),
+    }
+}
@@ -0,0 +1,142 @@
+//! Search over the chat history by meaning rather than by keywords, complementing `search_messages`.
+//!
+//! The messages are embedded with the model of the search when it runs, so only the messages added since the previous
+//! search are sent to the embedding provider. The embeddings are stored in `messageEmbedding` and deleted by a trigger
+//! when the content of the message changes.
+
+use crate::knowledge_base::{dot_blob, to_blob, EmbeddingProvider, EMBEDDING_BATCH_SIZE};
+use crate::{AppState, Error};
+use sqlx::{Connection, Row};
+use std::collections::HashMap;
+
+const DEFAULT_K: usize = 10;
+/// Longer messages are embedded by their beginning, which keeps them within the input limit of embedding models.
+const MAX_EMBEDDED_CHARS: usize = 6000;
+const SNIPPET_CHARS: usize = 200;
+
+#[derive(serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct SemanticSearchResult {
+    message_id: i64,
+    conversation_id: i64,
+    /// The beginning of the content.
+    snippet: String,
+    /// The cosine similarity to the query, higher is more relevant.
+    score: f32,
+}
+
+/// Embeds the completed user and assistant messages that have no embedding of the model.
+async fn embed_new_messages(
+    conn: &mut sqlx::SqliteConnection,
+    provider: &EmbeddingProvider,
+    model: &str,
+) -> Result<(), Error> {
+    let messages = sqlx::query(
+        "SELECT id, content FROM message WHERE role IN ('user', 'assistant') AND status = 0 AND content != '' AND id NOT IN (SELECT messageId FROM messageEmbedding WHERE model = ?)",
+    )
+    .bind(model)
+    .fetch_all(&mut *conn)
+    .await?
+    .into_iter()
+    .map(|row| {
+        let content: String = row.get("content");
+        (
+            row.get::<i64, _>("id"),
+            content.chars().take(MAX_EMBEDDED_CHARS).collect::<String>(),
+        )
+    })
+    .collect::<Vec<_>>();
+    for batch in messages.chunks(EMBEDDING_BATCH_SIZE) {
+        let contents = batch.iter().map(|(_, c)| c.clone()).collect::<Vec<_>>();
+        let embeddings = provider.embed(&contents).await?;
+        let mut tx = conn.begin().await?;
+        for ((id, _), embedding) in batch.iter().zip(embeddings) {
+            sqlx::query(
+                "INSERT OR REPLACE INTO messageEmbedding (messageId, model, embedding) VALUES (?, ?, ?)",
+            )
+            .bind(id)
+            .bind(model)
+            .bind(to_blob(&embedding))
+            .execute(&mut *tx)
+            .await?;
+        }
+        tx.commit().await?;
+    }
+    Ok(())
+}
+
+/// Returns the `k` messages most similar in meaning to the query, from the most relevant. Messages that have not been
+/// embedded with the model yet are embedded first, which takes a while on the first search.
+#[tauri::command]
+#[tracing::instrument(skip(query, provider, state), err)]
+pub(crate) async fn semantic_search_messages(
+    query: String,
+    k: Option<usize>, // defaults to DEFAULT_K
+    provider: EmbeddingProvider,
+    state: tauri::State<'_, AppState>,
+) -> Result<Vec<SemanticSearchResult>, Error> {
+    if query.trim().is_empty() {
+        return Ok(vec![]);
+    }
+    let model = provider.model_id();
+    let mut conn = state.db_pool.acquire().await?;
+    embed_new_messages(&mut conn, &provider, &model).await?;
+    let query = provider
+        .embed(&[query])
+        .await?
+        .pop()
+        .ok_or_else(|| Error::StringError("No embedding was returned".to_owned()))?;
+
+    let mut scores =
+        sqlx::query("SELECT messageId, embedding FROM messageEmbedding WHERE model = ?")
+            .bind(&model)
+            .fetch_all(&mut conn)
+            .await?
+            .into_iter()
+            .map(|row| {
+                let embedding: Vec<u8> = row.get("embedding");
+                (row.get::<i64, _>("messageId"), dot_blob(&query, &embedding))
+            })
+            .collect::<Vec<_>>();
+    scores.sort_by(|a, b| b.1.total_cmp(&a.1));
+    scores.truncate(k.unwrap_or(DEFAULT_K));
+
+    let ids = serde_json::to_string(&scores.iter().map(|(id, _)| id).collect::<Vec<_>>())?;
+    let mut found = sqlx::query(
+        "
+WITH RECURSIVE ancestors(hit, id, parent) AS (
+    SELECT message.id, message.id, message.parent FROM message WHERE message.id IN (SELECT value FROM json_each(?))
+    UNION ALL
+    SELECT ancestors.hit, message.id, message.parent FROM ancestors JOIN message ON message.id = ancestors.parent
+)
+SELECT ancestors.hit AS messageId, ancestors.id AS conversationId, substr(message.content, 1, ?) AS snippet
+FROM ancestors
+JOIN message ON message.id = ancestors.hit
+WHERE ancestors.parent IS NULL
+",
+    )
+    .bind(ids)
+    .bind(SNIPPET_CHARS as i64)
+    .fetch_all(&mut conn)
+    .await?
+    .into_iter()
+    .map(|row| {
+        (
+            row.get::<i64, _>("messageId"),
+            (row.get::<i64, _>("conversationId"), row.get::<String, _>("snippet")),
+        )
+    })
+    .collect::<HashMap<_, _>>();
+    Ok(scores
+        .into_iter()
+        .filter_map(|(message_id, score)| {
+            let (conversation_id, snippet) = found.remove(&message_id)?;
+            Some(SemanticSearchResult {
+                message_id,
+                conversation_id,
+                snippet,
+                score,
+            })
+        })
+        .collect())
+}
@@ -0,0 +1,162 @@
+//! The settings, stored in the config table of the database of the active profile.
+//!
+//! The frontend keeps them in `useConfigStore` and writes them through `set_setting`, which emits `settings://changed` so
+//! that every window, and not only the one that changed the setting, sees the new value. The backend reads them with
+//! `get`, which falls back to the typed defaults in `DEFAULTS` for the settings that have not been stored yet, so that it
+//! does not depend on the frontend having written them first.
+//!
+//! The API keys in `secrets::SETTINGS` are not settings: they are stored in the OS credential store, and the commands of
+//! this module reject them.
+
+use crate::{AppState, Error};
+use serde_json::Value;
+use sqlx::Row;
+use tauri::{AppHandle, Manager};
+
+#[derive(Clone, Copy, Debug)]
+enum DefaultValue {
+    Integer(i64),
+    Real(f64),
+    Text(&'static str),
+}
+
+/// The defaults of the settings that the backend reads, which must match `defaultConfigValues` of the frontend.
+const DEFAULTS: [(&str, DefaultValue); 17] = [
+    ("budget", DefaultValue::Real(1.0)),
+    ("codeInterpreterNetwork", DefaultValue::Integer(0)),
+    ("customInstructions", DefaultValue::Text("")),
+    ("dictationAutoGain", DefaultValue::Integer(0)),
+    ("dictationCommandPhrases", DefaultValue::Text("{}")),
+    ("dictationKeepLastRecording", DefaultValue::Integer(0)),
+    ("dictationKeepWav", DefaultValue::Integer(0)),
+    ("dictationMaxGainDb", DefaultValue::Real(20.0)),
+    ("maxCostPerMessage", DefaultValue::Real(0.015)),
+    ("model", DefaultValue::Text("gpt-3.5-turbo")),
+    ("openaiProxyUrl", DefaultValue::Text("")),
+    ("openaiService", DefaultValue::Text("openai")),
+    ("speechSkipCode", DefaultValue::Integer(1)),
+    ("speechSkipUrls", DefaultValue::Integer(1)),
+    (
+        "ttsCacheMaxBytes",
+        DefaultValue::Integer(crate::tts_cache::DEFAULT_MAX_BYTES),
+    ),
+    ("webSearchEndpoint", DefaultValue::Text("")),
+    ("webSearchProvider", DefaultValue::Text("searxng")),
+];
+
+impl DefaultValue {
+    fn of(key: &str) -> Option<Self> {
+        DEFAULTS
+            .iter()
+            .find(|(name, _)| *name == key)
+            .map(|(_, value)| *value)
+    }
+
+    fn to_value(self) -> Value {
+        match self {
+            Self::Integer(n) => n.into(),
+            Self::Real(x) => x.into(),
+            Self::Text(s) => s.into(),
+        }
+    }
+
+    /// Whether the value has the type of the default. A real setting accepts integers, since JSON does not tell 1 from 1.0.
+    fn accepts(self, value: &Value) -> bool {
+        match self {
+            Self::Integer(_) => value.is_i64() || value.is_u64(),
+            Self::Real(_) => value.is_number(),
+            Self::Text(_) => value.is_string(),
+        }
+    }
+}
+
+fn reject_secret(key: &str) -> Result<(), Error> {
+    if crate::secrets::SETTINGS.contains(&key) {
+        return Err(Error::StringError(format!(
+            "{key} is stored in the credential store, use get_secret and store_secret"
+        )));
+    }
+    Ok(())
+}
+
+#[derive(serde::Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+struct SettingChanged {
+    key: String,
+    value: Value,
+}
+
+/// Returns the stored value of the setting, its default if it has not been stored, or None if it has no default either.
+pub(crate) async fn get(
+    conn: &mut sqlx::SqliteConnection,
+    key: &str,
+) -> Result<Option<Value>, Error> {
+    let row = sqlx::query(
+        "SELECT typeof(value) AS type, CAST(value AS TEXT) AS text FROM config WHERE key = ?",
+    )
+    .bind(key)
+    .fetch_optional(conn)
+    .await?;
+    let Some(row) = row else {
+        return Ok(DefaultValue::of(key).map(DefaultValue::to_value));
+    };
+    let text: Option<String> = row.get("text");
+    let invalid = || Error::StringError(format!("The setting {key} has an invalid value"));
+    Ok(Some(match (row.get::<String, _>("type").as_str(), text) {
+        ("integer", Some(text)) => text.parse::<i64>().map_err(|_| invalid())?.into(),
+        ("real", Some(text)) => text.parse::<f64>().map_err(|_| invalid())?.into(),
+        ("text", Some(text)) => text.into(),
+        ("null", _) => Value::Null,
+        _ => return Err(invalid()),
+    }))
+}
+
+#[tauri::command]
+#[tracing::instrument(skip(state), err)]
+pub(crate) async fn get_setting(
+    state: tauri::State<'_, AppState>,
+    key: String,
+) -> Result<Option<Value>, Error> {
+    reject_secret(&key)?;
+    let mut conn = state.db_pool.acquire().await?;
+    get(&mut conn, &key).await
+}
+
+/// Stores the setting and emits `settings://changed`. The value must have the type of the default, if any.
+#[tauri::command]
+#[tracing::instrument(skip(app, state, value), err)]
+pub(crate) async fn set_setting(
+    app: AppHandle,
+    state: tauri::State<'_, AppState>,
+    key: String,
+    value: Value,
+) -> Result<(), Error> {
+    reject_secret(&key)?;
+    if let Some(default) = DefaultValue::of(&key) {
+        if !default.accepts(&value) {
+            return Err(Error::StringError(format!(
+                "The setting {key} must be like {}, but got {value}",
+                default.to_value()
+            )));
+        }
+    }
+    let query = sqlx::query("INSERT OR REPLACE INTO config VALUES (?, ?)").bind(&key);
+    let query = match &value {
+        Value::Null => query.bind(None::<String>),
+        Value::Bool(b) => query.bind(i64::from(*b)),
+        Value::Number(n) => match n.as_i64() {
+            Some(n) => query.bind(n),
+            None => query.bind(n.as_f64()),
+        },
+        Value::String(s) => query.bind(s.clone()),
+        Value::Array(_) | Value::Object(_) => {
+            return Err(Error::StringError(format!(
+                "The setting {key} must be a number or a string"
+            )))
+        }
+    };
+    let mut conn = state.db_pool.acquire().await?;
+    query.execute(&mut conn).await?;
+    app.emit_all("settings://changed", SettingChanged { key, value })?;
+    Ok(())
+}
@@ -0,0 +1,304 @@
+//! Turns the markdown of a message into the text that is read aloud.
+//!
+//! The markup of headings, lists, quotes, emphasis, inline code, links, and images is removed, numbered list items are
+//! read as "First, ...", "Second, ..." in English and as "1: ..." otherwise, and fenced code blocks and URLs are skipped
+//! unless the `speechSkipCode` or `speechSkipUrls` config is "0". Common English abbreviations such as "e.g." are
+//! expanded, and the user's rules in the `speechRule` table replace the other phrases, e.g. "w/" -> "with". The rules
+//! are matched as whole words, ignoring ASCII case, and take precedence over the built-in abbreviations. In English, the
+//! math and the units are read with `verbalizer`.
+
+use crate::{read_config, verbalizer, AppState, Error};
+use sqlx::Row;
+
+const CODE_OMITTED: &str = "Code omitted.";
+
+const ORDINALS: [&str; 10] = [
+    "First", "Second", "Third", "Fourth", "Fifth", "Sixth", "Seventh", "Eighth", "Ninth", "Tenth",
+];
+
+const ENGLISH_ABBREVIATIONS: [(&str, &str); 8] = [
+    ("e.g.", "for example"),
+    ("i.e.", "that is"),
+    ("etc.", "et cetera"),
+    ("vs.", "versus"),
+    ("approx.", "approximately"),
+    ("w/", "with"),
+    ("w/o", "without"),
+    ("FAQ", "frequently asked questions"),
+];
+
+#[derive(serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct SpeechRule {
+    pattern: String,
+    replacement: String,
+}
+
+/// The preprocessing of the speech in a language, with the options and rules read from the database.
+pub(crate) struct SpeechText {
+    english: bool,
+    skip_code: bool,
+    skip_urls: bool,
+    /// The user's rules followed by the built-in ones, the longest pattern first within each.
+    rules: Vec<(String, String)>,
+}
+
+async fn rules(conn: &mut sqlx::SqliteConnection) -> Result<Vec<SpeechRule>, Error> {
+    Ok(
+        sqlx::query("SELECT pattern, replacement FROM speechRule ORDER BY pattern")
+            .fetch_all(conn)
+            .await?
+            .into_iter()
+            .map(|row| SpeechRule {
+                pattern: row.get("pattern"),
+                replacement: row.get("replacement"),
+            })
+            .collect(),
+    )
+}
+
+impl SpeechText {
+    /// Loads the options and the rules for a language such as "en-US".
+    pub(crate) async fn load(conn: &mut sqlx::SqliteConnection, lang: &str) -> Result<Self, Error> {
+        let english = lang.to_lowercase().starts_with("en");
+        let skip_code = read_config(conn, "speechSkipCode").await?.as_deref() != Some("0");
+        let skip_urls = read_config(conn, "speechSkipUrls").await?.as_deref() != Some("0");
+        let mut user_rules = rules(conn)
+            .await?
+            .into_iter()
+            .map(|rule| (rule.pattern, rule.replacement))
+            .collect::<Vec<_>>();
+        user_rules.sort_by_key(|(pattern, _)| std::cmp::Reverse(pattern.len()));
+        let mut builtin_rules = if english {
+            ENGLISH_ABBREVIATIONS
+                .iter()
+                .map(|&(pattern, replacement)| (pattern.to_owned(), replacement.to_owned()))
+                .collect::<Vec<_>>()
+        } else {
+            vec![]
+        };
+        builtin_rules.sort_by_key(|(pattern, _)| std::cmp::Reverse(pattern.len()));
+        Ok(Self {
+            english,
+            skip_code,
+            skip_urls,
+            rules: user_rules.into_iter().chain(builtin_rules).collect(),
+        })
+    }
+
+    /// Returns the text of the markdown that is read aloud.
+    pub(crate) fn apply(&self, markdown: &str) -> String {
+        let mut lines = vec![];
+        let mut in_code_block = false;
+        // The lines of a display math block, between lines of `$$` or `\[` and `\]`.
+        let mut math_block: Option<Vec<&str>> = None;
+        for line in markdown.lines() {
+            let trimmed = line.trim_start();
+            if let Some(math) = &mut math_block {
+                if matches!(trimmed.trim_end(), "$$" | "\\]") {
+                    lines.push(verbalizer::verbalize_latex(&math.join(" ")));
+                    math_block = None;
+                } else {
+                    math.push(line);
+                }
+                continue;
+            }
+            if self.english && !in_code_block && matches!(trimmed.trim_end(), "$$" | "\\[") {
+                math_block = Some(vec![]);
+                continue;
+            }
+            if trimmed.starts_with("```") || trimmed.starts_with("~~~") {
+                if !in_code_block && self.skip_code {
+                    lines.push(CODE_OMITTED.to_owned());
+                }
+                in_code_block = !in_code_block;
+                continue;
+            }
+            if in_code_block {
+                if !self.skip_code {
+                    lines.push(line.to_owned());
+                }
+                continue;
+            }
+            let mut text = trimmed
+                .trim_start_matches('#')
+                .trim_start_matches('>')
+                .trim_start();
+            for bullet in ["- ", "* ", "+ "] {
+                text = text.strip_prefix(bullet).unwrap_or(text);
+            }
+            let mut line = String::new();
+            if let Some((number, item)) = numbered_item(text) {
+                match ORDINALS.get(number.wrapping_sub(1)) {
+                    Some(ordinal) if self.english => line.push_str(&format!("{ordinal}, ")),
+                    _ => line.push_str(&format!("{number}: ")),
+                }
+                text = item;
+            }
+            // The math is read before the markup is removed, since `_` and `*` are also LaTeX.
+            let mut text = if self.english {
+                strip_inline(&verbalizer::verbalize_inline_math(text.trim()))
+            } else {
+                strip_inline(text.trim())
+            };
+            if self.skip_urls {
+                text = strip_urls(&text);
+            }
+            if self.english {
+                text = verbalizer::verbalize_numbers_and_units(&text);
+            }
+            line.push_str(&replace_phrases(&text, &self.rules));
+            lines.push(line);
+        }
+        lines.join("\n")
+    }
+}
+
+/// Splits "1. item" and "1) item" into the number and the item.
+fn numbered_item(line: &str) -> Option<(usize, &str)> {
+    let digits = line.find(|c: char| !c.is_ascii_digit())?;
+    let number = line[..digits].parse().ok()?;
+    let rest = line[digits..]
+        .strip_prefix(". ")
+        .or_else(|| line[digits..].strip_prefix(") "))?;
+    Some((number, rest))
+}
+
+/// Removes the inline markup of a line: links and images are replaced with their text, and the emphasis and code markers
+/// are removed.
+fn strip_inline(line: &str) -> String {
+    let mut out = String::with_capacity(line.len());
+    let mut rest = line;
+    while let Some(c) = rest.chars().next() {
+        let next = rest[c.len_utf8()..].chars().next();
+        // [text](url) and ![alt](url)
+        let link_start = match (c, next) {
+            ('!', Some('[')) => Some(2),
+            ('[', _) => Some(1),
+            _ => None,
+        };
+        if let Some(start) = link_start {
+            if let Some(close) = rest[start..].find("](") {
+                let text_end = start + close;
+                if let Some(url_len) = rest[text_end + 2..].find(')') {
+                    out.push_str(&rest[start..text_end]);
+                    rest = &rest[text_end + 2 + url_len + 1..];
+                    continue;
+                }
+            }
+        }
+        match c {
+            '`' | '*' | '~' => {}
+            // Underscores within words, e.g. in snake_case, are kept.
+            '_' if !out.chars().next_back().map_or(false, char::is_alphanumeric)
+                || !next.map_or(false, char::is_alphanumeric) => {}
+            c => out.push(c),
+        }
+        rest = &rest[c.len_utf8()..];
+    }
+    out
+}
+
+/// Removes the words that are URLs.
+fn strip_urls(text: &str) -> String {
+    text.split(' ')
+        .filter(|word| {
+            let word = word.trim_start_matches(['(', '<', '"', '\'']);
+            !(word.starts_with("http://")
+                || word.starts_with("https://")
+                || word.starts_with("www."))
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Replaces the whole-word, ASCII case-insensitive occurrences of the patterns. The edges of a pattern that are not
+/// alphanumeric, e.g. the period of "e.g.", match next to anything.
+fn replace_phrases(text: &str, rules: &[(String, String)]) -> String {
+    let is_word_char = |c: Option<char>| c.map_or(false, char::is_alphanumeric);
+    let mut out = String::with_capacity(text.len());
+    let mut i = 0;
+    while i < text.len() {
+        let before = text[..i].chars().next_back();
+        let found = rules.iter().find(|(pattern, _)| {
+            let end = i + pattern.len();
+            !pattern.is_empty()
+                && end <= text.len()
+                && text.is_char_boundary(end)
+                && text.as_bytes()[i..end].eq_ignore_ascii_case(pattern.as_bytes())
+                && !(is_word_char(pattern.chars().next()) && is_word_char(before))
+                && !(is_word_char(pattern.chars().next_back())
+                    && is_word_char(text[end..].chars().next()))
+        });
+        match found {
+            Some((pattern, replacement)) => {
+                out.push_str(replacement);
+                i += pattern.len();
+            }
+            None => {
+                let c = text[i..].chars().next().unwrap();
+                out.push(c);
+                i += c.len_utf8();
+            }
+        }
+    }
+    out
+}
+
+/// Returns the text of the markdown that is read aloud in the language, e.g. for the Web Speech API.
+#[tauri::command]
+#[tracing::instrument(skip(text, state), err)]
+pub(crate) async fn preprocess_speech_text(
+    text: String,
+    lang: String,
+    state: tauri::State<'_, AppState>,
+) -> Result<String, Error> {
+    let mut conn = state.db_pool.acquire().await?;
+    Ok(SpeechText::load(&mut conn, &lang).await?.apply(&text))
+}
+
+/// Adds or replaces the rule of a pattern (case-insensitive), e.g. "w/" -> "with". An empty replacement removes the
+/// pattern from the speech.
+#[tauri::command]
+#[tracing::instrument(skip(state), err)]
+pub(crate) async fn add_speech_rule(
+    pattern: String,
+    replacement: String,
+    state: tauri::State<'_, AppState>,
+) -> Result<(), Error> {
+    if pattern.trim().is_empty() {
+        return Err(Error::StringError(
+            "The pattern must not be empty".to_owned(),
+        ));
+    }
+    let mut conn = state.db_pool.acquire().await?;
+    sqlx::query("INSERT OR REPLACE INTO speechRule (pattern, replacement) VALUES (?, ?)")
+        .bind(pattern.trim())
+        .bind(replacement.trim())
+        .execute(&mut conn)
+        .await?;
+    Ok(())
+}
+
+#[tauri::command]
+#[tracing::instrument(skip(state), err)]
+pub(crate) async fn list_speech_rules(
+    state: tauri::State<'_, AppState>,
+) -> Result<Vec<SpeechRule>, Error> {
+    let mut conn = state.db_pool.acquire().await?;
+    rules(&mut conn).await
+}
+
+#[tauri::command]
+#[tracing::instrument(skip(state), err)]
+pub(crate) async fn delete_speech_rule(
+    pattern: String,
+    state: tauri::State<'_, AppState>,
+) -> Result<(), Error> {
+    let mut conn = state.db_pool.acquire().await?;
+    sqlx::query("DELETE FROM speechRule WHERE pattern = ?")
+        .bind(pattern)
+        .execute(&mut conn)
+        .await?;
+    Ok(())
+}
@@ -0,0 +1,215 @@
+//! An incremental parser of server-sent events, following https://html.spec.whatwg.org/multipage/server-sent-events.html.
+//!
+//! Lines may end with "\r\n", "\n", or "\r", even split across chunks, and the `data` fields of an event are joined with
+//! "\n", as some proxies split a JSON payload over several `data:` lines.
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Event {
+    /// The `event` field, None for the default "message" type.
+    pub(crate) event: Option<String>,
+    pub(crate) data: String,
+    /// The last `id` received so far, which also applies to the events without one.
+    pub(crate) id: Option<String>,
+    /// The last reconnection time in milliseconds requested with the `retry` field so far.
+    pub(crate) retry: Option<u64>,
+}
+
+#[derive(Default)]
+pub(crate) struct Parser {
+    /// The incomplete line at the end of the previous chunk.
+    line: Vec<u8>,
+    /// Whether the previous chunk ended with "\r", whose "\n" may start the next chunk.
+    after_cr: bool,
+    event: Option<String>,
+    data: Option<String>,
+    id: Option<String>,
+    retry: Option<u64>,
+}
+
+impl Parser {
+    /// Parses a chunk of the stream and returns the events completed by it.
+    pub(crate) fn feed(&mut self, chunk: &[u8]) -> Vec<Event> {
+        let mut events = vec![];
+        for &byte in chunk {
+            let after_cr = std::mem::replace(&mut self.after_cr, byte == b'\r');
+            match byte {
+                b'\n' if after_cr => {} // the rest of "\r\n"
+                b'\r' | b'\n' => {
+                    let line = std::mem::take(&mut self.line);
+                    events.extend(self.process_line(&line));
+                }
+                _ => self.line.push(byte),
+            }
+        }
+        events
+    }
+
+    /// Returns the last event if the stream ended without a blank line after it.
+    pub(crate) fn finish(&mut self) -> Option<Event> {
+        let line = std::mem::take(&mut self.line);
+        self.after_cr = false;
+        if !line.is_empty() {
+            self.process_line(&line);
+        }
+        self.dispatch()
+    }
+
+    fn process_line(&mut self, line: &[u8]) -> Option<Event> {
+        if line.is_empty() {
+            return self.dispatch();
+        }
+        let line = String::from_utf8_lossy(line);
+        if line.starts_with(':') {
+            return None; // a comment, e.g. the keep-alive ": OPENROUTER PROCESSING"
+        }
+        let (field, value) = match line.split_once(':') {
+            Some((field, value)) => (field, value.strip_prefix(' ').unwrap_or(value)),
+            None => (line.as_ref(), ""),
+        };
+        match field {
+            "event" => self.event = Some(value.to_owned()),
+            "data" => match &mut self.data {
+                Some(data) => {
+                    data.push('\n');
+                    data.push_str(value);
+                }
+                None => self.data = Some(value.to_owned()),
+            },
+            "id" if !value.contains('\0') => self.id = Some(value.to_owned()),
+            // Values that are not only ASCII digits are ignored, as the spec says.
+            "retry" if !value.is_empty() && value.bytes().all(|b| b.is_ascii_digit()) => {
+                if let Ok(retry) = value.parse() {
+                    self.retry = Some(retry);
+                }
+            }
+            _ => {}
+        }
+        None
+    }
+
+    /// Ends the current event. Events without data are dropped, as browsers do.
+    fn dispatch(&mut self) -> Option<Event> {
+        let event = self.event.take();
+        Some(Event {
+            event: event.filter(|event| !event.is_empty()),
+            data: self.data.take()?,
+            id: self.id.clone(),
+            retry: self.retry,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(chunks: &[&[u8]]) -> Vec<Event> {
+        let mut parser = Parser::default();
+        let mut events = chunks
+            .iter()
+            .flat_map(|chunk| parser.feed(chunk))
+            .collect::<Vec<_>>();
+        events.extend(parser.finish());
+        events
+    }
+
+    fn data(events: &[Event]) -> Vec<&str> {
+        events.iter().map(|event| event.data.as_str()).collect()
+    }
+
+    #[test]
+    fn openai_stream() {
+        let events = parse(&[
+            b"data: {\"choices\":[{\"delta\":{\"content\":\"Hi\"}}]}\n\n",
+            b"data: {\"choices\":[{\"delta\":{\"content\":\"!\"}}]}\n\ndata: [DONE]\n\n",
+        ]);
+        assert_eq!(
+            data(&events),
+            [
+                r#"{"choices":[{"delta":{"content":"Hi"}}]}"#,
+                r#"{"choices":[{"delta":{"content":"!"}}]}"#,
+                "[DONE]",
+            ]
+        );
+    }
+
+    #[test]
+    fn crlf_delimiters() {
+        let events = parse(&[b"data: a\r\n\r\ndata: b\r\n\r\n"]);
+        assert_eq!(data(&events), ["a", "b"]);
+    }
+
+    #[test]
+    fn cr_delimiters() {
+        let events = parse(&[b"data: a\r\rdata: b\r\r"]);
+        assert_eq!(data(&events), ["a", "b"]);
+    }
+
+    #[test]
+    fn crlf_split_across_chunks() {
+        let events = parse(&[b"data: a\r", b"\n\r", b"\ndata: b\r\n", b"\r\n"]);
+        assert_eq!(data(&events), ["a", "b"]);
+    }
+
+    #[test]
+    fn multi_line_data() {
+        let events = parse(&[b"data: {\"a\":\ndata: 1}\n\n"]);
+        assert_eq!(data(&events), ["{\"a\":\n1}"]);
+    }
+
+    #[test]
+    fn line_split_across_chunks() {
+        let events = parse(&[b"da", b"ta: hel", b"lo\n", b"\n"]);
+        assert_eq!(data(&events), ["hello"]);
+    }
+
+    #[test]
+    fn event_id_and_retry() {
+        let mut parser = Parser::default();
+        let events = parser.feed(b"retry: 3000\nid: 1\nevent: error\ndata: oops\n\ndata: next\n\n");
+        assert_eq!(
+            events,
+            [
+                Event {
+                    event: Some("error".to_owned()),
+                    data: "oops".to_owned(),
+                    id: Some("1".to_owned()),
+                    retry: Some(3000),
+                },
+                Event {
+                    event: None,
+                    data: "next".to_owned(),
+                    id: Some("1".to_owned()),
+                    retry: Some(3000),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn retry_that_is_not_all_digits_is_ignored() {
+        let events = parse(&[b"retry: 100\n\nretry: 1s\ndata: a\n\nretry: -1\ndata: b\n\n"]);
+        assert_eq!(
+            events.iter().map(|event| event.retry).collect::<Vec<_>>(),
+            [Some(100), Some(100)]
+        );
+    }
+
+    #[test]
+    fn comments_and_events_without_data_are_ignored() {
+        let events = parse(&[b": keep-alive\n\nevent: ping\n\ndata: a\n\n"]);
+        assert_eq!(data(&events), ["a"]);
+    }
+
+    #[test]
+    fn value_without_space_and_field_without_colon() {
+        let events = parse(&[b"data:a\ndata\n\n"]);
+        assert_eq!(data(&events), ["a\n"]);
+    }
+
+    #[test]
+    fn last_event_without_blank_line() {
+        let events = parse(&[b"data: a\n\ndata: b"]);
+        assert_eq!(data(&events), ["a", "b"]);
+    }
+}
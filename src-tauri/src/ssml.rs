@@ -0,0 +1,80 @@
+//! Builds the SSML sent to Azure.
+//!
+//! Azure rejects a document that is not well-formed XML with 400 Bad Request, so the text is always escaped here rather
+//! than in the frontend. The markdown of a message is turned into the text that is read aloud by `speech_text` first.
+
+use crate::lexicon::escape_xml;
+use crate::speech_text::SpeechText;
+use crate::{AppState, Error};
+
+/// The relative change of a prosody attribute, e.g. "+20%" for 1.2.
+fn percent(value: f64) -> String {
+    format!("{:+.0}%", (value - 1.0) * 100.0)
+}
+
+/// Builds the SSML that speaks the text with the voice, e.g. "en-US-JennyNeural". `lang` defaults to the locale of
+/// the voice. `rate` and `pitch` are relative to the normal speech, where 1 is unchanged, and `style` is a speaking
+/// style of the voice, e.g. "cheerful".
+pub(crate) fn build(
+    text: &str,
+    voice: &str,
+    lang: Option<&str>,
+    rate: Option<f64>,
+    pitch: Option<f64>,
+    style: Option<&str>,
+) -> String {
+    // The locale is the first two components of the short name.
+    let lang = match lang.filter(|lang| !lang.trim().is_empty()) {
+        Some(lang) => lang.to_owned(),
+        None => voice.splitn(3, '-').take(2).collect::<Vec<_>>().join("-"),
+    };
+    let (voice, lang) = (escape_xml(voice), escape_xml(&lang));
+    let mut body = escape_xml(text);
+    if rate.is_some() || pitch.is_some() {
+        body = format!(
+            "<prosody rate='{}' pitch='{}'>{body}</prosody>",
+            percent(rate.unwrap_or(1.0)),
+            percent(pitch.unwrap_or(1.0)),
+        );
+    }
+    match style.filter(|style| !style.trim().is_empty()) {
+        Some(style) => format!(
+            "<speak version='1.0' xmlns='http://www.w3.org/2001/10/synthesis' xmlns:mstts='https://www.w3.org/2001/mstts' xml:lang='{lang}'><voice name='{voice}'><mstts:express-as style='{}'>{body}</mstts:express-as></voice></speak>",
+            escape_xml(style.trim()),
+        ),
+        None => format!(
+            "<speak version='1.0' xml:lang='{lang}'><voice xml:lang='{lang}' name='{voice}'>{body}</voice></speak>"
+        ),
+    }
+}
+
+/// Builds the SSML of the markdown for the frontend, e.g. to request the word boundaries. See `build`.
+#[tauri::command]
+#[tracing::instrument(skip(text, state), err)]
+pub(crate) async fn build_ssml(
+    text: String,
+    voice: String,
+    lang: Option<String>,
+    rate: Option<f64>,
+    pitch: Option<f64>,
+    style: Option<String>,
+    state: tauri::State<'_, AppState>,
+) -> Result<String, Error> {
+    if voice.trim().is_empty() {
+        return Err(Error::StringError("The voice must not be empty".to_owned()));
+    }
+    let text = {
+        let mut conn = state.db_pool.acquire().await?;
+        SpeechText::load(&mut conn, lang.as_deref().unwrap_or(voice.trim()))
+            .await?
+            .apply(&text)
+    };
+    Ok(build(
+        &text,
+        voice.trim(),
+        lang.as_deref(),
+        rate,
+        pitch,
+        style.as_deref(),
+    ))
+}
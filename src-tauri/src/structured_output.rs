@@ -0,0 +1,219 @@
+//! Validation of the responses to the requests with `response_format` `json_object` or `json_schema`.
+//!
+//! When the stream has finished, its content is parsed, and validated with `jsonschema` if a schema was given. Models
+//! occasionally wrap the JSON in a code fence, add prose around it, leave trailing commas, or are cut off by
+//! `max_tokens`, so an invalid response is repaired by each of `REPAIRS` in turn, each applied to the result of the
+//! previous one. The window then receives `chat-completion://invalid-json` with the attempts and the first repaired
+//! JSON that is valid, if any, so that the frontend can replace the content with it.
+
+use crate::Error;
+use serde_json::Value;
+use tauri::Window;
+
+/// The JSON that a request expects from its response.
+pub(crate) struct JsonExpectation {
+    /// The `schema` of `json_schema`, or None for `json_object`.
+    schema: Option<Value>,
+}
+
+#[derive(serde::Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+struct RepairAttempt {
+    repair: &'static str,
+    /// None if the repaired content is valid.
+    error: Option<String>,
+}
+
+#[derive(serde::Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+struct InvalidJson {
+    request_id: u64,
+    /// Why the content as streamed is invalid.
+    error: String,
+    attempts: Vec<RepairAttempt>,
+    /// The repaired JSON, or None if every repair has failed.
+    repaired: Option<String>,
+}
+
+const REPAIRS: [(&str, fn(&str) -> String); 4] = [
+    ("stripCodeFence", strip_code_fence),
+    ("extractJson", extract_json),
+    ("removeTrailingCommas", remove_trailing_commas),
+    ("closeTruncated", close_truncated),
+];
+
+impl JsonExpectation {
+    /// Returns the expectation of the OpenAI request body, or None if it does not request JSON.
+    pub(crate) fn from_request(request: &Value) -> Option<Self> {
+        let format = request.get("response_format")?;
+        match format.get("type").and_then(Value::as_str)? {
+            "json_object" => Some(Self { schema: None }),
+            "json_schema" => Some(Self {
+                schema: format.pointer("/json_schema/schema").cloned(),
+            }),
+            _ => None,
+        }
+    }
+
+    /// Parses the content and validates it against the schema.
+    fn check(&self, content: &str) -> Result<Value, String> {
+        let value = serde_json::from_str::<Value>(content).map_err(|err| err.to_string())?;
+        if let Some(schema) = &self.schema {
+            let compiled = jsonschema::JSONSchema::compile(schema)
+                .map_err(|err| format!("invalid schema: {err}"))?;
+            if let Err(errors) = compiled.validate(&value) {
+                return Err(errors
+                    .map(|err| format!("{}: {err}", err.instance_path))
+                    .collect::<Vec<_>>()
+                    .join("; "));
+            }
+        }
+        Ok(value)
+    }
+
+    /// Emits `chat-completion://invalid-json` if the content of the finished response is not valid.
+    pub(crate) fn validate_response(
+        &self,
+        window: &Window,
+        request_id: u64,
+        content: &str,
+    ) -> Result<(), Error> {
+        let Err(error) = self.check(content) else {
+            return Ok(());
+        };
+        let mut attempts = vec![];
+        let mut repaired = None;
+        let mut candidate = content.to_owned();
+        for (repair, apply) in REPAIRS {
+            candidate = apply(&candidate);
+            match self.check(&candidate) {
+                Ok(value) => {
+                    attempts.push(RepairAttempt {
+                        repair,
+                        error: None,
+                    });
+                    repaired = Some(serde_json::to_string_pretty(&value)?);
+                    break;
+                }
+                Err(err) => attempts.push(RepairAttempt {
+                    repair,
+                    error: Some(err),
+                }),
+            }
+        }
+        tracing::warn!(
+            repaired = repaired.is_some(),
+            "the response is not valid JSON: {error}"
+        );
+        window.emit(
+            "chat-completion://invalid-json",
+            InvalidJson {
+                request_id,
+                error,
+                attempts,
+                repaired,
+            },
+        )?;
+        Ok(())
+    }
+}
+
+/// Removes a Markdown code fence around the content, e.g. "```json\n{...}\n```".
+fn strip_code_fence(content: &str) -> String {
+    let trimmed = content.trim();
+    let Some(rest) = trimmed.strip_prefix("```") else {
+        return content.to_owned();
+    };
+    let rest = rest.split_once('\n').map_or("", |(_, rest)| rest);
+    rest.trim_end()
+        .strip_suffix("```")
+        .unwrap_or(rest)
+        .to_owned()
+}
+
+/// Removes the text before the first `{` or `[`, and the text after its closing bracket if it is closed.
+fn extract_json(content: &str) -> String {
+    let Some(start) = content.find(['{', '[']) else {
+        return content.to_owned();
+    };
+    let json = &content[start..];
+    let mut depth = 0usize;
+    let mut end = None;
+    scan(json, |i, c, outside| match c {
+        '{' | '[' if outside => depth += 1,
+        '}' | ']' if outside && end.is_none() => {
+            depth = depth.saturating_sub(1);
+            if depth == 0 {
+                end = Some(i);
+            }
+        }
+        _ => {}
+    });
+    match end {
+        Some(end) => json[..=end].to_owned(),
+        None => json.to_owned(),
+    }
+}
+
+/// Calls `f` with each character and whether it is outside of the strings, and returns whether the content ends in a
+/// string.
+fn scan(content: &str, mut f: impl FnMut(usize, char, bool)) -> bool {
+    let mut in_string = false;
+    let mut escaped = false;
+    for (i, c) in content.char_indices() {
+        f(i, c, !in_string && c != '"');
+        if escaped {
+            escaped = false;
+        } else if in_string && c == '\\' {
+            escaped = true;
+        } else if c == '"' {
+            in_string = !in_string;
+        }
+    }
+    in_string
+}
+
+/// Removes the commas followed by `}` or `]`.
+fn remove_trailing_commas(content: &str) -> String {
+    let mut commas = vec![];
+    scan(content, |i, c, outside| {
+        if outside && c == ',' {
+            let next = content[i + 1..].trim_start().chars().next();
+            if matches!(next, Some('}' | ']')) {
+                commas.push(i);
+            }
+        }
+    });
+    let mut out = content.to_owned();
+    for i in commas.into_iter().rev() {
+        out.remove(i);
+    }
+    out
+}
+
+/// Closes the string, the arrays, and the objects left open by a response that was cut off.
+fn close_truncated(content: &str) -> String {
+    let mut open = vec![];
+    let in_string = scan(content, |_, c, outside| {
+        if outside {
+            match c {
+                '{' => open.push('}'),
+                '[' => open.push(']'),
+                '}' | ']' => {
+                    open.pop();
+                }
+                _ => {}
+            }
+        }
+    });
+    let mut out = content.trim_end().to_owned();
+    if in_string {
+        out.push('"');
+    }
+    // A dangling comma or colon before the closing bracket.
+    while out.ends_with(',') || out.ends_with(':') {
+        out.pop();
+    }
+    out.extend(open.into_iter().rev());
+    out
+}
@@ -0,0 +1,117 @@
+//! Rolling summaries of long conversations, stored in `conversationSummary`.
+//!
+//! A summary covers the messages of the thread up to `upToMessageId`, and `build_request_messages` sends it in place of
+//! those messages. Summarizing again only feeds the messages after the previous summary, merged with it.
+
+use crate::context_window::context_window;
+use crate::conversation::{load_thread, StoredMessage};
+use crate::quick_ask::{complete, configured_model};
+use crate::{AppState, Error, CL100K_BASE};
+use serde_json::json;
+use sqlx::Row;
+
+const INSTRUCTIONS: &str = "Summarize the conversation below so that it can be continued without it. \
+Keep every fact, decision, name, number, and preference that may matter later, and leave out small talk. \
+If a previous summary is given, merge it into the new one. Answer with the summary only.";
+
+/// Returns the id of the last summarized message and the summary.
+pub(crate) async fn load_summary(
+    conn: &mut sqlx::SqliteConnection,
+    conversation_id: i64,
+) -> Result<Option<(i64, String)>, Error> {
+    Ok(sqlx::query(
+        "SELECT upToMessageId, summary FROM conversationSummary WHERE conversationId = ?",
+    )
+    .bind(conversation_id)
+    .fetch_optional(conn)
+    .await?
+    .map(|row| (row.get("upToMessageId"), row.get("summary"))))
+}
+
+async fn summarize(
+    conn: &mut sqlx::SqliteConnection,
+    model: &str,
+    previous: Option<&str>,
+    messages: &[&StoredMessage],
+) -> Result<String, Error> {
+    let transcript = messages
+        .iter()
+        .map(|m| format!("{}: {}", m.role, m.content))
+        .collect::<Vec<_>>()
+        .join("\n\n");
+    let content = match previous {
+        Some(previous) => format!("Previous summary:\n{previous}\n\nConversation:\n{transcript}"),
+        None => format!("Conversation:\n{transcript}"),
+    };
+    complete(
+        conn,
+        model,
+        vec![
+            json!({ "role": "system", "content": INSTRUCTIONS }),
+            json!({ "role": "user", "content": content }),
+        ],
+    )
+    .await
+}
+
+/// Summarizes the thread up to the message with the configured model and stores the summary, then returns it. Messages
+/// that do not fit in one request are summarized in several passes.
+#[tauri::command]
+#[tracing::instrument(skip(state), err)]
+pub(crate) async fn summarize_conversation(
+    conversation_id: i64,
+    up_to_message_id: i64,
+    state: tauri::State<'_, AppState>,
+) -> Result<String, Error> {
+    let mut conn = state.db_pool.acquire().await?;
+    let thread = load_thread(&mut conn, conversation_id).await?;
+    let end = thread
+        .iter()
+        .position(|m| m.id == up_to_message_id)
+        .ok_or_else(|| {
+            Error::StringError(format!(
+                "Message {up_to_message_id} is not in the thread of conversation {conversation_id}"
+            ))
+        })?;
+
+    // Continues from the previous summary if it covers a part of the same thread.
+    let (start, mut summary) = match load_summary(&mut conn, conversation_id).await? {
+        Some((previous_end, summary)) => match thread.iter().position(|m| m.id == previous_end) {
+            Some(previous_end) if previous_end == end => return Ok(summary),
+            Some(previous_end) if previous_end < end => (previous_end + 1, Some(summary)),
+            _ => (0, None),
+        },
+        None => (0, None),
+    };
+
+    let model = configured_model(&mut conn).await?;
+    let max_batch_tokens = context_window(&model) / 2;
+    let messages = thread[start..=end]
+        .iter()
+        .filter(|m| m.role != "root" && m.status != -1)
+        .collect::<Vec<_>>();
+    let mut batch = vec![];
+    let mut batch_tokens = 0;
+    for (i, message) in messages.iter().enumerate() {
+        batch.push(*message);
+        batch_tokens += CL100K_BASE
+            .encode_with_special_tokens(&message.content)
+            .len();
+        if batch_tokens >= max_batch_tokens || i == messages.len() - 1 {
+            summary = Some(summarize(&mut conn, &model, summary.as_deref(), &batch).await?);
+            batch.clear();
+            batch_tokens = 0;
+        }
+    }
+    let summary = summary.unwrap_or_default();
+
+    sqlx::query(
+        "INSERT OR REPLACE INTO conversationSummary (conversationId, upToMessageId, summary) VALUES (?, ?, ?)",
+    )
+    .bind(conversation_id)
+    .bind(up_to_message_id)
+    .bind(&summary)
+    .execute(&mut conn)
+    .await?;
+    Ok(summary)
+}
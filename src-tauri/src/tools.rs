@@ -0,0 +1,300 @@
+//! Tools that the backend executes itself when the model calls them.
+//!
+//! `start_chat_completion` with `tools` adds their definitions to an OpenAI request. When a response calls only these
+//! tools, the calls and their results are appended to the messages and the completion is requested again, streamed to
+//! the same request id, up to `MAX_ROUNDS` times. The window receives `chat-completion://tool-call`
+//! ({ requestId, name, arguments }) when a tool is executed. Calls of other tools are left to `get_chat_tool_calls`.
+//!
+//! The tools of MCP servers are handled in the same way, by the names of their functions given by `mcp`.
+//!
+//! The calls of the tools that run code, and of the tools of MCP servers that are not marked as read-only, are approved
+//! by the user one by one: the window receives
+//! `chat-completion://tool-approval` ({ id, requestId, name, arguments }) and answers it with `answer_tool_approval`. A
+//! call that is denied, not answered within `APPROVAL_TIMEOUT`, or whose request is canceled is not executed, and the model
+//! is told so. The window receives `chat-completion://tool-approval-closed` ({ id }) when the approval is no longer
+//! awaited.
+
+use crate::code_interpreter::{self, Language};
+use crate::{mcp, web_search};
+use crate::{AppState, Error, ToolCall, CHAT_COMPLETION_CANCELED, CHAT_TOOL_CALLS};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// The number of times that the results of tool calls are sent back in one request.
+pub(crate) const MAX_ROUNDS: usize = 5;
+const APPROVAL_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+/// How often the cancellation of the request is checked while the approval is awaited.
+const APPROVAL_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+lazy_static::lazy_static! {
+    static ref NEXT_APPROVAL_ID: AtomicU64 = AtomicU64::new(1);
+    /// The calls awaiting the approval of the user, by approval id.
+    static ref APPROVALS: Mutex<HashMap<u64, tokio::sync::oneshot::Sender<bool>>> = Mutex::new(HashMap::new());
+}
+
+#[derive(Clone, Copy, Debug)]
+enum BuiltinTool {
+    WebSearch,
+    RunCode,
+}
+
+impl BuiltinTool {
+    const ALL: [BuiltinTool; 2] = [BuiltinTool::WebSearch, BuiltinTool::RunCode];
+
+    fn name(self) -> &'static str {
+        match self {
+            BuiltinTool::WebSearch => "web_search",
+            BuiltinTool::RunCode => "run_code",
+        }
+    }
+
+    fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|tool| tool.name() == name)
+    }
+
+    /// Whether a call has to be approved by the user. The code comes from the model, which may follow instructions
+    /// injected by a web page.
+    fn requires_approval(self) -> bool {
+        match self {
+            BuiltinTool::WebSearch => false,
+            BuiltinTool::RunCode => true,
+        }
+    }
+
+    /// The definition in the format of OpenAI's `tools`.
+    fn definition(self) -> Value {
+        match self {
+            BuiltinTool::WebSearch => json!({
+                "type": "function",
+                "function": {
+                    "name": self.name(),
+                    "description": "Searches the web and returns the title, URL, and snippet of the top results. Use it for recent events or facts you are unsure of.",
+                    "parameters": {
+                        "type": "object",
+                        "properties": {
+                            "query": { "type": "string", "description": "The search query" },
+                        },
+                        "required": ["query"],
+                    },
+                },
+            }),
+            BuiltinTool::RunCode => json!({
+                "type": "function",
+                "function": {
+                    "name": self.name(),
+                    "description": "Runs a Python or JavaScript (Node.js) program and returns its exit code, stdout, and stderr. Use it for calculations and data processing. Print the values you need. The network may be unavailable, and the program is killed after 10 seconds.",
+                    "parameters": {
+                        "type": "object",
+                        "properties": {
+                            "language": { "type": "string", "enum": ["python", "javascript"] },
+                            "code": { "type": "string", "description": "The source code of the program" },
+                        },
+                        "required": ["language", "code"],
+                    },
+                },
+            }),
+        }
+    }
+
+    /// Returns the result given to the model.
+    async fn execute(self, state: &AppState, arguments: &str) -> Result<String, Error> {
+        let arguments: Value = serde_json::from_str(arguments)?;
+        match self {
+            BuiltinTool::WebSearch => {
+                let query = arguments
+                    .get("query")
+                    .and_then(Value::as_str)
+                    .ok_or_else(|| Error::StringError("query is not specified".to_owned()))?;
+                let mut conn = state.db_pool.acquire().await?;
+                let results = web_search::search(&mut conn, query).await?;
+                Ok(serde_json::to_string(&results)?)
+            }
+            BuiltinTool::RunCode => {
+                let language: Language =
+                    serde_json::from_value(arguments.get("language").cloned().unwrap_or_default())?;
+                let code = arguments
+                    .get("code")
+                    .and_then(Value::as_str)
+                    .ok_or_else(|| Error::StringError("code is not specified".to_owned()))?;
+                let mut conn = state.db_pool.acquire().await?;
+                code_interpreter::run(&mut conn, language, code).await
+            }
+        }
+    }
+}
+
+#[derive(serde::Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+struct ApprovalRequest {
+    id: u64,
+    request_id: u64,
+    name: String,
+    arguments: String,
+}
+
+#[derive(serde::Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+struct ApprovalClosed {
+    id: u64,
+}
+
+/// Asks the user whether to execute the call, and returns the answer.
+async fn ask_approval(
+    window: &tauri::Window,
+    request_id: u64,
+    call: &ToolCall,
+) -> Result<bool, Error> {
+    let id = NEXT_APPROVAL_ID.fetch_add(1, Ordering::SeqCst);
+    let (sender, mut receiver) = tokio::sync::oneshot::channel();
+    APPROVALS.lock()?.insert(id, sender);
+    window.emit(
+        "chat-completion://tool-approval",
+        ApprovalRequest {
+            id,
+            request_id,
+            name: call.function.name.clone(),
+            arguments: call.function.arguments.clone(),
+        },
+    )?;
+    let started = Instant::now();
+    let approved = loop {
+        match tokio::time::timeout(APPROVAL_POLL_INTERVAL, &mut receiver).await {
+            Ok(answer) => break answer.unwrap_or(false),
+            Err(_) => {
+                if started.elapsed() >= APPROVAL_TIMEOUT
+                    || CHAT_COMPLETION_CANCELED.lock()?.contains(&request_id)
+                {
+                    break false;
+                }
+            }
+        }
+    };
+    APPROVALS.lock()?.remove(&id);
+    window.emit(
+        "chat-completion://tool-approval-closed",
+        ApprovalClosed { id },
+    )?;
+    Ok(approved)
+}
+
+#[derive(serde::Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+struct ToolCallEvent {
+    request_id: u64,
+    name: String,
+    arguments: String,
+}
+
+/// Adds the definitions of the tools to the request body.
+pub(crate) fn add_definitions(
+    state: &AppState,
+    body: &str,
+    names: &[String],
+) -> Result<String, Error> {
+    let definitions = names
+        .iter()
+        .map(|name| match BuiltinTool::from_name(name) {
+            Some(tool) => Ok(tool.definition()),
+            None => mcp::definition(state, name)?
+                .ok_or_else(|| Error::StringError(format!("Unknown tool: {name}"))),
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    let mut body: Value = serde_json::from_str(body)?;
+    match body.get_mut("tools").and_then(Value::as_array_mut) {
+        Some(tools) => tools.extend(definitions),
+        None => body["tools"] = Value::Array(definitions),
+    }
+    Ok(body.to_string())
+}
+
+/// Takes the tool calls of the finished response if all of them are of the given tools.
+pub(crate) fn take_builtin_calls(
+    request_id: u64,
+    names: &[String],
+) -> Result<Option<Vec<ToolCall>>, Error> {
+    let mut tool_calls = CHAT_TOOL_CALLS.lock()?;
+    let executable = tool_calls.get(&request_id).is_some_and(|calls| {
+        !calls.is_empty()
+            && calls
+                .iter()
+                .all(|call| names.iter().any(|name| *name == call.function.name))
+    });
+    if !executable {
+        return Ok(None);
+    }
+    Ok(tool_calls.remove(&request_id))
+}
+
+/// Executes the calls and returns the request body with the calls and their results appended to the messages. A failed
+/// call gives its error to the model as the result.
+pub(crate) async fn run_calls(
+    window: &tauri::Window,
+    state: &AppState,
+    request_id: u64,
+    body: &str,
+    calls: Vec<ToolCall>,
+) -> Result<String, Error> {
+    let mut results = vec![];
+    for call in &calls {
+        let builtin = BuiltinTool::from_name(&call.function.name);
+        let requires_approval = match builtin {
+            Some(tool) => tool.requires_approval(),
+            None => mcp::requires_approval(state, &call.function.name)?,
+        };
+        let approved = !requires_approval || ask_approval(window, request_id, call).await?;
+        let result = if !approved {
+            Err(Error::StringError(
+                "The user did not allow this call".to_owned(),
+            ))
+        } else {
+            window.emit(
+                "chat-completion://tool-call",
+                ToolCallEvent {
+                    request_id,
+                    name: call.function.name.clone(),
+                    arguments: call.function.arguments.clone(),
+                },
+            )?;
+            match builtin {
+                Some(tool) => tool.execute(state, &call.function.arguments).await,
+                None => mcp::call_tool(state, &call.function.name, &call.function.arguments).await,
+            }
+        };
+        results.push(json!({
+            "role": "tool",
+            "tool_call_id": call.id,
+            "content": result.unwrap_or_else(|err| format!("Error: {err}")),
+        }));
+    }
+    let mut body: Value = serde_json::from_str(body)?;
+    let messages = body
+        .get_mut("messages")
+        .and_then(Value::as_array_mut)
+        .ok_or_else(|| Error::StringError("messages is not specified".to_owned()))?;
+    messages.push(json!({ "role": "assistant", "content": null, "tool_calls": calls }));
+    messages.extend(results);
+    Ok(body.to_string())
+}
+
+/// Approves or denies the call awaiting the approval of the user. Does nothing if it is no longer awaited.
+#[tauri::command]
+#[tracing::instrument(err)]
+pub(crate) fn answer_tool_approval(id: u64, approved: bool) -> Result<(), Error> {
+    if let Some(sender) = APPROVALS.lock()?.remove(&id) {
+        let _ = sender.send(approved);
+    }
+    Ok(())
+}
+
+/// Returns the definitions of the built-in tools, in the format of OpenAI's `tools`.
+#[tauri::command]
+#[tracing::instrument]
+pub(crate) fn list_builtin_tools() -> Vec<Value> {
+    BuiltinTool::ALL
+        .into_iter()
+        .map(BuiltinTool::definition)
+        .collect()
+}
@@ -0,0 +1,72 @@
+//! The system tray icon and the minimize-to-tray behavior.
+
+use crate::{screenshot, AppState, Error};
+use std::sync::atomic::Ordering;
+use tauri::{
+    AppHandle, CustomMenuItem, GlobalWindowEvent, Manager, SystemTray, SystemTrayEvent,
+    SystemTrayMenu, SystemTrayMenuItem, WindowEvent,
+};
+
+/// The ids of the menu items are the names of the frontend's `api` functions that are called via the `tray://action` event,
+/// except for the ones handled in Rust.
+pub(crate) fn system_tray() -> SystemTray {
+    SystemTray::new().with_menu(
+        SystemTrayMenu::new()
+            .add_item(CustomMenuItem::new("thread.new", "New chat"))
+            .add_item(CustomMenuItem::new("microphone.start", "Start dictation"))
+            .add_item(CustomMenuItem::new("speaker.stop", "Stop speaking"))
+            .add_item(CustomMenuItem::new("clipboard.ask", "Ask about clipboard"))
+            .add_item(CustomMenuItem::new("screenshot.capture", "Capture screen"))
+            .add_native_item(SystemTrayMenuItem::Separator)
+            .add_item(CustomMenuItem::new("quit", "Quit")),
+    )
+}
+
+pub(crate) fn show_main_window(app: &AppHandle) {
+    if let Some(window) = app.get_window("main") {
+        let _ = window.show();
+        let _ = window.unminimize();
+        let _ = window.set_focus();
+    }
+}
+
+pub(crate) fn handle_system_tray_event(app: &AppHandle, event: SystemTrayEvent) {
+    match event {
+        SystemTrayEvent::LeftClick { .. } => show_main_window(app),
+        SystemTrayEvent::MenuItemClick { id, .. } if id == "quit" => app.exit(0),
+        SystemTrayEvent::MenuItemClick { id, .. } if id == "screenshot.capture" => {
+            screenshot::capture_from_tray(app)
+        }
+        SystemTrayEvent::MenuItemClick { id, .. } => {
+            if id != "speaker.stop" {
+                show_main_window(app);
+            }
+            let _ = app.emit_all("tray://action", id);
+        }
+        _ => {}
+    }
+}
+
+/// Hides the window instead of closing it if minimize-to-tray is enabled.
+pub(crate) fn handle_window_event(event: GlobalWindowEvent) {
+    if let WindowEvent::CloseRequested { api, .. } = event.event() {
+        let state = event.window().state::<AppState>();
+        if state.config.minimize_to_tray.load(Ordering::SeqCst) {
+            let _ = event.window().hide();
+            api.prevent_close();
+        }
+    }
+}
+
+#[tauri::command]
+#[tracing::instrument(skip(state), err)]
+pub(crate) fn set_minimize_to_tray(
+    enabled: bool,
+    state: tauri::State<AppState>,
+) -> Result<(), Error> {
+    state
+        .config
+        .minimize_to_tray
+        .store(enabled, Ordering::SeqCst);
+    Ok(())
+}
@@ -0,0 +1,109 @@
+//! Size limit and least-recently-used eviction of the TTS caches (messageTTSCache and systemTTSCache).
+
+use crate::{settings, AppState, Error};
+use sqlx::{Connection, Row};
+
+/// The default of the `ttsCacheMaxBytes` setting.
+pub(crate) const DEFAULT_MAX_BYTES: i64 = 200 * 1024 * 1024;
+
+/// The SQL expression for `lastAccessedAt`.
+pub(crate) const NOW: &str = "CAST(strftime('%s', 'now') AS INTEGER)";
+
+#[derive(serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct TTSCacheStats {
+    entries: i64,
+    bytes: i64,
+    max_bytes: i64,
+}
+
+async fn max_bytes(conn: &mut sqlx::SqliteConnection) -> Result<i64, Error> {
+    Ok(settings::get(conn, "ttsCacheMaxBytes")
+        .await?
+        .and_then(|value| value.as_i64())
+        .unwrap_or(DEFAULT_MAX_BYTES))
+}
+
+/// Marks the cached audio of the SSML as recently used.
+pub(crate) async fn touch(conn: &mut sqlx::SqliteConnection, ssml: &str) -> Result<(), Error> {
+    for table in ["messageTTSCache", "systemTTSCache"] {
+        sqlx::query(&format!(
+            "UPDATE {table} SET lastAccessedAt = {NOW} WHERE ssml = ?"
+        ))
+        .bind(ssml)
+        .execute(&mut *conn)
+        .await?;
+    }
+    Ok(())
+}
+
+/// Deletes the least recently used audio until the total size of the caches fits in `ttsCacheMaxBytes`.
+pub(crate) async fn evict(conn: &mut sqlx::SqliteConnection) -> Result<(), Error> {
+    let max_bytes = max_bytes(&mut *conn).await?;
+    let entries = sqlx::query(
+        "
+SELECT 'messageTTSCache' AS tbl, rowid, length(audio) AS size, lastAccessedAt FROM messageTTSCache
+UNION ALL
+SELECT 'systemTTSCache' AS tbl, rowid, length(audio) AS size, lastAccessedAt FROM systemTTSCache
+ORDER BY lastAccessedAt DESC
+",
+    )
+    .fetch_all(&mut *conn)
+    .await?;
+
+    let mut total = 0i64;
+    let mut tx = conn.begin().await?;
+    for entry in entries {
+        total += entry.get::<i64, _>("size");
+        if total > max_bytes {
+            sqlx::query(&format!(
+                "DELETE FROM {} WHERE rowid = ?",
+                entry.get::<&str, _>("tbl")
+            ))
+            .bind(entry.get::<i64, _>("rowid"))
+            .execute(&mut *tx)
+            .await?;
+        }
+    }
+    tx.commit().await?;
+    Ok(())
+}
+
+#[tauri::command]
+#[tracing::instrument(skip(state), err)]
+pub(crate) async fn get_tts_cache_stats(
+    state: tauri::State<'_, AppState>,
+) -> Result<TTSCacheStats, Error> {
+    let mut conn = state.db_pool.acquire().await?;
+    let row = sqlx::query(
+        "
+SELECT count(*) AS entries, coalesce(sum(size), 0) AS bytes FROM (
+    SELECT length(audio) AS size FROM messageTTSCache
+    UNION ALL
+    SELECT length(audio) AS size FROM systemTTSCache
+)
+",
+    )
+    .fetch_one(&mut conn)
+    .await?;
+    Ok(TTSCacheStats {
+        entries: row.get("entries"),
+        bytes: row.get("bytes"),
+        max_bytes: max_bytes(&mut conn).await?,
+    })
+}
+
+#[tauri::command]
+#[tracing::instrument(skip(state), err)]
+pub(crate) async fn clear_tts_cache(state: tauri::State<'_, AppState>) -> Result<(), Error> {
+    let mut conn = state.db_pool.acquire().await?;
+    let mut tx = conn.begin().await?;
+    sqlx::query("DELETE FROM messageTTSCache")
+        .execute(&mut *tx)
+        .await?;
+    sqlx::query("DELETE FROM systemTTSCache")
+        .execute(&mut *tx)
+        .await?;
+    tx.commit().await?;
+    Ok(())
+}
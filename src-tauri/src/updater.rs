@@ -0,0 +1,56 @@
+//! Checks for and installs new releases with the Tauri updater.
+//!
+//! The updater is inactive in tauri.conf.json until the releases are signed and publish `latest.json`, with the public key
+//! of the signing key in `pubkey`. Until then, both commands fail.
+//!
+//! While `install_update` is running, Tauri emits `tauri://update-status` and `tauri://update-download-progress` ({ chunkLength, contentLength }) events.
+
+use crate::Error;
+use tauri::AppHandle;
+
+fn ensure_active(app: &AppHandle) -> Result<(), Error> {
+    if !app.config().tauri.updater.active {
+        return Err(Error::StringError(
+            "Updates are not available for this build".to_owned(),
+        ));
+    }
+    Ok(())
+}
+
+#[derive(serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct UpdateInfo {
+    available: bool,
+    current_version: String,
+    latest_version: String,
+    notes: Option<String>,
+    date: Option<String>,
+}
+
+#[tauri::command]
+#[tracing::instrument(skip(app), err)]
+pub(crate) async fn check_for_updates(app: AppHandle) -> Result<UpdateInfo, Error> {
+    ensure_active(&app)?;
+    let update = tauri::updater::builder(app).check().await?;
+    Ok(UpdateInfo {
+        available: update.is_update_available(),
+        current_version: update.current_version().to_string(),
+        latest_version: update.latest_version().to_owned(),
+        notes: update.body().cloned(),
+        date: update.date().map(|date| date.to_string()),
+    })
+}
+
+/// Downloads and installs the latest release, and restarts the app. Does nothing if the app is up to date.
+#[tauri::command]
+#[tracing::instrument(skip(app), err)]
+pub(crate) async fn install_update(app: AppHandle) -> Result<(), Error> {
+    ensure_active(&app)?;
+    let update = tauri::updater::builder(app.clone()).check().await?;
+    if !update.is_update_available() {
+        return Ok(());
+    }
+    update.download_and_install().await?;
+    app.restart();
+    Ok(())
+}
@@ -0,0 +1,216 @@
+//! Token usage of the chat completions, totaled per day, conversation, provider and model in the `usage` table.
+//!
+//! The counts reported by the provider are used when the response includes them (OpenAI with
+//! `stream_options.include_usage`, Ollama, Gemini). Otherwise they are estimated with cl100k_base, and the request is
+//! counted in `estimatedRequests`.
+
+use crate::{AppState, Error, CL100K_BASE};
+use serde_json::Value;
+use sqlx::Row;
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+/// The `conversationId` of the requests made outside a conversation, e.g. to name a thread.
+const NO_CONVERSATION: i64 = 0;
+
+lazy_static::lazy_static! {
+    /// The last usage reported in the stream of each request.
+    static ref REPORTED_USAGE: Mutex<HashMap<u64, (i64, i64)>> = Mutex::new(HashMap::new());
+}
+
+/// Remembers the usage if the streamed event reports it. Gemini reports the running total in every event, and Groq reports
+/// it in `x_groq` of the last chunk.
+pub(crate) fn capture(request_id: u64, data: &Value) -> Result<(), Error> {
+    let count = |pointer: &str| data.pointer(pointer).and_then(Value::as_i64);
+    let openai = || {
+        Some((
+            count("/usage/prompt_tokens")?,
+            count("/usage/completion_tokens")?,
+        ))
+    };
+    let groq = || {
+        Some((
+            count("/x_groq/usage/prompt_tokens")?,
+            count("/x_groq/usage/completion_tokens")?,
+        ))
+    };
+    let ollama = || Some((count("/prompt_eval_count")?, count("/eval_count")?));
+    let gemini = || {
+        Some((
+            count("/usageMetadata/promptTokenCount")?,
+            count("/usageMetadata/candidatesTokenCount").unwrap_or(0),
+        ))
+    };
+    if let Some(reported) = openai().or_else(groq).or_else(ollama).or_else(gemini) {
+        REPORTED_USAGE.lock()?.insert(request_id, reported);
+    }
+    Ok(())
+}
+
+/// Adds the usage of a finished or canceled request to the totals of the current day (UTC).
+pub(crate) async fn record(
+    state: &AppState,
+    request_id: u64,
+    conversation_id: Option<i64>,
+    provider: &str,
+    model: &str,
+    estimated_prompt_tokens: i64,
+    content: &str,
+) -> Result<(), Error> {
+    let reported = REPORTED_USAGE.lock()?.remove(&request_id);
+    let (prompt_tokens, completion_tokens) = reported.unwrap_or_else(|| {
+        (
+            estimated_prompt_tokens,
+            CL100K_BASE.encode_with_special_tokens(content).len() as i64,
+        )
+    });
+    let mut conn = state.db_pool.acquire().await?;
+    sqlx::query(
+        "
+INSERT INTO usage (day, conversationId, provider, model, promptTokens, completionTokens, requests, estimatedRequests)
+VALUES (date('now'), ?, ?, ?, ?, ?, 1, ?)
+ON CONFLICT (day, conversationId, provider, model) DO UPDATE SET
+    promptTokens = promptTokens + excluded.promptTokens,
+    completionTokens = completionTokens + excluded.completionTokens,
+    requests = requests + 1,
+    estimatedRequests = estimatedRequests + excluded.estimatedRequests
+",
+    )
+    .bind(conversation_id.unwrap_or(NO_CONVERSATION))
+    .bind(provider)
+    .bind(model)
+    .bind(prompt_tokens)
+    .bind(completion_tokens)
+    .bind(i64::from(reported.is_none()))
+    .execute(&mut conn)
+    .await?;
+    // The monthly budget is checked against textCompletionUsage.
+    sqlx::query("INSERT INTO textCompletionUsage (model, prompt_tokens, completion_tokens, total_tokens) VALUES (?, ?, ?, ?)")
+        .bind(model)
+        .bind(prompt_tokens)
+        .bind(completion_tokens)
+        .bind(prompt_tokens + completion_tokens)
+        .execute(&mut conn)
+        .await?;
+    Ok(())
+}
+
+/// Days in the form YYYY-MM-DD, both inclusive. Unbounded if omitted.
+#[derive(serde::Deserialize, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct UsageRange {
+    from: Option<String>,
+    to: Option<String>,
+}
+
+#[derive(serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct UsageTotals {
+    provider: String,
+    model: String,
+    prompt_tokens: i64,
+    completion_tokens: i64,
+    requests: i64,
+    /// The requests whose usage was not reported by the provider.
+    estimated_requests: i64,
+}
+
+#[derive(serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct DailyUsage {
+    day: String,
+    #[serde(flatten)]
+    totals: UsageTotals,
+}
+
+#[derive(serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct ConversationUsage {
+    conversation_id: i64,
+    name: Option<String>,
+    #[serde(flatten)]
+    totals: UsageTotals,
+}
+
+#[derive(serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct UsageStats {
+    daily: Vec<DailyUsage>,
+    conversations: Vec<ConversationUsage>,
+}
+
+fn totals(row: &sqlx::sqlite::SqliteRow) -> UsageTotals {
+    UsageTotals {
+        provider: row.get("provider"),
+        model: row.get("model"),
+        prompt_tokens: row.get("promptTokens"),
+        completion_tokens: row.get("completionTokens"),
+        requests: row.get("requests"),
+        estimated_requests: row.get("estimatedRequests"),
+    }
+}
+
+/// Returns the token usage per day and per conversation, for each provider and model. The cost is computed by the
+/// frontend with its price table.
+#[tauri::command]
+#[tracing::instrument(skip(state), err)]
+pub(crate) async fn get_usage_stats(
+    range: Option<UsageRange>,
+    state: tauri::State<'_, AppState>,
+) -> Result<UsageStats, Error> {
+    let range = range.unwrap_or_default();
+    let mut conn = state.db_pool.acquire().await?;
+    let daily = sqlx::query(
+        "
+SELECT day, provider, model,
+    sum(promptTokens) AS promptTokens,
+    sum(completionTokens) AS completionTokens,
+    sum(requests) AS requests,
+    sum(estimatedRequests) AS estimatedRequests
+FROM usage
+WHERE (?1 IS NULL OR day >= ?1) AND (?2 IS NULL OR day <= ?2)
+GROUP BY day, provider, model
+ORDER BY day, provider, model
+",
+    )
+    .bind(&range.from)
+    .bind(&range.to)
+    .fetch_all(&mut conn)
+    .await?
+    .iter()
+    .map(|row| DailyUsage {
+        day: row.get("day"),
+        totals: totals(row),
+    })
+    .collect();
+    let conversations = sqlx::query(
+        "
+SELECT conversationId, threadName.name AS name, provider, model,
+    sum(promptTokens) AS promptTokens,
+    sum(completionTokens) AS completionTokens,
+    sum(requests) AS requests,
+    sum(estimatedRequests) AS estimatedRequests
+FROM usage
+LEFT JOIN threadName ON threadName.messageId = usage.conversationId
+WHERE conversationId != ?3 AND (?1 IS NULL OR day >= ?1) AND (?2 IS NULL OR day <= ?2)
+GROUP BY conversationId, provider, model
+ORDER BY sum(promptTokens + completionTokens) DESC
+",
+    )
+    .bind(&range.from)
+    .bind(&range.to)
+    .bind(NO_CONVERSATION)
+    .fetch_all(&mut conn)
+    .await?
+    .iter()
+    .map(|row| ConversationUsage {
+        conversation_id: row.get("conversationId"),
+        name: row.get("name"),
+        totals: totals(row),
+    })
+    .collect();
+    Ok(UsageStats {
+        daily,
+        conversations,
+    })
+}
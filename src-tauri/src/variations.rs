@@ -0,0 +1,84 @@
+//! Regenerates a response at several temperatures at once, so that the user can pick the best of the candidates.
+//!
+//! The requests are sent to the service configured in the main window, like `quick_ask`, without streaming.
+
+use crate::quick_ask::{configured_service, send};
+use crate::{chunk_buffer, AppState, Error, CHAT_COMPLETION_CANCELED, CHAT_COMPLETION_WINDOW};
+use serde_json::Value;
+use std::time::Duration;
+
+const MAX_VARIATIONS: usize = 8;
+const CANCEL_POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+#[derive(serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct Candidate {
+    temperature: f32,
+    content: Option<String>,
+    /// The error of this candidate. The other candidates are returned regardless.
+    error: Option<String>,
+}
+
+async fn wait_canceled(request_id: u64) -> Result<(), Error> {
+    loop {
+        tokio::time::sleep(CANCEL_POLL_INTERVAL).await;
+        if CHAT_COMPLETION_CANCELED.lock()?.contains(&request_id) {
+            return Ok(());
+        }
+    }
+}
+
+/// Sends the body once for each temperature concurrently, and returns the candidates in the order of `temperatures`.
+/// Returns no candidates if the request is canceled by `stop_all_chat_completions`.
+#[tauri::command]
+#[tracing::instrument(skip(window, body, state), err)]
+pub(crate) async fn regenerate_with_variations(
+    window: tauri::Window,
+    request_id: u64,
+    body: String,
+    temperatures: Vec<f32>,
+    state: tauri::State<'_, AppState>,
+) -> Result<Vec<Candidate>, Error> {
+    if temperatures.is_empty() || temperatures.len() > MAX_VARIATIONS {
+        return Err(Error::StringError(format!(
+            "Between 1 and {MAX_VARIATIONS} temperatures are required"
+        )));
+    }
+    let mut request: Value = serde_json::from_str(&body)?;
+    request["stream"] = Value::Bool(false);
+    if let Some(request) = request.as_object_mut() {
+        request.remove("stream_options");
+    }
+    let (endpoint, secret_key) = {
+        let mut conn = state.db_pool.acquire().await?;
+        configured_service(&mut conn).await?
+    };
+
+    // Registered like a streaming request, so that stop_all_chat_completions() cancels it.
+    let _buffer = chunk_buffer::open(request_id)?;
+    CHAT_COMPLETION_WINDOW
+        .lock()?
+        .insert(request_id, window.label().to_owned());
+    let candidates = temperatures.iter().map(|&temperature| {
+        let mut request = request.clone();
+        request["temperature"] = serde_json::json!(temperature);
+        let (endpoint, secret_key) = (&endpoint, &secret_key);
+        async move {
+            let (content, error) = match send(endpoint, secret_key, &request).await {
+                Ok(content) => (Some(content), None),
+                Err(err) => (None, Some(err.to_string())),
+            };
+            Candidate {
+                temperature,
+                content,
+                error,
+            }
+        }
+    });
+    let result = tokio::select! {
+        candidates = futures_util::future::join_all(candidates) => Ok(candidates),
+        canceled = wait_canceled(request_id) => canceled.map(|()| vec![]),
+    };
+    chunk_buffer::remove(request_id)?;
+    result
+}
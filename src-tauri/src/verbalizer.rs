@@ -0,0 +1,481 @@
+//! Reads math and units aloud in English, e.g. "$\frac{a}{b}$" as "a over b" and "3.5e-2 m/s²" as "3.5 times 10 to the
+//! power of minus 2 meters per second squared".
+//!
+//! The math is the LaTeX between `$...$`, `$$...$$`, `\(...\)`, and `\[...\]`. Only the common commands are known; the
+//! name of an unknown command is read as is. A unit is read only when it directly follows a number and every part of it
+//! is known, so that ordinary words after numbers are left alone.
+
+/// The words of a LaTeX command without arguments, e.g. "alpha" for `\alpha`.
+fn command_words(name: &str) -> Option<&'static str> {
+    Some(match name {
+        "alpha" => "alpha",
+        "beta" => "beta",
+        "gamma" | "Gamma" => "gamma",
+        "delta" | "Delta" => "delta",
+        "epsilon" | "varepsilon" => "epsilon",
+        "zeta" => "zeta",
+        "eta" => "eta",
+        "theta" | "Theta" | "vartheta" => "theta",
+        "iota" => "iota",
+        "kappa" => "kappa",
+        "lambda" | "Lambda" => "lambda",
+        "mu" => "mu",
+        "nu" => "nu",
+        "xi" | "Xi" => "xi",
+        "pi" | "Pi" => "pi",
+        "rho" => "rho",
+        "sigma" | "Sigma" => "sigma",
+        "tau" => "tau",
+        "phi" | "Phi" | "varphi" => "phi",
+        "chi" => "chi",
+        "psi" | "Psi" => "psi",
+        "omega" | "Omega" => "omega",
+        "times" | "cdot" | "ast" => "times",
+        "div" => "divided by",
+        "pm" => "plus or minus",
+        "mp" => "minus or plus",
+        "leq" | "le" => "is less than or equal to",
+        "geq" | "ge" => "is greater than or equal to",
+        "neq" | "ne" => "is not equal to",
+        "approx" => "is approximately",
+        "equiv" => "is equivalent to",
+        "propto" => "is proportional to",
+        "ll" => "is much less than",
+        "gg" => "is much greater than",
+        "infty" => "infinity",
+        "sum" => "the sum",
+        "prod" => "the product",
+        "int" => "the integral",
+        "oint" => "the contour integral",
+        "partial" => "partial",
+        "nabla" => "nabla",
+        "lim" => "the limit",
+        "to" | "rightarrow" => "to",
+        "Rightarrow" | "implies" => "implies",
+        "Leftrightarrow" | "iff" => "if and only if",
+        "in" => "in",
+        "notin" => "not in",
+        "subset" => "subset of",
+        "cup" => "union",
+        "cap" => "intersection",
+        "forall" => "for all",
+        "exists" => "there exists",
+        "ldots" | "cdots" | "dots" => "dot dot dot",
+        "sin" => "sine",
+        "cos" => "cosine",
+        "tan" => "tangent",
+        "log" => "log",
+        "ln" => "natural log",
+        "exp" => "exp",
+        "max" => "max",
+        "min" => "min",
+        "circ" | "degree" => "degrees",
+        "prime" => "prime",
+        _ => return None,
+    })
+}
+
+struct LatexReader<'a> {
+    rest: &'a str,
+}
+
+impl LatexReader<'_> {
+    fn peek(&self) -> Option<char> {
+        self.rest.chars().next()
+    }
+
+    fn bump(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.rest = &self.rest[c.len_utf8()..];
+        Some(c)
+    }
+
+    fn skip_whitespace(&mut self) {
+        self.rest = self.rest.trim_start();
+    }
+
+    fn command_name(&mut self) -> String {
+        let len = self
+            .rest
+            .find(|c: char| !c.is_ascii_alphabetic())
+            .unwrap_or(self.rest.len());
+        if len == 0 {
+            // A command of one symbol, e.g. `\,` or `\{`.
+            return self.bump().map(String::from).unwrap_or_default();
+        }
+        let name = self.rest[..len].to_owned();
+        self.rest = &self.rest[len..];
+        name
+    }
+
+    /// Reads the argument of a command: a group in braces, a command, or a character.
+    fn argument(&mut self) -> String {
+        self.skip_whitespace();
+        match self.peek() {
+            Some('{') => {
+                self.bump();
+                self.sequence(Some('}'))
+            }
+            Some('\\') => {
+                self.bump();
+                let name = self.command_name();
+                self.command(&name)
+            }
+            Some(c) => {
+                self.bump();
+                symbol_words(c).unwrap_or_else(|| c.to_string())
+            }
+            None => String::new(),
+        }
+    }
+
+    /// Reads the text of a `\text{...}` argument as is.
+    fn text_argument(&mut self) -> String {
+        self.skip_whitespace();
+        if self.peek() != Some('{') {
+            return self.argument();
+        }
+        self.bump();
+        let mut depth = 0;
+        let mut text = String::new();
+        while let Some(c) = self.bump() {
+            match c {
+                '{' => depth += 1,
+                '}' if depth == 0 => break,
+                '}' => depth -= 1,
+                _ => text.push(c),
+            }
+        }
+        text.trim().to_owned()
+    }
+
+    fn command(&mut self, name: &str) -> String {
+        match name {
+            "frac" | "dfrac" | "tfrac" => {
+                let numerator = self.argument();
+                let denominator = self.argument();
+                format!("{numerator} over {denominator}")
+            }
+            "sqrt" => {
+                self.skip_whitespace();
+                let root = if self.peek() == Some('[') {
+                    self.bump();
+                    Some(self.sequence(Some(']')))
+                } else {
+                    None
+                };
+                let radicand = self.argument();
+                match root.as_deref() {
+                    None | Some("2") => format!("the square root of {radicand}"),
+                    Some("3") => format!("the cube root of {radicand}"),
+                    Some(n) => format!("the {n}th root of {radicand}"),
+                }
+            }
+            "text" | "mathrm" | "textrm" | "operatorname" | "mathbf" | "mathit" | "mathbb"
+            | "mathcal" | "boldsymbol" => self.text_argument(),
+            "left" | "right" | "big" | "Big" | "bigg" | "Bigg" => {
+                // The delimiter that follows is not read.
+                self.skip_whitespace();
+                if self.peek() == Some('\\') {
+                    self.bump();
+                    self.command_name();
+                } else {
+                    self.bump();
+                }
+                String::new()
+            }
+            "," | ";" | ":" | "!" | " " | "quad" | "qquad" | "\\" | "displaystyle" => String::new(),
+            "{" | "}" | "|" => String::new(),
+            "%" => "percent".to_owned(),
+            name => command_words(name).map_or_else(|| name.to_owned(), str::to_owned),
+        }
+    }
+
+    /// Reads the words until `end` or the end of the math.
+    fn sequence(&mut self, end: Option<char>) -> String {
+        let mut words: Vec<String> = vec![];
+        loop {
+            self.skip_whitespace();
+            let Some(c) = self.peek() else {
+                break;
+            };
+            if Some(c) == end {
+                self.bump();
+                break;
+            }
+            match c {
+                '{' => {
+                    self.bump();
+                    words.push(self.sequence(Some('}')));
+                }
+                '\\' => {
+                    self.bump();
+                    let name = self.command_name();
+                    words.push(self.command(&name));
+                }
+                '^' => {
+                    self.bump();
+                    let exponent = self.argument();
+                    words.push(match exponent.as_str() {
+                        "2" => "squared".to_owned(),
+                        "3" => "cubed".to_owned(),
+                        "degrees" | "prime" => exponent,
+                        _ => format!("to the power of {exponent}"),
+                    });
+                }
+                '_' => {
+                    self.bump();
+                    words.push(format!("sub {}", self.argument()));
+                }
+                c if c.is_ascii_digit() || c == '.' => {
+                    let len = self
+                        .rest
+                        .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+                        .unwrap_or(self.rest.len());
+                    words.push(self.rest[..len].to_owned());
+                    self.rest = &self.rest[len..];
+                }
+                c => {
+                    self.bump();
+                    match symbol_words(c) {
+                        Some(symbol) => words.push(symbol),
+                        None if c.is_alphanumeric() => words.push(c.to_string()),
+                        None => {}
+                    }
+                }
+            }
+        }
+        words.retain(|word| !word.is_empty());
+        words.join(" ").replace(" ,", ",")
+    }
+}
+
+fn symbol_words(c: char) -> Option<String> {
+    Some(
+        match c {
+            '=' => "equals",
+            '+' => "plus",
+            '-' | '−' => "minus",
+            '*' | '×' | '·' => "times",
+            '/' => "over",
+            '<' => "is less than",
+            '>' => "is greater than",
+            '!' => "factorial",
+            '\'' => "prime",
+            ',' => ",",
+            '%' => "percent",
+            _ => return None,
+        }
+        .to_owned(),
+    )
+}
+
+/// Reads LaTeX math aloud.
+pub(crate) fn verbalize_latex(latex: &str) -> String {
+    LatexReader { rest: latex }.sequence(None)
+}
+
+/// Replaces the inline math of a line, between `$...$`, `$$...$$`, `\(...\)`, or `\[...\]`, with its words. A dollar
+/// sign followed by a digit or whitespace, e.g. in "$5 and $10", is a currency.
+pub(crate) fn verbalize_inline_math(line: &str) -> String {
+    let mut out = String::with_capacity(line.len());
+    let mut rest = line;
+    while !rest.is_empty() {
+        let delimiters = [("$$", "$$"), ("\\(", "\\)"), ("\\[", "\\]"), ("$", "$")];
+        let found = delimiters.iter().find_map(|&(open, close)| {
+            let math = rest.strip_prefix(open)?;
+            if open == "$" && math.starts_with(|c: char| c.is_ascii_digit() || c.is_whitespace()) {
+                return None;
+            }
+            let end = math.find(close).filter(|&end| end > 0)?;
+            Some((&math[..end], open.len() + end + close.len()))
+        });
+        match found {
+            Some((math, len)) => {
+                out.push_str(&verbalize_latex(math));
+                rest = &rest[len..];
+            }
+            None => {
+                let c = rest.chars().next().unwrap();
+                out.push(c);
+                rest = &rest[c.len_utf8()..];
+            }
+        }
+    }
+    out
+}
+
+/// The singular and plural names of a unit symbol, and whether it must be attached to the number, e.g. "5A" but not
+/// "grade 5 A".
+fn unit_names(symbol: &str) -> Option<(&'static str, &'static str, bool)> {
+    Some(match symbol {
+        "m" => ("meter", "meters", false),
+        "km" => ("kilometer", "kilometers", false),
+        "cm" => ("centimeter", "centimeters", false),
+        "mm" => ("millimeter", "millimeters", false),
+        "µm" | "μm" | "um" => ("micrometer", "micrometers", false),
+        "nm" => ("nanometer", "nanometers", false),
+        "s" | "sec" => ("second", "seconds", false),
+        "ms" => ("millisecond", "milliseconds", false),
+        "µs" | "μs" | "us" => ("microsecond", "microseconds", false),
+        "ns" => ("nanosecond", "nanoseconds", false),
+        "min" => ("minute", "minutes", false),
+        "h" | "hr" => ("hour", "hours", false),
+        "g" => ("gram", "grams", false),
+        "kg" => ("kilogram", "kilograms", false),
+        "mg" => ("milligram", "milligrams", false),
+        "L" | "l" => ("liter", "liters", false),
+        "mL" | "ml" => ("milliliter", "milliliters", false),
+        "Hz" => ("hertz", "hertz", false),
+        "kHz" => ("kilohertz", "kilohertz", false),
+        "MHz" => ("megahertz", "megahertz", false),
+        "GHz" => ("gigahertz", "gigahertz", false),
+        "N" => ("newton", "newtons", true),
+        "J" => ("joule", "joules", false),
+        "kJ" => ("kilojoule", "kilojoules", false),
+        "W" => ("watt", "watts", true),
+        "kW" => ("kilowatt", "kilowatts", false),
+        "kWh" => ("kilowatt hour", "kilowatt hours", false),
+        "V" => ("volt", "volts", true),
+        "mV" => ("millivolt", "millivolts", false),
+        "A" => ("ampere", "amperes", true),
+        "mA" => ("milliampere", "milliamperes", false),
+        "Ω" => ("ohm", "ohms", false),
+        "Pa" => ("pascal", "pascals", false),
+        "kPa" => ("kilopascal", "kilopascals", false),
+        "K" => ("kelvin", "kelvin", true),
+        "°C" => ("degree Celsius", "degrees Celsius", false),
+        "°F" => ("degree Fahrenheit", "degrees Fahrenheit", false),
+        "°" => ("degree", "degrees", false),
+        "mol" => ("mole", "moles", false),
+        "B" => ("byte", "bytes", true),
+        "KB" | "kB" => ("kilobyte", "kilobytes", false),
+        "MB" => ("megabyte", "megabytes", false),
+        "GB" => ("gigabyte", "gigabytes", false),
+        "TB" => ("terabyte", "terabytes", false),
+        "km/h" => ("kilometer per hour", "kilometers per hour", false),
+        "mph" => ("mile per hour", "miles per hour", false),
+        _ => return None,
+    })
+}
+
+/// Reads a unit such as "m/s²" or "kg·m^2", or returns None if a part of it is unknown.
+fn verbalize_unit(unit: &str, singular: bool, attached: bool) -> Option<String> {
+    if let Some((one, many, _)) = unit_names(unit) {
+        return Some(if singular { one } else { many }.to_owned());
+    }
+    let mut parts = unit.split('/');
+    let numerator = parts.next()?;
+    let denominators = parts.collect::<Vec<_>>();
+    let mut words = vec![];
+    for (i, factor) in numerator.split(['·', '*', '⋅']).enumerate() {
+        // Only the last factor of the numerator is plural, e.g. "newton meters".
+        let is_last = i == numerator.split(['·', '*', '⋅']).count() - 1;
+        words.push(verbalize_factor(factor, singular || !is_last, attached)?);
+    }
+    for denominator in denominators {
+        words.push("per".to_owned());
+        for factor in denominator.split(['·', '*', '⋅']) {
+            words.push(verbalize_factor(factor, true, attached)?);
+        }
+    }
+    Some(words.join(" "))
+}
+
+fn verbalize_factor(factor: &str, singular: bool, attached: bool) -> Option<String> {
+    let (symbol, power) = if let Some(symbol) = factor.strip_suffix('²') {
+        (symbol, Some("squared"))
+    } else if let Some(symbol) = factor.strip_suffix('³') {
+        (symbol, Some("cubed"))
+    } else if let Some(symbol) = factor.strip_suffix("^2") {
+        (symbol, Some("squared"))
+    } else if let Some(symbol) = factor.strip_suffix("^3") {
+        (symbol, Some("cubed"))
+    } else {
+        (factor, None)
+    };
+    let (one, many, needs_attached) = unit_names(symbol)?;
+    if needs_attached && !attached {
+        return None;
+    }
+    let name = if singular { one } else { many };
+    Some(match power {
+        Some(power) => format!("{name} {power}"),
+        None => name.to_owned(),
+    })
+}
+
+/// Reads the numbers in scientific notation and the units after numbers, e.g. "3.5e-2 m/s²".
+pub(crate) fn verbalize_numbers_and_units(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(c) = rest.chars().next() {
+        let at_word_start = !out.chars().next_back().map_or(false, char::is_alphanumeric);
+        if !(c.is_ascii_digit() && at_word_start) {
+            out.push(c);
+            rest = &rest[c.len_utf8()..];
+            continue;
+        }
+        // The number, e.g. "3.5".
+        let mut len = rest
+            .find(|c: char| !(c.is_ascii_digit() || c == '.' || c == ','))
+            .unwrap_or(rest.len());
+        while rest[..len].ends_with(['.', ',']) {
+            len -= 1;
+        }
+        let number = &rest[..len];
+        rest = &rest[len..];
+        out.push_str(number);
+        let mut singular = number == "1";
+
+        // The exponent, e.g. "e-2" or "^-2".
+        for marker in ["e", "E", "^"] {
+            let Some(exponent) = rest.strip_prefix(marker) else {
+                continue;
+            };
+            let sign_len = exponent
+                .starts_with(['-', '+', '−'])
+                .then(|| exponent.chars().next().unwrap().len_utf8())
+                .unwrap_or(0);
+            let digits = exponent[sign_len..]
+                .find(|c: char| !c.is_ascii_digit())
+                .unwrap_or(exponent.len() - sign_len);
+            if digits == 0 || exponent[sign_len + digits..].starts_with(char::is_alphanumeric) {
+                continue;
+            }
+            let sign = if exponent.starts_with(['-', '−']) {
+                "minus "
+            } else {
+                ""
+            };
+            let power = &exponent[sign_len..sign_len + digits];
+            if marker == "^" {
+                out.push_str(&format!(" to the power of {sign}{power}"));
+            } else {
+                out.push_str(&format!(" times 10 to the power of {sign}{power}"));
+            }
+            rest = &exponent[sign_len + digits..];
+            singular = false;
+            break;
+        }
+
+        // The unit, which ends at whitespace or at punctuation followed by whitespace.
+        let attached = !rest.starts_with(' ');
+        let unit_text = rest.strip_prefix(' ').unwrap_or(rest);
+        let mut unit_len = unit_text
+            .find(char::is_whitespace)
+            .unwrap_or(unit_text.len());
+        while unit_text[..unit_len].ends_with(['.', ',', ';', ':', ')', '!', '?']) {
+            unit_len -= 1;
+        }
+        if unit_len == 0 {
+            continue;
+        }
+        if let Some(words) = verbalize_unit(&unit_text[..unit_len], singular, attached) {
+            out.push(' ');
+            out.push_str(&words);
+            rest = &unit_text[unit_len..];
+        }
+    }
+    out
+}
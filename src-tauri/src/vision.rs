@@ -0,0 +1,123 @@
+//! Images attached to the messages of a chat completion.
+//!
+//! A message of the request body may have `images`, a list of data URLs returned by `prepare_image_attachment`.
+//! `expand_images` moves them into the format of the provider before the request is sent: content parts for OpenAI,
+//! base64 strings in `images` for Ollama, and `inline_data` parts for Gemini (in `gemini_request_body`).
+
+use crate::{ChatProvider, Error};
+use base64::Engine;
+use image::imageops::FilterType;
+use image::{GenericImageView, ImageOutputFormat};
+use serde_json::{json, Value};
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+
+/// The default of the longest side, large enough for the high detail of GPT-4V.
+pub(crate) const DEFAULT_MAX_DIMENSION: u32 = 2048;
+const JPEG_QUALITY: u8 = 85;
+
+#[derive(serde::Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct ImageAttachment {
+    data_url: String,
+    width: u32,
+    height: u32,
+    /// The number of tokens charged by OpenAI for the image in high detail.
+    estimated_tokens: u32,
+}
+
+/// The image is scaled to fit in 2048x2048 and then its shortest side to 768, and each 512x512 tile costs 170 tokens in
+/// addition to the base 85. See https://platform.openai.com/docs/guides/vision/calculating-costs
+fn estimate_image_tokens(width: u32, height: u32) -> u32 {
+    let (mut w, mut h) = (width as f64, height as f64);
+    let scale = (2048.0 / w.max(h)).min(1.0);
+    (w, h) = (w * scale, h * scale);
+    let scale = (768.0 / w.min(h)).min(1.0);
+    (w, h) = (w * scale, h * scale);
+    let tiles = (w / 512.0).ceil() * (h / 512.0).ceil();
+    170 * tiles as u32 + 85
+}
+
+/// Loads the image, shrinks it so that its longest side is at most `max_dimension`, and encodes it as a data URL: PNG if
+/// it has transparency, JPEG otherwise.
+pub(crate) fn prepare(path: &Path, max_dimension: u32) -> Result<ImageAttachment, Error> {
+    let mut image = image::open(path)
+        .map_err(|err| Error::StringError(format!("Failed to read the image: {err}")))?;
+    if image.width().max(image.height()) > max_dimension {
+        image = image.resize(max_dimension, max_dimension, FilterType::Triangle);
+    }
+    let (width, height) = image.dimensions();
+    let mut buf = Cursor::new(vec![]);
+    let mime_type = if image.color().has_alpha() {
+        image
+            .write_to(&mut buf, ImageOutputFormat::Png)
+            .map(|_| "image/png")
+    } else {
+        image::DynamicImage::ImageRgb8(image.to_rgb8())
+            .write_to(&mut buf, ImageOutputFormat::Jpeg(JPEG_QUALITY))
+            .map(|_| "image/jpeg")
+    }
+    .map_err(|err| Error::StringError(format!("Failed to encode the image: {err}")))?;
+    Ok(ImageAttachment {
+        data_url: format!(
+            "data:{mime_type};base64,{}",
+            base64::engine::general_purpose::STANDARD.encode(buf.into_inner())
+        ),
+        width,
+        height,
+        estimated_tokens: estimate_image_tokens(width, height),
+    })
+}
+
+#[tauri::command]
+#[tracing::instrument(err)]
+pub(crate) async fn prepare_image_attachment(
+    path: PathBuf,
+    max_dimension: Option<u32>,
+) -> Result<ImageAttachment, Error> {
+    let max_dimension = max_dimension.unwrap_or(DEFAULT_MAX_DIMENSION);
+    tauri::async_runtime::spawn_blocking(move || prepare(&path, max_dimension)).await?
+}
+
+/// Returns the MIME type and the base64 data of a data URL.
+pub(crate) fn split_data_url(url: &str) -> Option<(&str, &str)> {
+    url.strip_prefix("data:")?.split_once(";base64,")
+}
+
+/// Converts the `images` of the messages in the request body into the format of the provider. Gemini requests are left
+/// as is for `gemini_request_body`.
+pub(crate) fn expand_images(provider: ChatProvider, body: String) -> Result<String, Error> {
+    if provider == ChatProvider::Gemini || !body.contains("\"images\"") {
+        return Ok(body);
+    }
+    let mut body: Value = serde_json::from_str(&body)?;
+    for message in body
+        .get_mut("messages")
+        .and_then(Value::as_array_mut)
+        .into_iter()
+        .flatten()
+    {
+        let Some(Value::Array(images)) = message.as_object_mut().and_then(|m| m.remove("images"))
+        else {
+            continue;
+        };
+        let urls = images.iter().filter_map(Value::as_str);
+        match provider {
+            ChatProvider::OpenAI | ChatProvider::OpenRouter => {
+                let text = message.get("content").and_then(Value::as_str).unwrap_or("");
+                let mut parts = vec![json!({ "type": "text", "text": text })];
+                parts.extend(
+                    urls.map(|url| json!({ "type": "image_url", "image_url": { "url": url } })),
+                );
+                message["content"] = Value::Array(parts);
+            }
+            ChatProvider::Ollama => {
+                message["images"] = urls
+                    .filter_map(|url| split_data_url(url).map(|(_, data)| json!(data)))
+                    .collect();
+            }
+            ChatProvider::Gemini => {}
+        }
+    }
+    Ok(body.to_string())
+}
@@ -0,0 +1,79 @@
+//! A user dictionary of domain terms, e.g. product names and jargon, stored in the `vocabulary` table.
+//!
+//! The terms are given to Whisper as the prompt of each dictation, which makes it prefer their spelling. Whisper only
+//! reads the last 224 tokens of the prompt, so the context hint of the dictation comes last, and the terms added first
+//! are the ones dropped from a long dictionary.
+
+use crate::{AppState, Error};
+use sqlx::Row;
+
+async fn terms(conn: &mut sqlx::SqliteConnection) -> Result<Vec<String>, Error> {
+    Ok(
+        sqlx::query("SELECT term FROM vocabulary ORDER BY createdAt, term")
+            .fetch_all(conn)
+            .await?
+            .into_iter()
+            .map(|row| row.get("term"))
+            .collect(),
+    )
+}
+
+/// The prompt for Whisper: the terms of the dictionary followed by `context_hint`, or "" if both are empty.
+pub(crate) async fn whisper_prompt(
+    conn: &mut sqlx::SqliteConnection,
+    context_hint: &str,
+) -> Result<String, Error> {
+    let terms = terms(conn).await?;
+    let mut prompt = String::new();
+    if !terms.is_empty() {
+        prompt = format!("{}.", terms.join(", "));
+    }
+    if !context_hint.trim().is_empty() {
+        if !prompt.is_empty() {
+            prompt.push(' ');
+        }
+        prompt.push_str(context_hint.trim());
+    }
+    Ok(prompt)
+}
+
+/// Adds a term to the dictionary. Terms differing only in case are the same term, and the last spelling is kept.
+#[tauri::command]
+#[tracing::instrument(skip(state), err)]
+pub(crate) async fn add_vocabulary_term(
+    term: String,
+    state: tauri::State<'_, AppState>,
+) -> Result<(), Error> {
+    if term.trim().is_empty() {
+        return Err(Error::StringError("The term must not be empty".to_owned()));
+    }
+    let mut conn = state.db_pool.acquire().await?;
+    sqlx::query("INSERT INTO vocabulary (term) VALUES (?) ON CONFLICT (term) DO UPDATE SET term = excluded.term")
+        .bind(term.trim())
+        .execute(&mut conn)
+        .await?;
+    Ok(())
+}
+
+#[tauri::command]
+#[tracing::instrument(skip(state), err)]
+pub(crate) async fn list_vocabulary_terms(
+    state: tauri::State<'_, AppState>,
+) -> Result<Vec<String>, Error> {
+    let mut conn = state.db_pool.acquire().await?;
+    terms(&mut conn).await
+}
+
+#[tauri::command]
+#[tracing::instrument(skip(state), err)]
+pub(crate) async fn delete_vocabulary_term(
+    term: String,
+    state: tauri::State<'_, AppState>,
+) -> Result<(), Error> {
+    let mut conn = state.db_pool.acquire().await?;
+    sqlx::query("DELETE FROM vocabulary WHERE term = ?")
+        .bind(term)
+        .execute(&mut conn)
+        .await?;
+    Ok(())
+}
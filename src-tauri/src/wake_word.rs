@@ -0,0 +1,185 @@
+//! The optional wake word, which starts the dictation hands-free when a keyword such as "hey assistant" is heard.
+//!
+//! While it is enabled, the default input device stays open and its audio is given to rustpotter, in memory and on
+//! this device only: nothing is recorded or sent. Each window receives `wake-word://detected` ({ keyword, score }) when
+//! the keyword is heard, and the frontend starts the dictation as if the microphone button was clicked.
+//!
+//! A keyword is a rustpotter wakeword file (.rpw), given either as its path or as the name of a file in the
+//! `wake_words` directory of the app data directory, e.g. "hey_assistant" for `wake_words/hey_assistant.rpw`.
+
+use crate::{AppState, Error};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc};
+use std::time::{Duration, Instant};
+use tauri::{AppHandle, Manager};
+
+/// The detections within this time after a detection are ignored, since the same utterance is detected in a few frames.
+const COOLDOWN: Duration = Duration::from_secs(2);
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+#[derive(serde::Serialize, Clone)]
+struct Detected {
+    keyword: String,
+    score: f32,
+}
+
+fn wakeword_path(app: &AppHandle, keyword: &str) -> Result<PathBuf, Error> {
+    let path = PathBuf::from(keyword);
+    if path.extension().is_some_and(|ext| ext == "rpw") {
+        return Ok(path);
+    }
+    let dir = app
+        .path_resolver()
+        .app_data_dir()
+        .ok_or_else(|| Error::StringError("The app data directory is unknown".to_owned()))?;
+    Ok(dir.join("wake_words").join(format!("{keyword}.rpw")))
+}
+
+/// Listens to the default input device and emits `wake-word://detected` until `stop` is set.
+fn detect(
+    app: AppHandle,
+    keyword: String,
+    path: PathBuf,
+    sensitivity: f32,
+    stop: Arc<AtomicBool>,
+) -> Result<(), Error> {
+    use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+    use cpal::SampleFormat;
+    use dasp_sample::conv;
+
+    let device = cpal::default_host()
+        .default_input_device()
+        .ok_or_else(|| Error::StringError("No input device is available".to_owned()))?;
+    let config = device.default_input_config()?;
+    let channels = config.channels() as usize;
+
+    let mut detector_config = rustpotter::RustpotterConfig::default();
+    detector_config.fmt.sample_rate = config.sample_rate().0 as usize;
+    detector_config.fmt.sample_format = rustpotter::SampleFormat::F32;
+    detector_config.fmt.channels = 1;
+    // A higher sensitivity detects the keyword with a lower score, at the cost of more false detections.
+    detector_config.detector.threshold = 1.0 - sensitivity;
+    let mut detector = rustpotter::Rustpotter::new(&detector_config).map_err(Error::StringError)?;
+    detector
+        .add_wakeword_from_file(&keyword, &path.to_string_lossy())
+        .map_err(|err| Error::StringError(format!("Failed to load {}: {err}", path.display())))?;
+    let samples_per_frame = detector.get_samples_per_frame();
+
+    // The samples are processed on this thread rather than in the callback of the stream.
+    let (tx, rx) = mpsc::channel::<Vec<f32>>();
+    macro_rules! build {
+        ($sample_converter:expr) => {{
+            let tx = tx.clone();
+            device.build_input_stream(
+                &config.config(),
+                move |data, _| {
+                    let mono = data
+                        .chunks(channels)
+                        .map(|frame| {
+                            frame.iter().map($sample_converter).sum::<f32>() / channels as f32
+                        })
+                        .collect();
+                    let _ = tx.send(mono);
+                },
+                |err| tracing::warn!("wake word input stream error: {err}"),
+                None,
+            )?
+        }};
+    }
+    let stream = match config.sample_format() {
+        SampleFormat::I8 => build!(|&x| conv::i8::to_f32(x)),
+        SampleFormat::I16 => build!(|&x| conv::i16::to_f32(x)),
+        SampleFormat::I32 => build!(|&x| conv::i32::to_f32(x)),
+        SampleFormat::I64 => build!(|&x| conv::i64::to_f32(x)),
+        SampleFormat::U8 => build!(|&x| conv::u8::to_f32(x)),
+        SampleFormat::U16 => build!(|&x| conv::u16::to_f32(x)),
+        SampleFormat::U32 => build!(|&x| conv::u32::to_f32(x)),
+        SampleFormat::U64 => build!(|&x| conv::u64::to_f32(x)),
+        SampleFormat::F32 => build!(|&x| x),
+        SampleFormat::F64 => build!(|&x| conv::f64::to_f32(x)),
+        sample_format => {
+            return Err(Error::StringError(format!(
+                "Unsupported sample format: {sample_format}"
+            )))
+        }
+    };
+    stream.play()?;
+    tracing::info!(keyword, "the wake word detector is listening");
+
+    let mut pending = vec![];
+    let mut last_detection: Option<Instant> = None;
+    while !stop.load(Ordering::SeqCst) {
+        let Ok(samples) = rx.recv_timeout(POLL_INTERVAL) else {
+            continue;
+        };
+        pending.extend(samples);
+        while pending.len() >= samples_per_frame {
+            let frame = pending.drain(..samples_per_frame).collect::<Vec<f32>>();
+            let Some(detection) = detector.process_samples(frame) else {
+                continue;
+            };
+            if last_detection.is_some_and(|at| at.elapsed() < COOLDOWN) {
+                continue;
+            }
+            last_detection = Some(Instant::now());
+            tracing::info!(keyword, score = detection.score, "wake word detected");
+            app.emit_all(
+                "wake-word://detected",
+                Detected {
+                    keyword: keyword.clone(),
+                    score: detection.score,
+                },
+            )?;
+        }
+    }
+    drop(stream);
+    tracing::info!(keyword, "the wake word detector stopped");
+    Ok(())
+}
+
+/// Starts listening for the keyword, replacing the keyword being listened for. `sensitivity` is between 0 and 1, and
+/// defaults to 0.5.
+#[tauri::command]
+#[tracing::instrument(skip(app, state), err)]
+pub(crate) fn enable_wake_word(
+    app: AppHandle,
+    keyword: String,
+    sensitivity: Option<f32>,
+    state: tauri::State<AppState>,
+) -> Result<(), Error> {
+    let path = wakeword_path(&app, keyword.trim())?;
+    if !path.is_file() {
+        return Err(Error::StringError(format!(
+            "The wakeword file {} does not exist",
+            path.display()
+        )));
+    }
+    let sensitivity = sensitivity.unwrap_or(0.5).clamp(0.0, 1.0);
+
+    let mut wake_word = state.audio.wake_word.lock()?;
+    if let Some(stop) = wake_word.take() {
+        stop.store(true, Ordering::SeqCst);
+    }
+    let stop = Arc::new(AtomicBool::new(false));
+    *wake_word = Some(stop.clone());
+    let keyword = keyword.trim().to_owned();
+    // `cpal::Stream` is not Send, so the detector owns a thread.
+    std::thread::spawn(move || {
+        if let Err(err) = detect(app.clone(), keyword, path, sensitivity, stop) {
+            tracing::error!("the wake word detector failed: {err}");
+            let _ = app.emit_all("wake-word://error", err.to_string());
+        }
+    });
+    Ok(())
+}
+
+/// Stops listening for the wake word and closes the input device.
+#[tauri::command]
+#[tracing::instrument(skip(state), err)]
+pub(crate) fn disable_wake_word(state: tauri::State<AppState>) -> Result<(), Error> {
+    if let Some(stop) = state.audio.wake_word.lock()?.take() {
+        stop.store(true, Ordering::SeqCst);
+    }
+    Ok(())
+}
@@ -0,0 +1,104 @@
+//! Fetches web pages for the frontend, which cannot read other origins because of CORS, e.g. to summarize a link.
+//!
+//! The main content of an HTML page is extracted by the readability algorithm, which drops navigation, ads, and other
+//! boilerplate, and converted to markdown.
+
+use crate::{Error, CL100K_BASE};
+use reqwest::Url;
+use std::time::Duration;
+
+const TIMEOUT: Duration = Duration::from_secs(30);
+/// Larger pages are rejected rather than read into memory.
+const MAX_BYTES: u64 = 5 * 1024 * 1024;
+/// Some sites reject requests without the user agent of a browser.
+const USER_AGENT: &str =
+    "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/120.0 Safari/537.36";
+
+#[derive(serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct WebPage {
+    /// The URL after redirects.
+    url: String,
+    title: String,
+    markdown: String,
+    token_count: usize,
+}
+
+/// Removes the blank lines that html2md leaves between the blocks of nested elements.
+fn collapse_blank_lines(markdown: &str) -> String {
+    let mut lines: Vec<&str> = vec![];
+    for line in markdown.lines().map(str::trim_end) {
+        if line.is_empty() && lines.last().copied().unwrap_or_default().is_empty() {
+            continue;
+        }
+        lines.push(line);
+    }
+    lines.join("\n").trim().to_owned()
+}
+
+/// Returns the title and the markdown of the main content of the page.
+fn extract(html: &str, url: &Url) -> Result<(String, String), Error> {
+    let product = readability::extractor::extract(&mut html.as_bytes(), url)
+        .map_err(|err| Error::StringError(format!("Failed to extract the content: {err}")))?;
+    let markdown = collapse_blank_lines(&html2md::parse_html(&product.content));
+    Ok((product.title.trim().to_owned(), markdown))
+}
+
+/// Downloads the page and returns its main content as markdown and its number of tokens. Plain text and markdown are
+/// returned as they are.
+#[tauri::command]
+#[tracing::instrument(err)]
+pub(crate) async fn fetch_url_content(url: String) -> Result<WebPage, Error> {
+    let url =
+        Url::parse(url.trim()).map_err(|err| Error::StringError(format!("Invalid URL: {err}")))?;
+    if url.scheme() != "http" && url.scheme() != "https" {
+        return Err(Error::StringError(format!(
+            "Unsupported URL scheme: {}",
+            url.scheme()
+        )));
+    }
+    let res = reqwest::Client::builder()
+        .user_agent(USER_AGENT)
+        .timeout(TIMEOUT)
+        .build()?
+        .get(url)
+        .send()
+        .await?;
+    if res.status() != 200 {
+        return Err(Error::StatusIsNot200(format!(
+            "{}: {}",
+            res.status(),
+            res.url()
+        )));
+    }
+    if res.content_length().is_some_and(|len| len > MAX_BYTES) {
+        return Err(Error::StringError("The page is too large".to_owned()));
+    }
+    let url = res.url().clone();
+    let content_type = res
+        .headers()
+        .get("Content-Type")
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or("text/html")
+        .to_lowercase();
+    let body = res.text().await?;
+
+    let (title, markdown) = if content_type.starts_with("text/html")
+        || content_type.starts_with("application/xhtml+xml")
+    {
+        let page_url = url.clone();
+        tauri::async_runtime::spawn_blocking(move || extract(&body, &page_url)).await??
+    } else if content_type.starts_with("text/") {
+        (String::new(), body.trim().to_owned())
+    } else {
+        return Err(Error::StringError(format!(
+            "Unsupported content type: {content_type}"
+        )));
+    };
+    Ok(WebPage {
+        url: url.to_string(),
+        title,
+        token_count: CL100K_BASE.encode_with_special_tokens(&markdown).len(),
+        markdown,
+    })
+}
@@ -0,0 +1,91 @@
+//! Web search for the `web_search` tool, with the provider configured by `webSearchProvider` ("searxng", "brave", or
+//! "bing"), `webSearchEndpoint` (the URL of the SearxNG instance), and `webSearchAPIKey`.
+
+use crate::{read_config, Error};
+use serde_json::Value;
+
+const BRAVE_ENDPOINT: &str = "https://api.search.brave.com/res/v1/web/search";
+const BING_ENDPOINT: &str = "https://api.bing.microsoft.com/v7.0/search";
+pub(crate) const MAX_RESULTS: usize = 5;
+
+#[derive(serde::Serialize)]
+pub(crate) struct SearchResult {
+    title: String,
+    url: String,
+    snippet: String,
+}
+
+/// Reads `[title, url, snippet]` from each item of the array at `pointer`.
+fn parse_results(data: &Value, pointer: &str, fields: [&str; 3]) -> Vec<SearchResult> {
+    data.pointer(pointer)
+        .and_then(Value::as_array)
+        .into_iter()
+        .flatten()
+        .take(MAX_RESULTS)
+        .map(|item| {
+            let field = |name: &str| {
+                item.get(name)
+                    .and_then(Value::as_str)
+                    .unwrap_or_default()
+                    .to_owned()
+            };
+            SearchResult {
+                title: field(fields[0]),
+                url: field(fields[1]),
+                snippet: field(fields[2]),
+            }
+        })
+        .collect()
+}
+
+pub(crate) async fn search(
+    conn: &mut sqlx::SqliteConnection,
+    query: &str,
+) -> Result<Vec<SearchResult>, Error> {
+    let provider = read_config(conn, "webSearchProvider")
+        .await?
+        .unwrap_or_else(|| "searxng".to_owned());
+    let api_key = crate::secrets::read("webSearchAPIKey")?.unwrap_or_default();
+    let client = reqwest::Client::new();
+    let request = match provider.as_str() {
+        "searxng" => {
+            let endpoint = read_config(conn, "webSearchEndpoint")
+                .await?
+                .filter(|e| !e.is_empty())
+                .ok_or_else(|| {
+                    Error::StringError("The URL of the SearxNG instance is not set".to_owned())
+                })?;
+            client
+                .get(format!("{}/search", endpoint.trim_end_matches('/')))
+                .query(&[("q", query), ("format", "json")])
+        }
+        "brave" => client
+            .get(BRAVE_ENDPOINT)
+            .query(&[("q", query)])
+            .header("Accept", "application/json")
+            .header("X-Subscription-Token", &api_key),
+        "bing" => client
+            .get(BING_ENDPOINT)
+            .query(&[("q", query)])
+            .header("Ocp-Apim-Subscription-Key", &api_key),
+        _ => {
+            return Err(Error::StringError(format!(
+                "Unknown web search provider: {provider}"
+            )))
+        }
+    };
+    let res = request.send().await?;
+    if res.status() != 200 {
+        return Err(Error::StatusIsNot200(format!(
+            "{}: {}",
+            res.status(),
+            res.text().await?
+        )));
+    }
+    let data: Value = serde_json::from_str(&res.text().await?)?;
+    Ok(match provider.as_str() {
+        "searxng" => parse_results(&data, "/results", ["title", "url", "content"]),
+        "brave" => parse_results(&data, "/web/results", ["title", "url", "description"]),
+        _ => parse_results(&data, "/webPages/value", ["name", "url", "snippet"]),
+    })
+}
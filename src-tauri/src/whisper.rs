@@ -0,0 +1,440 @@
+//! Speech-to-text with the OpenAI Whisper API.
+
+use crate::recording::RecordingFormat;
+use crate::{rate_limit, Error};
+use serde_json::Value;
+use std::path::Path;
+
+const TRANSCRIPTIONS_ENDPOINT: &str = "https://api.openai.com/v1/audio/transcriptions";
+const TRANSLATIONS_ENDPOINT: &str = "https://api.openai.com/v1/audio/translations";
+
+/// The maximum size of a file accepted by the API.
+pub(crate) const MAX_UPLOAD_BYTES: u64 = 25 * 1024 * 1024;
+
+/// Whisper resamples its input to 16 kHz, so higher sample rates only inflate the upload.
+const DOWNSAMPLED_SAMPLE_RATE: u32 = 16000;
+
+/// Averages mono samples down to `DOWNSAMPLED_SAMPLE_RATE` one at a time, so that recordings can be downsampled while they are captured.
+/// Each output sample is the mean of the input samples it covers, which also attenuates the frequencies above the new Nyquist frequency.
+pub(crate) struct Downsampler {
+    ratio: f64,
+    sum: f64,
+    count: u32,
+    samples: u64,
+    next_boundary: f64,
+}
+
+impl Downsampler {
+    pub(crate) fn new(input_sample_rate: u32) -> Self {
+        let ratio = input_sample_rate as f64 / Self::output_sample_rate(input_sample_rate) as f64;
+        Self {
+            ratio,
+            sum: 0.0,
+            count: 0,
+            samples: 0,
+            next_boundary: ratio,
+        }
+    }
+
+    /// Lower sample rates are kept as is.
+    pub(crate) fn output_sample_rate(input_sample_rate: u32) -> u32 {
+        input_sample_rate.min(DOWNSAMPLED_SAMPLE_RATE)
+    }
+
+    /// Returns a 16-bit sample when `sample` completes one.
+    pub(crate) fn push(&mut self, sample: f32) -> Option<i16> {
+        self.sum += sample as f64;
+        self.count += 1;
+        self.samples += 1;
+        if (self.samples as f64) < self.next_boundary {
+            return None;
+        }
+        self.next_boundary += self.ratio;
+        self.flush()
+    }
+
+    /// Returns the sample of the remaining input, if any.
+    pub(crate) fn flush(&mut self) -> Option<i16> {
+        if self.count == 0 {
+            return None;
+        }
+        let mean = (self.sum / self.count as f64).clamp(-1.0, 1.0);
+        (self.sum, self.count) = (0.0, 0);
+        Some((mean * i16::MAX as f64) as i16)
+    }
+}
+
+/// Converts a WAV file to mono 16-bit PCM at 16 kHz or less, and returns the sample rate.
+pub(crate) fn downsample(src: &Path, dst: &Path) -> Result<u32, Error> {
+    let mut reader = hound::WavReader::open(src)?;
+    let spec = reader.spec();
+    let sample_rate = Downsampler::output_sample_rate(spec.sample_rate);
+    let mut writer = hound::WavWriter::create(
+        dst,
+        hound::WavSpec {
+            channels: 1,
+            sample_rate,
+            bits_per_sample: 16,
+            sample_format: hound::SampleFormat::Int,
+        },
+    )?;
+    let samples: Box<dyn Iterator<Item = Result<f32, hound::Error>> + '_> = match spec.sample_format
+    {
+        hound::SampleFormat::Float => Box::new(reader.samples::<f32>()),
+        hound::SampleFormat::Int => {
+            let scale = (1i64 << (spec.bits_per_sample - 1)) as f32;
+            Box::new(
+                reader
+                    .samples::<i32>()
+                    .map(move |s| s.map(|s| s as f32 / scale)),
+            )
+        }
+    };
+
+    let channels = spec.channels as usize;
+    let mut downsampler = Downsampler::new(spec.sample_rate);
+    let mut frame = Vec::with_capacity(channels);
+    for sample in samples {
+        frame.push(sample?);
+        if frame.len() < channels {
+            continue;
+        }
+        if let Some(sample) = downsampler.push(frame.iter().sum::<f32>() / channels as f32) {
+            writer.write_sample(sample)?;
+        }
+        frame.clear();
+    }
+    if let Some(sample) = downsampler.flush() {
+        writer.write_sample(sample)?;
+    }
+    writer.finalize()?;
+    Ok(sample_rate)
+}
+
+/// The languages of Whisper, by the names in its responses.
+const LANGUAGES: [(&str, &str); 100] = [
+    ("english", "en"),
+    ("chinese", "zh"),
+    ("german", "de"),
+    ("spanish", "es"),
+    ("russian", "ru"),
+    ("korean", "ko"),
+    ("french", "fr"),
+    ("japanese", "ja"),
+    ("portuguese", "pt"),
+    ("turkish", "tr"),
+    ("polish", "pl"),
+    ("catalan", "ca"),
+    ("dutch", "nl"),
+    ("arabic", "ar"),
+    ("swedish", "sv"),
+    ("italian", "it"),
+    ("indonesian", "id"),
+    ("hindi", "hi"),
+    ("finnish", "fi"),
+    ("vietnamese", "vi"),
+    ("hebrew", "he"),
+    ("ukrainian", "uk"),
+    ("greek", "el"),
+    ("malay", "ms"),
+    ("czech", "cs"),
+    ("romanian", "ro"),
+    ("danish", "da"),
+    ("hungarian", "hu"),
+    ("tamil", "ta"),
+    ("norwegian", "no"),
+    ("thai", "th"),
+    ("urdu", "ur"),
+    ("croatian", "hr"),
+    ("bulgarian", "bg"),
+    ("lithuanian", "lt"),
+    ("latin", "la"),
+    ("maori", "mi"),
+    ("malayalam", "ml"),
+    ("welsh", "cy"),
+    ("slovak", "sk"),
+    ("telugu", "te"),
+    ("persian", "fa"),
+    ("latvian", "lv"),
+    ("bengali", "bn"),
+    ("serbian", "sr"),
+    ("azerbaijani", "az"),
+    ("slovenian", "sl"),
+    ("kannada", "kn"),
+    ("estonian", "et"),
+    ("macedonian", "mk"),
+    ("breton", "br"),
+    ("basque", "eu"),
+    ("icelandic", "is"),
+    ("armenian", "hy"),
+    ("nepali", "ne"),
+    ("mongolian", "mn"),
+    ("bosnian", "bs"),
+    ("kazakh", "kk"),
+    ("albanian", "sq"),
+    ("swahili", "sw"),
+    ("galician", "gl"),
+    ("marathi", "mr"),
+    ("punjabi", "pa"),
+    ("sinhala", "si"),
+    ("khmer", "km"),
+    ("shona", "sn"),
+    ("yoruba", "yo"),
+    ("somali", "so"),
+    ("afrikaans", "af"),
+    ("occitan", "oc"),
+    ("georgian", "ka"),
+    ("belarusian", "be"),
+    ("tajik", "tg"),
+    ("sindhi", "sd"),
+    ("gujarati", "gu"),
+    ("amharic", "am"),
+    ("yiddish", "yi"),
+    ("lao", "lo"),
+    ("uzbek", "uz"),
+    ("faroese", "fo"),
+    ("haitian creole", "ht"),
+    ("pashto", "ps"),
+    ("turkmen", "tk"),
+    ("nynorsk", "nn"),
+    ("maltese", "mt"),
+    ("sanskrit", "sa"),
+    ("luxembourgish", "lb"),
+    ("myanmar", "my"),
+    ("tibetan", "bo"),
+    ("tagalog", "tl"),
+    ("malagasy", "mg"),
+    ("assamese", "as"),
+    ("tatar", "tt"),
+    ("hawaiian", "haw"),
+    ("lingala", "ln"),
+    ("hausa", "ha"),
+    ("bashkir", "ba"),
+    ("javanese", "jw"),
+    ("sundanese", "su"),
+    ("cantonese", "yue"),
+];
+
+/// The ISO-639-1 code of a language in a response, which is its English name, e.g. "english".
+fn language_code(name: &str) -> Option<String> {
+    let name = name.trim().to_lowercase();
+    LANGUAGES
+        .iter()
+        .find(|&&(n, code)| n == name || code == name)
+        .map(|&(_, code)| code.to_owned())
+}
+
+pub(crate) struct Transcript {
+    pub(crate) text: String,
+    /// The language detected by Whisper as an ISO-639-1 code, if the language was not given.
+    pub(crate) language: Option<String>,
+}
+
+/// Transcribes a recording in one of the `RecordingFormat`s. A WAV file is downsampled first if it exceeds the upload limit.
+/// The file is streamed instead of being read into memory. `prompt` is text in the style of the transcript, e.g. the
+/// spelling of the names in it, which Whisper continues from; only its last 224 tokens are used.
+pub(crate) async fn transcribe(
+    path: &Path,
+    format: RecordingFormat,
+    openai_key: &str,
+    language: &str, // "" to auto-detect
+    prompt: &str,   // "" for none
+) -> Result<Transcript, Error> {
+    // Only the verbose response has the detected language.
+    let response_format = if language.is_empty() {
+        "verbose_json"
+    } else {
+        "json"
+    };
+    let data = recording_request(
+        TRANSCRIPTIONS_ENDPOINT,
+        path,
+        format,
+        openai_key,
+        language,
+        prompt,
+        response_format,
+    )
+    .await?;
+    let detected = data
+        .get("language")
+        .and_then(Value::as_str)
+        .and_then(language_code);
+    Ok(Transcript {
+        text: text(data)?,
+        language: detected,
+    })
+}
+
+/// Translates a recording in any language into English text, like `transcribe`. The prompt should be in English.
+pub(crate) async fn translate(
+    path: &Path,
+    format: RecordingFormat,
+    openai_key: &str,
+    prompt: &str, // "" for none
+) -> Result<String, Error> {
+    text(
+        recording_request(
+            TRANSLATIONS_ENDPOINT,
+            path,
+            format,
+            openai_key,
+            "",
+            prompt,
+            "json",
+        )
+        .await?,
+    )
+}
+
+async fn recording_request(
+    endpoint: &str,
+    path: &Path,
+    format: RecordingFormat,
+    openai_key: &str,
+    language: &str,
+    prompt: &str,
+    response_format: &str,
+) -> Result<Value, Error> {
+    let downsampled;
+    let mut path = path;
+    if format == RecordingFormat::Wav && tokio::fs::metadata(path).await?.len() > MAX_UPLOAD_BYTES {
+        downsampled = tempfile::Builder::new().suffix(".wav").tempfile()?;
+        let (src, dst) = (path.to_owned(), downsampled.path().to_owned());
+        tokio::task::spawn_blocking(move || downsample(&src, &dst)).await??;
+        path = downsampled.path();
+    }
+    request(
+        endpoint,
+        path,
+        &format!("audio.{}", format.extension()),
+        format.mime(),
+        openai_key,
+        language,
+        prompt,
+        response_format,
+    )
+    .await
+}
+
+fn text(data: Value) -> Result<String, Error> {
+    Ok(data
+        .get("text")
+        .and_then(Value::as_str)
+        .ok_or_else(|| Error::StringError(format!("Unexpected response: {data}")))?
+        .to_owned())
+}
+
+/// Transcribes an audio file in a format supported by the API as is.
+pub(crate) async fn upload(
+    path: &Path,
+    file_name: &str,
+    mime: &str,
+    openai_key: &str,
+    language: &str, // "" to auto-detect
+) -> Result<String, Error> {
+    text(
+        request(
+            TRANSCRIPTIONS_ENDPOINT,
+            path,
+            file_name,
+            mime,
+            openai_key,
+            language,
+            "",
+            "json",
+        )
+        .await?,
+    )
+}
+
+/// A part of a transcript with its time in seconds.
+pub(crate) struct Segment {
+    pub(crate) start: f64,
+    pub(crate) end: f64,
+    pub(crate) text: String,
+}
+
+/// Transcribes an audio file like `upload`, and returns the transcript in segments with timestamps.
+pub(crate) async fn upload_segments(
+    path: &Path,
+    file_name: &str,
+    mime: &str,
+    openai_key: &str,
+    language: &str, // "" to auto-detect
+) -> Result<Vec<Segment>, Error> {
+    let data = request(
+        path,
+        file_name,
+        mime,
+        openai_key,
+        language,
+        "",
+        "verbose_json",
+    )
+    .await?;
+    data.get("segments")
+        .and_then(Value::as_array)
+        .ok_or_else(|| Error::StringError(format!("Unexpected response: {data}")))?
+        .iter()
+        .map(|segment| {
+            Some(Segment {
+                start: segment.get("start")?.as_f64()?,
+                end: segment.get("end")?.as_f64()?,
+                text: segment.get("text")?.as_str()?.trim().to_owned(),
+            })
+            .ok_or_else(|| Error::StringError(format!("Unexpected segment: {segment}")))
+        })
+        .collect()
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn request(
+    endpoint: &str,
+    path: &Path,
+    file_name: &str,
+    mime: &str,
+    openai_key: &str,
+    language: &str,
+    prompt: &str,
+    response_format: &str,
+) -> Result<Value, Error> {
+    let file = tokio::fs::File::open(path).await?;
+    let len = file.metadata().await?.len();
+    if len > MAX_UPLOAD_BYTES {
+        return Err(Error::StringError(format!(
+            "The recording is too long to transcribe ({len} bytes, the limit is {MAX_UPLOAD_BYTES} bytes)"
+        )));
+    }
+
+    rate_limit::acquire("whisper", 0, None).await?;
+    let mut form = reqwest::multipart::Form::new()
+        .part(
+            "file",
+            reqwest::multipart::Part::stream_with_length(file, len)
+                .file_name(file_name.to_owned())
+                .mime_str(mime)?,
+        )
+        .text("model", "whisper-1")
+        .text("response_format", response_format.to_owned());
+    if !language.is_empty() {
+        form = form.text("language", language.to_owned());
+    }
+    if !prompt.is_empty() {
+        form = form.text("prompt", prompt.to_owned());
+    }
+    let res = reqwest::Client::new()
+        .post(endpoint)
+        .header("Authorization", format!("Bearer {openai_key}"))
+        .multipart(form)
+        .send()
+        .await?;
+    if res.status() != 200 {
+        return Err(Error::StringError(format!(
+            "{}: {}",
+            res.status(),
+            res.text().await?
+        )));
+    }
+    Ok(serde_json::from_str(&res.text().await?)?)
+}
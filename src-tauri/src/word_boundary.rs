@@ -0,0 +1,233 @@
+//! Azure text-to-speech over the WebSocket protocol of the Speech SDK, which also returns the timing of each word.
+//!
+//! While the audio is played, `tts://word-boundary` events ({ messageId, text, textOffset, audioOffsetMs, durationMs }) are
+//! emitted when each word starts, so the frontend can highlight the word being spoken. `textOffset` is the position of the
+//! word in the SSML, if Azure reports it.
+
+use crate::{audio_log, conversation, lexicon};
+use crate::{loudness, play_audio, AppState, Error};
+use futures_util::{SinkExt, StreamExt};
+use serde_json::{json, Value};
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::Message;
+
+/// Offsets and durations are in ticks of 100 ns.
+const TICKS_PER_MS: u64 = 10_000;
+
+#[derive(serde::Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+struct WordBoundary {
+    message_id: Option<i64>,
+    text: String,
+    text_offset: Option<u64>,
+    audio_offset_ms: u64,
+    duration_ms: u64,
+}
+
+/// A random-enough 32-digit hex id for the `X-ConnectionId` and `X-RequestId` headers.
+fn request_id() -> String {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos();
+    format!("{:032x}", nanos ^ ((std::process::id() as u128) << 96))
+}
+
+/// The current time in ISO 8601, e.g. "2023-05-01T12:34:56.789Z".
+fn timestamp() -> String {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    let (days, secs) = ((now.as_secs() / 86400) as i64, now.as_secs() % 86400);
+    // Howard Hinnant's days_from_civil inverse
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}.{:03}Z",
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60,
+        now.subsec_millis()
+    )
+}
+
+/// Splits a text message into its `Path` header and its body.
+fn parse_text_message(message: &str) -> (Option<&str>, &str) {
+    let (headers, body) = message.split_once("\r\n\r\n").unwrap_or((message, ""));
+    let path = headers
+        .lines()
+        .find_map(|line| line.strip_prefix("Path:"))
+        .map(str::trim);
+    (path, body)
+}
+
+/// Synthesizes the SSML and returns the MP3 audio and the word boundaries.
+async fn synthesize(
+    region: &str,
+    resource_key: &str,
+    ssml: &str,
+    message_id: Option<i64>,
+) -> Result<(Vec<u8>, Vec<WordBoundary>), Error> {
+    let mut request =
+        format!("wss://{region}.tts.speech.microsoft.com/cognitiveservices/websocket/v1")
+            .into_client_request()?;
+    let headers = request.headers_mut();
+    headers.insert(
+        "Ocp-Apim-Subscription-Key",
+        resource_key
+            .parse()
+            .map_err(|_| Error::StringError("Invalid resource key".to_owned()))?,
+    );
+    headers.insert("X-ConnectionId", request_id().parse().unwrap());
+    let (ws, _) = tokio_tungstenite::connect_async(request).await?;
+    let (mut write, mut read) = ws.split();
+
+    let config = json!({
+        "context": {
+            "synthesis": {
+                "audio": {
+                    "metadataOptions": { "wordBoundaryEnabled": "true", "sentenceBoundaryEnabled": "false" },
+                    "outputFormat": "audio-48khz-96kbitrate-mono-mp3",
+                }
+            }
+        }
+    });
+    write
+        .send(Message::Text(format!(
+            "X-Timestamp:{}\r\nPath:speech.config\r\nContent-Type:application/json; charset=utf-8\r\n\r\n{config}",
+            timestamp()
+        )))
+        .await?;
+    write
+        .send(Message::Text(format!(
+            "X-RequestId:{}\r\nContent-Type:application/ssml+xml\r\nX-Timestamp:{}\r\nPath:ssml\r\n\r\n{ssml}",
+            request_id(),
+            timestamp()
+        )))
+        .await?;
+
+    let mut audio = vec![];
+    let mut boundaries = vec![];
+    while let Some(message) = read.next().await {
+        match message? {
+            Message::Binary(data) => {
+                // A big-endian 16-bit header length, the headers, and the audio.
+                if data.len() < 2 {
+                    continue;
+                }
+                let header_len = u16::from_be_bytes([data[0], data[1]]) as usize;
+                let Some(headers) = data.get(2..2 + header_len) else {
+                    continue;
+                };
+                if String::from_utf8_lossy(headers).contains("Path:audio") {
+                    audio.extend_from_slice(&data[2 + header_len..]);
+                }
+            }
+            Message::Text(message) => match parse_text_message(&message) {
+                (Some("audio.metadata"), body) => {
+                    let data: Value = serde_json::from_str(body)?;
+                    for metadata in data
+                        .get("Metadata")
+                        .and_then(Value::as_array)
+                        .into_iter()
+                        .flatten()
+                    {
+                        if metadata.get("Type").and_then(Value::as_str) != Some("WordBoundary") {
+                            continue;
+                        }
+                        let ticks = |key: &str| {
+                            metadata
+                                .pointer(&format!("/Data/{key}"))
+                                .and_then(Value::as_u64)
+                                .unwrap_or_default()
+                        };
+                        boundaries.push(WordBoundary {
+                            message_id,
+                            text: metadata
+                                .pointer("/Data/text/Text")
+                                .and_then(Value::as_str)
+                                .unwrap_or_default()
+                                .to_owned(),
+                            text_offset: metadata
+                                .pointer("/Data/text/TextOffset")
+                                .and_then(Value::as_u64),
+                            audio_offset_ms: ticks("Offset") / TICKS_PER_MS,
+                            duration_ms: ticks("Duration") / TICKS_PER_MS,
+                        });
+                    }
+                }
+                (Some("turn.end"), _) => break,
+                _ => {}
+            },
+            Message::Close(frame) => {
+                return Err(Error::StringError(format!(
+                    "The connection was closed: {}",
+                    frame.map(|f| f.reason.into_owned()).unwrap_or_default()
+                )))
+            }
+            _ => {}
+        }
+    }
+    let _ = write.close().await;
+    Ok((audio, boundaries))
+}
+
+/// Speaks the SSML like `speak_azure`, and emits `tts://word-boundary` events during the playback. The audio is not cached
+/// because the word boundaries are only returned when it is synthesized.
+#[tauri::command]
+#[tracing::instrument(skip(window, resource_key, ssml, state), err)]
+pub(crate) async fn speak_azure_with_word_boundaries(
+    window: tauri::Window,
+    message_id: Option<i64>,
+    region: String,
+    resource_key: String,
+    ssml: String,
+    state: tauri::State<'_, AppState>,
+) -> Result<(), Error> {
+    let precedence = state.audio.playback_counter.fetch_add(1, Ordering::SeqCst) + 1;
+    let (ssml, title) = {
+        let mut conn = state.db_pool.acquire().await?;
+        let title = match message_id {
+            Some(message_id) => conversation::name_of(&mut conn, message_id).await?,
+            None => None,
+        };
+        (lexicon::apply_to_ssml(&mut conn, &ssml).await?, title)
+    };
+    let (audio, boundaries) = synthesize(&region, &resource_key, &ssml, message_id).await?;
+    let audio = tokio::task::spawn_blocking(move || loudness::normalize_mp3(audio)).await?;
+    if state.audio.playback_counter.load(Ordering::SeqCst) != precedence {
+        return Ok(());
+    }
+    // No word is highlighted when the speech is written to the audio log.
+    if let Some(path) = audio_log::output_path(&state, message_id).await? {
+        return audio_log::write(path, vec![audio]).await;
+    }
+
+    let emit = async {
+        let start = Instant::now();
+        for boundary in boundaries {
+            tokio::time::sleep_until(
+                (start + Duration::from_millis(boundary.audio_offset_ms)).into(),
+            )
+            .await;
+            if state.audio.playback_counter.load(Ordering::SeqCst) != precedence {
+                break;
+            }
+            let _ = window.emit("tts://word-boundary", boundary);
+        }
+    };
+    let ((), played) = tokio::join!(
+        emit,
+        play_audio(state.audio.clone(), audio, precedence, title)
+    );
+    played
+}
@@ -1,4 +1,4 @@
-import { clipboard, invoke as _invoke } from "@tauri-apps/api"
+import { clipboard, event, invoke as _invoke } from "@tauri-apps/api"
 import { open, Command } from '@tauri-apps/api/shell'
 import { create } from "zustand"
 // @ts-ignore
@@ -15,28 +15,282 @@ import findParentsSQL from "./find_parents.sql?raw"
 import "element.scrollintoviewifneeded-polyfill"
 import Toastify from "toastify-js"
 
+export type AzureVoiceInfo = {
+    Name: string  // 'Microsoft Server Speech Text to Speech Voice (af-ZA, AdriNeural)'
+    DisplayName: string  // 'Adri'
+    LocalName: string  // 'Adri'
+    ShortName: string  // 'af-ZA-AdriNeural'
+    Gender: string  // 'Female'
+    Locale: string  // 'af-ZA'
+    LocaleName: string  // 'Afrikaans (South Africa)'
+    SampleRateHertz: string  // '48000'
+    VoiceType: string  // 'Neural'
+    Status: string  // 'GA'
+    WordsPerMinute: string  // '147'
+}
+
+/** A deployment of an Azure OpenAI resource. */
+export type AzureDeployment = {
+    id: string  // the deployment name in the endpoint
+    model: string  // 'gpt-35-turbo'
+    status: string  // 'succeeded'
+}
+
+/** An OpenAI-compatible provider, and how its API differs from OpenAI's. */
+export type ProviderPreset = {
+    id: string
+    name: string
+    baseUrl: string
+    auth: "bearer" | "apiKeyHeader"
+    streamUsage: "streamOptions" | "always" | "xGroq"
+    unsupportedFields: string[]
+    renamedFields: [string, string][]
+    keysUrl: string
+}
+
+/** The llama.cpp server or llamafile launched by the app. */
+export type LocalModelStatus = {
+    state: "stopped" | "starting" | "ready" | "failed"
+    port: number | null
+    pid: number | null
+    modelPath: string | null
+    restarts: number
+    error: string | null
+    output: string[]  // the last lines of stdout and stderr
+}
+
+/** Where the conversations are synced. The secrets are in SyncCredentials. */
+export type SyncBackend = { type: "webdav", url: string } | { type: "s3", endpoint: string, region: string, bucket: string }
+export type SyncCredentials = { username?: string, password?: string, accessKeyId?: string, secretAccessKey?: string, passphrase: string }
+/** The result of the last sync. `pulled` is the number of messages inserted or updated. */
+export type SyncStatus = { configured: boolean, lastSyncedAt: number | null, lastError: string | null, pulled: number, pushed: number }
+/** A set of conversations, settings, and API keys, stored in its own database file. */
+export type Profile = { id: string, name: string, databaseFile: string }
+export type Profiles = { active: string, profiles: Profile[] }
+/** Whether a passphrase decrypts the file pushed by the other computers. `noRemoteData` if none has pushed it yet. */
+export type PassphraseCheck = "valid" | "invalid" | "noRemoteData"
+
+/** `model-download://progress`. `downloaded` includes the part downloaded before a resume. */
+export type ModelDownloadProgress = { id: number, fileName: string, downloaded: number, total: number | null }
+
+/** What this computer can run locally. The memory is in bytes. */
+export type InferenceCapabilities = {
+    totalMemory: number
+    availableMemory: number
+    cpu: { brand: string, physicalCores: number | null, logicalCores: number, features: string[] }
+    gpus: { name: string, memory: number, unified: boolean }[]
+    recommendations: { kind: "whisper" | "llm", size: string, requiredMemory: number, fit: "gpu" | "cpu" | "slowCpu" | "tooLarge" }[]
+}
+
+/** The credits of an OpenRouter account, in US dollars. */
+export type OpenRouterCredits = {
+    totalCredits: number
+    totalUsage: number
+    remaining: number
+}
+
+/** A chat model of a provider. */
+export type ModelInfo = {
+    id: string
+    contextWindow: number  // tokens of the prompt and the response
+    maxOutputTokens: number | null
+}
+
+export type EarconEvent = "test" | "focusInput" | "waitingTextCompletion"
+
 type ChatMLMessage = { role: "assistant" | "user" | "system", name?: string, content: string }
 
+/** The payload of `chat-completion://invalid-json`, emitted when a JSON response is invalid. `repaired` is null if it could not be repaired. */
+export type InvalidJson = { requestId: number, error: string, attempts: { repair: string, error: string | null }[], repaired: string | null }
+
+/** The body of `start_chat_completion`, validated by the backend. Either `messages` or `prompt` is required. */
+export type CompletionRequest = {
+    model?: string
+    /** The models that OpenRouter falls back to, in order */
+    models?: string[]
+    messages?: (ChatMLMessage & { images?: string[] })[]
+    /** The ChatML prompt of the legacy Azure completions API */
+    prompt?: string
+    stop?: string[]
+    temperature?: number
+    topP?: number
+    presencePenalty?: number
+    frequencyPenalty?: number
+    responseFormat?: { type: "text" } | { type: "json_object" } | { type: "json_schema", json_schema: { name: string, schema?: unknown, strict?: boolean } }
+    seed?: number
+    tools?: { type: "function", function: { name: string, description?: string, parameters?: unknown } }[]
+    maxTokens?: number
+    /** OpenAI only */
+    includeUsage?: boolean
+}
+
 export const invoke = _invoke as any as {
     (cmd: "sound_test"): Promise<void>
     (cmd: "sound_focus_input"): Promise<void>
     (cmd: "sound_waiting_text_completion"): Promise<void>
+    (cmd: "set_earcon", args: { event: EarconEvent, sound: string }): Promise<void>
+    (cmd: "list_earcons"): Promise<{ event: EarconEvent, sound: string }[]>
+    (cmd: "list_builtin_earcons"): Promise<string[]>
     (cmd: "speak_azure", args: { messageId: number | null, region: string, resourceKey: string, ssml: string, beepVolume: number, preFetch: boolean, noCache: boolean }): Promise<string>
+    (cmd: "list_azure_voices", args: { region: string, resourceKey: string, refresh?: boolean }): Promise<AzureVoiceInfo[]>
+    (cmd: "list_azure_deployments", args: { endpoint: string, key: string, apiKeyAuthentication: boolean }): Promise<AzureDeployment[]>
+    (cmd: "list_models", args: { provider: "openai" | "ollama" | "gemini" | "openrouter", key: string }): Promise<ModelInfo[]>
+    (cmd: "get_openrouter_credits", args: { key: string }): Promise<OpenRouterCredits>
+    (cmd: "list_provider_presets"): Promise<ProviderPreset[]>
+    (cmd: "launch_local_model", args: { binaryPath: string, modelPath?: string, port?: number }): Promise<void>
+    (cmd: "stop_local_model"): Promise<void>
+    (cmd: "local_model_status"): Promise<LocalModelStatus>
+    (cmd: "probe_inference_capabilities"): Promise<InferenceCapabilities>
+    (cmd: "start_model_download", args: { url: string, sha256: string }): Promise<number>
+    (cmd: "cancel_model_download", args: { id: number }): Promise<void>
+    (cmd: "list_downloaded_models"): Promise<{ fileName: string, path: string, size: number }[]>
+    (cmd: "configure_sync", args: { backend: SyncBackend | null, credentials: SyncCredentials | null }): Promise<SyncStatus>
+    (cmd: "sync_now"): Promise<SyncStatus>
+    (cmd: "get_sync_status"): Promise<[SyncStatus, SyncBackend | null]>
+    (cmd: "verify_sync_passphrase", args: { backend: SyncBackend, credentials: SyncCredentials }): Promise<PassphraseCheck>
+    (cmd: "list_profiles"): Promise<Profiles>
+    (cmd: "create_profile", args: { name: string }): Promise<Profile>
+    (cmd: "switch_profile", args: { id: string }): Promise<Profile>
+    (cmd: "get_setting", args: { key: string }): Promise<number | string | null>
+    (cmd: "set_setting", args: { key: string, value: unknown }): Promise<void>
+    (cmd: "get_context_window", args: { model: string }): Promise<number>
+    (cmd: "preview_azure_voice", args: { region: string, resourceKey: string, voice: string, sampleText?: string }): Promise<void>
+    (cmd: "preprocess_speech_text", args: { text: string, lang: string }): Promise<string>
+    (cmd: "add_speech_rule", args: { pattern: string, replacement: string }): Promise<void>
+    (cmd: "list_speech_rules"): Promise<{ pattern: string, replacement: string }[]>
+    (cmd: "delete_speech_rule", args: { pattern: string }): Promise<void>
+    (cmd: "build_ssml", args: { text: string, voice: string, lang?: string, rate?: number, pitch?: number, style?: string }): Promise<string>
+    (cmd: "speak_azure_text", args: { messageId: number | null, region: string, resourceKey: string, voice: string, lang: string, content: string, preFetch: boolean, noCache: boolean }): Promise<void>
+    (cmd: "get_conversation_settings", args: { messageId: number }): Promise<ConversationSettings>
+    (cmd: "set_conversation_settings", args: { conversationId: number, settings: ConversationSettings }): Promise<void>
+    (cmd: "speak_azure_sentences", args: { messageId: number | null, region: string, resourceKey: string, voice: string, lang: string, content: string, noCache: boolean }): Promise<void>
+    (cmd: "speak_azure_with_word_boundaries", args: { messageId: number | null, region: string, resourceKey: string, ssml: string }): Promise<void>
+    (cmd: "add_lexicon_entry", args: { word: string, ipaOrAlias: string }): Promise<void>
+    (cmd: "list_lexicon_entries"): Promise<{ word: string, pronunciation: string }[]>
+    (cmd: "delete_lexicon_entry", args: { word: string }): Promise<void>
+    (cmd: "add_vocabulary_term", args: { term: string }): Promise<void>
+    (cmd: "list_vocabulary_terms"): Promise<string[]>
+    (cmd: "delete_vocabulary_term", args: { term: string }): Promise<void>
     (cmd: "count_tokens", args: { content: string }): Promise<number>
+    (cmd: "build_request_messages", args: { conversationId: number, model: string, maxTokens?: number }): Promise<{ messages: { role: string, name?: string, content: string }[], promptTokens: number, dropped: number, truncated: boolean, summarized: boolean, pinned: number }>
+    (cmd: "summarize_conversation", args: { conversationId: number, upToMessageId: number }): Promise<string>
+    (cmd: "save_prompt_template", args: { id?: number, name: string, content: string }): Promise<number>
+    (cmd: "list_prompt_templates"): Promise<{ id: number, name: string, content: string, variables: string[], modifiedAt: string }[]>
+    (cmd: "delete_prompt_template", args: { id: number }): Promise<void>
+    (cmd: "render_prompt_template", args: { id: number, vars: Record<string, string> }): Promise<string>
     (cmd: "speak_pico2wave", args: { content: string, lang: string }): Promise<void>
     (cmd: "get_input_loudness"): Promise<number>
-    (cmd: "start_listening", args: { openaiKey: string, language: string }): Promise<string>
+    (cmd: "start_listening", args: { openaiKey: string, language: string, autoGain?: boolean, maxGainDb?: number, trimSilence?: boolean, maxPauseMs?: number, contextHint?: string, translate?: boolean }): Promise<{ text: string, original: string | null, language: string | null }>
+    (cmd: "start_listening_azure", args: { region: string, resourceKey: string, language: string }): Promise<string>
+    (cmd: "start_listening_deepgram", args: { apiKey: string, language: string }): Promise<string>
     (cmd: "stop_listening"): Promise<void>
+    (cmd: "enable_wake_word", args: { keyword: string, sensitivity?: number }): Promise<void>
+    (cmd: "disable_wake_word"): Promise<void>
     (cmd: "cancel_listening"): Promise<void>
-    (cmd: "start_chat_completion", args: { requestId: number, secretKey: string, body: string, endpoint: string, apiKeyAuthentication: boolean }): Promise<undefined>
+    (cmd: "parse_dictation_commands", args: { transcript: string, language: string }): Promise<DictationEdit[]>
+    (cmd: "start_realtime_session", args: { apiKey: string, model?: string, instructions?: string, voice?: string }): Promise<void>
+    (cmd: "end_realtime_session"): Promise<void>
+    (cmd: "start_chat_completion", args: { requestId: number, secretKey: string, request: CompletionRequest, endpoint: string, apiKeyAuthentication: boolean, provider?: "openai" | "ollama" | "gemini" | "openrouter", maxRetries?: number, connectTimeoutSecs?: number, stallTimeoutSecs?: number, headers?: Record<string, string>, query?: Record<string, string>, conversationId?: number, model?: string, tools?: string[], mcp?: boolean, queueWhenOffline?: boolean, stopSequences?: string[], maxOutputTokens?: number, preset?: string }): Promise<undefined>
+    (cmd: "get_usage_stats", args: { range?: { from?: string, to?: string } }): Promise<{
+        daily: { day: string, provider: string, model: string, promptTokens: number, completionTokens: number, requests: number, estimatedRequests: number }[]
+        conversations: { conversationId: number, name: string | null, provider: string, model: string, promptTokens: number, completionTokens: number, requests: number, estimatedRequests: number }[]
+    }>
+    (cmd: "set_azure_ad_credential", args: { credential: AzureAdCredential | null }): Promise<void>
+    (cmd: "get_azure_ad_credential"): Promise<{ type: "clientSecret", tenantId: string, clientId: string } | { type: "azureCli" } | null>
     (cmd: "stop_all_chat_completions"): Promise<void>
-    (cmd: "get_chat_completion", args: { requestId: number }): Promise<string[]>
+    (cmd: "open_chat_window", args: { conversationId: number }): Promise<void>
+    (cmd: "start_chat_completion_multi", args: { requestId: number, targets: ProviderTarget[], body: string }): Promise<{ requestId: number, model: string, error: string | null }[]>
+    (cmd: "regenerate_with_variations", args: { requestId: number, body: string, temperatures: number[] }): Promise<{ temperature: number, content: string | null, error: string | null }[]>
+    (cmd: "get_chat_completion", args: { requestId: number }): Promise<CompletionChunk[]>
+    (cmd: "list_builtin_tools"): Promise<{ type: "function", function: { name: string, description: string, parameters: unknown } }[]>
+    (cmd: "answer_tool_approval", args: { id: number, approved: boolean }): Promise<void>
+    (cmd: "add_mcp_server", args: { commandOrUrl: string }): Promise<McpServer>
+    (cmd: "list_mcp_servers"): Promise<McpServer[]>
+    (cmd: "remove_mcp_server", args: { id: number }): Promise<void>
+    (cmd: "read_mcp_resource", args: { serverId: number, uri: string }): Promise<string>
+    (cmd: "get_chat_tool_calls", args: { requestId: number }): Promise<{ id: string, type: string, function: { name: string, arguments: string } }[]>
     (cmd: "stop_audio"): Promise<void>
+    (cmd: "pause_audio"): Promise<void>
+    (cmd: "resume_audio"): Promise<void>
+    (cmd: "open_mini_player"): Promise<void>
+    (cmd: "set_clipboard_watch", args: { enabled: boolean }): Promise<void>
+    (cmd: "set_rate_limits", args: { limits: Record<string, { rpm?: number, tpm?: number }> }): Promise<void>
+    (cmd: "extract_file_text", args: { path: string }): Promise<ExtractedText>
+    (cmd: "fetch_url_content", args: { url: string }): Promise<WebPage>
+    (cmd: "index_documents", args: { paths: string[], provider: EmbeddingProvider }): Promise<{ documents: IndexedDocument[], failures: { path: string, message: string }[] }>
+    (cmd: "retrieve", args: { query: string, k?: number, provider: EmbeddingProvider }): Promise<{ path: string, chunkIndex: number, content: string, score: number }[]>
+    (cmd: "list_indexed_documents"): Promise<IndexedDocument[]>
+    (cmd: "remove_indexed_document", args: { id: number }): Promise<void>
+    (cmd: "prepare_image_attachment", args: { path: string, maxDimension?: number }): Promise<ImageAttachment>
+    (cmd: "capture_screenshot", args: { target: CaptureTarget }): Promise<Screenshot>
+    (cmd: "transcribe_file", args: { path: string, language: string, provider: { type: "openai", openaiKey: string } | { type: "azure", region: string, resourceKey: string }, diarize?: boolean, maxSpeakers?: number }): Promise<string>
+    (cmd: "ask_about_clipboard", args: { templateId: number | null }): Promise<number>
+    (cmd: "set_playback_speed", args: { speed: number }): Promise<void>
+    (cmd: "seek_audio", args: { ms: number }): Promise<void>
+    (cmd: "skip_sentence_forward"): Promise<void>
+    (cmd: "skip_sentence_back"): Promise<void>
+    (cmd: "set_audio_output_to_file", args: { enabled: boolean }): Promise<void>
+    (cmd: "list_audio_log", args: { conversationId: number | null }): Promise<AudioLogEntry[]>
+    (cmd: "play_audio_log", args: { path: string }): Promise<void>
+    (cmd: "set_beep_ducking", args: { volume: number }): Promise<void>
+    (cmd: "set_barge_in", args: { policy: "stop" | "duck" | "voice" }): Promise<void>
     (cmd: "count_tokens", args: { model: string, messages: ChatMLMessage[] }): Promise<number>
+    (cmd: "is_token_count_estimate", args: { model: string }): Promise<boolean>
+    (cmd: "list_ollama_models", args: { baseUrl?: string }): Promise<{ name: string, size: number, modified_at: string, digest: string }[]>
+    (cmd: "store_secret", args: { name: string, value: string }): Promise<void>
+    (cmd: "get_secret", args: { name: string }): Promise<string | null>
+    (cmd: "delete_secret", args: { name: string }): Promise<void>
+    (cmd: "create_conversation", args: { name?: string }): Promise<number>
+    (cmd: "append_message", args: { parent: number, role: string, status: number, content: string, model?: string }): Promise<number>
+    (cmd: "list_conversations", args: { page: number, pageSize?: number, filter?: string, tag?: string, folderId?: number }): Promise<ConversationSummary[]>
+    (cmd: "list_by_tag", args: { tag: string }): Promise<ConversationSummary[]>
+    (cmd: "create_folder", args: { name: string, parent?: number }): Promise<number>
+    (cmd: "rename_folder", args: { folderId: number, name: string }): Promise<void>
+    (cmd: "delete_folder", args: { folderId: number }): Promise<void>
+    (cmd: "list_folders"): Promise<{ id: number, name: string, parent: number | null, conversations: number }[]>
+    (cmd: "move_to_folder", args: { conversationId: number, folderId?: number }): Promise<void>
+    (cmd: "tag_conversation", args: { conversationId: number, tag: string }): Promise<void>
+    (cmd: "untag_conversation", args: { conversationId: number, tag: string }): Promise<void>
+    (cmd: "list_tags"): Promise<{ tag: string, conversations: number }[]>
+    (cmd: "schedule_prompt", args: { cron: string, templateId: number, conversationId?: number, speak?: boolean }): Promise<number>
+    (cmd: "list_scheduled_prompts"): Promise<{ id: number, cron: string, templateId: number, templateName: string, conversationId: number | null, speak: boolean, lastRunAt: string | null }[]>
+    (cmd: "delete_scheduled_prompt", args: { id: number }): Promise<void>
+    (cmd: "delete_conversation", args: { conversationId: number }): Promise<void>
+    (cmd: "search_messages", args: { query: string, limit: number, offset: number }): Promise<{ messageId: number, conversationId: number, snippet: string, rank: number }[]>
+    (cmd: "branch_from_message", args: { messageId: number }): Promise<number>
+    (cmd: "list_branches", args: { conversationId: number }): Promise<{ leafId: number, length: number, preview: string, createdAt: string, selected: boolean }[]>
+    (cmd: "switch_branch", args: { leafId: number }): Promise<number[]>
+    (cmd: "pin_message", args: { messageId: number }): Promise<void>
+    (cmd: "unpin_message", args: { messageId: number }): Promise<void>
+    (cmd: "list_pinned", args: { conversationId?: number }): Promise<{ messageId: number, conversationId: number, role: string, content: string, pinnedAt: string }[]>
+    (cmd: "semantic_search_messages", args: { query: string, k?: number, provider: EmbeddingProvider }): Promise<{ messageId: number, conversationId: number, snippet: string, score: number }[]>
+    (cmd: "get_tts_cache_stats"): Promise<{ entries: number, bytes: number, maxBytes: number }>
+    (cmd: "clear_tts_cache"): Promise<void>
+    (cmd: "export_conversation", args: { conversationId: number, format: "markdown" | "json" | "html", path?: string }): Promise<string | null>
+    (cmd: "export_message_audio", args: { messageId: number, path?: string, region: string, resourceKey: string, voice: string, lang: string }): Promise<string | null>
+    (cmd: "import_openai_export", args: { zipPath: string }): Promise<number>
+    (cmd: "is_database_locked"): Promise<boolean>
+    (cmd: "unlock_database", args: { passphrase: string }): Promise<void>
+    (cmd: "set_database_passphrase", args: { passphrase: string | null }): Promise<void>
+    (cmd: "get_database_passphrase"): Promise<string | null>
+    (cmd: "set_minimize_to_tray", args: { enabled: boolean }): Promise<void>
+    (cmd: "quick_ask", args: { prompt: string }): Promise<string>
+    (cmd: "check_for_updates"): Promise<{ available: boolean, currentVersion: string, latestVersion: string, notes: string | null, date: string | null }>
+    (cmd: "install_update"): Promise<void>
+    (cmd: "get_recent_logs", args: { lines: number }): Promise<string[]>
+    (cmd: "save_last_recording", args: { path: string }): Promise<string>
+    (cmd: "set_mic_processing", args: { options: { noiseSuppression?: boolean, highPassHz?: number | null, noiseGateThreshold?: number | null } }): Promise<void>
 }
 
 class Canceled extends Error { }
 
+/** The language detected in the last dictation if the speech should be in it rather than in `lang`, e.g. "ja" for "en-US". */
+const dictatedLanguageOtherThan = (lang: string) => {
+    const language = useStore.getState().dictatedLanguage
+    if (!useConfigStore.getState().ttsMatchDictatedLanguage || !language || lang.toLowerCase().split("-")[0] === language) { return null }
+    return language
+}
+
 window.addEventListener("unhandledrejection", (err) => {
     if (err.reason instanceof Canceled) { return }
     const text = err.reason + ""
@@ -66,6 +320,43 @@ type PartialMessage = {
 
 export type MessageId = number
 
+/** A WAV file of the speech written while `audioOutputToFile` is set. */
+export type AudioLogEntry = { path: string, messageId: number | null, createdAt: number }
+
+/** The payload of `audio://playback`. */
+export type PlaybackStatus = { playing: boolean, paused: boolean, positionMs: number, durationMs: number, speed: number, title: string | null, sentence: string | null }
+export type ExtractedText = { path: string, text: string, tokenCount: number }
+export type WebPage = { url: string, title: string, markdown: string, tokenCount: number }
+export type EmbeddingProvider = { type: "openai", openaiKey: string, model?: string } | { type: "ollama", model: string }
+export type IndexedDocument = { id: number, path: string, model: string, chunks: number, indexedAt: string }
+/** The speech settings of a conversation. null uses the global setting. */
+export type ConversationSettings = { voice: string | null, rate: number | null, pitch: number | null, autoSpeak: boolean | null }
+export type DictationEdit = { type: "insert", text: string } | { type: "newLine" | "newParagraph" | "deleteLastSentence" | "submit" }
+export type ImageAttachment = { dataUrl: string, width: number, height: number, estimatedTokens: number }
+export type Screenshot = ImageAttachment
+/** Obtains Azure AD tokens for Azure OpenAI. get_azure_ad_credential returns it without the client secret. */
+export type AzureAdCredential = { type: "clientSecret", tenantId: string, clientId: string, clientSecret: string } | { type: "azureCli" }
+export type ConversationSummary = { id: number, name: string | null, createdAt: string, modifiedAt: string, folderId: number | null, tags: string[] }
+/** A model of start_chat_completion_multi. Its response is streamed under the request id plus its index. */
+export type ProviderTarget = { secretKey: string, endpoint: string, apiKeyAuthentication?: boolean, provider?: "openai" | "ollama" | "gemini" | "openrouter", model: string, headers?: Record<string, string>, query?: Record<string, string> }
+/** A streamed chunk of any provider, parsed by the backend. */
+export type CompletionChunk = {
+    role: string | null
+    content: string | null
+    finishReason: string | null
+    toolCalls: { index: number, id: string | null, name: string | null, arguments: string | null }[]
+    usage: { promptTokens: number, completionTokens: number } | null
+}
+export type CaptureTarget = { type: "full" } | { type: "window", title?: string } | { type: "region", x: number, y: number, width: number, height: number }
+export type McpServer = {
+    id: number
+    commandOrUrl: string
+    name: string
+    tools: { name: string, description: string | null, inputSchema: unknown }[]
+    resources: { uri: string, name: string, description: string | null, mimeType: string | null }[]
+    error: string | null
+}
+
 type Message = PartialMessage & {
     id: MessageId
     model: string | null
@@ -74,26 +365,48 @@ type Message = PartialMessage & {
     createdAt: string
     modifiedAt: string
     note: string | null  // bookmark
+    pinnedAt: string | null
 }
 
+/** Splits a streamed text into lines. A fenced code block is passed as a whole, so that the backend can omit it from the speech. */
 class SplitLines {
     private content = ""
+    private codeBlock: string[] | null = null
     constructor(private readonly callback: (line: string) => void) { }
     add(delta: string) {
         this.content += delta
         while (true) {
             const i = this.content.indexOf("\n")
             if (i === -1) { break }
-            this.callback(this.content.slice(0, i))
+            this.line(this.content.slice(0, i))
             this.content = this.content.slice(i + 1)
         }
     }
     end() {
-        this.callback(this.content)
+        this.line(this.content)
+        if (this.codeBlock) { this.callback(this.codeBlock.join("\n")) }
+        this.codeBlock = null
         this.content = ""
     }
+    private line(line: string) {
+        const isFence = /^\s*(```|~~~)/.test(line)
+        if (this.codeBlock) {
+            this.codeBlock.push(line)
+            if (isFence) {
+                this.callback(this.codeBlock.join("\n"))
+                this.codeBlock = null
+            }
+        } else if (isFence) {
+            this.codeBlock = [line]
+        } else {
+            this.callback(line)
+        }
+    }
 }
 
+/** The number of characters above which a text is spoken with speak_azure_sentences. */
+const longTextToSpeechThreshold = 200
+
 class TextToSpeechQueue {
     private preparationQueue = new PQueue({ concurrency: 1 })
     private audioQueue = new PQueue({ concurrency: 1 })
@@ -139,7 +452,7 @@ class TextToSpeechQueue {
     private async prepare(content: string | null, messageIdForDeletion: MessageId | null, noCache: boolean = false): Promise<(() => Promise<void>) | void> {
         console.log(`text-to-speech: ${content?.length ?? "-"} characters`)
         if (content?.trim() === "") { return }
-        const { ttsBackend, azureTTSRegion, azureTTSResourceKey, azureTTSVoice, azureTTSLang, pico2waveVoice, webSpeechAPILang, webSpeechAPIRate, webSpeechAPIVoice, webSpeechAPIPitch } = useConfigStore.getState()
+        let { ttsBackend, azureTTSRegion, azureTTSResourceKey, azureTTSVoice, azureTTSLang, azureTTSWordBoundaries, pico2waveVoice, webSpeechAPILang, webSpeechAPIRate, webSpeechAPIVoice, webSpeechAPIPitch } = useConfigStore.getState()
         switch (ttsBackend) {
             case "off": {
                 break
@@ -147,11 +460,18 @@ class TextToSpeechQueue {
                 if (!window.speechSynthesis) { return }
                 return async () => {
                     speechSynthesis.cancel()
-                    const utterance = new SpeechSynthesisUtterance(content ?? "Web Speech API")
+                    const utterance = new SpeechSynthesisUtterance(content === null ? "Web Speech API" : await invoke("preprocess_speech_text", { text: content, lang: webSpeechAPILang }))
                     utterance.lang = webSpeechAPILang
-                    utterance.pitch = webSpeechAPIPitch
-                    utterance.rate = webSpeechAPIRate
-                    const voice = webSpeechAPIVoice === "default" ? null : window.speechSynthesis.getVoices().find((v) => v.name === webSpeechAPIVoice)
+                    const settings = messageIdForDeletion === null ? null : await invoke("get_conversation_settings", { messageId: messageIdForDeletion })
+                    utterance.pitch = settings?.pitch ?? webSpeechAPIPitch
+                    utterance.rate = settings?.rate ?? webSpeechAPIRate
+                    const dictatedLanguage = dictatedLanguageOtherThan(webSpeechAPILang)
+                    const voice = dictatedLanguage
+                        ? window.speechSynthesis.getVoices().find((v) => v.lang.toLowerCase().startsWith(dictatedLanguage))
+                        : webSpeechAPIVoice === "default" ? null : window.speechSynthesis.getVoices().find((v) => v.name === webSpeechAPIVoice)
+                    if (dictatedLanguage) {
+                        utterance.lang = voice?.lang ?? dictatedLanguage
+                    }
                     if (voice) {
                         utterance.voice = voice
                     }
@@ -171,35 +491,59 @@ class TextToSpeechQueue {
                     })
                 }
             } case "pico2wave": {
-                return async () => { await invoke("speak_pico2wave", { content: content ?? "pico2wave", lang: pico2waveVoice }) }
+                return async () => { await invoke("speak_pico2wave", { content: content === null ? "pico2wave" : await invoke("preprocess_speech_text", { text: content, lang: pico2waveVoice }), lang: pico2waveVoice }) }
             } case "azure": {
                 if (!azureTTSRegion || !/^[a-z0-9_\-]+$/i.test(azureTTSRegion) || !azureTTSResourceKey || !azureTTSVoice) { return }
+                const dictatedLanguage = dictatedLanguageOtherThan(azureTTSLang)
+                if (dictatedLanguage) {
+                    const voice = (await invoke("list_azure_voices", { region: azureTTSRegion, resourceKey: azureTTSResourceKey }))
+                        .find((v) => v.Locale.toLowerCase().startsWith(dictatedLanguage + "-"))
+                    if (voice) {
+                        azureTTSVoice = voice.ShortName
+                        azureTTSLang = voice.Locale
+                    }
+                }
                 const pronouncedContent = content ?? "Microsoft Speech Service Text-to-Speech API"
-                const ssml = `<speak version='1.0' xml:lang='${azureTTSLang}'><voice xml:lang='${azureTTSLang}' name='${azureTTSVoice}'>${pronouncedContent.replaceAll("&", "&amp;").replaceAll('"', "&quot;").replaceAll("'", "&apos;").replaceAll("<", "&lt;").replaceAll(">", "&gt;")}</voice></speak>`
 
                 // > You're billed for each character that's converted to speech, including punctuation. Although the SSML document itself is not billable, optional elements that are used to adjust how the text is converted to speech, like phonemes and pitch, are counted as billable characters.
                 // > https://learn.microsoft.com/en-us/azure/cognitive-services/speech-service/speech-synthesis-markup
                 await db.current.execute("INSERT INTO textToSpeechUsage (region, numCharacters) VALUES (?, ?)", [azureTTSRegion, pronouncedContent.length])
 
-                await invoke("speak_azure", {
+                if (azureTTSWordBoundaries) {
+                    const ssml = await invoke("build_ssml", { text: pronouncedContent, voice: azureTTSVoice, lang: azureTTSLang })
+                    return async () => {
+                        await invoke("speak_azure_with_word_boundaries", { messageId: messageIdForDeletion, region: azureTTSRegion, resourceKey: azureTTSResourceKey, ssml })
+                    }
+                }
+
+                // Long texts are synthesized sentence by sentence so that the playback starts sooner.
+                if (pronouncedContent.length > longTextToSpeechThreshold) {
+                    return async () => {
+                        await invoke("speak_azure_sentences", {
+                            messageId: messageIdForDeletion,
+                            region: azureTTSRegion,
+                            resourceKey: azureTTSResourceKey,
+                            voice: azureTTSVoice,
+                            lang: azureTTSLang,
+                            content: pronouncedContent,
+                            noCache,
+                        })
+                    }
+                }
+
+                // The backend builds the SSML with the voice, rate, and pitch of the conversation.
+                const args = {
                     messageId: messageIdForDeletion,
                     region: azureTTSRegion,
                     resourceKey: azureTTSResourceKey,
-                    ssml,
-                    beepVolume: 0,
-                    preFetch: true,
+                    voice: azureTTSVoice,
+                    lang: azureTTSLang,
+                    content: pronouncedContent,
                     noCache,
-                })
+                }
+                await invoke("speak_azure_text", { ...args, preFetch: true })
                 return async () => {
-                    await invoke("speak_azure", {
-                        messageId: messageIdForDeletion,
-                        region: azureTTSRegion,
-                        resourceKey: azureTTSResourceKey,
-                        ssml,
-                        beepVolume: 0,
-                        preFetch: false,
-                        noCache,
-                    })
+                    await invoke("speak_azure_text", { ...args, preFetch: false })
                 }
             } default: {
                 ttsBackend satisfies never
@@ -212,32 +556,105 @@ export const isMac = navigator.platform.startsWith("Mac")
 export const isWindows = navigator.platform.startsWith("Win")
 export const ctrlOrCmd = (ev: KeyboardEvent) => (isMac ? /* cmd */ev.metaKey : ev.ctrlKey)
 
+/** Parses the extra headers of the OpenAI-compatible endpoint, e.g. `{"Helicone-Auth": "Bearer ..."}`. Throws if they are not an object of strings. */
+export const parseHeaders = (json: string): Record<string, string> => {
+    let headers: unknown
+    try {
+        headers = JSON.parse(json || "{}")
+    } catch (err) {
+        throw new Error(`The extra headers are not valid JSON: ${err}`)
+    }
+    if (typeof headers !== "object" || headers === null || Array.isArray(headers)) {
+        throw new Error("The extra headers must be a JSON object")
+    }
+    for (const [name, value] of Object.entries(headers)) {
+        if (typeof value !== "string") { throw new Error(`The value of the extra header ${name} must be a string`) }
+    }
+    return headers as Record<string, string>
+}
+
 const defaultConfigValues = {
     APIKey: "",
     azureApiKeyAuthentication: 1,
     azureAPIKey: "",
     azureEndpoint: "",
-    openaiService: "openai" as "openai" | "openai-proxy" | "azure",
+    openaiService: "openai" as "openai" | "openai-proxy" | "azure" | "openrouter" | "local",
     ttsBackend: (window.speechSynthesis ? "web-speech-api" : "off") as "off" | "pico2wave" | "web-speech-api" | "azure",
     azureTTSRegion: "",
     azureTTSResourceKey: "",
     azureTTSVoice: "en-US-ChristopherNeural",
     azureTTSLang: "en-US",
+    azureTTSWordBoundaries: 0,
     pico2waveVoice: "en-US" as "en-US" | "en-GB" | "de-DE" | "es-ES" | "fr-FR" | "it-IT",
     budget: 1,
     maxCostPerMessage: 0.015,
+    ttsCacheMaxBytes: 209715200,
     audioFeedback: 1,
     webSpeechAPILang: "en-US",
     webSpeechAPIPitch: 1,
     webSpeechAPIRate: 1,
     webSpeechAPIVoice: "default",
+    /** Reads "Code omitted." instead of the code blocks */
+    speechSkipCode: 1,
+    /** Skips the URLs in the text that is read aloud */
+    speechSkipUrls: 1,
+    /** Writes the speech to the audio log of each conversation instead of playing it, except for Web Speech API */
+    audioOutputToFile: 0,
     reversedView: 0,
     whisperLanguage: "",
+    speechToTextBackend: "whisper" as "whisper" | "azure" | "deepgram",
+    deepgramAPIKey: "",
     editVoiceInputBeforeSending: 0,
+    dictationKeepWav: 0,
+    dictationKeepLastRecording: 0,
+    dictationAutoGain: 0,
+    dictationMaxGainDb: 20,
+    /** Trims the silence around the dictation before it is sent to Whisper */
+    dictationTrimSilence: 0,
+    /** Pauses longer than this are shortened when the silence is trimmed, 0 to keep them */
+    dictationMaxPauseMs: 0,
+    /** Speaks the replies with a voice of the language detected in the last dictation, when the Whisper language is not set */
+    ttsMatchDictatedLanguage: 1,
+    /** Dictates in any language and inserts the English translation. The transcript in the spoken language is shown in a toast. */
+    dictationTranslate: 0,
+    /** Recognizes spoken editing commands such as "new line", "delete that", and "send it" in the dictation */
+    dictationCommands: 0,
+    /** Additional command phrases per language, e.g. {"en": {"next line": "newLine"}} */
+    dictationCommandPhrases: "{}",
+    /** Keeps the microphone open to listen for the wake word. Off by default. */
+    wakeWord: 0,
+    /** A path to a rustpotter .rpw file, or the name of one in the wake_words directory of the app data directory */
+    wakeWordKeyword: "",
+    wakeWordSensitivity: 0.5,
+    /** What happens to the speech when dictation starts: stopped, ducked, or stopped once the user's voice is heard */
+    bargeIn: "stop" as "stop" | "duck" | "voice",
     theme: "automatic" as "automatic" | "light" | "dark" | "light-3d",
     sidebar: "automatic" as "automatic" | "hide" | "show",
     openaiProxyAPIKey: "",
     openaiProxyUrl: "",
+    openaiProxyHeaders: "{}",
+    /** The id of a provider preset, e.g. "groq", whose endpoint is used when openaiProxyUrl is empty */
+    openaiProxyPreset: "",
+    openrouterAPIKey: "",
+    /** llama-server of llama.cpp, or a llamafile */
+    localModelBinaryPath: "",
+    /** A GGUF file, or empty for a llamafile that contains the model */
+    localModelPath: "",
+    localModelPort: 8080,
+    /** The models that OpenRouter falls back to when the model fails, separated by commas, e.g. "anthropic/claude-3.5-sonnet, openai/gpt-4o" */
+    openrouterFallbackModels: "",
+    clipboardWatch: 0,
+    offlineQueue: 0,
+    /** e.g. {"openai": {"rpm": 500, "tpm": 30000}}, keyed by provider or "whisper" */
+    rateLimits: "{}",
+    clipboardTemplateId: 0,
+    webSearch: 0,
+    webSearchProvider: "searxng" as "searxng" | "brave" | "bing",
+    webSearchEndpoint: "",
+    webSearchAPIKey: "",
+    codeInterpreter: 0,
+    codeInterpreterNetwork: 0,
+    mcpTools: 0,
     searchEngine: `https://www.google.com/search?q={searchTerms}`,
     zoomLevel: 0,
     gravatarEmail: "",
@@ -246,6 +663,9 @@ const defaultConfigValues = {
     customInstructions: "",
 } satisfies Record<string, string | number>
 
+/** The settings stored in the OS credential store with store_secret instead of the config table, which must match secrets::SETTINGS of the backend. */
+const secretConfigKeys: readonly string[] = ["APIKey", "azureAPIKey", "deepgramAPIKey", "openaiProxyAPIKey", "openrouterAPIKey", "webSearchAPIKey"] satisfies (keyof typeof defaultConfigValues)[]
+
 const _useConfigStore = create<typeof defaultConfigValues>()(() => defaultConfigValues)
 const _setState = _useConfigStore.setState
 type AsyncStore<S> = {
@@ -257,7 +677,11 @@ type AsyncStore<S> = {
 export const useConfigStore: AsyncStore<typeof defaultConfigValues> = Object.assign(_useConfigStore, {
     setState: async (partial: Partial<typeof defaultConfigValues>) => {
         for (const [k, v] of Object.entries(partial)) {
-            await db.current.execute("INSERT OR REPLACE INTO config VALUES (?, ?)", [k, v])
+            if (secretConfigKeys.includes(k)) {
+                await (v ? invoke("store_secret", { name: k, value: `${v}` }) : invoke("delete_secret", { name: k }))
+            } else {
+                await invoke("set_setting", { key: k, value: v })
+            }
         }
         _setState.call(_useConfigStore, partial)
     }
@@ -268,31 +692,177 @@ const loadConfig = async () => {
     // Retrieve data from the database
     const obj = Object.fromEntries((await db.current.select<{ key: string, value: string }[]>("SELECT key, value FROM config", []))
         .map(({ key, value }) => [key, typeof defaultConfigValues[key as keyof typeof defaultConfigValues] === "number" ? +value : value]))
+    for (const key of secretConfigKeys) {
+        try {
+            obj[key] = await invoke("get_secret", { name: key }) ?? ""
+        } catch (err) {
+            console.error(err)
+            obj[key] ??= "" // secrets::migrate() leaves the key in the config table if the credential store is unavailable
+        }
+    }
 
     // Set default values
+    const defaults: Record<string, any> = {}
     for (const [k, v] of Object.entries(defaultConfigValues)) {
         if (!(k in obj)) {
-            obj[k] = v
+            defaults[k] = v
         }
     }
 
-    await useConfigStore.setState(obj)
+    _setState.call(_useConfigStore, obj)
+    await useConfigStore.setState(defaults)
+
+    // Changes made by the other windows
+    await event.listen<{ key: string, value: any }>("settings://changed", ({ payload }) => {
+        if (payload.key in defaultConfigValues) {
+            _setState.call(_useConfigStore, { [payload.key]: payload.value })
+        }
+    })
 }
 
-export const init = async () => {
-    db.current = await Database.load("sqlite:chatgpt_tauri.db")
+/**
+ * Keys the connection of an encrypted database. The queries run one at a time, so that tauri-plugin-sql keeps a single
+ * connection, and a query that fails because the plugin has opened a new connection is retried after keying it.
+ */
+const keyDatabase = async (database: DatabaseType, passphrase: string): Promise<DatabaseType> => {
+    const pragma = `PRAGMA key = '${passphrase.replaceAll("'", "''")}'`
+    await database.execute(pragma)
+    const queue = new PQueue({ concurrency: 1 })
+    const run = <T>(query: () => Promise<T>) => queue.add(async () => {
+        try {
+            return await query()
+        } catch (err) {
+            if (!/file is not a database/.test(`${err}`)) { throw err }
+            await database.execute(pragma)
+            return await query()
+        }
+    }) as Promise<T>
+    return Object.assign(Object.create(database), {
+        execute: (query: string, bindValues?: unknown[]) => run(() => database.execute(query, bindValues)),
+        select: <T>(query: string, bindValues?: unknown[]) => run(() => database.select<T>(query, bindValues)),
+    })
+}
+
+/** Closes the connection of the windows, which must not be open while the backend replaces the file, and re-encrypts the database. The windows reload on database://passphrase-changed. */
+export const setDatabasePassphrase = async (passphrase: string | null) => {
+    await db.current.close()
+    await invoke("set_database_passphrase", { passphrase })
+}
+
+/** `askPassphrase` is called until the passphrase of an encrypted database is entered, with the error of the previous attempt. */
+export const init = async (askPassphrase: (error: string | null) => Promise<string>) => {
+    let error: string | null = null
+    while (await invoke("is_database_locked")) {
+        try {
+            await invoke("unlock_database", { passphrase: await askPassphrase(error) })
+        } catch (err) {
+            error = `${err}`
+        }
+    }
+    const { active, profiles } = await invoke("list_profiles")
+    db.current = await Database.load(`sqlite:${profiles.find((v) => v.id === active)?.databaseFile ?? "chatgpt_tauri.db"}`)
+    const passphrase = await invoke("get_database_passphrase")
+    if (passphrase !== null) { db.current = await keyDatabase(db.current, passphrase) }
     await db.current.execute(createTablesSQL)
-    await reload([])
+    const conversation = /^#conversation=(\d+)$/.exec(location.hash)
+    await reload(conversation ? [+conversation[1]!] : [])
     await loadConfig()
 
     const { sidebar } = useConfigStore.getState()
     useStore.setState({ isSideBarOpen: sidebar === "show" || sidebar === "automatic" && window.innerWidth > 800 })
+
+    await event.listen<"thread.new" | "microphone.start" | "speaker.stop" | "clipboard.ask">("tray://action", ({ payload }) => { api[payload]() })
+    // Every state comes from the database of the profile, so the window is reloaded to open the new one.
+    await event.listen<Profile>("profile://switched", () => { location.reload() })
+    await event.listen("database://passphrase-changed", () => { location.reload() })
+
+    invoke("set_clipboard_watch", { enabled: !!useConfigStore.getState().clipboardWatch })
+    useConfigStore.subscribe((state, prev) => {
+        if (state.clipboardWatch !== prev.clipboardWatch) { invoke("set_clipboard_watch", { enabled: !!state.clipboardWatch }) }
+    })
+
+    invoke("set_audio_output_to_file", { enabled: !!useConfigStore.getState().audioOutputToFile })
+    useConfigStore.subscribe((state, prev) => {
+        if (state.audioOutputToFile !== prev.audioOutputToFile) { invoke("set_audio_output_to_file", { enabled: !!state.audioOutputToFile }) }
+    })
+
+    invoke("set_barge_in", { policy: useConfigStore.getState().bargeIn })
+    useConfigStore.subscribe((state, prev) => {
+        if (state.bargeIn !== prev.bargeIn) { invoke("set_barge_in", { policy: state.bargeIn }) }
+    })
+    await event.listen("audio://barge-in", () => { useStore.getState().ttsQueue.cancel() })
+
+    const setWakeWord = ({ wakeWord, wakeWordKeyword, wakeWordSensitivity }: typeof defaultConfigValues) => {
+        if (wakeWord && wakeWordKeyword.trim()) {
+            invoke("enable_wake_word", { keyword: wakeWordKeyword, sensitivity: wakeWordSensitivity }).catch((err) => { console.error(err) })
+        } else {
+            invoke("disable_wake_word")
+        }
+    }
+    setWakeWord(useConfigStore.getState())
+    useConfigStore.subscribe((state, prev) => {
+        if (state.wakeWord !== prev.wakeWord || state.wakeWordKeyword !== prev.wakeWordKeyword || state.wakeWordSensitivity !== prev.wakeWordSensitivity) { setWakeWord(state) }
+    })
+    await event.listen<{ keyword: string, score: number }>("wake-word://detected", () => {
+        if (!useStore.getState().listening) { api["microphone.start"]() }
+    })
+    await event.listen<string>("wake-word://error", ({ payload }) => { console.error(payload) })
+
+    const setRateLimits = (rateLimits: string) => {
+        try {
+            invoke("set_rate_limits", { limits: JSON.parse(rateLimits || "{}") })
+        } catch {
+            // Ignore the invalid JSON while it is being typed.
+        }
+    }
+    setRateLimits(useConfigStore.getState().rateLimits)
+    useConfigStore.subscribe((state, prev) => {
+        if (state.rateLimits !== prev.rateLimits) { setRateLimits(state.rateLimits) }
+    })
+
+    await event.listen<{ requestId: number }>("chat-completion://queued", ({ payload }) => {
+        useStore.setState((s) => ({ queuedRequests: [...s.queuedRequests, payload.requestId] }))
+    })
+    await event.listen<{ requestId: number }>("chat-completion://dequeued", ({ payload }) => {
+        useStore.setState((s) => ({ queuedRequests: s.queuedRequests.filter((v) => v !== payload.requestId) }))
+    })
+    await event.listen<{ requestId: number, conversationId: number | null }>("chat-completion://replayed", () => {
+        reload(useStore.getState().visibleMessages.map((v) => v.id))
+    })
+    await event.listen<{ scheduleId: number, conversationId: number, content: string, speak: boolean }>("scheduler://completed", ({ payload }) => {
+        reload(useStore.getState().visibleMessages.map((v) => v.id))
+        if (payload.speak) { useStore.getState().ttsQueue.speakText(payload.content, null) }
+    })
+    await event.listen<ToolApproval>("chat-completion://tool-approval", ({ payload }) => {
+        useStore.setState((s) => ({ toolApprovals: [...s.toolApprovals, payload] }))
+    })
+    await event.listen<{ id: number }>("chat-completion://tool-approval-closed", ({ payload }) => {
+        useStore.setState((s) => ({ toolApprovals: s.toolApprovals.filter((v) => v.id !== payload.id) }))
+    })
+    // Messages synced from other computers.
+    await event.listen<SyncStatus>("sync://completed", ({ payload }) => {
+        if (payload.pulled > 0) { reload(useStore.getState().visibleMessages.map((v) => v.id)) }
+    })
+    // Dropped documents are extracted by the backend and appended to the prompt.
+    await event.listen<ExtractedText>("file-drop://extracted", ({ payload }) => { api["messageInput.attach"](payload) })
+    await event.listen<{ path: string, message: string }>("file-drop://error", ({ payload }) => { alert(payload.message) })
+    await event.listen<Screenshot>("screenshot://captured", ({ payload }) => {
+        useStore.setState((s) => ({ imageAttachments: [...s.imageAttachments, payload] }))
+        api["messageInput.focus"](false)
+    })
 }
 
+/** `chat-completion://tool-approval`. A tool call that runs only if the user approves it. */
+export type ToolApproval = { id: number, requestId: number, name: string, arguments: string }
+
 export type State = {
     waitingAssistantsResponse: MessageId[]
+    /** The chat completion requests waiting for the network. */
+    queuedRequests: number[]
     threads: { id: MessageId, name: string | null }[]
     visibleMessages: (Message & { children: Message[] })[]
+    /** The language of the last dictation detected by Whisper, as an ISO-639-1 code. */
+    dictatedLanguage: string | null
     search: string
     folded: Set<MessageId>
     scrollIntoView: MessageId | null
@@ -303,12 +873,17 @@ export type State = {
     renamingThread: MessageId | null
     shouldDisplayAPIKeyInputOverride: boolean
     settingsTab: "general" | "budget" | "bookmark" | "speaker" | "microphone" | "customInstructions",
+    /** Sent with the next message to the OpenAI API. */
+    imageAttachments: ImageAttachment[]
+    toolApprovals: ToolApproval[]
 }
 
 let _useStore = create<State>()(() => ({
     waitingAssistantsResponse: [],
+    queuedRequests: [],
     threads: [],
     visibleMessages: [],
+    dictatedLanguage: null,
     password: "",
     search: "",
     folded: new Set(),
@@ -320,6 +895,8 @@ let _useStore = create<State>()(() => ({
     renamingThread: null,
     shouldDisplayAPIKeyInputOverride: false,
     settingsTab: "general",
+    imageAttachments: [],
+    toolApprovals: [],
 }))
 
 // @ts-ignore
@@ -339,16 +916,18 @@ const setTextareaValueAndAutoResize = (textarea: HTMLTextAreaElement, value: str
  */
 const reload = async (path: MessageId[]) => {
     const visibleMessages: (Message & { children: Message[] })[] = []
-    let node = path.length === 0 ? undefined : (await db.current.select<Message[]>("SELECT * FROM message LEFT OUTER JOIN bookmark ON message.id = bookmark.messageId LEFT OUTER JOIN messageModelV2 ON message.id = messageModelV2.messageId WHERE id = ?", [path[0]!]))[0]
+    let node = path.length === 0 ? undefined : (await db.current.select<Message[]>("SELECT * FROM message LEFT OUTER JOIN bookmark ON message.id = bookmark.messageId LEFT OUTER JOIN messageModelV2 ON message.id = messageModelV2.messageId LEFT OUTER JOIN pinnedMessage ON message.id = pinnedMessage.messageId WHERE id = ?", [path[0]!]))[0]
     let depth = 1
     while (node) {
-        const children = await db.current.select<Message[]>("SELECT * FROM message LEFT OUTER JOIN bookmark ON message.id = bookmark.messageId LEFT OUTER JOIN messageModelV2 ON message.id = messageModelV2.messageId WHERE parent = ?", [node.id])
+        const children = await db.current.select<Message[]>("SELECT * FROM message LEFT OUTER JOIN bookmark ON message.id = bookmark.messageId LEFT OUTER JOIN messageModelV2 ON message.id = messageModelV2.messageId LEFT OUTER JOIN pinnedMessage ON message.id = pinnedMessage.messageId WHERE parent = ?", [node.id])
         visibleMessages.push({ ...node, children })
         node = children.find((v) => v.id === path[depth]) ?? children.at(-1)
         depth++
     }
 
     useStore.setState({ visibleMessages })
+    // Remembers the branch, so that the thread is reopened with it.
+    if (visibleMessages.length > 1) { invoke("switch_branch", { leafId: visibleMessages.at(-1)!.id }) }
     db.current.select<{ id: number, name: string | null }[]>("SELECT message.id as id, threadName.name as name FROM message LEFT OUTER JOIN threadName ON message.id = threadName.messageId WHERE message.parent IS NULL ORDER BY message.createdAt DESC")
         .then((threads) => { useStore.setState({ threads }) })
 
@@ -370,7 +949,7 @@ export const getPricePerToken = (model: string): { prompt: number, generated: nu
 export const getTokenUsage = (now = new Date()) => db.current.select<{ model: string, prompt_tokens_sum: number, completion_tokens_sum: number, count: number }[]>(getTokenUsageSQL, [now.toISOString()])
 
 /** Generates an assistant's response. */
-const complete = async (messages: readonly Pick<PartialMessage, "role" | "content">[], model: string, handleStream?: (content: string, delta: string) => Promise<void>): Promise<PartialMessage> => {
+const complete = async (messages: readonly Pick<PartialMessage, "role" | "content">[], model: string, handleStream?: (content: string, delta: string) => Promise<void>, conversationId?: MessageId): Promise<PartialMessage> => {
     try {
         const usage = await getTokenUsage()
         if (
@@ -393,6 +972,10 @@ const complete = async (messages: readonly Pick<PartialMessage, "role" | "conten
             .map((v) => ({ role: v.role, content: v.content }))
         let numParentsFed = -1 // all
 
+        const { imageAttachments } = useStore.getState()
+        const images = imageAttachments.length > 0 && messagesFed.at(-1)?.role === "user" ? imageAttachments.map((v) => v.dataUrl) : undefined
+        if (images) { useStore.setState({ imageAttachments: [] }) }
+
         // Drop messages
         const expectedGeneratedTokenCount = 150
         let omitted = false
@@ -426,37 +1009,26 @@ const complete = async (messages: readonly Pick<PartialMessage, "role" | "conten
         // FIXME: display the number of parents fed
         console.log(`numParentsFed: ${numParentsFed}`)
         console.log(messagesFed)
+        const messagesWithImages = images ? [...messagesFed.slice(0, -1), { ...messagesFed.at(-1)!, images }] : messagesFed
 
         let done = false
         let err: string | null | undefined
         const requestId = Math.floor(Math.random() * Number.MAX_SAFE_INTEGER)
+        // A JSON response that the backend had to repair is replaced with the repaired JSON.
+        let repairedJson: string | null = null
+        const unlistenInvalidJson = await event.listen<InvalidJson>("chat-completion://invalid-json", ({ payload }) => {
+            if (payload.requestId !== requestId) { return }
+            console.warn(`invalid JSON response: ${payload.error}`, payload.attempts)
+            repairedJson = payload.repaired
+        })
         const dataFetchPromise = new Promise<PartialMessage & { role: "assistant" }>((resolve, reject) => {
             const result: PartialMessage & { role: "assistant" } = { content: "", role: "assistant", status: 0 }
             const loop = async () => {
                 const done2 = done
                 try {
                     let delta = ""
-                    for (const v of await invoke("get_chat_completion", { requestId })) {
-                        let partial: {
-                            choices: {
-                                delta?: {
-                                    role?: "assistant"
-                                    content?: string
-                                }
-                                index?: number
-                                finish_reason?: string | null
-                            }[]
-                            created?: number
-                            id?: string
-                            model?: string
-                            object?: string
-                        }
-                        try { partial = JSON.parse(v) } catch (err) {
-                            throw new Error(`Parse error: ${v}`)
-                        }
-                        if (partial.choices[0]?.delta?.content) {
-                            delta += partial.choices[0].delta.content
-                        }
+                    for (const chunk of await invoke("get_chat_completion", { requestId })) {
+                        delta += chunk.content ?? ""
                     }
                     result.content += delta
                     if (!err) {
@@ -471,46 +1043,98 @@ const complete = async (messages: readonly Pick<PartialMessage, "role" | "conten
             loop()
         })
         try {
-            const { APIKey, openaiService, azureEndpoint, azureApiKeyAuthentication, azureAPIKey, openaiProxyAPIKey, openaiProxyUrl } = useConfigStore.getState()
+            const { APIKey, openaiService, azureEndpoint, azureApiKeyAuthentication, azureAPIKey, openaiProxyAPIKey, openaiProxyUrl, openaiProxyHeaders, openaiProxyPreset, openrouterAPIKey, openrouterFallbackModels, localModelPort, webSearch, codeInterpreter, mcpTools, offlineQueue } = useConfigStore.getState()
+            const enabledTools = [...webSearch ? ["web_search"] : [], ...codeInterpreter && !isWindows ? ["run_code"] : []]
+            const tools = enabledTools.length > 0 ? enabledTools : undefined
             if (openaiService === "azure") {
                 err = await invoke("start_chat_completion", {
                     requestId,
                     secretKey: azureAPIKey,
-                    body: JSON.stringify({
+                    request: {
                         prompt: messagesFed.map((v) => `<|im_start|>${v.role}\n${v.content}\n<|im_end|>\n`).join("") + "<|im_start|>assistant",
-                        stream: true,
                         stop: ["<|im_end|>"],
-                    }),
+                    },
                     endpoint: azureEndpoint,
                     apiKeyAuthentication: !!azureApiKeyAuthentication,
+                    conversationId,
+                    model,
+                    queueWhenOffline: !!offlineQueue,
                 }).catch((err) => err + "")
             } else if (openaiService === "openai-proxy") {
+                let headers: Record<string, string> | null = null
+                try {
+                    headers = parseHeaders(openaiProxyHeaders)
+                } catch (e) {
+                    err = `${e}`
+                }
+                if (headers) {
+                    err = await invoke("start_chat_completion", {
+                        requestId,
+                        secretKey: openaiProxyAPIKey,
+                        request: {
+                            model,
+                            messages: messagesWithImages,
+                        },
+                        endpoint: openaiProxyUrl,
+                        apiKeyAuthentication: false,
+                        preset: openaiProxyPreset || undefined,
+                        headers,
+                        conversationId,
+                        tools,
+                        mcp: !!mcpTools,
+                        queueWhenOffline: !!offlineQueue,
+                    }).catch((err) => err + "")
+                }
+            } else if (openaiService === "openrouter") {
+                const fallbackModels = openrouterFallbackModels.split(",").map((v) => v.trim()).filter((v) => v)
                 err = await invoke("start_chat_completion", {
                     requestId,
-                    secretKey: openaiProxyAPIKey,
-                    body: JSON.stringify({
+                    secretKey: openrouterAPIKey,
+                    request: {
                         model,
-                        messages: messagesFed,
-                        stream: true,
-                    }),
-                    endpoint: openaiProxyUrl,
+                        models: fallbackModels.length > 0 ? fallbackModels : undefined,
+                        messages: messagesWithImages,
+                    },
+                    endpoint: "",
                     apiKeyAuthentication: false,
+                    provider: "openrouter",
+                    conversationId,
+                    tools,
+                    queueWhenOffline: !!offlineQueue,
+                }).catch((err) => err + "")
+            } else if (openaiService === "local") {
+                err = await invoke("start_chat_completion", {
+                    requestId,
+                    secretKey: "",
+                    request: {
+                        model,
+                        messages: messagesWithImages,
+                    },
+                    endpoint: `http://127.0.0.1:${localModelPort}/v1/chat/completions`,
+                    apiKeyAuthentication: false,
+                    conversationId,
+                    queueWhenOffline: !!offlineQueue,
                 }).catch((err) => err + "")
             } else {  // openai
                 err = await invoke("start_chat_completion", {
                     requestId,
                     secretKey: APIKey,
-                    body: JSON.stringify({
+                    request: {
                         model,
-                        messages: messagesFed,
-                        stream: true,
-                    }),
+                        messages: messagesWithImages,
+                        includeUsage: true,
+                    },
                     endpoint: "https://api.openai.com/v1/chat/completions",
                     apiKeyAuthentication: false,
+                    conversationId,
+                    tools,
+                    mcp: !!mcpTools,
+                    queueWhenOffline: !!offlineQueue,
                 }).catch((err) => err + "")
             }
         } finally {
             done = true
+            unlistenInvalidJson()
         }
         if (err) {
             let json: unknown = null
@@ -521,13 +1145,7 @@ const complete = async (messages: readonly Pick<PartialMessage, "role" | "conten
             return { role: "assistant", status: 1, content: err }
         } else {
             const result = await dataFetchPromise
-            Promise.all([
-                invoke("count_tokens", { model, messages: messagesFed }),
-                invoke("count_tokens", { model, messages: [result] })
-            ]).then(([promptTokens, completionTokens]) => {
-                completionTokens -= 4 // "<im_start>" "assistant" "<im_start>" "assistant"
-                db.current.execute("INSERT INTO textCompletionUsage (model, prompt_tokens, completion_tokens, total_tokens) VALUES (?, ?, ?, ?)", [model, promptTokens, completionTokens, promptTokens + completionTokens])
-            })
+            if (repairedJson !== null) { result.content = repairedJson }
             return result
         }
     } catch (err) {
@@ -577,7 +1195,9 @@ const completeAndAppend = async (messages: readonly MessageId[]): Promise<{ mess
     scrollToBottom()
     try {
         const ttsId = Math.floor(Math.random() * Number.MAX_SAFE_INTEGER)
+        const { autoSpeak } = await invoke("get_conversation_settings", { messageId: id })
         const splitLines = new SplitLines((line) => {
+            if (autoSpeak === false) { return }
             useStore.getState().ttsQueue.pushSegment(ttsId, line, id)
         })
         const newMessage = await complete(
@@ -591,6 +1211,7 @@ const completeAndAppend = async (messages: readonly MessageId[]): Promise<{ mess
                 reload(path)
                 scrollToBottom()
             },
+            messages[0],
         )
         splitLines.end()
         if (newMessage.status === 1) {
@@ -657,6 +1278,17 @@ export const api = {
         if (!textarea) { return } // TODO:
         setTextareaValueAndAutoResize(textarea, value)
     },
+    "messageInput.attach": ({ path, text }: ExtractedText) => {
+        const name = path.split(/[\\/]/).at(-1)
+        const value = api["messageInput.get"]()
+        api["messageInput.set"]((value ? value + "\n\n" : "") + `${name}:\n\`\`\`\n${text}\n\`\`\`\n`)
+    },
+    /** Appends the main content of the web page to the message input. */
+    "messageInput.attachUrl": async (url: string) => {
+        const page = await invoke("fetch_url_content", { url })
+        const value = api["messageInput.get"]()
+        api["messageInput.set"]((value ? value + "\n\n" : "") + `${page.title || page.url} (${page.url}):\n\`\`\`markdown\n${page.markdown}\n\`\`\`\n`)
+    },
     "messageInput.get": (): string => {
         const textarea = getChatInput()
         if (!textarea) { return "" } // TODO:
@@ -777,7 +1409,8 @@ export const api = {
         await reload(useStore.getState().visibleMessages.map((v) => v.id))
     },
     "thread.open": async (id: MessageId, audioFeedback = false) => {
-        reload([id])
+        const branch = (await invoke("list_branches", { conversationId: id })).find((v) => v.selected)
+        reload(branch ? await invoke("switch_branch", { leafId: branch.leafId }) : [id])
         await api["messageInput.focus"](audioFeedback)
         if (useConfigStore.getState().audioFeedback && audioFeedback) { useStore.getState().ttsQueue.speakText(useStore.getState().threads.find((v) => v.id === id)?.name ?? "untitled thread", id) }
         // scrollIntoViewIfNeeded is polyfilled by the "element.scrollintoviewifneeded-polyfill" package
@@ -837,17 +1470,52 @@ export const api = {
         const message = useStore.getState().visibleMessages.findLast((v) => v.role === "assistant")
         return message?.id ?? null
     },
+    "speaker.stop": () => { useStore.getState().ttsQueue.cancel() },
+    "speaker.openMiniPlayer": () => { invoke("open_mini_player") },
+    "clipboard.ask": async () => {
+        const { clipboardTemplateId } = useConfigStore.getState()
+        const id = await invoke("ask_about_clipboard", { templateId: clipboardTemplateId || null })
+        await api["thread.open"](id)
+    },
     "sideBar.show": () => { useStore.setState({ isSideBarOpen: true }) },
     "sideBar.hide": () => { useStore.setState({ isSideBarOpen: false }) },
     "sideBar.toggle": () => { useStore.setState((s) => ({ isSideBarOpen: !s.isSideBarOpen })) },
     "microphone.start": () => {
         const startTime = Date.now()
-        useStore.getState().ttsQueue.cancel()
-        invoke("start_listening", { openaiKey: useConfigStore.getState().APIKey, language: useConfigStore.getState().whisperLanguage.trim() })
-            .then((res) => {
-                db.current.execute("INSERT INTO speechToTextUsage (model, durationMs) VALUES (?, ?)", ["whisper-1", Date.now() - startTime])
-                api["messageInput.set"](api["messageInput.get"]() + res as string)
-                if (!useConfigStore.getState().editVoiceInputBeforeSending) {
+        const config = useConfigStore.getState()
+        // Otherwise the backend ducks the speech or stops it when the user's voice is heard.
+        if (config.bargeIn === "stop") { useStore.getState().ttsQueue.cancel() }
+        const backend = config.speechToTextBackend
+        const promise = backend === "azure"
+            ? invoke("start_listening_azure", { region: config.azureTTSRegion, resourceKey: config.azureTTSResourceKey, language: config.azureTTSLang })
+            : backend === "deepgram"
+                ? invoke("start_listening_deepgram", { apiKey: config.deepgramAPIKey, language: config.whisperLanguage.trim() })
+                : invoke("start_listening", { openaiKey: config.APIKey, language: config.whisperLanguage.trim(), autoGain: !!config.dictationAutoGain, maxGainDb: config.dictationMaxGainDb, trimSilence: !!config.dictationTrimSilence, maxPauseMs: config.dictationMaxPauseMs, contextHint: useStore.getState().visibleMessages.at(-1)?.content.slice(-500), translate: !!config.dictationTranslate })
+                    .then(({ text, original, language }) => {
+                        useStore.setState({ dictatedLanguage: language })
+                        if (original !== null) { Toastify({ text: original, duration: 5000 }).showToast() }
+                        return text
+                    })
+        promise
+            .then(async (res) => {
+                db.current.execute("INSERT INTO speechToTextUsage (model, durationMs) VALUES (?, ?)", [backend === "whisper" ? "whisper-1" : backend, Date.now() - startTime])
+                const edits: DictationEdit[] = useConfigStore.getState().dictationCommands
+                    ? await invoke("parse_dictation_commands", { transcript: res, language: backend === "azure" ? config.azureTTSLang : backend === "whisper" && config.dictationTranslate ? "en" : config.whisperLanguage.trim() })
+                    : [{ type: "insert", text: res }]
+                let value = api["messageInput.get"]()
+                let submit = !useConfigStore.getState().editVoiceInputBeforeSending
+                for (const edit of edits) {
+                    switch (edit.type) {
+                        case "insert": value += edit.text; break
+                        case "newLine": value += "\n"; break
+                        case "newParagraph": value += "\n\n"; break
+                        // Removes the last sentence with its punctuation, e.g. " World." in "Hello. World."
+                        case "deleteLastSentence": value = value.replace(/[^.!?。！？\n]*[.!?。！？]?\s*$/, ""); break
+                        case "submit": submit = true; break
+                    }
+                }
+                api["messageInput.set"](value)
+                if (submit) {
                     api["messageInput.submit"]()
                 }
             })
@@ -959,6 +1627,12 @@ export const api = {
             api["message.bookmark"](id)
         }
     },
+    "message.togglePin": async (id: MessageId) => {
+        const message = useStore.getState().visibleMessages.find((v) => v.id === id)
+        if (!message) { return }
+        await invoke(message.pinnedAt === null ? "pin_message" : "unpin_message", { messageId: id })
+        await reload(useStore.getState().visibleMessages.map((v) => v.id))
+    },
     "message.show": async (id: MessageId) => {
         findParents(id).then(async (res) => {
             await reload(res)
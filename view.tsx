@@ -5,14 +5,13 @@ import { render } from "preact"
 import { Ref, useEffect, useLayoutEffect, useMemo, useRef, useState } from "preact/hooks"
 import ReactMarkdown from "react-markdown"
 import { open } from '@tauri-apps/api/shell'
-import { fetch } from '@tauri-apps/api/http'
 import hljs from "highlight.js"
-import { clipboard } from "@tauri-apps/api"
+import { clipboard, event } from "@tauri-apps/api"
 import { appWindow } from "@tauri-apps/api/window"
 import { useEventListener } from "usehooks-ts"
 import remarkGfm from "remark-gfm"
 import { getMatches } from '@tauri-apps/api/cli'
-import { MessageId, State, api, ctrlOrCmd, db, extractFirstCodeBlock, getTokenUsage, init, isMac, isWindows, useConfigStore, useStore, invoke, getPricePerToken } from "./state"
+import { MessageId, State, api, ctrlOrCmd, db, extractFirstCodeBlock, getTokenUsage, init, isMac, isWindows, useConfigStore, useStore, invoke, getPricePerToken, AzureVoiceInfo, AzureDeployment, ModelInfo, OpenRouterCredits, ProviderPreset, LocalModelStatus, InferenceCapabilities, ModelDownloadProgress, SyncBackend, SyncCredentials, SyncStatus, Profiles, PlaybackStatus, McpServer, setDatabasePassphrase, AzureAdCredential, parseHeaders } from "./state"
 import { JSXInternal } from "preact/src/jsx"
 import * as icon from "@tabler/icons-react"
 import md5 from "md5"
@@ -93,6 +92,7 @@ const CodeBlockCopyButton = (props: { content: string }) => {
 const MessageRenderer = (props: { depth: number }) => {
     const role = useStore((s) => s.visibleMessages[props.depth]?.role)
     const bookmarked = useStore((s) => typeof s.visibleMessages[props.depth]?.note === "string")
+    const pinned = useStore((s) => typeof s.visibleMessages[props.depth]?.pinnedAt === "string")
     const status = useStore((s) => s.visibleMessages[props.depth]?.status)
     const content = useStore((s) => s.visibleMessages[props.depth]?.content)
     const id = useStore((s) => s.visibleMessages[props.depth]?.id)
@@ -168,6 +168,10 @@ const MessageRenderer = (props: { depth: number }) => {
                             onClick={() => { api["message.toggleBookmark"](id!) }}>
                             <icon.IconBookmark className="inline stroke-zinc-500 dark:stroke-zinc-300 dark:text-zinc-100" size="1.25em" strokeWidth={1.25} fill={bookmarked ? "currentColor" : "none"} />
                         </span>}
+                        <span title={pinned ? "Unpin (pinned messages are always sent to the model)" : "Pin (pinned messages are always sent to the model)"} class="text-zinc-600 select-none cursor-pointer hover:bg-zinc-200 dark:hover:bg-zinc-600"
+                            onClick={() => { api["message.togglePin"](id!) }}>
+                            <icon.IconPin className="inline stroke-zinc-500 dark:stroke-zinc-300 dark:text-zinc-100" size="1.25em" strokeWidth={1.25} fill={pinned ? "currentColor" : "none"} />
+                        </span>
                         {/* Play audio */}
                         <span title="Text-to-speech" class="text-zinc-600 select-none cursor-pointer hover:bg-zinc-200 dark:hover:bg-zinc-600"
                             onClick={() => { api["message.speak"](id!) }}>
@@ -264,6 +268,7 @@ const ThreadListItem = (props: { i: number }) => {
         render(<>
             <button class="text-gray-800 dark:text-zinc-100 bg-transparent border-none m-0 py-[0.15rem] px-6 text-left text-sm hover:bg-zinc-200 dark:hover:bg-zinc-600 select-none rounded-lg disabled:text-gray-400 [&::backdrop]:bg-transparent focus-within:outline-none" onClick={() => { api["thread.editTitle"](id!) }}>Rename</button>
             <button class="text-gray-800 dark:text-zinc-100 bg-transparent border-none m-0 py-[0.15rem] px-6 text-left text-sm hover:bg-zinc-200 dark:hover:bg-zinc-600 select-none rounded-lg disabled:text-gray-400 [&::backdrop]:bg-transparent focus-within:outline-none" onClick={() => { api["thread.autoTitle"](id!) }}>Regenerate thread name</button>
+            <button class="text-gray-800 dark:text-zinc-100 bg-transparent border-none m-0 py-[0.15rem] px-6 text-left text-sm hover:bg-zinc-200 dark:hover:bg-zinc-600 select-none rounded-lg disabled:text-gray-400 [&::backdrop]:bg-transparent focus-within:outline-none" onClick={() => { invoke("open_chat_window", { conversationId: id! }) }}>Open in new window</button>
             <button class="text-gray-800 dark:text-zinc-100 bg-transparent border-none m-0 py-[0.15rem] px-6 text-left text-sm hover:bg-zinc-200 dark:hover:bg-zinc-600 select-none rounded-lg disabled:text-gray-400 [&::backdrop]:bg-transparent focus-within:outline-none" onClick={() => { api["thread.delete"](id!) }}>Delete</button>
         </>, dialog)
 
@@ -401,6 +406,9 @@ const App = (props: { send?: boolean, prompt?: string, voiceInput?: boolean }) =
         } else if (ctrlOrCmd(ev) && ev.shiftKey && ev.code === "KeyO") {
             ev.preventDefault()
             api["dialog.bookmark"]()
+        } else if (ctrlOrCmd(ev) && ev.shiftKey && ev.code === "KeyM") {
+            ev.preventDefault()
+            api["speaker.openMiniPlayer"]()
         } else if (ctrlOrCmd(ev) && ev.code === "KeyU") {
             ev.preventDefault()
             api["messageInput.speak"]()
@@ -459,6 +467,7 @@ const App = (props: { send?: boolean, prompt?: string, voiceInput?: boolean }) =
         textareaRef.current!.style.height = Math.min(window.innerHeight / 2, textareaRef.current!.scrollHeight) + "px"
     }
     const isSideBarOpen = useStore((s) => s.isSideBarOpen)
+    const imageAttachments = useStore((s) => s.imageAttachments)
 
     const shouldDisplayAPIKeyInputOverride = useStore((s) => s.shouldDisplayAPIKeyInputOverride)
     const shouldDisplayAPIKeyInput = useStore((s) => s.threads.length === 0) || shouldDisplayAPIKeyInputOverride
@@ -546,6 +555,10 @@ const App = (props: { send?: boolean, prompt?: string, voiceInput?: boolean }) =
                         </>}
                         {!isResponseInIntegratedTerminal && <>
                             <div class={"shadow-light dark:shadow-dark rounded-lg bg-white light-3d:bg-opacity-20 light-3d:focus-within:bg-opacity-70 light-3d:transition-colors light-3d-floating-glass relative flex-1 " + (isSideBarOpen ? "" : "ml-16 51rem:ml-0 ") + (reversed ? "dark:bg-zinc-600" : "dark:bg-zinc-700")}>
+                                {imageAttachments.length > 0 && <div class="flex gap-2 px-4 pt-2">
+                                    {imageAttachments.map((v, i) => <img key={i} src={v.dataUrl} class="h-12 rounded cursor-pointer hover:opacity-50" title="Click to remove"
+                                        onClick={() => { useStore.setState((s) => ({ imageAttachments: s.imageAttachments.filter((_, j) => j !== i) })) }} />)}
+                                </div>}
                                 <textarea
                                     id="userPromptTextarea"
                                     ref={textareaRef}
@@ -584,6 +597,7 @@ const App = (props: { send?: boolean, prompt?: string, voiceInput?: boolean }) =
         </div>
         <InputVolumeIndicator />
         <SettingsDialog />
+        <ToolApprovalPrompt />
         <Dialog
             id="contextmenu"
             class="m-0 px-0 py-[0.15rem] absolute left-0 top-0 z-30 flex flex-col bg-zinc-100 dark:bg-zinc-800 outline-gray-200 dark:outline-zinc-600 shadow-lg whitespace-pre rounded-lg [&:not([open])]:hidden [&::backdrop]:bg-transparent"
@@ -591,6 +605,117 @@ const App = (props: { send?: boolean, prompt?: string, voiceInput?: boolean }) =
     </>
 }
 
+/** Asks whether to execute a tool call of the model, showing the code that it runs. */
+const ToolApprovalPrompt = () => {
+    const approval = useStore((s) => s.toolApprovals[0])
+    if (!approval) { return <></> }
+    let code: { language: string, code: string } | null = null
+    try {
+        const args = JSON.parse(approval.arguments)
+        if (approval.name === "run_code" && typeof args.code === "string") { code = { language: `${args.language}`, code: args.code } }
+    } catch { }
+    const answer = (approved: boolean) => {
+        invoke("answer_tool_approval", { id: approval.id, approved })
+        useStore.setState((s) => ({ toolApprovals: s.toolApprovals.filter((v) => v.id !== approval.id) }))
+    }
+    return <div class="fixed inset-0 z-40 flex items-center justify-center bg-black/30">
+        <div class="w-[40rem] max-w-[90vw] rounded-lg bg-white dark:bg-zinc-800 dark:text-zinc-100 shadow-dark p-4">
+            <div class="mb-2">{code ? `The assistant wants to run this ${code.language} program:` : `The assistant wants to call ${approval.name} with:`}</div>
+            <pre class="max-h-[60vh] overflow-auto rounded bg-zinc-100 dark:bg-zinc-900 p-2 text-sm font-mono whitespace-pre-wrap">{code ? code.code : approval.arguments}</pre>
+            <div class="mt-3 flex justify-end gap-2">
+                <button class="rounded border border-neutral-400 text-sm px-3" onClick={() => { answer(false) }}>deny</button>
+                <button class="rounded border border-neutral-400 text-sm px-3 bg-green-600 text-white" onClick={() => { answer(true) }}>{code ? "run" : "allow"}</button>
+            </div>
+        </div>
+    </div>
+}
+
+/** The rows to obtain Azure AD tokens from a service principal or the Azure CLI, or to enter a token by hand. */
+const AzureAdCredentialSettings = () => {
+    const azureAPIKey = useConfigStore((s) => s.azureAPIKey)
+    const [type, setType] = useState<"token" | "clientSecret" | "azureCli">("token")
+    const [tenantId, setTenantId] = useState("")
+    const [clientId, setClientId] = useState("")
+    const [clientSecret, setClientSecret] = useState("")
+    const [status, setStatus] = useState("")
+    const [error, setError] = useState("")
+    useEffect(() => {
+        invoke("get_azure_ad_credential").then((credential) => {
+            if (credential === null) { return }
+            setType(credential.type)
+            if (credential.type === "clientSecret") {
+                setTenantId(credential.tenantId)
+                setClientId(credential.clientId)
+                setStatus("The client secret is stored.")
+            }
+        })
+    }, [])
+    const save = async (credential: AzureAdCredential | null) => {
+        setError("")
+        setStatus("")
+        try {
+            await invoke("set_azure_ad_credential", { credential })
+            setClientSecret("")
+            setStatus(credential === null ? "" : "Saved.")
+        } catch (err) {
+            setError(`${err}`)
+        }
+    }
+    const inputClass = "w-80 shadow-light dark:shadow-dark rounded-lg font-mono px-4 dark:bg-zinc-700 dark:text-zinc-100"
+
+    return <>
+        <tr>
+            <td>Token source</td>
+            <td><select value={type}
+                onChange={(ev) => {
+                    const value = ev.currentTarget.value as typeof type
+                    setType(value)
+                    if (value === "token") { save(null) }
+                    else if (value === "azureCli") { save({ type: "azureCli" }) }
+                }}
+                class="mb-2 px-2 text-zinc-600">
+                <option value="token">Enter a token</option>
+                <option value="clientSecret">Service principal (client secret)</option>
+                <option value="azureCli">Azure CLI (az login)</option>
+            </select></td>
+        </tr>
+        {type === "token" && <tr>
+            <td>Azure Active Directory token</td>
+            <td><input
+                type="password"
+                autocomplete="off"
+                value={azureAPIKey}
+                onChange={(ev) => { useConfigStore.setState({ azureAPIKey: ev.currentTarget.value }) }}
+                class={inputClass}></input></td>
+        </tr>}
+        {type === "clientSecret" && <>
+            <tr>
+                <td>Tenant ID</td>
+                <td><input autocomplete="off" value={tenantId} onChange={(ev) => { setTenantId(ev.currentTarget.value) }} class={"mb-2 " + inputClass}></input></td>
+            </tr>
+            <tr>
+                <td>Client ID</td>
+                <td><input autocomplete="off" value={clientId} onChange={(ev) => { setClientId(ev.currentTarget.value) }} class={"mb-2 " + inputClass}></input></td>
+            </tr>
+            <tr>
+                <td>Client secret</td>
+                <td>
+                    <input type="password" autocomplete="off" value={clientSecret} onChange={(ev) => { setClientSecret(ev.currentTarget.value) }} class={inputClass}></input>
+                    <button class="ml-1 inline rounded border border-neutral-400 text-sm px-3 disabled:bg-zinc-400" disabled={!tenantId || !clientId || !clientSecret}
+                        onClick={() => { save({ type: "clientSecret", tenantId, clientId, clientSecret }) }}>save</button>
+                </td>
+            </tr>
+        </>}
+        {(status || error) && <tr>
+            <td></td>
+            <td>
+                {status && <div class="text-xs text-zinc-500">{status}</div>}
+                {error && <div class="text-xs text-red-500">{error}</div>}
+            </td>
+        </tr>}
+    </>
+}
+
 const APIKeyInputDialog = ({ isSideBarOpen }: { isSideBarOpen: boolean }) => {
     const apiKey = useConfigStore((s) => s.APIKey)
     const azureAPIKey = useConfigStore((s) => s.azureAPIKey)
@@ -600,7 +725,102 @@ const APIKeyInputDialog = ({ isSideBarOpen }: { isSideBarOpen: boolean }) => {
     const hasMessage = useStore((s) => s.visibleMessages.length > 0)
     const openaiProxyAPIKey = useConfigStore((s) => s.openaiProxyAPIKey)
     const openaiProxyUrl = useConfigStore((s) => s.openaiProxyUrl)
+    const openaiProxyHeaders = useConfigStore((s) => s.openaiProxyHeaders)
+    const headersError = useMemo(() => {
+        try {
+            parseHeaders(openaiProxyHeaders)
+            return null
+        } catch (err) {
+            return err instanceof Error ? err.message : `${err}`
+        }
+    }, [openaiProxyHeaders])
+    const openaiProxyPreset = useConfigStore((s) => s.openaiProxyPreset)
+    const [providerPresets, setProviderPresets] = useState<ProviderPreset[]>([])
+    useEffect(() => { invoke("list_provider_presets").then(setProviderPresets).catch(console.error) }, [])
+    const selectedPreset = providerPresets.find((v) => v.id === openaiProxyPreset)
+    const openrouterAPIKey = useConfigStore((s) => s.openrouterAPIKey)
+    const localModelBinaryPath = useConfigStore((s) => s.localModelBinaryPath)
+    const localModelPath = useConfigStore((s) => s.localModelPath)
+    const localModelPort = useConfigStore((s) => s.localModelPort)
+    const [localModelStatus, setLocalModelStatus] = useState<LocalModelStatus | null>(null)
+    useEffect(() => {
+        if (openaiService !== "local") { return }
+        let stop = false
+        const loop = async () => {
+            if (stop) { return }
+            try { setLocalModelStatus(await invoke("local_model_status")) } catch (err) { console.error(err) }
+            setTimeout(loop, 2000)
+        }
+        loop()
+        return () => { stop = true }
+    }, [openaiService])
+    const [capabilities, setCapabilities] = useState<InferenceCapabilities | null>(null)
+    const [downloadUrl, setDownloadUrl] = useState("")
+    const [downloadSha256, setDownloadSha256] = useState("")
+    const [download, setDownload] = useState<{ id: number, progress: ModelDownloadProgress | null, error: string | null } | null>(null)
+    const [downloadedModels, setDownloadedModels] = useState<{ fileName: string, path: string, size: number }[]>([])
+    const refreshDownloadedModels = () => { invoke("list_downloaded_models").then(setDownloadedModels).catch((err) => { console.error(err) }) }
+    useEffect(() => {
+        if (openaiService === "local") { refreshDownloadedModels() }
+    }, [openaiService])
+    useEffect(() => {
+        const unlisten = [
+            event.listen<ModelDownloadProgress>("model-download://progress", (ev) => { setDownload((d) => d?.id === ev.payload.id ? { ...d, progress: ev.payload } : d) }),
+            event.listen<{ id: number, path: string }>("model-download://finished", (ev) => {
+                useConfigStore.setState({ localModelPath: ev.payload.path })
+                setDownload((d) => d?.id === ev.payload.id ? null : d)
+                refreshDownloadedModels()
+            }),
+            event.listen<{ id: number, error: string }>("model-download://failed", (ev) => { setDownload((d) => d?.id === ev.payload.id ? { ...d, error: ev.payload.error } : d) }),
+        ]
+        return () => { unlisten.forEach((v) => v.then((f) => f())) }
+    }, [])
+    const startDownload = () => {
+        invoke("start_model_download", { url: downloadUrl.trim(), sha256: downloadSha256.trim() })
+            .then((id) => { setDownload({ id, progress: null, error: null }) })
+            .catch((err) => { setDownload({ id: 0, progress: null, error: err + "" }) })
+    }
+    const launchLocalModel = () => {
+        invoke("launch_local_model", { binaryPath: localModelBinaryPath.trim(), modelPath: localModelPath.trim() || undefined, port: localModelPort })
+            .then(() => invoke("local_model_status").then(setLocalModelStatus))
+            .catch((err) => { alert(err) })
+    }
+    const openrouterFallbackModels = useConfigStore((s) => s.openrouterFallbackModels)
+    const [openrouterCredits, setOpenrouterCredits] = useState<OpenRouterCredits | null>(null)
+    useEffect(() => {
+        if (openaiService !== "openrouter" || !openrouterAPIKey) { setOpenrouterCredits(null); return }
+        let canceled = false
+        invoke("get_openrouter_credits", { key: openrouterAPIKey })
+            .then((credits) => { if (!canceled) { setOpenrouterCredits(credits) } })
+            .catch((err) => { console.error(err); if (!canceled) { setOpenrouterCredits(null) } })
+        return () => { canceled = true }
+    }, [openaiService, openrouterAPIKey])
     const model = useConfigStore((s) => s.model)
+    const [azureDeployments, setAzureDeployments] = useState<AzureDeployment[]>([])
+    useEffect(() => {
+        if (openaiService !== "azure" || !azureEndpoint.trim() || !azureAPIKey) { setAzureDeployments([]); return }
+        let canceled = false
+        invoke("list_azure_deployments", { endpoint: azureEndpoint, key: azureAPIKey, apiKeyAuthentication: !!azureApiKeyAuthentication })
+            .then((deployments) => { if (!canceled) { setAzureDeployments(deployments) } })
+            .catch((err) => { console.error(err); if (!canceled) { setAzureDeployments([]) } })
+        return () => { canceled = true }
+    }, [openaiService, azureEndpoint, azureAPIKey, azureApiKeyAuthentication])
+    /** Replaces the deployment in the endpoint, keeping its API version. */
+    const selectAzureDeployment = (id: string) => {
+        const url = new URL(azureEndpoint.trim())
+        const apiVersion = url.searchParams.get("api-version") ?? "2023-05-15"
+        useConfigStore.setState({ azureEndpoint: `${url.origin}/openai/deployments/${encodeURIComponent(id)}/completions?api-version=${apiVersion}` })
+    }
+    const azureDeployment = /\/openai\/deployments\/([^/?]+)/.exec(azureEndpoint)?.[1]
+    const [models, setModels] = useState<ModelInfo[]>([])
+    useEffect(() => {
+        if (openaiService === "openai" ? !apiKey : openaiService !== "openrouter") { setModels([]); return }
+        let canceled = false
+        invoke("list_models", openaiService === "openai" ? { provider: "openai", key: apiKey } : { provider: "openrouter", key: "" })
+            .then((models) => { if (!canceled) { setModels(models) } })
+            .catch((err) => { console.error(err); if (!canceled) { setModels([]) } })
+        return () => { canceled = true }
+    }, [openaiService, apiKey])
 
     return <div class={"absolute rounded-lg top-32 left-0 right-0 z-50 text-center w-fit max-w-full m-auto overflow-auto" + (hasMessage ? " bg-white dark:bg-black bg-opacity-40 dark:bg-opacity-25 backdrop-blur shadow-light dark:shadow-dark" : "") + (isSideBarOpen ? "" : " px-16")}>
         <div class="p-8">
@@ -609,6 +829,8 @@ const APIKeyInputDialog = ({ isSideBarOpen }: { isSideBarOpen: boolean }) => {
                     <option value="openai">OpenAI API</option>
                     <option value="openai-proxy">OpenAI API (custom endpoint)</option>
                     <option value="azure">Azure OpenAI Service</option>
+                    <option value="openrouter">OpenRouter</option>
+                    <option value="local">Local model (llama.cpp / llamafile)</option>
                 </select>
             </p>
             {hasMessage && <icon.IconX className="absolute right-3 top-3 cursor-pointer dark:stroke-slate-100" size="1.25em" strokeWidth={1.25} onClick={() => { useStore.setState({ shouldDisplayAPIKeyInputOverride: false }) }} />}
@@ -630,7 +852,17 @@ const APIKeyInputDialog = ({ isSideBarOpen }: { isSideBarOpen: boolean }) => {
                 <table>
                     <tbody class="text-left [&_td]:px-2">
                         <tr>
-                            <td>OpenAI API key</td>
+                            <td>Provider</td>
+                            <td>
+                                <select value={openaiProxyPreset} onChange={(ev) => { useConfigStore.setState({ openaiProxyPreset: ev.currentTarget.value }) }} class="mb-2 px-2 text-zinc-600 bg-zinc-200">
+                                    <option value="">Custom</option>
+                                    {providerPresets.map((v) => <option value={v.id}>{v.name}</option>)}
+                                </select>
+                                {selectedPreset && <a class="cursor-pointer ml-4 text-blue-700 dark:text-blue-300 border-b border-b-blue-700 dark:border-b-blue-300 whitespace-nowrap" onClick={(ev) => { ev.preventDefault(); open(selectedPreset.keysUrl) }}>Get your API key here</a>}
+                            </td>
+                        </tr>
+                        <tr>
+                            <td>API key</td>
                             <td><input
                                 type="password"
                                 autocomplete="off"
@@ -646,7 +878,17 @@ const APIKeyInputDialog = ({ isSideBarOpen }: { isSideBarOpen: boolean }) => {
                                 value={openaiProxyUrl}
                                 onChange={(ev) => { useConfigStore.setState({ openaiProxyUrl: ev.currentTarget.value }) }}
                                 class="mb-2 w-[35rem] shadow-light dark:shadow-dark rounded-lg font-mono px-4 dark:bg-zinc-700 dark:text-zinc-100"
-                                placeholder="https://api.openai.com/v1/chat/completions"></input></td>
+                                placeholder={selectedPreset ? `${selectedPreset.baseUrl}/chat/completions` : "https://api.openai.com/v1/chat/completions"}></input></td>
+                        </tr>
+                        <tr>
+                            <td>Extra headers (JSON)</td>
+                            <td><input
+                                autocomplete="off"
+                                value={openaiProxyHeaders}
+                                onChange={(ev) => { useConfigStore.setState({ openaiProxyHeaders: ev.currentTarget.value }) }}
+                                class="mb-2 w-[35rem] shadow-light dark:shadow-dark rounded-lg font-mono px-4 dark:bg-zinc-700 dark:text-zinc-100"
+                                placeholder='{"Helicone-Auth": "Bearer ..."}'></input>
+                                {headersError && <div class="text-xs text-red-500">{headersError}</div>}</td>
                         </tr>
                     </tbody>
                 </table>
@@ -659,6 +901,110 @@ const APIKeyInputDialog = ({ isSideBarOpen }: { isSideBarOpen: boolean }) => {
                     </p>
                 </p>
             </>}
+            {openaiService === "openrouter" && <>
+                <table>
+                    <tbody class="text-left [&_td]:px-2">
+                        <tr>
+                            <td>OpenRouter API key</td>
+                            <td><input
+                                type="password"
+                                autocomplete="off"
+                                value={openrouterAPIKey}
+                                onChange={(ev) => { useConfigStore.setState({ openrouterAPIKey: ev.currentTarget.value }) }}
+                                class="mb-2 w-80 shadow-light dark:shadow-dark rounded-lg font-mono px-4 dark:bg-zinc-700 dark:text-zinc-100"
+                                placeholder="sk-or-..."></input></td>
+                        </tr>
+                        <tr>
+                            <td>Fallback models</td>
+                            <td><input
+                                autocomplete="off"
+                                value={openrouterFallbackModels}
+                                onChange={(ev) => { useConfigStore.setState({ openrouterFallbackModels: ev.currentTarget.value }) }}
+                                class="mb-2 w-[35rem] shadow-light dark:shadow-dark rounded-lg font-mono px-4 dark:bg-zinc-700 dark:text-zinc-100"
+                                placeholder="anthropic/claude-3.5-sonnet, openai/gpt-4o"></input></td>
+                        </tr>
+                    </tbody>
+                </table>
+                {openrouterCredits && <p class="dark:text-zinc-100">Remaining credits: ${openrouterCredits.remaining.toFixed(2)} (used ${openrouterCredits.totalUsage.toFixed(2)} of ${openrouterCredits.totalCredits.toFixed(2)})</p>}
+                <p>
+                    <a class="cursor-pointer ml-4 text-blue-700 dark:text-blue-300 border-b border-b-blue-700 dark:border-b-blue-300 whitespace-nowrap" onClick={(ev) => { ev.preventDefault(); open("https://openrouter.ai/keys") }}>Get your API key here</a>
+                </p>
+            </>}
+            {openaiService === "local" && <>
+                <table>
+                    <tbody class="text-left [&_td]:px-2">
+                        <tr>
+                            <td>Server</td>
+                            <td><input
+                                autocomplete="off"
+                                value={localModelBinaryPath}
+                                onChange={(ev) => { useConfigStore.setState({ localModelBinaryPath: ev.currentTarget.value }) }}
+                                class="mb-2 w-[35rem] shadow-light dark:shadow-dark rounded-lg font-mono px-4 dark:bg-zinc-700 dark:text-zinc-100"
+                                placeholder="/path/to/llama-server or /path/to/model.llamafile"></input></td>
+                        </tr>
+                        <tr>
+                            <td>Model</td>
+                            <td><input
+                                autocomplete="off"
+                                value={localModelPath}
+                                list="downloaded-models"
+                                onChange={(ev) => { useConfigStore.setState({ localModelPath: ev.currentTarget.value }) }}
+                                class="mb-2 w-[35rem] shadow-light dark:shadow-dark rounded-lg font-mono px-4 dark:bg-zinc-700 dark:text-zinc-100"
+                                placeholder="/path/to/model.gguf (not needed for a llamafile)"></input>
+                                <datalist id="downloaded-models">
+                                    {downloadedModels.map((v) => <option key={v.path} value={v.path}>{v.fileName} ({(v.size / 1024 / 1024 / 1024).toFixed(1)} GiB)</option>)}
+                                </datalist></td>
+                        </tr>
+                        <tr>
+                            <td>Download a model</td>
+                            <td>
+                                <input
+                                    autocomplete="off"
+                                    value={downloadUrl}
+                                    onChange={(ev) => { setDownloadUrl(ev.currentTarget.value) }}
+                                    class="mb-2 w-80 shadow-light dark:shadow-dark rounded-lg font-mono px-4 dark:bg-zinc-700 dark:text-zinc-100"
+                                    placeholder="https://huggingface.co/.../model.gguf"></input>
+                                <input
+                                    autocomplete="off"
+                                    value={downloadSha256}
+                                    onChange={(ev) => { setDownloadSha256(ev.currentTarget.value) }}
+                                    class="mb-2 ml-2 w-48 shadow-light dark:shadow-dark rounded-lg font-mono px-4 dark:bg-zinc-700 dark:text-zinc-100"
+                                    placeholder="SHA-256"></input>
+                                {download && !download.error
+                                    ? <button class="ml-2 px-3 rounded bg-zinc-200 text-zinc-600" onClick={() => { invoke("cancel_model_download", { id: download.id }).catch(console.error) }}>Cancel</button>
+                                    : <button class="ml-2 px-3 rounded bg-zinc-200 text-zinc-600" disabled={!downloadUrl.trim() || !downloadSha256.trim()} onClick={startDownload}>Download</button>}
+                                {download?.progress && !download.error && <p class="dark:text-zinc-100">{download.progress.fileName}: {(download.progress.downloaded / 2 ** 20).toFixed(0)}{download.progress.total !== null && ` / ${(download.progress.total / 2 ** 20).toFixed(0)}`} MiB</p>}
+                                {download?.error && <p class="text-red-600">{download.error}</p>}
+                            </td>
+                        </tr>
+                        <tr>
+                            <td>Port</td>
+                            <td><input
+                                type="number"
+                                min={1}
+                                max={65535}
+                                value={localModelPort}
+                                onChange={(ev) => { useConfigStore.setState({ localModelPort: +ev.currentTarget.value }) }}
+                                class="mb-2 w-32 shadow-light dark:shadow-dark rounded-lg font-mono px-4 dark:bg-zinc-700 dark:text-zinc-100"></input></td>
+                        </tr>
+                    </tbody>
+                </table>
+                <p class="dark:text-zinc-100">
+                    {localModelStatus?.state ?? "stopped"}{localModelStatus?.pid ? ` (pid ${localModelStatus.pid})` : ""}{localModelStatus?.restarts ? `, restarted ${localModelStatus.restarts} times` : ""}
+                    <button class="ml-4 px-3 rounded bg-zinc-200 text-zinc-600" disabled={!localModelBinaryPath.trim()} onClick={launchLocalModel}>{localModelStatus && localModelStatus.state !== "stopped" ? "Restart" : "Launch"}</button>
+                    {localModelStatus && localModelStatus.state !== "stopped" && <button class="ml-2 px-3 rounded bg-zinc-200 text-zinc-600" onClick={() => { invoke("stop_local_model").then(() => invoke("local_model_status").then(setLocalModelStatus)).catch(console.error) }}>Stop</button>}
+                </p>
+                {localModelStatus?.error && <p class="text-red-600">{localModelStatus.error}</p>}
+                {capabilities === null
+                    ? <p><a class="cursor-pointer text-blue-700 dark:text-blue-300 border-b border-b-blue-700 dark:border-b-blue-300" onClick={() => { invoke("probe_inference_capabilities").then(setCapabilities).catch(console.error) }}>Which models can this computer run?</a></p>
+                    : <div class="text-left dark:text-zinc-100">
+                        <p>{capabilities.cpu.brand} ({capabilities.cpu.features.join(", ") || "no SIMD extensions"}), {(capabilities.totalMemory / 2 ** 30).toFixed(1)} GiB RAM{capabilities.gpus.filter((v) => !v.unified).map((v) => `, ${v.name} (${(v.memory / 2 ** 30).toFixed(1)} GiB)`).join("")}</p>
+                        {(["llm", "whisper"] as const).map((kind) => <p>
+                            {kind === "llm" ? "LLM" : "Whisper"}: {capabilities.recommendations.filter((v) => v.kind === kind && v.fit !== "tooLarge").map((v) => `${v.size}${v.fit === "gpu" ? " (GPU)" : v.fit === "slowCpu" ? " (slow)" : ""}`).join(", ") || "none"}
+                        </p>)}
+                    </div>}
+                {localModelStatus && localModelStatus.output.length > 0 && <pre class="text-left text-xs max-h-40 max-w-[45rem] overflow-auto select-text bg-zinc-100 dark:bg-zinc-800 dark:text-zinc-100 p-2 rounded">{localModelStatus.output.slice(-20).join("\n")}</pre>}
+            </>}
             {openaiService === "azure" && <>
                 <table>
                     <tbody class="text-left [&_td]:px-2">
@@ -671,6 +1017,15 @@ const APIKeyInputDialog = ({ isSideBarOpen }: { isSideBarOpen: boolean }) => {
                                 class="mb-2 w-80 shadow-light dark:shadow-dark rounded-lg font-mono px-4 dark:bg-zinc-700 dark:text-zinc-100"
                                 placeholder="endpoint"></input></td>
                         </tr>
+                        {azureDeployments.length > 0 && <tr>
+                            <td>Deployment</td>
+                            <td><select value={azureDeployment ? decodeURIComponent(azureDeployment) : ""}
+                                onChange={(ev) => { selectAzureDeployment(ev.currentTarget.value) }}
+                                class="mb-2 px-2 text-zinc-600">
+                                {!azureDeployment && <option value="">select a deployment</option>}
+                                {azureDeployments.map((v) => <option value={v.id} disabled={v.status !== "succeeded"}>{v.id} ({v.model})</option>)}
+                            </select></td>
+                        </tr>}
                         <tr>
                             <td>Authentication method</td>
                             <td><select value={azureApiKeyAuthentication ? "api-key" : "active-directory"}
@@ -680,15 +1035,15 @@ const APIKeyInputDialog = ({ isSideBarOpen }: { isSideBarOpen: boolean }) => {
                                 <option value="active-directory">Azure Active Directory token</option>
                             </select></td>
                         </tr>
-                        <tr>
-                            <td>{azureApiKeyAuthentication ? "API key" : "Azure Active Directory token"}</td>
+                        {azureApiKeyAuthentication ? <tr>
+                            <td>API key</td>
                             <td><input
                                 type="password"
                                 autocomplete="off"
                                 value={azureAPIKey}
                                 onChange={(ev) => { useConfigStore.setState({ azureAPIKey: ev.currentTarget.value }) }}
                                 class="w-80 shadow-light dark:shadow-dark rounded-lg font-mono px-4 dark:bg-zinc-700 dark:text-zinc-100"></input></td>
-                        </tr>
+                        </tr> : <AzureAdCredentialSettings />}
                     </tbody>
                 </table>
                 <p class="italic text-left mt-8">
@@ -705,9 +1060,13 @@ const APIKeyInputDialog = ({ isSideBarOpen }: { isSideBarOpen: boolean }) => {
                 <input
                     autocomplete="off"
                     value={model}
+                    list="chat-models"
                     onChange={(ev) => { useConfigStore.setState({ model: ev.currentTarget.value }) }}
                     class="mb-2 w-80 shadow-light dark:shadow-dark rounded-lg font-mono px-4 dark:bg-zinc-700 dark:text-zinc-100"
                     placeholder="gpt-3.5-turbo"></input>
+                <datalist id="chat-models">
+                    {models.map((v) => <option value={v.id}>{v.contextWindow} tokens</option>)}
+                </datalist>
                 {model !== "gpt-3.5-turbo" && <p class="opacity-50 hover:opacity-70 cursor-pointer" onClick={() => { api["dialog.budget"]() }}>Adjust budget</p>}
             </p>}
         </div>
@@ -749,9 +1108,19 @@ const SettingsGeneral = () => {
     const searchEngine = useConfigStore((s) => s.searchEngine)
     const showAvatar = useConfigStore((s) => !!s.showAvatar)
     const gravatarEmail = useConfigStore((s) => s.gravatarEmail)
+    const webSearch = useConfigStore((s) => !!s.webSearch)
+    const webSearchProvider = useConfigStore((s) => s.webSearchProvider)
+    const webSearchEndpoint = useConfigStore((s) => s.webSearchEndpoint)
+    const webSearchAPIKey = useConfigStore((s) => s.webSearchAPIKey)
+    const codeInterpreter = useConfigStore((s) => s.codeInterpreter)
+    const codeInterpreterNetwork = useConfigStore((s) => s.codeInterpreterNetwork)
+    const offlineQueue = useConfigStore((s) => !!s.offlineQueue)
+    const rateLimits = useConfigStore((s) => s.rateLimits)
 
     return <table>
         <tbody>
+            <ProfileSettings />
+            <DatabaseEncryptionSettings />
             <tr>
                 <td>Theme</td>
                 <td><select class="ml-2" value={theme} onChange={(ev) => {
@@ -824,10 +1193,278 @@ What's the template URL for Bing?`)
                     }}>help</button>
                 </td>
             </tr>
+            <tr>
+                <td>Web search by the model</td>
+                <td><select class="ml-2" value={webSearch ? webSearchProvider : "off"} onChange={(ev) => {
+                    const value = ev.currentTarget.value
+                    useConfigStore.setState(value === "off" ? { webSearch: 0 } : { webSearch: 1, webSearchProvider: value as any })
+                }}>
+                    <option value="off">off</option>
+                    <option value="searxng">SearxNG</option>
+                    <option value="brave">Brave Search API</option>
+                    <option value="bing">Bing Web Search API</option>
+                </select></td>
+            </tr>
+            {webSearch && webSearchProvider === "searxng" && <tr>
+                <td>SearxNG URL</td>
+                <td><input
+                    type="text"
+                    class="ml-2 w-80"
+                    value={webSearchEndpoint}
+                    onChange={(ev) => { useConfigStore.setState({ webSearchEndpoint: ev.currentTarget.value }) }}
+                    placeholder="https://searx.example.com"></input></td>
+            </tr>}
+            {webSearch && webSearchProvider !== "searxng" && <tr>
+                <td>Search API key</td>
+                <td><input
+                    type="password"
+                    autocomplete="off"
+                    class="ml-2 w-80"
+                    value={webSearchAPIKey}
+                    onChange={(ev) => { useConfigStore.setState({ webSearchAPIKey: ev.currentTarget.value }) }}></input></td>
+            </tr>}
+            <tr>
+                <td>Code execution by the model</td>
+                <td>{isWindows
+                    ? <span class="ml-2 text-zinc-500">not supported on Windows, where the programs cannot be sandboxed</span>
+                    : <><select class="ml-2" value={!codeInterpreter ? "off" : codeInterpreterNetwork ? "network" : "on"} onChange={(ev) => {
+                        const value = ev.currentTarget.value
+                        useConfigStore.setState({ codeInterpreter: value === "off" ? 0 : 1, codeInterpreterNetwork: value === "network" ? 1 : 0 })
+                    }}>
+                        <option value="off">off</option>
+                        <option value="on">Python and JavaScript, without network</option>
+                        <option value="network">Python and JavaScript, with network</option>
+                    </select>
+                        <div class="ml-2 text-xs text-zinc-500">Each program is shown for approval, then runs in a sandbox that can only access its own temporary folder{navigator.platform.startsWith("Linux") ? " (requires bubblewrap)" : ""}.</div></>}</td>
+            </tr>
+            <tr>
+                <td>Queue messages while offline</td>
+                <td><select class="ml-2" value={offlineQueue ? "1" : "0"} onChange={(ev) => {
+                    useConfigStore.setState({ offlineQueue: ev.currentTarget.value === "1" ? 1 : 0 })
+                }}>
+                    <option value="1">on</option>
+                    <option value="0">off</option>
+                </select></td>
+            </tr>
+            <tr>
+                <td>Rate limits (JSON)</td>
+                <td><input
+                    type="text"
+                    class="ml-2 w-80 font-mono"
+                    value={rateLimits}
+                    onChange={(ev) => { useConfigStore.setState({ rateLimits: ev.currentTarget.value }) }}
+                    placeholder='{"openai": {"rpm": 500, "tpm": 30000}}'></input></td>
+            </tr>
+            <McpServerSettings />
+            <SyncSettings />
         </tbody>
     </table>
 }
 
+/** Rows of the settings to add and remove MCP servers, whose tools are given to the model. */
+const McpServerSettings = () => {
+    const [servers, setServers] = useState<McpServer[]>([])
+    const [commandOrUrl, setCommandOrUrl] = useState("")
+    const [error, setError] = useState("")
+    const [adding, setAdding] = useState(false)
+    const mcpTools = useConfigStore((s) => !!s.mcpTools)
+    useEffect(() => {
+        invoke("list_mcp_servers").then(setServers)
+    }, [])
+    const add = async () => {
+        setAdding(true)
+        setError("")
+        try {
+            const server = await invoke("add_mcp_server", { commandOrUrl })
+            setServers([...servers, server])
+            setCommandOrUrl("")
+        } catch (err) {
+            setError(`${err}`)
+        } finally {
+            setAdding(false)
+        }
+    }
+
+    return <>
+        <tr>
+            <td>MCP tools</td>
+            <td>
+                <select class="ml-2" value={mcpTools ? "1" : "0"} onChange={(ev) => {
+                    useConfigStore.setState({ mcpTools: ev.currentTarget.value === "1" ? 1 : 0 })
+                }}>
+                    <option value="1">on</option>
+                    <option value="0">off</option>
+                </select>
+                <span class="ml-2 text-xs text-zinc-500">Give the tools of the servers to OpenAI models. Calls of tools that are not read-only are confirmed first.</span>
+            </td>
+        </tr>
+        {servers.map((server) => <tr key={server.id}>
+            <td>MCP server</td>
+            <td>
+                <span class="ml-2" title={server.commandOrUrl}>{server.name}</span>
+                <span class="ml-2 text-xs text-zinc-500">{server.error ?? `${server.tools.length} tools, ${server.resources.length} resources`}</span>
+                <icon.IconX className="inline ml-2 cursor-pointer" size="1em" onClick={async () => {
+                    await invoke("remove_mcp_server", { id: server.id })
+                    setServers(servers.filter((s) => s.id !== server.id))
+                }} />
+            </td>
+        </tr>)}
+        <tr>
+            <td>Add MCP server</td>
+            <td>
+                <input
+                    type="text"
+                    class="ml-2 w-80"
+                    value={commandOrUrl}
+                    disabled={adding}
+                    onChange={(ev) => { setCommandOrUrl(ev.currentTarget.value) }}
+                    onKeyDown={(ev) => { if (ev.key === "Enter" && commandOrUrl.trim()) { add() } }}
+                    placeholder="npx -y @modelcontextprotocol/server-filesystem /path/to/dir"></input>
+                {error && <div class="ml-2 text-xs text-red-500">{error}</div>}
+            </td>
+        </tr>
+    </>
+}
+
+/** The row to encrypt the database with SQLCipher, to change its passphrase, or to decrypt it. */
+const DatabaseEncryptionSettings = () => {
+    const [encrypted, setEncrypted] = useState(false)
+    const [passphrase, setPassphrase] = useState("")
+    const [error, setError] = useState("")
+    useEffect(() => {
+        invoke("get_database_passphrase").then((v) => { setEncrypted(v !== null) })
+    }, [])
+    const apply = async (passphrase: string | null) => {
+        setError("")
+        try {
+            // The window reloads on database://passphrase-changed.
+            await setDatabasePassphrase(passphrase)
+        } catch (err) {
+            setError(`${err}`)
+        }
+    }
+
+    return <tr>
+        <td>Database encryption</td>
+        <td>
+            <input type="password" autocomplete="off" class="ml-2 w-40" value={passphrase} onChange={(ev) => { setPassphrase(ev.currentTarget.value) }} placeholder="passphrase"></input>
+            <button class="ml-1 inline rounded border border-neutral-400 text-sm px-3 disabled:bg-zinc-400" disabled={!passphrase} onClick={() => { apply(passphrase) }}>{encrypted ? "change passphrase" : "encrypt"}</button>
+            {encrypted && <button class="ml-1 inline rounded border border-neutral-400 text-sm px-3" onClick={() => { apply(null) }}>decrypt</button>}
+            <div class="ml-2 text-xs text-zinc-500">The passphrase is asked on every start and cannot be recovered.</div>
+            {error && <div class="ml-2 text-xs text-red-500">{error}</div>}
+        </td>
+    </tr>
+}
+
+/** The row to switch between the profiles and to create one. */
+const ProfileSettings = () => {
+    const [profiles, setProfiles] = useState<Profiles | null>(null)
+    const [name, setName] = useState("")
+    const [error, setError] = useState("")
+    useEffect(() => {
+        invoke("list_profiles").then(setProfiles)
+    }, [])
+    const switchProfile = (id: string) => {
+        setError("")
+        // The window reloads on profile://switched.
+        invoke("switch_profile", { id }).catch((err) => { setError(`${err}`) })
+    }
+
+    return <tr>
+        <td>Profile</td>
+        <td>
+            <select class="ml-2" value={profiles?.active} onChange={(ev) => { switchProfile(ev.currentTarget.value) }}>
+                {profiles?.profiles.map((v) => <option value={v.id}>{v.name}</option>)}
+            </select>
+            <input type="text" class="ml-2 w-40" value={name} onChange={(ev) => { setName(ev.currentTarget.value) }} placeholder="Work"></input>
+            <button class="ml-1 inline rounded border border-neutral-400 text-sm px-3 disabled:bg-zinc-400" disabled={!name.trim()} onClick={async () => {
+                try {
+                    const profile = await invoke("create_profile", { name })
+                    setName("")
+                    switchProfile(profile.id)
+                } catch (err) {
+                    setError(`${err}`)
+                }
+            }}>new profile</button>
+            <div class="ml-2 text-xs text-zinc-500">Each profile has its own conversations, settings, and API keys.</div>
+            {error && <div class="ml-2 text-xs text-red-500">{error}</div>}
+        </td>
+    </tr>
+}
+
+/** Rows of the settings of the sync of the conversations through WebDAV or S3. */
+const SyncSettings = () => {
+    const [status, setStatus] = useState<SyncStatus | null>(null)
+    const [backend, setBackend] = useState<SyncBackend | null>(null)
+    const [url, setUrl] = useState("")
+    const [region, setRegion] = useState("")
+    const [bucket, setBucket] = useState("")
+    const [user, setUser] = useState("")
+    const [secret, setSecret] = useState("")
+    const [passphrase, setPassphrase] = useState("")
+    const [error, setError] = useState("")
+    useEffect(() => {
+        invoke("get_sync_status").then(([status, backend]) => {
+            setStatus(status)
+            setBackend(backend)
+            if (backend?.type === "webdav") { setUrl(backend.url) }
+            if (backend?.type === "s3") { setUrl(backend.endpoint); setRegion(backend.region); setBucket(backend.bucket) }
+        })
+    }, [])
+    const save = async (enabled: boolean) => {
+        setError("")
+        try {
+            const newBackend: SyncBackend | null = !enabled ? null : backend?.type === "s3" ? { type: "s3", endpoint: url, region, bucket } : { type: "webdav", url }
+            const credentials: SyncCredentials | null = !enabled ? null : newBackend?.type === "s3" ? { accessKeyId: user, secretAccessKey: secret, passphrase } : { username: user, password: secret, passphrase }
+            if (newBackend && credentials) {
+                const check = await invoke("verify_sync_passphrase", { backend: newBackend, credentials })
+                if (check === "invalid") {
+                    setError("The passphrase differs from the one used on the other computers.")
+                    return
+                }
+            }
+            setStatus(await invoke("configure_sync", { backend: newBackend, credentials }))
+            setBackend(newBackend)
+            setSecret("")
+            setPassphrase("")
+        } catch (err) {
+            setError(`${err}`)
+        }
+    }
+
+    return <>
+        <tr>
+            <td>Sync conversations</td>
+            <td><select class="ml-2" value={backend?.type ?? "off"} onChange={(ev) => {
+                const value = ev.currentTarget.value
+                if (value === "off") { save(false) } else { setBackend(value === "s3" ? { type: "s3", endpoint: "", region: "", bucket: "" } : { type: "webdav", url: "" }) }
+            }}>
+                <option value="off">off</option>
+                <option value="webdav">WebDAV</option>
+                <option value="s3">S3-compatible storage</option>
+            </select></td>
+        </tr>
+        {backend && <tr>
+            <td>{backend.type === "s3" ? "S3 endpoint" : "WebDAV folder"}</td>
+            <td>
+                <input type="text" class="ml-2 w-80" value={url} onChange={(ev) => { setUrl(ev.currentTarget.value) }} placeholder={backend.type === "s3" ? "https://s3.us-east-1.amazonaws.com" : "https://cloud.example.com/remote.php/dav/files/me/chatgpt"}></input>
+                {backend.type === "s3" && <>
+                    <input type="text" class="ml-2 w-36" value={region} onChange={(ev) => { setRegion(ev.currentTarget.value) }} placeholder="us-east-1"></input>
+                    <input type="text" class="ml-2 w-40" value={bucket} onChange={(ev) => { setBucket(ev.currentTarget.value) }} placeholder="bucket"></input>
+                </>}
+                <input type="text" class="ml-2 w-40" value={user} onChange={(ev) => { setUser(ev.currentTarget.value) }} placeholder={backend.type === "s3" ? "access key ID" : "username"}></input>
+                <input type="password" autocomplete="off" class="ml-2 w-40" value={secret} onChange={(ev) => { setSecret(ev.currentTarget.value) }} placeholder={backend.type === "s3" ? "secret access key" : "password"}></input>
+                <input type="password" autocomplete="off" class="ml-2 w-40" value={passphrase} onChange={(ev) => { setPassphrase(ev.currentTarget.value) }} placeholder="encryption passphrase"></input>
+                <button class="ml-1 inline rounded border border-neutral-400 text-sm px-3 disabled:bg-zinc-400" disabled={!url.trim() || !passphrase} onClick={() => { save(true) }}>save</button>
+                {status?.configured && <button class="ml-1 inline rounded border border-neutral-400 text-sm px-3" onClick={() => { invoke("sync_now").then(setStatus).catch((err) => { setError(`${err}`) }) }}>sync now</button>}
+                <div class="ml-2 text-xs text-zinc-500">The conversations are encrypted on this computer with the passphrase before they are uploaded, so the storage provider cannot read them. The passphrase must be the same on every computer and cannot be recovered. Deleted messages are not synced.</div>
+                {status?.configured && <div class="ml-2 text-xs text-zinc-500">{status.lastError ?? (status.lastSyncedAt ? `Synced at ${new Date(status.lastSyncedAt * 1000).toLocaleString()}: ${status.pulled} messages received, ${status.pushed} messages stored` : "Not synced yet")}</div>}
+                {error && <div class="ml-2 text-xs text-red-500">{error}</div>}
+            </td>
+        </tr>}
+    </>
+}
+
 const SettingsBookmark = () => {
     type Bookmark = { id: MessageId, content: String, note: String, createdAt: string, modifiedAt: string }
     const [bookmarks, setBookmarks] = useState<Bookmark[]>([])
@@ -849,22 +1486,31 @@ const SettingsBookmark = () => {
 const InputVolumeIndicator = () => {
     const listening = useStore((s) => s.listening)
     const [volume, setVolume] = useState(0)
+    const [clipping, setClipping] = useState(false)
     const [transcribing, setTranscribing] = useState(false)
+    const [interimTranscript, setInterimTranscript] = useState("")
     useEffect(() => {
-        let canceled = false
-        const loop = async () => {
-            if (canceled) { return }
-            const value = await invoke("get_input_loudness")
-            if (value === -1) {
+        if (!listening) { return }
+        setVolume(0)
+        setClipping(false)
+        setTranscribing(false)
+        setInterimTranscript("")
+        const unlistenTranscript = appWindow.listen<{ text: string, isFinal: boolean }>("stt://transcript", ({ payload }) => {
+            setInterimTranscript(payload.text)
+        })
+        const unlisten = appWindow.listen<{ rms: number, peak: number, clipping: boolean }>("mic://loudness", ({ payload }) => {
+            if (payload.rms === -1) {
                 setTranscribing(true)
             } else {
-                setVolume(value * 250)
+                setVolume(Math.min(100, payload.rms * 250))
+                setClipping(payload.clipping)
                 setTranscribing(false)
             }
-            setTimeout(loop, 100)
+        })
+        return () => {
+            unlisten.then((f) => f())
+            unlistenTranscript.then((f) => f())
         }
-        if (listening) { loop() }
-        return () => { canceled = true }
     }, [listening])
     if (!listening) { return <></> }
     return <div class="absolute top-[35%] left-0 right-0 mx-0 text-center z-50 pointer-events-none">
@@ -874,6 +1520,9 @@ const InputVolumeIndicator = () => {
             {transcribing && <div class="dark:text-zinc-100">
                 Transcribing...
             </div>}
+            {interimTranscript && <div class="max-w-md mx-auto mt-4 text-sm text-zinc-500 dark:text-zinc-300">
+                {interimTranscript}
+            </div>}
             {!transcribing && <div class="h-3 w-44 mx-auto mt-4">
                 <svg xmlns="http://www.w3.org/2000/svg" width="100%" height="100%">
                     <defs>
@@ -884,8 +1533,11 @@ const InputVolumeIndicator = () => {
                         <pattern id="pattern_gray" patternUnits="userSpaceOnUse" width="13" height="13" patternTransform="rotate(0)">
                             <line x1="0" y="0" x2="0" y2="13" stroke="#aaaaaa" stroke-width="18" />
                         </pattern>
+                        <pattern id="pattern_red" patternUnits="userSpaceOnUse" width="13" height="13" patternTransform="rotate(0)">
+                            <line x1="0" y="0" x2="0" y2="13" stroke="#b91c1c" stroke-width="18" />
+                        </pattern>
                     </defs>
-                    <rect width={Math.round(volume) + "%"} height="100%" fill="url(#pattern_green)" opacity="1" />
+                    <rect width={Math.round(volume) + "%"} height="100%" fill={clipping ? "url(#pattern_red)" : "url(#pattern_green)"} opacity="1" />
                     <rect x={Math.round(volume) + "%"} width={(100 - Math.round(volume)) + "%"} height="100%" fill="url(#pattern_gray)" opacity="1" />
                 </svg>
             </div>}
@@ -922,8 +1574,14 @@ const SearchResult = () => {
 
 const TokenCounter = (props: { textareaRef: Ref<HTMLTextAreaElement> }) => {
     const [count, setCount] = useState(0)
+    const [contextWindow, setContextWindow] = useState<number | null>(null)
+    const [estimate, setEstimate] = useState(false)
     const model = useConfigStore((s) => s.model)
     const customInstructions = useConfigStore((s) => s.customInstructions)
+    useEffect(() => {
+        invoke("get_context_window", { model }).then(setContextWindow).catch(console.error)
+        invoke("is_token_count_estimate", { model }).then(setEstimate).catch(console.error)
+    }, [model])
     useEffect(() => {
         let stop = false
         const loop = async () => {
@@ -941,26 +1599,29 @@ const TokenCounter = (props: { textareaRef: Ref<HTMLTextAreaElement> }) => {
         loop()
         return () => { stop = true }
     }, [props.textareaRef, model, customInstructions])
-    return <span class="inline-block bg-zinc-300 py-1 px-3 ml-4 mb-2 text-zinc-600 rounded cursor-pointer" onClick={() => { open("https://tiktokenizer.vercel.app") }}>{count}</span>
-}
-
-type AzureVoiceInfo = {
-    Name: string  // 'Microsoft Server Speech Text to Speech Voice (af-ZA, AdriNeural)'
-    DisplayName: string  // 'Adri'
-    LocalName: string  // 'Adri'
-    ShortName: string  // 'af-ZA-AdriNeural'
-    Gender: string  // 'Female'
-    Locale: string  // 'af-ZA'
-    LocaleName: string  // 'Afrikaans (South Africa)'
-    SampleRateHertz: string  // '48000'
-    VoiceType: string  // 'Neural'
-    Status: string  // 'GA'
-    WordsPerMinute: string  // '147'
+    const exceeded = contextWindow !== null && count > contextWindow
+    return <span
+        class={"inline-block py-1 px-3 ml-4 mb-2 rounded cursor-pointer" + (exceeded ? " bg-red-200 text-red-700" : " bg-zinc-300 text-zinc-600")}
+        title={[
+            exceeded && `The conversation exceeds the context window of ${model} (${contextWindow} tokens). The oldest messages will be dropped.`,
+            estimate && `An estimate with the tokenizer of gpt-4. ${model} may count differently.`,
+        ].filter(Boolean).join("\n") || undefined}
+        onClick={() => { open("https://tiktokenizer.vercel.app") }}>
+        {exceeded && <icon.IconAlertTriangle className="inline-block mr-1" size="1em" />}{estimate && "~"}{count}{contextWindow !== null && ` / ${contextWindow}`}
+    </span>
 }
 
 const SettingsSpeechToText = () => {
     const whisperLanguage = useConfigStore((s) => s.whisperLanguage)
     const editVoiceInputBeforeSending = useConfigStore((s) => !!s.editVoiceInputBeforeSending)
+    const wakeWord = useConfigStore((s) => !!s.wakeWord)
+    const wakeWordKeyword = useConfigStore((s) => s.wakeWordKeyword)
+    const wakeWordSensitivity = useConfigStore((s) => s.wakeWordSensitivity)
+    const bargeIn = useConfigStore((s) => s.bargeIn)
+    const ttsMatchDictatedLanguage = useConfigStore((s) => !!s.ttsMatchDictatedLanguage)
+    const dictationTranslate = useConfigStore((s) => !!s.dictationTranslate)
+    const dictationCommands = useConfigStore((s) => !!s.dictationCommands)
+    const dictationCommandPhrases = useConfigStore((s) => s.dictationCommandPhrases)
     return <>
         <h2>Keybindings</h2>
         <ul>
@@ -973,11 +1634,54 @@ const SettingsSpeechToText = () => {
         <p>
             Specify an <a class="cursor-pointer text-blue-500 border-b border-b-blue-500 whitespace-nowrap" onClick={() => { open("https://en.wikipedia.org/wiki/List_of_ISO_639-1_codes") }}>ISO-639-1 language code</a> for improved performance.
         </p>
+        {!whisperLanguage.trim() && <>
+            <select value={ttsMatchDictatedLanguage ? "enabled" : "disabled"} onChange={(ev) => { useConfigStore.setState({ ttsMatchDictatedLanguage: ev.currentTarget.value === "enabled" ? 1 : 0 }) }}>
+                <option value="enabled">reply with a voice of the detected language</option>
+                <option value="disabled">reply with the text-to-speech voice</option>
+            </select>
+            <p>Whisper detects the language when none is specified. Azure and Web Speech API only.</p>
+        </>}
         <h2>Edit text before sending</h2>
         <select value={editVoiceInputBeforeSending ? "enabled" : "disabled"} onChange={(ev) => { useConfigStore.setState({ editVoiceInputBeforeSending: ev.currentTarget.value === "enabled" ? 1 : 0 }) }}>
             <option value="enabled">yes</option>
             <option value="disabled">no</option>
         </select>
+        <h2>Translate into English</h2>
+        <select value={dictationTranslate ? "enabled" : "disabled"} onChange={(ev) => { useConfigStore.setState({ dictationTranslate: ev.currentTarget.value === "enabled" ? 1 : 0 }) }}>
+            <option value="enabled">yes</option>
+            <option value="disabled">no</option>
+        </select>
+        <p>Dictate in any language and insert the English translation. The original transcript is shown briefly. Whisper only.</p>
+        <h2>Editing commands</h2>
+        <select value={dictationCommands ? "enabled" : "disabled"} onChange={(ev) => { useConfigStore.setState({ dictationCommands: ev.currentTarget.value === "enabled" ? 1 : 0 }) }}>
+            <option value="enabled">yes</option>
+            <option value="disabled">no</option>
+        </select>
+        <p>Say "new line", "new paragraph", "delete that", or "send it" to edit the message instead of typing the words. "delete that" and "send it" must be said as a sentence of their own. English, Japanese, German, French, and Spanish are built in.</p>
+        {dictationCommands && <>
+            <textarea class="font-mono" value={dictationCommandPhrases} onChange={(ev) => { useConfigStore.setState({ dictationCommandPhrases: ev.currentTarget.value }) }} placeholder='{"en": {"next line": "newLine"}}'></textarea>
+            <p>Additional phrases per language code. The commands are newLine, newParagraph, deleteLastSentence, and submit.</p>
+        </>}
+        <h2>Speaking over the assistant</h2>
+        <select value={bargeIn} onChange={(ev) => { useConfigStore.setState({ bargeIn: ev.currentTarget.value as any }) }}>
+            <option value="stop">stop the speech when dictation starts</option>
+            <option value="duck">lower the speech while dictating</option>
+            <option value="voice">stop the speech when I start talking</option>
+        </select>
+        <p>While the speech is playing, the microphone is muted when it only hears the speech, so that the assistant's voice is not transcribed.</p>
+        <h2>Wake word</h2>
+        <select value={wakeWord ? "enabled" : "disabled"} onChange={(ev) => { useConfigStore.setState({ wakeWord: ev.currentTarget.value === "enabled" ? 1 : 0 }) }}>
+            <option value="enabled">listen for the wake word</option>
+            <option value="disabled">off</option>
+        </select>
+        <p>
+            While enabled, the microphone stays open and dictation starts when the wake word is heard. The audio is processed on this device only, and nothing is recorded or sent until dictation starts.
+        </p>
+        {wakeWord && <>
+            <input type="text" class="w-80" value={wakeWordKeyword} onChange={(ev) => { useConfigStore.setState({ wakeWordKeyword: ev.currentTarget.value }) }} placeholder="hey_assistant or /path/to/wakeword.rpw"></input>
+            <p>A rustpotter wakeword file (.rpw), or its name in the wake_words directory of the app data directory.</p>
+            <label>Sensitivity <input type="range" min={0} max={1} step={0.05} value={wakeWordSensitivity} onChange={(ev) => { useConfigStore.setState({ wakeWordSensitivity: +ev.currentTarget.value }) }}></input></label>
+        </>}
     </>
 }
 
@@ -1005,11 +1709,12 @@ const TextToSpeechDialog = () => {
     const [voiceList, setVoiceList] = useState<AzureVoiceInfo[]>([])
     const [isPasswordVisible, setIsPasswordVisible] = useState(false)
     const audioFeedback = useConfigStore((s) => s.audioFeedback)
+    const speechSkipCode = useConfigStore((s) => !!s.speechSkipCode)
+    const speechSkipUrls = useConfigStore((s) => !!s.speechSkipUrls)
+    const audioOutputToFile = useConfigStore((s) => !!s.audioOutputToFile)
     const getVoiceList = async () => {
         if (!azureTTSRegion || !/^[a-z0-9_\-]+$/i.test(azureTTSRegion) || !azureTTSResourceKey) { return }
-        const res = await fetch<AzureVoiceInfo[]>(`https://${azureTTSRegion}.tts.speech.microsoft.com/cognitiveservices/voices/list`, { method: "GET", headers: { "Ocp-Apim-Subscription-Key": azureTTSResourceKey } })
-        if (!res.ok) { return }
-        setVoiceList(res.data)
+        setVoiceList(await invoke("list_azure_voices", { region: azureTTSRegion, resourceKey: azureTTSResourceKey }))
     }
     useEffect(() => {
         if (ttsBackend === "web-speech-api" && window.speechSynthesis && window.speechSynthesis.getVoices) {
@@ -1087,6 +1792,10 @@ const TextToSpeechDialog = () => {
                             <button
                                 class="ml-2 inline rounded border border-green-700 dark:border-green-700 text-sm px-3 py-1 text-white bg-green-600 hover:bg-green-500 disabled:bg-zinc-400"
                                 onClick={() => { getVoiceList() }}>Edit</button>
+                            <button
+                                class="ml-2 inline rounded border text-sm px-3 py-1 border-neutral-400 disabled:bg-zinc-400"
+                                disabled={!azureTTSRegion || !azureTTSResourceKey || !azureTTSVoice}
+                                onClick={() => { invoke("preview_azure_voice", { region: azureTTSRegion, resourceKey: azureTTSResourceKey, voice: azureTTSVoice }) }}>Preview</button>
                         </td>
                     </tr>
                 </tbody>
@@ -1145,6 +1854,21 @@ const TextToSpeechDialog = () => {
                 </tbody>
             </table>
         </>}
+        <h2>Code blocks and URLs</h2>
+        <select value={speechSkipCode ? "on" : "off"} onChange={(ev) => { useConfigStore.setState({ speechSkipCode: ev.currentTarget.value === "on" ? 1 : 0 }) }}>
+            <option value="on">say "code omitted" instead of code blocks</option>
+            <option value="off">read code blocks</option>
+        </select>
+        <select class="ml-2" value={speechSkipUrls ? "on" : "off"} onChange={(ev) => { useConfigStore.setState({ speechSkipUrls: ev.currentTarget.value === "on" ? 1 : 0 }) }}>
+            <option value="on">skip URLs</option>
+            <option value="off">read URLs</option>
+        </select>
+        <h2>Output</h2>
+        <select value={audioOutputToFile ? "on" : "off"} onChange={(ev) => { useConfigStore.setState({ audioOutputToFile: ev.currentTarget.value === "on" ? 1 : 0 }) }}>
+            <option value="off">play the speech</option>
+            <option value="on">save the speech to files instead of playing it</option>
+        </select>
+        <p>For quiet places. The speech is saved to the audio_log directory of the app data directory, in a folder per conversation. Azure and pico2wave only.</p>
         <h2>Audio Feedback</h2>
         <select value={audioFeedback ? "on" : "off"} onChange={(ev) => { useConfigStore.setState({ audioFeedback: ev.currentTarget.value === "on" ? 1 : 0 }) }}>
             <option value="on">enabled</option>
@@ -1188,7 +1912,7 @@ WHERE date(timestamp, 'start of month') = date(?, 'start of month')`, [now.toISO
 SELECT
     coalesce(sum(durationMs), 0) as sumMs
 FROM speechToTextUsage
-WHERE date(timestamp, 'start of month') = date(?, 'start of month')`, []))[0]?.sumMs ?? 0) / 1000 / 60)
+WHERE model = 'whisper-1' AND date(timestamp, 'start of month') = date(?, 'start of month')`, []))[0]?.sumMs ?? 0) / 1000 / 60)
         })()
     }, [visible])
 
@@ -1249,12 +1973,13 @@ const RegenerateResponse = () => {
     const reversed = useConfigStore((s) => !!s.reversedView)
     const canRegenerateResponse = useStore((s) => s.visibleMessages.length >= 2 && s.visibleMessages.at(-1)?.role === "assistant")
     const waitingAssistantsResponse = useStore((s) => s.waitingAssistantsResponse.includes(s.visibleMessages.at(-1)?.id as number))
+    const queued = useStore((s) => s.queuedRequests.length > 0)
     if (waitingAssistantsResponse) {
         return <div class={"border border-zinc-200 dark:border-zinc-600 bg-white light-3d:bg-opacity-50 light-3d-floating-glass dark:bg-zinc-700 hover:bg-zinc-100 dark:hover:bg-zinc-600 cursor-pointer w-fit px-3 py-2 rounded-lg absolute left-0 right-0 mx-auto text-center bottom-full text-sm " + (reversed ? "top-full mt-2 h-fit" : "mb-2")} onClick={() => {
             invoke("stop_all_chat_completions")
         }}>
             <icon.IconPlayerStop className="inline mr-2" size="1.125em" strokeWidth={1.25} />
-            Stop generating
+            {queued ? "Queued, will send when online" : "Stop generating"}
         </div>
     }
     if (canRegenerateResponse) {
@@ -1266,9 +1991,78 @@ const RegenerateResponse = () => {
     return <></>
 }
 
+/** The Spotlight-style window toggled with the global shortcut (Ctrl + Shift + Space). */
+const QuickAsk = () => {
+    const [answer, setAnswer] = useState("")
+    const [waiting, setWaiting] = useState(false)
+    return <div class="p-3 h-screen flex flex-col bg-white dark:bg-zinc-800 dark:text-zinc-100">
+        <input autoFocus class="w-full px-3 py-2 rounded border border-zinc-300 dark:border-zinc-600 dark:bg-zinc-700" placeholder="Ask anything" disabled={waiting} onKeyDown={async (ev) => {
+            if (ev.code === "Escape") {
+                await appWindow.close()
+                return
+            }
+            if (ev.code !== "Enter" || ev.currentTarget.value.trim() === "") { return }
+            setWaiting(true)
+            try {
+                const content = await invoke("quick_ask", { prompt: ev.currentTarget.value })
+                setAnswer(content)
+                useStore.getState().ttsQueue.speakText(content, null)
+            } finally {
+                setWaiting(false)
+            }
+        }} />
+        <div class="mt-2 flex-1 overflow-auto"><Markdown content={answer} waiting={waiting} /></div>
+    </div>
+}
+
+/** The always-on-top window that controls the speech. */
+const MiniPlayer = () => {
+    const [status, setStatus] = useState<PlaybackStatus | null>(null)
+    useEffect(() => {
+        const unlisten = event.listen<PlaybackStatus>("audio://playback", ({ payload }) => { setStatus(payload) })
+        return () => { unlisten.then((f) => f()) }
+    }, [])
+    const format = (ms: number) => `${Math.floor(ms / 60000)}:${(Math.floor(ms / 1000) % 60).toString().padStart(2, "0")}`
+    const playing = !!status?.playing
+    const speed = status?.speed ?? 1
+    return <div class="p-3 h-screen flex flex-col justify-center gap-2 bg-white dark:bg-zinc-800 dark:text-zinc-100 select-none">
+        {playing && (status!.sentence || status!.title) && <div class="text-xs truncate" title={status!.sentence ?? ""}>{status!.sentence ?? status!.title}</div>}
+        <div class="flex items-center gap-2 text-xs">
+            <span class="w-10 text-right">{format(playing ? status!.positionMs : 0)}</span>
+            <input type="range" class="flex-1" disabled={!playing} value={playing ? status!.positionMs : 0} max={playing ? status!.durationMs || 1 : 1} onChange={(ev) => { invoke("seek_audio", { ms: +ev.currentTarget.value }) }} />
+            <span class="w-10">{format(playing ? status!.durationMs : 0)}</span>
+        </div>
+        <div class="flex items-center justify-center gap-2">
+            <icon.IconPlayerSkipBack className="cursor-pointer" onClick={() => { invoke("skip_sentence_back") }} />
+            {status?.paused
+                ? <icon.IconPlayerPlay className="cursor-pointer" onClick={() => { invoke("resume_audio") }} />
+                : <icon.IconPlayerPause className="cursor-pointer" onClick={() => { invoke("pause_audio") }} />}
+            <icon.IconPlayerSkipForward className="cursor-pointer" onClick={() => { invoke("skip_sentence_forward") }} />
+            <icon.IconPlayerStop className="cursor-pointer" onClick={() => { event.emit("tray://action", "speaker.stop") }} />
+            <select class="ml-4 text-sm dark:bg-zinc-700" value={speed} onChange={(ev) => { invoke("set_playback_speed", { speed: +ev.currentTarget.value }) }}>
+                {[0.75, 1, 1.25, 1.5, 2].map((v) => <option value={v}>{v}x</option>)}
+            </select>
+        </div>
+    </div>
+}
+
+/** Asks the passphrase of the encrypted database before anything else is rendered. */
+const UnlockScreen = (props: { error: string | null, onSubmit: (passphrase: string) => void }) => {
+    const [passphrase, setPassphrase] = useState("")
+    return <form class="h-screen flex flex-col items-center justify-center gap-2" onSubmit={(ev) => { ev.preventDefault(); props.onSubmit(passphrase) }}>
+        <div>The database is encrypted. Enter its passphrase to open it.</div>
+        <input type="password" autocomplete="off" autofocus class="w-80 shadow-light dark:shadow-dark rounded-lg font-mono px-4 dark:bg-zinc-700 dark:text-zinc-100" value={passphrase} onChange={(ev) => { setPassphrase(ev.currentTarget.value) }}></input>
+        <button type="submit" class="rounded border border-neutral-400 text-sm px-3" disabled={!passphrase}>unlock</button>
+        {props.error && <div class="text-xs text-red-500">{props.error}</div>}
+    </form>
+}
+
 /** The entry point. */
 const main = async () => {
-    await init()
+    await init((error) => new Promise((resolve) => {
+        render(<UnlockScreen error={error} onSubmit={resolve} />, document.body)
+    }))
+    render(null, document.body)
 
     // Theme
     const applyTheme = () => {
@@ -1299,6 +2093,16 @@ const main = async () => {
     // zoom
     document.documentElement.style.fontSize = Math.round(1.2 ** useConfigStore.getState().zoomLevel * 100) + "%"
 
+    if (location.hash === "#quick-ask") {
+        render(<QuickAsk />, document.body)
+        return
+    }
+
+    if (location.hash === "#mini-player") {
+        render(<MiniPlayer />, document.body)
+        return
+    }
+
     const args = (await getMatches()).args
     render(<App prompt={typeof args.prompt?.value === "string" ? args.prompt.value : undefined} send={args.send?.occurrences === 1} voiceInput={args["voice-input"]?.occurrences === 1} />, document.body)
 }